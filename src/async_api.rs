@@ -0,0 +1,556 @@
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    future::Future,
+    io::{self, Seek, SeekFrom, Write},
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    sync::Mutex,
+};
+
+use crate::{
+    basic_api::Cogtainer,
+    container_file::{ContainerFooter, ContainerHeader, DefragmentBudget, FileOffset, Identifier},
+    error::CogtainerError,
+    storage::Storage,
+};
+
+/// Files an `AsyncCogtainer` can be kept in, such as `tokio::fs::File`.
+pub trait AsyncFile: AsyncRead + AsyncWrite + AsyncSeek + Unpin + Send + 'static {}
+impl<T: AsyncRead + AsyncWrite + AsyncSeek + Unpin + Send + 'static> AsyncFile for T {}
+
+/// An async handle to a container in an async file.
+///
+/// The header, footer and block layout are handled by the same code as `Cogtainer`. That code
+/// works on the parts of the file read so far: when an operation needs a part that hasn't been
+/// read, it is undone, the part is read from the file and the operation runs again. The writes
+/// of an operation are made to the file once it is done, in order, with `flush()` where
+/// `Cogtainer` syncs. (`tokio::fs::File::flush` doesn't sync to disk.)
+///
+/// Clones share the container. Operations run one at a time, as they share the file's cursor.
+#[derive(Debug)]
+pub struct AsyncCogtainer<F> {
+    pub(crate) inner: Arc<Mutex<Inner<F>>>,
+}
+impl<F> Clone for AsyncCogtainer<F> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<F: AsyncFile> AsyncCogtainer<F> {
+    /// Opens an existing Cogtainer file. See `Cogtainer::open`.
+    pub async fn open(mut file: F) -> Result<Self, CogtainerError> {
+        let mut staged = StagedFile::new(&mut file).await?;
+        let (header, footer) = loop {
+            let result = ContainerHeader::read_from(&staged).and_then(|mut header| {
+                let footer = ContainerFooter::read_latest(&staged, &mut header)?;
+                Ok((header, footer))
+            });
+            if !staged.fetch(&mut file).await? {
+                break result?;
+            }
+        };
+        Ok(Self::new(
+            file,
+            Cogtainer::from_parts(staged, header, footer),
+        ))
+    }
+    /// Creates a new Cogtainer in the given file. See `Cogtainer::create`.
+    pub async fn create(mut file: F) -> Result<Self, CogtainerError> {
+        let mut container = Cogtainer::create(StagedFile::new(&mut file).await?)?;
+        container.file.drain(&mut file).await?;
+        Ok(Self::new(file, container))
+    }
+    fn new(file: F, container: Cogtainer<StagedFile>) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner { file, container })),
+        }
+    }
+
+    /// Returns the file if this is the last handle to the container, otherwise gives the handle
+    /// back. Every change has been written to the file by then.
+    pub fn try_unwrap(self) -> Result<F, Self> {
+        match Arc::try_unwrap(self.inner) {
+            Ok(inner) => Ok(inner.into_inner().file),
+            Err(inner) => Err(Self { inner }),
+        }
+    }
+
+    /// Get the metadata and data of a specific block.
+    /// Returns Err if block not found.
+    pub async fn get_block(
+        &self,
+        identifier: &Identifier,
+    ) -> Result<(rmpv::Value, Vec<u8>), CogtainerError> {
+        let mut inner = self.inner.lock().await;
+        inner
+            .read(|c| {
+                let (metadata, data) = c.get_block(identifier)?;
+                Ok((metadata.clone(), data))
+            })
+            .await
+    }
+
+    /// Inserts a block with the given unique identifier.
+    /// If a block already exists with the given identifier, it will be replaced.
+    pub async fn insert_block(
+        &self,
+        identifier: &Identifier,
+        metadata: rmpv::Value,
+        data: Vec<u8>,
+    ) -> Result<(), CogtainerError> {
+        let mut inner = self.inner.lock().await;
+        inner
+            .write(|c| {
+                c.insert_block(identifier, metadata.clone(), &data)?;
+                Ok(())
+            })
+            .await
+    }
+
+    /// Deletes the specified block.
+    ///
+    /// (Requires a call to flush() to persist changes)
+    pub async fn delete_block(&self, identifier: &Identifier) -> Result<(), CogtainerError> {
+        let mut inner = self.inner.lock().await;
+        inner
+            .write(|c| {
+                c.delete_block(identifier)?;
+                Ok(())
+            })
+            .await
+    }
+
+    /// Saves all pending changes to the file.
+    pub async fn flush(&self) -> Result<(), CogtainerError> {
+        let mut inner = self.inner.lock().await;
+        inner.write(|c| c.flush().map(|_| ())).await
+    }
+
+    /// Moves every block as close to the start of the file as possible. See
+    /// `Cogtainer::defragment`. Runs one block at a time, so an operation that is run again
+    /// only repeats that block.
+    pub async fn defragment(&self) -> Result<(), CogtainerError> {
+        let mut inner = self.inner.lock().await;
+        inner.container.footer.defragmented_to = FileOffset(0);
+        while !inner
+            .write(|c| c.defragment_step(DefragmentBudget::Blocks(1)))
+            .await?
+            .done
+        {}
+        Ok(())
+    }
+
+    /// Gets an internal block as if it were an async file.
+    pub fn get_block_as_file(&self, identifier: &Identifier) -> AsyncInternalFile<F> {
+        AsyncInternalFile {
+            container: self.clone(),
+            block_id: identifier.clone(),
+            cursor: 0,
+            seek: None,
+            busy: None,
+        }
+    }
+}
+
+/// The async file and the container working on what has been read of it.
+#[derive(Debug)]
+pub(crate) struct Inner<F> {
+    file: F,
+    pub(crate) container: Cogtainer<StagedFile>,
+}
+impl<F: AsyncFile> Inner<F> {
+    /// Runs an operation that doesn't change the container, reading what it needs first.
+    async fn read<T>(
+        &mut self,
+        mut op: impl FnMut(&Cogtainer<StagedFile>) -> Result<T, CogtainerError>,
+    ) -> Result<T, CogtainerError> {
+        loop {
+            let result = op(&self.container);
+            if !self.container.file.fetch(&mut self.file).await? {
+                return result;
+            }
+        }
+    }
+
+    /// Runs a change, undoing and repeating it until it doesn't need any more of the file, then
+    /// writes it to the file.
+    async fn write<T>(
+        &mut self,
+        mut op: impl FnMut(&mut Cogtainer<StagedFile>) -> Result<T, CogtainerError>,
+    ) -> Result<T, CogtainerError> {
+        loop {
+            let header = self.container.header.clone();
+            let footer = self.container.footer.clone();
+            let result = op(&mut self.container);
+            if self.container.file.missing.get().is_none() {
+                self.container.file.drain(&mut self.file).await?;
+                return result;
+            }
+            self.container.file.discard();
+            self.container.header = header;
+            self.container.footer = footer;
+            self.container.file.fetch(&mut self.file).await?;
+        }
+    }
+}
+
+/// The view of an async file that the sync container code works on: the pages read so far, and
+/// the writes made since they were last written to the file.
+///
+/// Reading a page that hasn't been read fails with `WouldBlock`, and the page is noted in
+/// `missing` for `fetch` to read.
+#[derive(Debug, Default)]
+pub(crate) struct StagedFile {
+    /// Pages by index, as in the file. The last page of the file may be short.
+    pages: HashMap<u64, Vec<u8>>,
+    /// The length of the file.
+    file_len: u64,
+    /// The length with the staged writes.
+    len: u64,
+    staged: Vec<Staged>,
+    /// The first and end index of the pages needed but not read.
+    missing: Cell<Option<(u64, u64)>>,
+}
+#[derive(Debug)]
+enum Staged {
+    Write(u64, Vec<u8>),
+    Sync,
+}
+impl StagedFile {
+    const PAGE_SIZE: u64 = 64 * 1024;
+    /// Read pages are dropped once there are more than this many, between operations.
+    const CACHED_PAGES: usize = 256;
+
+    async fn new<F: AsyncFile>(file: &mut F) -> io::Result<Self> {
+        let file_len = file.seek(SeekFrom::End(0)).await?;
+        Ok(Self {
+            file_len,
+            len: file_len,
+            ..Self::default()
+        })
+    }
+
+    /// Reads the pages that were missing. Returns false if none were.
+    async fn fetch<F: AsyncFile>(&mut self, file: &mut F) -> io::Result<bool> {
+        let Some((first, end)) = self.missing.take() else {
+            return Ok(false);
+        };
+        file.seek(SeekFrom::Start(first * Self::PAGE_SIZE)).await?;
+        for page in first..end {
+            let len = Self::PAGE_SIZE.min(self.file_len - page * Self::PAGE_SIZE);
+            let mut data = vec![0u8; len as usize];
+            file.read_exact(&mut data).await?;
+            self.pages.insert(page, data);
+        }
+        Ok(true)
+    }
+
+    /// Drops the writes of an operation that is run again.
+    fn discard(&mut self) {
+        self.staged.clear();
+        self.len = self.file_len;
+    }
+
+    /// Writes the staged writes to the file in order, flushing the file where they were synced.
+    async fn drain<F: AsyncFile>(&mut self, file: &mut F) -> io::Result<()> {
+        if self.pages.len() > Self::CACHED_PAGES {
+            self.pages.clear();
+        }
+        if self.staged.is_empty() {
+            return Ok(());
+        }
+        let result = self.write_staged(file).await;
+        if result.is_err() {
+            // unknown how much was written
+            self.pages.clear();
+        }
+        self.staged.clear();
+        self.len = self.file_len;
+        result
+    }
+    async fn write_staged<F: AsyncFile>(&mut self, file: &mut F) -> io::Result<()> {
+        for staged in &self.staged {
+            let (offset, data) = match staged {
+                Staged::Write(offset, data) => (*offset, data),
+                Staged::Sync => {
+                    file.flush().await?;
+                    continue;
+                }
+            };
+            file.seek(SeekFrom::Start(offset)).await?;
+            file.write_all(data).await?;
+            let end = offset + data.len() as u64;
+            self.file_len = self.file_len.max(end);
+            for page in offset / Self::PAGE_SIZE..end.div_ceil(Self::PAGE_SIZE) {
+                if let Some(cached) = self.pages.get_mut(&page) {
+                    let start = page * Self::PAGE_SIZE;
+                    let written = (end.min(start + Self::PAGE_SIZE) - start) as usize;
+                    if Vec::len(cached) < written {
+                        cached.resize(written, 0);
+                    }
+                    overlay(cached, start, data, offset);
+                }
+            }
+        }
+        file.flush().await
+    }
+
+    fn note_missing(&self, first: u64, end: u64) {
+        let missing = match self.missing.get() {
+            Some((f, e)) => (f.min(first), e.max(end)),
+            None => (first, end),
+        };
+        self.missing.set(Some(missing));
+    }
+}
+impl Storage for StagedFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        if offset >= self.len {
+            return Ok(0);
+        }
+        let len = (self.len - offset).min(buf.len() as u64);
+        let buf = &mut buf[..len as usize];
+        let end = offset + len;
+        buf.fill(0);
+        if offset < self.file_len {
+            let pages = offset / Self::PAGE_SIZE..end.min(self.file_len).div_ceil(Self::PAGE_SIZE);
+            let mut unread = pages.clone().filter(|page| !self.pages.contains_key(page));
+            if let Some(first) = unread.next() {
+                let last = unread.last().unwrap_or(first);
+                self.note_missing(first, last + 1);
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            for page in pages {
+                overlay(buf, offset, &self.pages[&page], page * Self::PAGE_SIZE);
+            }
+        }
+        for staged in &self.staged {
+            if let Staged::Write(at, data) = staged {
+                overlay(buf, offset, data, *at);
+            }
+        }
+        Ok(buf.len())
+    }
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        self.staged.push(Staged::Write(offset, buf.to_vec()));
+        self.len = self.len.max(offset + buf.len() as u64);
+        Ok(buf.len())
+    }
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.len)
+    }
+    fn set_len(&mut self, _len: u64) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "async files can't be resized",
+        ))
+    }
+    fn sync(&mut self) -> io::Result<()> {
+        self.staged.push(Staged::Sync);
+        Ok(())
+    }
+}
+
+/// Copies the part of `data` (which belongs at `at`) that overlaps `buf` (which belongs at
+/// `offset`) into `buf`.
+fn overlay(buf: &mut [u8], offset: u64, data: &[u8], at: u64) {
+    let start = offset.max(at);
+    let end = (offset + buf.len() as u64).min(at + data.len() as u64);
+    if start < end {
+        buf[(start - offset) as usize..(end - offset) as usize]
+            .copy_from_slice(&data[(start - at) as usize..(end - at) as usize]);
+    }
+}
+
+/// The result of an operation run by an `AsyncInternalFile`.
+enum Done {
+    Read(Vec<u8>),
+    Wrote(usize),
+    Sought(u64),
+    Flushed,
+}
+
+type Operation = Pin<Box<dyn Future<Output = io::Result<Done>> + Send>>;
+
+/// The async counterpart of `InternalFile`: a block with an `AsyncRead`, `AsyncWrite` and
+/// `AsyncSeek` API. Operations run one at a time.
+pub struct AsyncInternalFile<F> {
+    container: AsyncCogtainer<F>,
+    block_id: Identifier,
+
+    cursor: u64,
+    /// Set by `start_seek`, run by `poll_complete`.
+    seek: Option<SeekFrom>,
+    /// The operation in progress, if any.
+    busy: Option<Operation>,
+}
+impl<F: AsyncFile> AsyncInternalFile<F> {
+    /// Starts `op` (given the container, the block and the cursor) unless an operation is
+    /// already running, then polls whichever one is. The result may belong to an earlier
+    /// operation whose future was dropped, so callers must check which it is.
+    fn poll_op(
+        &mut self,
+        cx: &mut Context<'_>,
+        op: impl FnOnce(AsyncCogtainer<F>, Identifier, u64) -> Operation,
+    ) -> Poll<io::Result<Done>> {
+        let operation = self
+            .busy
+            .get_or_insert_with(|| op(self.container.clone(), self.block_id.clone(), self.cursor));
+        let result = ready!(operation.as_mut().poll(cx));
+        self.busy = None;
+        Poll::Ready(result)
+    }
+
+    /// Applies the result of an operation whose future was dropped before it finished.
+    fn finish(&mut self, done: Done) {
+        match done {
+            Done::Wrote(len) => self.cursor += len as u64,
+            Done::Sought(position) => self.cursor = position,
+            Done::Read(_) | Done::Flushed => {}
+        }
+    }
+
+    fn poll_flush_op(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            let done = ready!(self.poll_op(cx, |container, _, _| {
+                Box::pin(async move {
+                    container.flush().await?;
+                    Ok(Done::Flushed)
+                })
+            }))?;
+            match done {
+                Done::Flushed => return Poll::Ready(Ok(())),
+                done => self.finish(done),
+            }
+        }
+    }
+}
+impl<F: AsyncFile> AsyncRead for AsyncInternalFile<F> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let me = self.get_mut();
+        let len = buf.remaining();
+        loop {
+            let done = ready!(me.poll_op(cx, move |container, block_id, cursor| {
+                Box::pin(async move {
+                    let mut inner = container.inner.lock().await;
+                    let data = inner
+                        .read(|c| {
+                            let mut data = vec![0u8; len];
+                            let read = c
+                                .footer
+                                .get_block_slice(&c.file, &block_id, cursor, &mut data)?;
+                            data.truncate(read as usize);
+                            Ok(data)
+                        })
+                        .await?;
+                    Ok(Done::Read(data))
+                })
+            }))?;
+            match done {
+                Done::Read(data) => {
+                    // the buffer may have shrunk since the read started
+                    let len = data.len().min(buf.remaining());
+                    buf.put_slice(&data[..len]);
+                    me.cursor += len as u64;
+                    return Poll::Ready(Ok(()));
+                }
+                done => me.finish(done),
+            }
+        }
+    }
+}
+impl<F: AsyncFile> AsyncWrite for AsyncInternalFile<F> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let me = self.get_mut();
+        let data = buf.to_vec();
+        loop {
+            let data = data.clone();
+            let done = ready!(me.poll_op(cx, move |container, block_id, cursor| {
+                Box::pin(async move {
+                    let mut inner = container.inner.lock().await;
+                    let wrote = inner
+                        .write(|c| {
+                            let mut file = c.get_block_as_file(&block_id);
+                            file.seek(SeekFrom::Start(cursor))?;
+                            Ok(file.write(&data)?)
+                        })
+                        .await?;
+                    Ok(Done::Wrote(wrote))
+                })
+            }))?;
+            match done {
+                Done::Wrote(len) => {
+                    me.cursor += len as u64;
+                    return Poll::Ready(Ok(len));
+                }
+                done => me.finish(done),
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_flush_op(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_flush_op(cx)
+    }
+}
+impl<F: AsyncFile> AsyncSeek for AsyncInternalFile<F> {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let me = self.get_mut();
+        if me.busy.is_some() {
+            return Err(io::Error::other(
+                "other file operation is pending, call poll_complete before start_seek",
+            ));
+        }
+        me.seek = Some(position);
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        let me = self.get_mut();
+        let Some(position) = me.seek else {
+            if me.busy.is_none() {
+                return Poll::Ready(Ok(me.cursor));
+            }
+            // finish the abandoned operation first, so the cursor is up to date
+            let done = ready!(me.poll_op(cx, |_, _, _| Box::pin(async { Ok(Done::Flushed) })))?;
+            me.finish(done);
+            return Poll::Ready(Ok(me.cursor));
+        };
+        let done = ready!(me.poll_op(cx, move |container, block_id, cursor| {
+            Box::pin(async move {
+                let mut inner = container.inner.lock().await;
+                let position = inner
+                    .write(|c| {
+                        let mut file = c.get_block_as_file(&block_id);
+                        file.seek(SeekFrom::Start(cursor))?;
+                        Ok(file.seek(position)?)
+                    })
+                    .await?;
+                Ok(Done::Sought(position))
+            })
+        }));
+        me.seek = None;
+        me.finish(done?);
+        Poll::Ready(Ok(me.cursor))
+    }
+}
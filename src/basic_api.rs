@@ -1,48 +1,195 @@
 use std::{
     collections::HashMap,
-    io::{Read, Seek, Write},
+    io::{Read, Write},
 };
 
 use flate2::Compression;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 use crate::{
+    block_reader::BlockReader,
+    block_writer::BlockWriter,
     container_file::{
-        BlockDescriptor, ContainerFooter, ContainerHeader, Identifier, OverallocationPolicy,
+        scrub, AllocationStrategy, BlockDescriptor, BlockMap, ChecksumAlgorithm,
+        ChecksumGranularity, ContainerFooter, ContainerHeader, DefragmentBudget,
+        DefragmentProgress, DurabilityMode, FileOffset, Identifier, OverallocationPolicy,
+        ScrubMode, VerifyReport,
     },
     error::CogtainerError,
     internal_file::InternalFile,
-    traits::Truncate,
+    storage::Storage,
+    traits::{Sparse, Truncate},
+    transaction::Transaction,
 };
 
+type FlushFn<F> = fn(&mut Cogtainer<F>) -> Result<&mut Cogtainer<F>, CogtainerError>;
+pub(crate) type TruncateFn<F> = fn(&mut F, u64) -> std::io::Result<()>;
+pub(crate) type PunchFn<F> = fn(&mut F, u64, u64) -> std::io::Result<()>;
+
 #[derive(Debug)]
 pub struct Cogtainer<F> {
-    pub(crate) file: F,
+    pub(crate) file: FileSlot<F>,
     pub(crate) header: ContainerHeader,
     pub(crate) footer: ContainerFooter,
 
     pub(crate) overallocation_policy: OverallocationPolicy,
+
+    /// Set when write-back mode is enabled, so that dropping the container can write the footer
+    /// (`Drop` can't require `F: Write`).
+    pub(crate) flush_on_drop: Option<FlushFn<F>>,
+    /// Set when the file is cut off after the footer on every flush (`flush()` can't require
+    /// `F: Truncate`).
+    pub(crate) truncate_on_flush: Option<TruncateFn<F>>,
+    /// Set when empty space is punched out of the file on every flush (`flush()` can't require
+    /// `F: Sparse`).
+    pub(crate) punch_on_flush: Option<PunchFn<F>>,
 }
 //#[cfg(test)]
 impl<F> Cogtainer<F> {
     pub fn get_inner_file(&mut self) -> &mut F {
         &mut self.file
     }
+
+    /// Returns the underlying file.
+    /// In write-back mode, pending changes are written first, the same as when dropping.
+    pub fn into_inner(mut self) -> Result<F, CogtainerError> {
+        self.write_back()?;
+        Ok(self.file.0.take().expect("the file is only taken here"))
+    }
+
+    /// Writes pending changes if the container is in write-back mode.
+    fn write_back(&mut self) -> Result<(), CogtainerError> {
+        match self.flush_on_drop {
+            Some(flush) if self.file.0.is_some() && self.footer.is_dirty() => {
+                flush(self).map(|_| ())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+/// The container's file. It is only empty once `into_inner` has taken it, which tells `Drop`
+/// there is nothing left to write back.
+#[derive(Debug)]
+pub(crate) struct FileSlot<F>(Option<F>);
+impl<F> FileSlot<F> {
+    pub(crate) fn new(file: F) -> Self {
+        Self(Some(file))
+    }
+}
+impl<F> std::ops::Deref for FileSlot<F> {
+    type Target = F;
+    fn deref(&self) -> &F {
+        self.0.as_ref().expect("the file was taken by into_inner")
+    }
+}
+impl<F> std::ops::DerefMut for FileSlot<F> {
+    fn deref_mut(&mut self) -> &mut F {
+        self.0.as_mut().expect("the file was taken by into_inner")
+    }
+}
+impl<F: Storage> Storage for FileSlot<F> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        (**self).read_at(buf, offset)
+    }
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> std::io::Result<usize> {
+        (**self).write_at(buf, offset)
+    }
+    fn len(&self) -> std::io::Result<u64> {
+        (**self).len()
+    }
+    fn set_len(&mut self, len: u64) -> std::io::Result<()> {
+        (**self).set_len(len)
+    }
+    fn sync(&mut self) -> std::io::Result<()> {
+        (**self).sync()
+    }
+    fn preallocate(&mut self, offset: u64, len: u64) -> std::io::Result<()> {
+        (**self).preallocate(offset, len)
+    }
+    fn freed_space_reusable(&mut self) -> bool {
+        (**self).freed_space_reusable()
+    }
 }
 
-impl<F: Seek + Read> Cogtainer<F> {
-    pub fn open(mut file: F) -> Result<Self, CogtainerError> {
+impl<F> Drop for Cogtainer<F> {
+    fn drop(&mut self) {
+        // errors can't be reported from drop, call flush() to see them
+        let _ = self.write_back();
+    }
+}
+
+impl<F: Storage> Cogtainer<F> {
+    /// Opens an existing Cogtainer file.
+    /// If the newest footer is damaged (for example the process died while committing it), the
+    /// previously committed footer is used instead.
+    /// If a transaction was interrupted after its journal was committed, the journal is replayed.
+    pub fn open(file: F) -> Result<Self, CogtainerError> {
         // check format and header for compatibility before opening.
-        let header = ContainerHeader::read_from(&mut file)?;
-        let footer = ContainerFooter::read_from(&mut file, &header)?;
-        Ok(Self {
-            file,
+        let mut header = ContainerHeader::read_from(&file)?;
+        let footer = ContainerFooter::read_latest(&file, &mut header)?;
+        Ok(Self::from_parts(file, header, footer))
+    }
+    /// A container with the default settings, for a header and footer read from or written to
+    /// `file`.
+    pub(crate) fn from_parts(file: F, header: ContainerHeader, footer: ContainerFooter) -> Self {
+        Self {
+            file: FileSlot::new(file),
             header,
             footer,
             overallocation_policy: OverallocationPolicy::default(),
+            flush_on_drop: None,
+            truncate_on_flush: None,
+            punch_on_flush: None,
+        }
+    }
+
+    /// The same container over another handle to its file, which `map` makes from the file and
+    /// its truncate and punch hooks, returning the hooks for the new handle. Pending changes are
+    /// kept, not written.
+    pub(crate) fn map_file<G: Storage>(
+        mut self,
+        map: impl FnOnce(
+            F,
+            Option<TruncateFn<F>>,
+            Option<PunchFn<F>>,
+        )
+            -> Result<(G, Option<TruncateFn<G>>, Option<PunchFn<G>>), CogtainerError>,
+    ) -> Result<Cogtainer<G>, CogtainerError> {
+        let file = self.file.0.take().expect("the file is only taken here");
+        let (file, truncate_on_flush, punch_on_flush) =
+            map(file, self.truncate_on_flush, self.punch_on_flush)?;
+        Ok(Cogtainer {
+            file: FileSlot::new(file),
+            header: self.header.clone(),
+            footer: self.footer.clone(),
+            overallocation_policy: self.overallocation_policy,
+            flush_on_drop: self
+                .flush_on_drop
+                .map(|_| Cogtainer::<G>::flush as FlushFn<G>),
+            truncate_on_flush,
+            punch_on_flush,
         })
     }
 
+    /// Picks up changes committed by another process: rereads the header, and reloads the footer
+    /// if its checksum or generation changed. Returns true if the footer was reloaded.
+    ///
+    /// Meant for readers, changes made here that haven't been committed are lost. Settings that
+    /// aren't stored in the file are kept.
+    pub fn refresh(&mut self) -> Result<bool, CogtainerError> {
+        let mut header = ContainerHeader::read_from(&self.file)?;
+        if header.generation == self.header.generation
+            && header.footer_checksum == self.header.footer_checksum
+        {
+            return Ok(false);
+        }
+        let mut footer = ContainerFooter::read_latest(&self.file, &mut header)?;
+        footer.keep_settings(&self.footer);
+        self.header = header;
+        self.footer = footer;
+        Ok(true)
+    }
+
     /// Returns the actual used size of the data in this container, from the header to the end of the footer.
     /// This can be used to truncate files after defragementing.
     pub fn file_length(&self) -> u64 {
@@ -53,51 +200,292 @@ impl<F: Seek + Read> Cogtainer<F> {
         &self.footer.metadata
     }
 
-    /// Return a list of all occupied block identifiers with their metadata
-    pub fn get_blocks_list(&self) -> &HashMap<Identifier, BlockDescriptor> {
-        &self.footer.blocks
+    /// Return a list of all occupied block identifiers with their metadata.
+    /// With a paged index, every page is read first, and a page that can't be read is an error
+    /// (see `verify()`).
+    pub fn get_blocks_list(&self) -> Result<&BlockMap, CogtainerError> {
+        self.footer.load_all_blocks(&self.file)?;
+        Ok(&self.footer.blocks)
     }
 
     /// Get the data of a specific block.
     /// Returns Err if block not found.
     pub fn get_block(
-        &mut self,
+        &self,
         identifier: &Identifier,
     ) -> Result<(&rmpv::Value, Vec<u8>), CogtainerError> {
-        self.footer.get_block(&mut self.file, identifier)
+        self.footer.get_block(&self.file, identifier)
+    }
+
+    /// Returns a reader that streams the block from the file instead of reading it into memory.
+    /// The checksum is verified once the block has been read to the end.
+    pub fn block_reader(
+        &self,
+        identifier: &Identifier,
+    ) -> Result<BlockReader<'_, F>, CogtainerError> {
+        self.footer.load_block(&self.file, identifier)?;
+        let descriptor = self
+            .footer
+            .blocks
+            .get(identifier)
+            .ok_or_else(|| CogtainerError::BlockNotFound(identifier.clone()))?;
+        Ok(BlockReader::new(
+            &self.file,
+            identifier.clone(),
+            descriptor,
+            self.footer.checksum_algorithm,
+        ))
+    }
+
+    /// Checks every block checksum and the consistency of the block and empty space lists.
+    /// Reports problems instead of stopping at the first one. See `repair()` to fix them.
+    pub fn verify(&self) -> VerifyReport {
+        self.footer.verify(&self.file, &self.header)
+    }
+
+    /// Blocks that `repair()` removed because their data was damaged.
+    pub fn get_quarantined_blocks(&self) -> &HashMap<Identifier, BlockDescriptor> {
+        &self.footer.quarantine
+    }
+}
+impl<F: Storage + AsRef<[u8]>> Cogtainer<F> {
+    /// Like `get_block`, but returns the data as a slice of the file (such as a memory map)
+    /// instead of copying it. The checksum is verified on every call.
+    pub fn get_block_ref(
+        &self,
+        identifier: &Identifier,
+    ) -> Result<(&rmpv::Value, &[u8]), CogtainerError> {
+        self.footer.get_block_ref(self.file.as_ref(), identifier)
+    }
+}
+impl<'a> Cogtainer<&'a [u8]> {
+    /// Opens a container held in memory, such as one embedded with `include_bytes!`. Blocks can
+    /// be read without copying with `get_block_ref`. Changes fail with
+    /// `ErrorKind::PermissionDenied`.
+    pub fn from_slice(data: &'a [u8]) -> Result<Self, CogtainerError> {
+        Self::open(data)
+    }
+}
+#[cfg(feature = "bytes")]
+impl Cogtainer<bytes::Bytes> {
+    /// Opens a container held in a `Bytes` buffer. Changes fail with
+    /// `ErrorKind::PermissionDenied`.
+    pub fn from_bytes(data: bytes::Bytes) -> Result<Self, CogtainerError> {
+        Self::open(data)
+    }
+    /// Like `get_block_ref`, but returns the data as a `Bytes` sharing the container's buffer,
+    /// which can outlive the container.
+    pub fn get_block_bytes(
+        &self,
+        identifier: &Identifier,
+    ) -> Result<(&rmpv::Value, bytes::Bytes), CogtainerError> {
+        let (metadata, data) = self.get_block_ref(identifier)?;
+        Ok((metadata, self.file.slice_ref(data)))
+    }
+}
+#[cfg(feature = "mmap")]
+impl Cogtainer<memmap2::Mmap> {
+    /// Maps the file at `path` read-only and opens it. Blocks can then be read without copying
+    /// with `get_block_ref`. Changes fail with `ErrorKind::PermissionDenied`.
+    ///
+    /// As with any memory map, the file must not be changed (by this or any other process)
+    /// while it is open.
+    pub fn open_mmap(path: impl AsRef<std::path::Path>) -> Result<Self, CogtainerError> {
+        let file = std::fs::File::open(path)?;
+        // SAFETY: the map is only ever read, and the caller keeps the file unchanged (see above).
+        let map = unsafe { memmap2::Mmap::map(&file)? };
+        Self::open(map)
     }
 }
 
-impl<F: Seek + Write> Cogtainer<F> {
+impl<F: Storage> Cogtainer<F> {
     /// Creates a new Cogtainer file, initializing the header and footer
-    pub fn create(mut file: F) -> Result<Self, CogtainerError> {
-        let (header, footer) = ContainerHeader::create(&mut file)?;
-        Ok(Self {
-            file,
-            header,
-            footer,
-            overallocation_policy: OverallocationPolicy::default(),
-        })
+    pub fn create(file: F) -> Result<Self, CogtainerError> {
+        Self::create_with_checksum(file, ChecksumAlgorithm::default())
+    }
+    /// Creates a new Cogtainer file whose blocks and footers are checksummed with the given
+    /// algorithm. The algorithm is recorded in the header, `open` picks it up from there.
+    pub fn create_with_checksum(
+        mut file: F,
+        checksum_algorithm: ChecksumAlgorithm,
+    ) -> Result<Self, CogtainerError> {
+        let (header, footer) =
+            ContainerHeader::create_with_checksum(&mut file, checksum_algorithm)?;
+        Ok(Self::from_parts(file, header, footer))
     }
     /// Configure optional overallocation to decrease chance that updating a block will require moving the footer and growing the file.
     pub fn set_overallocation_policy(&mut self, policy: OverallocationPolicy) -> &mut Self {
         self.overallocation_policy = policy;
         self
     }
+    /// Configure the boundary block data starts on, for example 4096 for page-aligned blocks that
+    /// can be memory mapped or read with direct I/O. Applies to blocks written from now on; the
+    /// padding skipped to align a block stays available as empty space. 0 or 1 disables alignment.
+    pub fn set_block_alignment(&mut self, alignment: u64) -> &mut Self {
+        self.footer.block_alignment = alignment;
+        self
+    }
+    /// Configure how free space is reused when blocks are inserted or grown. The default,
+    /// `AllocationStrategy::FirstFit`, takes the lowest hole that fits. `defragment()` always uses
+    /// first-fit.
+    pub fn set_allocation_strategy(&mut self, strategy: AllocationStrategy) -> &mut Self {
+        self.footer.allocation_strategy = strategy;
+        self
+    }
+    /// Configure whether new blocks are written with an inline header (identifier, length and
+    /// checksum), which lets `salvage()` find them if every footer is lost.
+    /// Existing blocks are unaffected. The setting is stored in the file with the next footer.
+    pub fn set_inline_block_headers(&mut self, enabled: bool) -> &mut Self {
+        if enabled {
+            self.header.flags |= ContainerHeader::FLAG_INLINE_BLOCK_HEADERS;
+        } else {
+            self.header.flags &= !ContainerHeader::FLAG_INLINE_BLOCK_HEADERS;
+        }
+        self
+    }
+    /// Configure when changes are written to the file.
+    ///
+    /// The default, `DurabilityMode::WriteThrough`, commits the footer after every change.
+    /// `DurabilityMode::WriteBack` defers that until `flush()`, the container is dropped, or a
+    /// number of changes has accumulated, which makes bulk imports much faster.
+    pub fn set_durability_mode(&mut self, mode: DurabilityMode) -> &mut Self {
+        self.footer.durability = mode;
+        self.flush_on_drop = match mode {
+            DurabilityMode::WriteThrough => None,
+            DurabilityMode::WriteBack { .. } => Some(Self::flush),
+        };
+        self
+    }
+    /// Configure how blocks written from now on are checksummed. Existing blocks keep their
+    /// checksums until they are rewritten.
+    ///
+    /// With `ChecksumGranularity::Chunks`, reads through `InternalFile`, `BlockReader` and
+    /// `get_block_slice` only verify the chunks they touch, and in-place writes only rehash the
+    /// chunks they change.
+    pub fn set_checksum_granularity(&mut self, granularity: ChecksumGranularity) -> &mut Self {
+        self.footer.checksum_granularity = granularity;
+        self
+    }
+    /// Configure secure delete: space freed by deleting, replacing, moving or shrinking blocks is
+    /// overwritten (with zeros or random bytes) when the change is committed, before it can be
+    /// handed out again. In `DurabilityMode::WriteBack`, and for deletes in either mode, that is
+    /// on the next `flush()`. Use `scrub_free_space()` for space freed before this was set.
+    pub fn set_secure_delete(&mut self, mode: ScrubMode) -> &mut Self {
+        self.footer.scrub_mode = mode;
+        self
+    }
+    /// Configure a paged block index, for containers with too many blocks to read the whole
+    /// block list on open and rewrite it on every commit. The list is split into pages of up to
+    /// `page_size` blocks, stored in the data region. Opening only reads the list of pages, a page
+    /// is read the first time a block on it is needed, and only pages that changed are rewritten
+    /// on commit. 0 (the default) stores the whole list in the footer.
+    ///
+    /// Every page is rewritten on the next commit. The setting is stored in the file, which can't
+    /// be opened by versions without paged indexes while it is on.
+    pub fn set_index_page_size(&mut self, page_size: usize) -> Result<&mut Self, CogtainerError> {
+        if page_size == self.footer.blocks.page_size() {
+            return Ok(self);
+        }
+        self.footer.load_all_blocks(&self.file)?;
+        self.footer.blocks.set_page_size(page_size);
+        self.footer
+            .record_change(&mut self.file, &mut self.header)?;
+        Ok(self)
+    }
     /// Flush any pending changes to the file and flush the file
     pub fn flush(&mut self) -> Result<&mut Self, CogtainerError> {
-        self.footer.write_to(&mut self.file, &mut self.header)?;
-        self.file.flush()?;
+        self.footer.persist(&mut self.file, &mut self.header)?;
+        self.file.sync()?;
+        if let Some(truncate) = self.truncate_on_flush {
+            truncate(&mut self.file, self.header.file_length())?;
+        }
+        if let Some(punch) = self.punch_on_flush {
+            self.punch_empty_space(punch)?;
+        }
+        Ok(self)
+    }
+    /// Punches out the whole pages of the holes freed since the last flush. Pages a freed range
+    /// only shares with an older part of its hole are punched as well. Stops quietly if the file
+    /// system can't.
+    fn punch_empty_space(&mut self, punch: PunchFn<F>) -> Result<(), CogtainerError> {
+        const PAGE: u64 = 4096;
+        let freed = self.footer.unpunched.replace(vec![]).unwrap_or_default();
+        let mut pages = vec![];
+        for (offset, len) in freed {
+            for (hole, hole_end) in self.footer.holes_overlapping(offset, len) {
+                let start = hole.0.next_multiple_of(PAGE).max(offset.0 / PAGE * PAGE);
+                let end =
+                    (hole_end.0 / PAGE * PAGE).min(offset.end_offset(len).0.next_multiple_of(PAGE));
+                if end > start {
+                    pages.push((start, end));
+                }
+            }
+        }
+        // freed ranges next to each other share pages, which are punched once
+        pages.sort_unstable();
+        let mut merged: Vec<(u64, u64)> = vec![];
+        for (start, end) in pages {
+            match merged.last_mut() {
+                Some((_, last_end)) if start <= *last_end => *last_end = (*last_end).max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        for (start, end) in merged {
+            match punch(&mut self.file, start, end - start) {
+                Err(err) if err.kind() == std::io::ErrorKind::Unsupported => break,
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+    /// Flushes, then overwrites all empty space, and everything in the file after the footer
+    /// (such as old footers, which still list deleted blocks), using the secure delete mode or
+    /// zeros if secure delete is off.
+    ///
+    /// This also overwrites the previously committed footer, the fallback if the latest one is
+    /// damaged, unless the data has grown over it since.
+    pub fn scrub_free_space(&mut self) -> Result<&mut Self, CogtainerError> {
+        self.flush()?;
+        let mode = match self.footer.scrub_mode {
+            ScrubMode::Off => ScrubMode::Zeros,
+            mode => mode,
+        };
+        for (offset, len) in &self.footer.empty_space {
+            scrub(&mut self.file, *offset, *len, mode)?;
+        }
+        let end = self.file.len()?;
+        let tail = self.header.file_length();
+        if end > tail {
+            scrub(&mut self.file, FileOffset(tail), end - tail, mode)?;
+        }
+        self.file.sync()?;
         Ok(self)
     }
 
     /// Updates container-wide metadata
     pub fn set_metadata(&mut self, value: rmpv::Value) -> Result<&mut Self, CogtainerError> {
         self.footer.metadata = value;
-
-        self.flush()
+        self.footer
+            .record_change(&mut self.file, &mut self.header)?;
+        if self.footer.durability == DurabilityMode::WriteThrough {
+            self.file.sync()?;
+        }
+        Ok(self)
     }
 
+    /// Delete the specified block.
+    /// (Requires a call to flush() to persist changes)
+    pub fn delete_block(&mut self, identifier: &Identifier) -> Result<&mut Self, CogtainerError> {
+        self.footer.load_block(&self.file, identifier)?;
+        self.footer.delete_block(identifier)?;
+        // in write-through mode deletes still wait for flush()
+        if self.footer.durability != DurabilityMode::WriteThrough {
+            self.footer.write_if_due(&mut self.file, &mut self.header)?;
+        }
+        Ok(self)
+    }
+}
+impl<F: Storage> Cogtainer<F> {
     /// Inserts a block with the given unique identifier.
     /// If a block already exists with the given identifier, it will be replaced.
     ///
@@ -118,8 +506,82 @@ impl<F: Seek + Write> Cogtainer<F> {
         )?;
         Ok(self)
     }
+    /// Inserts a block like `insert_block`, with its data starting on a multiple of `alignment`
+    /// bytes regardless of the container-wide setting (see `set_block_alignment`).
+    pub fn insert_block_aligned(
+        &mut self,
+        identifier: &Identifier,
+        metadata: rmpv::Value,
+        data: &[u8],
+        alignment: u64,
+    ) -> Result<&mut Self, CogtainerError> {
+        let container_alignment = std::mem::replace(&mut self.footer.block_alignment, alignment);
+        let result = self.insert_block(identifier, metadata, data).map(|_| ());
+        self.footer.block_alignment = container_alignment;
+        result.map(|_| self)
+    }
+
+    /// Fixes what `verify()` finds as far as possible, and writes the repaired footer.
+    /// Corrupt blocks are moved to the quarantine list (see `get_quarantined_blocks()`), and the
+    /// empty space list is rebuilt from the remaining blocks.
+    ///
+    /// Returns the problems found before repairing.
+    pub fn repair(&mut self) -> Result<VerifyReport, CogtainerError> {
+        let report = self.footer.repair(&self.file, &mut self.header);
+        self.flush()?;
+        Ok(report)
+    }
+
+    /// Forgets the quarantined blocks, making the space they occupied available again.
+    pub fn clear_quarantine(&mut self) -> Result<&mut Self, CogtainerError> {
+        self.footer.load_all_blocks(&self.file)?;
+        self.footer.clear_quarantine(&self.header);
+        self.footer.write_if_due(&mut self.file, &mut self.header)?;
+        Ok(self)
+    }
+
+    /// Rebuilds a container whose footers are all lost, from the inline block headers in the file.
+    /// See `ContainerFooter::salvage()` for what can be recovered.
+    ///
+    /// The rebuilt footer is written past the end of the file, so no existing data is overwritten.
+    pub fn salvage(mut file: F) -> Result<Self, CogtainerError> {
+        let (mut header, mut footer) = ContainerFooter::salvage(&file)?;
+        let data_end = header.footer_offset;
+        header.footer_offset = FileOffset(file.len()?).max(data_end);
+        footer.persist(&mut file, &mut header)?;
+        file.sync()?;
+        header.footer_offset = data_end;
+        Ok(Self {
+            file: FileSlot::new(file),
+            header,
+            footer,
+            overallocation_policy: OverallocationPolicy::default(),
+            flush_on_drop: None,
+            truncate_on_flush: None,
+            punch_on_flush: None,
+        })
+    }
+
+    /// Returns a writer that streams data into a new block, which replaces any existing block with
+    /// the given identifier once `finish()` is called on it.
+    /// Unlike `get_block_as_file()`, the block is never held in memory.
+    pub fn block_writer(
+        &mut self,
+        identifier: &Identifier,
+        metadata: rmpv::Value,
+    ) -> Result<BlockWriter<'_, F>, CogtainerError> {
+        BlockWriter::new(self, identifier.clone(), metadata)
+    }
+
+    /// Starts a transaction: a set of changes that are applied together, or not at all.
+    /// Any pending changes (such as deleted blocks) are flushed first.
+    pub fn transaction(&mut self) -> Result<Transaction<'_, F>, CogtainerError> {
+        self.flush()?;
+        Ok(Transaction::new(self))
+    }
 
     /// Adds the given block (or replaces it if it already exists).
+    #[allow(dead_code)]
     pub(crate) fn insert_block_at(
         &mut self,
         identifier: &Identifier,
@@ -135,115 +597,173 @@ impl<F: Seek + Write> Cogtainer<F> {
             data,
         )
     }
-    /// Delete the specified block.
-    /// (Requires a call to flush() to persist changes)
-    pub fn delete_block(&mut self, identifier: &Identifier) -> Result<&mut Self, CogtainerError> {
-        self.footer.delete_block(identifier)?;
-        Ok(self)
-    }
 }
-impl<F: Seek + Read + Write + Truncate> Cogtainer<F> {
+impl<F: Storage + Truncate> Cogtainer<F> {
+    /// Cut the file off after the footer on every `flush()`, so the space freed at the end of
+    /// the data (such as by deleting the last blocks) goes back to the file system.
+    ///
+    /// The previously committed footer, the fallback if the latest one is damaged, is usually
+    /// cut off as well.
+    pub fn set_truncate_on_flush(&mut self, enabled: bool) -> &mut Self {
+        self.truncate_on_flush = enabled.then_some(F::truncate as TruncateFn<F>);
+        self
+    }
+    /// Defragments (see `defragment`), then cuts the file off after the footer.
     pub fn defragment_then_truncate(&mut self) -> Result<&mut Self, CogtainerError> {
         self.defragment()?;
+        // the file ends after the footer that follows the data, which must be the committed one
+        if self.header.committed_footer().offset != self.header.footer_offset {
+            self.flush()?;
+        }
         let length = self.file_length();
-        if let Err(_err) = self.file.truncate(length) {
-            return Err(CogtainerError::IOError(std::io::Error::other(
-                "Unable to truncate file",
-            )));
+        self.file.truncate(length)?;
+        Ok(self)
+    }
+}
+impl<F: Storage + Sparse> Cogtainer<F> {
+    /// Give the pages of empty space back to the file system on every `flush()`, so large holes
+    /// don't take up disk space, without having to defragment. The file length doesn't change.
+    /// Each flush punches the space freed since the previous one; the next flush after enabling
+    /// this punches all empty space. Growth at the end is preallocated 1 MiB ahead, unless
+    /// `preallocate()` set another distance.
+    ///
+    /// Like secure delete, this zeroes the previously committed footer if it is in empty space.
+    pub fn set_punch_holes(&mut self, enabled: bool) -> &mut Self {
+        self.punch_on_flush = enabled.then_some(F::punch_hole as PunchFn<F>);
+        self.footer.unpunched = enabled.then(|| {
+            self.footer
+                .empty_space
+                .iter()
+                .map(|(offset, len)| (*offset, *len))
+                .chain(self.footer.empty_space.released())
+                .collect()
+        });
+        self
+    }
+}
+impl<F: Storage> Cogtainer<F> {
+    /// Reserves disk space for `len` bytes of growth at the end of the data, where new blocks
+    /// and the footer go, such as before a bulk import. From then on, whenever the data grows
+    /// past the reserved space, another `len` bytes past the new end are reserved. 0 stops that.
+    pub fn preallocate(&mut self, len: u64) -> Result<&mut Self, CogtainerError> {
+        let data_end = self.header.footer_offset;
+        if len > 0 {
+            self.file.preallocate(data_end.0, len)?;
         }
+        self.footer.preallocation = len;
+        self.footer.preallocated_to = data_end.end_offset(len);
         Ok(self)
     }
 }
 #[cfg(feature = "full")]
-impl<F: Seek + Read + Write> Cogtainer<F> {
+impl<F: Storage> Cogtainer<F> {
     /// Consolidates all blocks to remove all empty space.
     ///
-    /// 1. If there is no empty space, returns success
-    /// 2. Loop
-    /// 2.1. Consolidate and sort empty space list
-    /// 2.2. Get the first empty block
-    /// 2.3. Get the next used block following the selected empty block. If none found, break.
-    /// 2.4. Write the block into the start of the empty space.
-    /// 2.5. Add the leftover to the empty list
-    /// At this point, all blocks are consolidated, followed by one empty block, followed by the footer
-    /// 3. Move the footer to the empty location.
+    /// Runs `defragment_step` without a budget: every block that has empty space before it is
+    /// moved into the lowest hole it fits in (or slid down into the hole right before it), then
+    /// the footer is moved to right after the last block.
     ///
     /// Note: does not truncate the end of the file.
+    /// Note: moved blocks are aligned to the container-wide block alignment, and the padding this
+    /// needs is left as empty space.
+    /// Note: index pages (see `set_index_page_size`) aren't moved down, they are rewritten after
+    /// the data, which can leave some empty space before them.
     pub fn defragment(&mut self) -> Result<&mut Self, CogtainerError> {
-        if self.footer.empty_space.is_empty() {
-            return Ok(self);
+        self.footer.defragmented_to = FileOffset(0);
+        self.defragment_step(DefragmentBudget::Blocks(usize::MAX))?;
+        Ok(self)
+    }
+    /// Does part of the work of `defragment`, moving blocks until the budget is used up, and
+    /// commits the footer. Call it repeatedly (such as from an idle loop) until the returned
+    /// progress is `done`. Other changes may be made between steps.
+    ///
+    /// The container is valid and committed after every step, and a crash during a step leaves
+    /// the previously committed state intact.
+    pub fn defragment_step(
+        &mut self,
+        budget: DefragmentBudget,
+    ) -> Result<DefragmentProgress, CogtainerError> {
+        self.footer.load_all_blocks(&self.file)?;
+        // blocks must move into the lowest hole they fit in, whatever the configured strategy
+        let strategy = std::mem::take(&mut self.footer.allocation_strategy);
+        // and index pages must stay out of those holes
+        self.footer.index_pages_at_end = true;
+        let result = self.defragment_first_fit(budget).and_then(|progress| {
+            if self.footer.is_dirty() {
+                self.footer.persist(&mut self.file, &mut self.header)?;
+            }
+            Ok(progress)
+        });
+        self.footer.allocation_strategy = strategy;
+        self.footer.index_pages_at_end = false;
+        let mut progress = result?;
+        self.file.sync()?;
+        progress.empty_space = self.footer.empty_space.values().sum();
+        Ok(progress)
+    }
+    fn defragment_first_fit(
+        &mut self,
+        budget: DefragmentBudget,
+    ) -> Result<DefragmentProgress, CogtainerError> {
+        let mut progress = DefragmentProgress::default();
+        // start from a committed footer, so the space freed below is all that it still uses
+        if self.footer.is_dirty() || self.footer.empty_space.released().next().is_some() {
+            self.footer.persist(&mut self.file, &mut self.header)?;
         }
-
-        loop {
-            // ensure all free space blocks are consolidated and sorted
-            self.footer.consolidate_empty_space();
-
-            // ensure all blocks are sorted.
-            // let mut blocks: Vec<_> = self.footer.blocks.iter().collect();
-            // blocks.sort_by(|a, b| a.1.file_offset.cmp(&b.1.file_offset));
-
-            // let (empty_offset, empty_len) = match self.footer.empty_space.first_entry() {
-            //     Some(e) => e.remove_entry(),
-            //     None => {
-            //         // there is no empty space
-            //         return Ok(self);
-            //     }
-            // };
-            let (empty_offset, _empty_len) = match self.footer.empty_space.iter().next() {
-                Some(e) => e,
-                None => {
-                    // there is no empty space
-                    return Ok(self);
-                }
+        let mut freed = vec![];
+        let mut blocks = self.footer.blocks_by_offset();
+        // empty space before `defragmented_to` is alignment padding that can't be filled
+        while let Some((&empty_offset, _)) = self
+            .footer
+            .empty_space
+            .range(self.footer.defragmented_to..)
+            .next()
+        {
+            // the first block after the empty space
+            let Some((&block_offset, block_id)) = blocks.range(empty_offset..).next() else {
+                break;
             };
-            // Get the first occupied block after empty_offset
-            let mut found_block_id = None;
-            let mut found_block_offset = None;
-            for (block_id, block_desc) in self.footer.blocks.iter() {
-                // if the occupied block is *after* the empty space (note that accounting for length is unnecessary since blocks don't overlap)
-                if empty_offset < &block_desc.file_offset {
-                    // if this is the first found block, or if the block is closer than the previous closest, record it
-                    if found_block_offset.is_none()
-                        || block_desc.file_offset < found_block_offset.unwrap()
-                    {
-                        found_block_id = Some(block_id.clone());
-                        found_block_offset = Some(block_desc.file_offset);
-                    }
-                }
+            let block_id = block_id.clone();
+            let descriptor = &self.footer.blocks[&block_id];
+            let (length, block_end) = (
+                descriptor.header_length + descriptor.used_length,
+                block_offset.end_offset(descriptor.allocated_length),
+            );
+            if !budget.allows(progress.blocks_moved, progress.bytes_moved, length) {
+                return Ok(progress);
             }
-            // Move the block
-            if let Some(block_id) = found_block_id {
-                let (metadata, data) = self.get_block(&block_id)?;
-                let metadata = metadata.clone();
-                // deleting the block automatically consolidates and sorts free space
-                self.delete_block(&block_id)?;
-                // inserting always goes in the first empty block the data will fit, which since we
-                // just deleted and consolidated the space previously occupied by this block will move
-                // it closer to the start of the file.
-                self.insert_block(&block_id, metadata, data.as_slice())?;
-            } else {
-                // There are no more blocks after the empty space, so proceed to moving the footer
-                break;
+            let moved = self.footer.move_block_down(
+                &mut self.file,
+                &mut self.header,
+                &block_id,
+                &mut freed,
+            )?;
+            blocks.remove(&block_offset);
+            let descriptor = &self.footer.blocks[&block_id];
+            if descriptor.allocated_length > 0 {
+                blocks.insert(descriptor.file_offset, block_id);
             }
-        }
-        // no more blocks after the empty space
-        let (empty_offset, _empty_len) = match self.footer.empty_space.first_entry() {
-            Some(e) => e.remove_entry(),
-            None => {
-                // there is no empty space
-                return Ok(self);
+            match moved {
+                Some(bytes) => {
+                    progress.blocks_moved += 1;
+                    progress.bytes_moved += bytes;
+                }
+                None => self.footer.defragmented_to = block_end,
             }
-        };
-        // move the footer
-        self.header.footer_offset = empty_offset;
-        // handles the new offset, recalculates checksum and length
-        self.footer.write_to(&mut self.file, &mut self.header)?;
+        }
 
-        Ok(self)
+        // no more blocks after the last empty space, so the footer can move down into it
+        progress.done = true;
+        self.footer.defragmented_to = FileOffset(0);
+        if self.footer.trailing_empty_space(&self.header).is_some() {
+            // commits, then moves the footer down
+            self.footer.persist(&mut self.file, &mut self.header)?;
+        }
+        Ok(progress)
     }
 
     /// Gets an internal block as if it were a file
-    pub fn get_block_as_file(&mut self, identifier: &Identifier) -> InternalFile<F> {
+    pub fn get_block_as_file(&mut self, identifier: &Identifier) -> InternalFile<'_, F> {
         InternalFile::new(self, identifier.clone())
     }
 }
@@ -261,7 +781,7 @@ impl BlockCompression {
             Self::Gzip(level) => {
                 let mut encoder =
                     flate2::write::GzEncoder::new(Vec::new(), Compression::new(*level));
-                encoder.write(data.as_slice())?;
+                encoder.write_all(data.as_slice())?;
 
                 Ok(encoder.finish()?)
             }
@@ -289,7 +809,7 @@ pub struct BlockHeader<T> {
 }
 
 #[cfg(feature = "full")]
-impl<F: Seek + Read> Cogtainer<F> {
+impl<F: Storage> Cogtainer<F> {
     pub fn get_metadata_as<T: DeserializeOwned>(&self) -> Result<T, CogtainerError> {
         let metadata = self.get_container_metadata();
         let metadata = rmpv::ext::from_value(metadata.clone())?;
@@ -299,7 +819,7 @@ impl<F: Seek + Read> Cogtainer<F> {
     /// Get the data of a specific block.
     /// Returns None if block not found.
     pub fn get_as<M: DeserializeOwned, D: DeserializeOwned>(
-        &mut self,
+        &self,
         identifier: &Identifier,
     ) -> Result<(M, D), CogtainerError> {
         let (meta, data) = self.get_as_raw(identifier)?;
@@ -309,7 +829,7 @@ impl<F: Seek + Read> Cogtainer<F> {
         Ok((meta, data))
     }
     pub fn get_as_raw<M: DeserializeOwned>(
-        &mut self,
+        &self,
         identifier: &Identifier,
     ) -> Result<(M, Vec<u8>), CogtainerError> {
         let (metadata, data) = self.get_block(identifier)?;
@@ -321,11 +841,14 @@ impl<F: Seek + Read> Cogtainer<F> {
     }
 }
 #[cfg(feature = "full")]
-impl<F: Seek + Write> Cogtainer<F> {
+impl<F: Storage> Cogtainer<F> {
     pub fn set_metadata_as<T: Serialize>(&mut self, meta: &T) -> Result<&mut Self, CogtainerError> {
         let meta = rmpv::ext::to_value(meta)?;
         self.set_metadata(meta)
     }
+}
+#[cfg(feature = "full")]
+impl<F: Storage> Cogtainer<F> {
     /// Inserts a block with the given unique identifier.
     /// If a block already exists with the given identifier, it will be replaced.
     pub fn insert_block_as<M: Serialize, D: Serialize>(
@@ -0,0 +1,174 @@
+use std::io::{BufRead, Error, ErrorKind, Read, Seek, SeekFrom};
+
+use crate::{
+    container_file::{
+        BlockDescriptor, Checksum, ChecksumAlgorithm, ChecksumHasher, Identifier, CHUNK_SIZE,
+    },
+    error::CogtainerError,
+    storage::Storage,
+};
+
+/// Streams a block from the underlying file.
+///
+/// Data is hashed as it is read. Once the whole block has been read sequentially from the start,
+/// reaching the end checks the checksum, and returns an `ErrorKind::InvalidData` error wrapping
+/// `CogtainerError::BlockChecksumError` if it doesn't match. Seeking is allowed; bytes are only
+/// hashed the first time they are read in order, so the checksum is still verified if the reader
+/// seeks back and forth but eventually reads every byte in order.
+///
+/// Blocks with chunk checksums are read a chunk at a time instead, and each chunk is verified
+/// before any of it is returned, so random access is verified too.
+pub struct BlockReader<'a, F: Storage> {
+    file: &'a F,
+    identifier: Identifier,
+    data_offset: u64,
+    used_length: u64,
+    checksum: Checksum,
+    chunk_checksums: Vec<Checksum>,
+    algorithm: ChecksumAlgorithm,
+
+    position: u64,
+    buf: Vec<u8>,
+    /// Range of `buf` that hasn't been consumed yet. `buf[buf_pos]` is the byte at `position`.
+    buf_pos: usize,
+    buf_len: usize,
+
+    hasher: ChecksumHasher,
+    /// Number of bytes from the start of the block that have been hashed.
+    hashed_to: u64,
+}
+impl<'a, F: Storage> BlockReader<'a, F> {
+    const BUFFER_SIZE: usize = 64 * 1024;
+
+    pub(crate) fn new(
+        file: &'a F,
+        identifier: Identifier,
+        descriptor: &BlockDescriptor,
+        algorithm: ChecksumAlgorithm,
+    ) -> Self {
+        Self {
+            file,
+            identifier,
+            data_offset: descriptor.data_offset().0,
+            used_length: descriptor.used_length,
+            checksum: descriptor.checksum,
+            chunk_checksums: descriptor.chunk_checksums.clone(),
+            algorithm,
+            position: 0,
+            buf: vec![],
+            buf_pos: 0,
+            buf_len: 0,
+            hasher: algorithm.hasher(),
+            hashed_to: 0,
+        }
+    }
+
+    /// The length of the block.
+    pub fn len(&self) -> u64 {
+        self.used_length
+    }
+    pub fn is_empty(&self) -> bool {
+        self.used_length == 0
+    }
+
+    /// Checks the checksum once every byte has been hashed.
+    fn verify(&self) -> std::io::Result<()> {
+        if self.chunk_checksums.is_empty()
+            && self.hashed_to == self.used_length
+            && self.hasher.finish() != self.checksum
+        {
+            return Err(self.checksum_error());
+        }
+        Ok(())
+    }
+
+    fn checksum_error(&self) -> Error {
+        Error::new(
+            ErrorKind::InvalidData,
+            CogtainerError::BlockChecksumError(self.identifier.clone()),
+        )
+    }
+
+    /// Fills the buffer with the chunk containing the current position, and verifies it.
+    fn fill_chunk(&mut self) -> std::io::Result<()> {
+        let index = self.position / CHUNK_SIZE;
+        let start = index * CHUNK_SIZE;
+        let len = ((start + CHUNK_SIZE).min(self.used_length) - start) as usize;
+        self.buf.resize(len, 0);
+        self.file
+            .read_exact_at(&mut self.buf[..len], self.data_offset + start)?;
+        if self.chunk_checksums.get(index as usize)
+            != Some(&self.algorithm.checksum(&self.buf[..len]))
+        {
+            return Err(self.checksum_error());
+        }
+        self.buf_pos = (self.position - start) as usize;
+        self.buf_len = len;
+        Ok(())
+    }
+}
+impl<'a, F: Storage> BufRead for BlockReader<'a, F> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        if self.buf_pos == self.buf_len {
+            let remaining = self.used_length.saturating_sub(self.position);
+            if remaining == 0 {
+                self.verify()?;
+                return Ok(&[]);
+            }
+            if !self.chunk_checksums.is_empty() {
+                self.fill_chunk()?;
+                return Ok(&self.buf[self.buf_pos..self.buf_len]);
+            }
+            let len = remaining.min(Self::BUFFER_SIZE as u64) as usize;
+            self.buf.resize(len, 0);
+            self.file
+                .read_exact_at(&mut self.buf[..len], self.data_offset + self.position)?;
+            self.buf_pos = 0;
+            self.buf_len = len;
+        }
+        Ok(&self.buf[self.buf_pos..self.buf_len])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        let amt = amt.min(self.buf_len - self.buf_pos);
+        if self.position == self.hashed_to {
+            self.hasher
+                .write(&self.buf[self.buf_pos..self.buf_pos + amt]);
+            self.hashed_to += amt as u64;
+        }
+        self.buf_pos += amt;
+        self.position += amt as u64;
+    }
+}
+impl<'a, F: Storage> Read for BlockReader<'a, F> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let available = self.fill_buf()?;
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.consume(len);
+        Ok(len)
+    }
+}
+impl<'a, F: Storage> Seek for BlockReader<'a, F> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(p) => p as i128,
+            SeekFrom::End(p) => self.used_length as i128 + p as i128,
+            SeekFrom::Current(p) => self.position as i128 + p as i128,
+        };
+        if new_pos < 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid seek"));
+        }
+        let new_pos = new_pos as u64;
+        // keep the buffer if the new position is inside it
+        let buf_start = self.position - self.buf_pos as u64;
+        if new_pos >= buf_start && new_pos <= buf_start + self.buf_len as u64 {
+            self.buf_pos = (new_pos - buf_start) as usize;
+        } else {
+            self.buf_pos = 0;
+            self.buf_len = 0;
+        }
+        self.position = new_pos;
+        Ok(self.position)
+    }
+}
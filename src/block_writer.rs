@@ -0,0 +1,304 @@
+use std::io::{Error, ErrorKind, Seek, SeekFrom, Write};
+
+use crate::{
+    basic_api::Cogtainer,
+    container_file::{
+        BlockDescriptor, Checksum, ChecksumGranularity, ChecksumHasher, ChunkHasher,
+        ContainerFooter, FileOffset, Identifier, InlineBlockHeader, OverallocationPolicy,
+    },
+    error::CogtainerError,
+    storage::Storage,
+};
+
+/// Writes a block incrementally, without holding the whole block in memory.
+///
+/// Space is reserved as the block grows: in place when the block is at the end of the data,
+/// otherwise by moving what was written so far to a region twice as large. The checksum is
+/// computed as data is appended. Nothing is visible through the container until `finish()`
+/// commits the block, replacing any existing block with the same identifier. Dropping the writer
+/// without finishing discards it.
+pub struct BlockWriter<'a, F: Storage> {
+    container: &'a mut Cogtainer<F>,
+    identifier: Identifier,
+    metadata: rmpv::Value,
+
+    /// The reserved region, including the inline header (if any).
+    file_offset: FileOffset,
+    allocated_length: u64,
+    header_length: u64,
+
+    /// Number of bytes written so far.
+    len: u64,
+    position: u64,
+    /// Hash of every byte written so far. Only valid while the block was written sequentially.
+    /// Chunked blocks use `chunk_hasher` instead.
+    hasher: ChecksumHasher,
+    chunk_hasher: Option<ChunkHasher>,
+    hash_valid: bool,
+}
+impl<'a, F: Storage> BlockWriter<'a, F> {
+    /// Smallest amount of space reserved at once.
+    const MIN_RESERVATION: u64 = 4096;
+
+    pub(crate) fn new(
+        container: &'a mut Cogtainer<F>,
+        identifier: Identifier,
+        metadata: rmpv::Value,
+    ) -> Result<Self, CogtainerError> {
+        let header_length = InlineBlockHeader::length_for(&container.header, &identifier)?;
+        let algorithm = container.footer.checksum_algorithm;
+        let chunk_hasher = (container.footer.checksum_granularity == ChecksumGranularity::Chunks)
+            .then(|| ChunkHasher::new(algorithm));
+        Ok(Self {
+            container,
+            identifier,
+            metadata,
+            file_offset: FileOffset(0),
+            allocated_length: 0,
+            header_length,
+            len: 0,
+            position: 0,
+            hasher: algorithm.hasher(),
+            chunk_hasher,
+            hash_valid: true,
+        })
+    }
+
+    /// Number of bytes written so far.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn capacity(&self) -> u64 {
+        self.allocated_length.saturating_sub(self.header_length)
+    }
+    fn data_offset(&self) -> u64 {
+        self.file_offset.0 + self.header_length
+    }
+
+    /// Makes sure at least `required` bytes of data fit in the reserved region.
+    fn reserve(&mut self, required: u64) -> Result<(), CogtainerError> {
+        if required <= self.capacity() {
+            return Ok(());
+        }
+        let capacity = required.max(self.capacity() * 2).max(Self::MIN_RESERVATION);
+        let end = self.file_offset.end_offset(self.allocated_length);
+        let data_offset = self.data_offset();
+        let container = &mut *self.container;
+
+        // at the end of the data, the region can grow in place
+        if self.allocated_length > 0 && end == container.header.footer_offset {
+            let new_end = FileOffset(data_offset + capacity);
+            container
+                .footer
+                .preallocate_growth(&mut container.file, end, new_end)?;
+            ContainerFooter::protect_committed_footer(
+                &mut container.file,
+                &mut container.header,
+                end,
+                new_end,
+            )?;
+            container.header.footer_offset = new_end;
+            self.allocated_length = new_end.0 - self.file_offset.0;
+            return Ok(());
+        }
+
+        let (file_offset, allocated_length) = container.footer.reserve_block_space(
+            &mut container.file,
+            &mut container.header,
+            self.header_length,
+            capacity,
+            OverallocationPolicy::None,
+        )?;
+        // move what was written so far
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut copied = 0;
+        while copied < self.len {
+            let chunk = (self.len - copied).min(buf.len() as u64) as usize;
+            container
+                .file
+                .read_exact_at(&mut buf[..chunk], data_offset + copied)?;
+            container
+                .file
+                .write_all_at(&buf[..chunk], file_offset.0 + self.header_length + copied)?;
+            copied += chunk as u64;
+        }
+        self.release(self.file_offset, self.allocated_length);
+        self.file_offset = file_offset;
+        self.allocated_length = allocated_length;
+        Ok(())
+    }
+
+    /// Gives a region back, either to the empty space list or to the end of the data.
+    fn release(&mut self, offset: FileOffset, len: u64) {
+        if len == 0 {
+            return;
+        }
+        let container = &mut *self.container;
+        if offset.end_offset(len) == container.header.footer_offset {
+            container.header.footer_offset = offset;
+        } else {
+            container.footer.release_space(offset, len);
+            container.footer.consolidate_empty_space();
+        }
+    }
+
+    /// Commits the block, replacing any existing block with the same identifier.
+    /// Space reserved beyond what was written (and the container's overallocation policy) is
+    /// given back.
+    pub fn finish(mut self) -> Result<(), CogtainerError> {
+        let (checksum, chunk_checksums) = if !self.hash_valid {
+            self.rehash()?
+        } else if self.len == 0 {
+            (self.hasher.finish(), vec![])
+        } else if let Some(chunk_hasher) = self.chunk_hasher.take() {
+            chunk_hasher.finish()
+        } else {
+            (self.hasher.finish(), vec![])
+        };
+
+        let descriptor = if self.len == 0 {
+            self.release(self.file_offset, self.allocated_length);
+            BlockDescriptor {
+                file_offset: FileOffset(0),
+                used_length: 0,
+                allocated_length: 0,
+                checksum,
+                metadata: std::mem::replace(&mut self.metadata, rmpv::Value::Nil),
+                header_length: 0,
+                chunk_checksums: vec![],
+            }
+        } else {
+            let keep = (self.header_length
+                + self.container.overallocation_policy.calculate(self.len))
+            .min(self.allocated_length);
+            self.release(
+                self.file_offset.end_offset(keep),
+                self.allocated_length - keep,
+            );
+            BlockDescriptor {
+                file_offset: self.file_offset,
+                used_length: self.len,
+                allocated_length: keep,
+                checksum,
+                metadata: std::mem::replace(&mut self.metadata, rmpv::Value::Nil),
+                header_length: self.header_length,
+                chunk_checksums,
+            }
+        };
+        // the region now belongs to the block
+        self.allocated_length = 0;
+
+        let container = &mut *self.container;
+        container
+            .footer
+            .load_block(&container.file, &self.identifier)?;
+        if let Some(old) = container
+            .footer
+            .blocks
+            .insert(self.identifier.clone(), descriptor)
+        {
+            container
+                .footer
+                .release_space(old.file_offset, old.allocated_length);
+            container.footer.consolidate_empty_space();
+        }
+        container.footer.write_inline_header(
+            &mut container.file,
+            &mut container.header,
+            &self.identifier,
+        )?;
+        container
+            .footer
+            .record_change(&mut container.file, &mut container.header)
+    }
+
+    /// Computes the checksums by reading back everything written.
+    fn rehash(&mut self) -> Result<(Checksum, Vec<Checksum>), CogtainerError> {
+        let algorithm = self.container.footer.checksum_algorithm;
+        let mut hasher = algorithm.hasher();
+        let mut chunk_hasher = self
+            .chunk_hasher
+            .is_some()
+            .then(|| ChunkHasher::new(algorithm));
+        let data_offset = self.data_offset();
+        let file = &self.container.file;
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut read = 0;
+        while read < self.len {
+            let chunk = (self.len - read).min(buf.len() as u64) as usize;
+            file.read_exact_at(&mut buf[..chunk], data_offset + read)?;
+            match &mut chunk_hasher {
+                Some(chunk_hasher) => chunk_hasher.write(&buf[..chunk]),
+                None => hasher.write(&buf[..chunk]),
+            }
+            read += chunk as u64;
+        }
+        match chunk_hasher {
+            Some(chunk_hasher) if self.len > 0 => Ok(chunk_hasher.finish()),
+            _ => Ok((hasher.finish(), vec![])),
+        }
+    }
+}
+impl<'a, F: Storage> Write for BlockWriter<'a, F> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let end = self
+            .position
+            .checked_add(buf.len() as u64)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "size overflow"))?;
+        self.reserve(end)?;
+
+        let data_offset = self.data_offset();
+        self.container
+            .file
+            .write_all_at(buf, data_offset + self.position)?;
+
+        if self.hash_valid && self.position == self.len {
+            match &mut self.chunk_hasher {
+                Some(chunk_hasher) => chunk_hasher.write(buf),
+                None => self.hasher.write(buf),
+            }
+        } else {
+            // overwriting earlier data, the checksum is recomputed on finish()
+            self.hash_valid = false;
+        }
+        self.position = end;
+        self.len = self.len.max(end);
+        Ok(buf.len())
+    }
+
+    /// Nothing is buffered, and the block is only committed by `finish()`.
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+impl<'a, F: Storage> Seek for BlockWriter<'a, F> {
+    /// Seeks within the bytes written so far.
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(p) => p as i128,
+            SeekFrom::End(p) => self.len as i128 + p as i128,
+            SeekFrom::Current(p) => self.position as i128 + p as i128,
+        };
+        if new_pos < 0 || new_pos > self.len as i128 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "can only seek within the bytes written so far",
+            ));
+        }
+        self.position = new_pos as u64;
+        Ok(self.position)
+    }
+}
+impl<'a, F: Storage> Drop for BlockWriter<'a, F> {
+    fn drop(&mut self) {
+        // give back the space reserved by a writer that wasn't finished
+        self.release(self.file_offset, self.allocated_length);
+    }
+}
@@ -0,0 +1,189 @@
+use std::{fs::File, io::BufRead, path::Path};
+
+use crate::{
+    basic_api::Cogtainer,
+    container_file::{BlockDescriptor, FileOffset, Identifier, InlineBlockHeader},
+    error::CogtainerError,
+    storage::Storage,
+};
+
+/// The order `compact_into` writes the blocks in.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum CompactionOrder {
+    /// The order they are in now.
+    #[default]
+    FileOffset,
+    /// Sorted by identifier.
+    Identifier,
+    /// The listed blocks first, in the given order, followed by the rest by file offset.
+    /// Listed identifiers that don't exist are ignored.
+    Custom(Vec<Identifier>),
+}
+
+impl<F: Storage> Cogtainer<F> {
+    /// Writes a copy of this container to `dest` with no empty space: every block is streamed
+    /// across (verifying its checksum) in the given order, with no overallocation, followed by
+    /// the container metadata. Quarantined blocks are left out.
+    ///
+    /// The copy uses the same checksum algorithm, inline block header setting and index page size.
+    /// This container is not changed, including any uncommitted changes, which are part of the
+    /// copy. The blocks are written first and the copy is committed once at the end, so `dest`
+    /// only holds a valid container once this returns. `dest` is cut off after the copy.
+    pub fn compact_into<G: Storage>(
+        &self,
+        dest: G,
+        order: CompactionOrder,
+    ) -> Result<Cogtainer<G>, CogtainerError> {
+        self.footer.load_all_blocks(&self.file)?;
+        let mut compacted = Cogtainer::create_with_checksum(dest, self.header.checksum_algorithm)?;
+        compacted.set_inline_block_headers(self.header.inline_block_headers());
+        compacted
+            .footer
+            .blocks
+            .set_page_size(self.footer.blocks.page_size());
+
+        let order = self.compaction_order(order);
+        let mut copies = Vec::with_capacity(order.len());
+        let mut total_length = 0;
+        for identifier in order {
+            let descriptor = &self.footer.blocks[&identifier];
+            let mut copy = BlockDescriptor {
+                file_offset: FileOffset(0),
+                allocated_length: 0,
+                header_length: 0,
+                ..descriptor.clone()
+            };
+            if descriptor.used_length > 0 {
+                copy.header_length = InlineBlockHeader::length_for(&compacted.header, &identifier)?;
+                copy.allocated_length = copy.header_length + copy.used_length;
+                total_length += copy.allocated_length;
+            }
+            copies.push((identifier, copy));
+        }
+        // reserve the space for every block at once, so the empty footer written by `create` is
+        // moved out of the way only once
+        let (mut offset, _) = compacted.footer.reserve_space_at_end(
+            &mut compacted.file,
+            &mut compacted.header,
+            total_length,
+        )?;
+
+        for (identifier, mut copy) in copies {
+            if copy.used_length > 0 {
+                copy.file_offset = offset;
+                offset = offset.end_offset(copy.allocated_length);
+                let mut reader = self.block_reader(&identifier)?;
+                let mut position = copy.data_offset().0;
+                loop {
+                    let buf = reader.fill_buf()?;
+                    if buf.is_empty() {
+                        break;
+                    }
+                    compacted.file.write_all_at(buf, position)?;
+                    let len = buf.len();
+                    position += len as u64;
+                    reader.consume(len);
+                }
+            }
+            compacted.footer.blocks.insert(identifier.clone(), copy);
+            compacted.footer.write_inline_header(
+                &mut compacted.file,
+                &mut compacted.header,
+                &identifier,
+            )?;
+        }
+        compacted.footer.metadata = self.footer.metadata.clone();
+        compacted.flush()?;
+        // drops the copies of earlier footers past the final one
+        let length = compacted.file_length();
+        compacted.file.set_len(length)?;
+        Ok(compacted)
+    }
+
+    fn compaction_order(&self, order: CompactionOrder) -> Vec<Identifier> {
+        let mut by_offset: Vec<_> = self.footer.blocks.iter().collect();
+        by_offset.sort_by_key(|(identifier, descriptor)| (descriptor.file_offset, *identifier));
+        let by_offset = by_offset
+            .into_iter()
+            .map(|(identifier, _)| identifier.clone());
+        match order {
+            CompactionOrder::FileOffset => by_offset.collect(),
+            CompactionOrder::Identifier => {
+                let mut identifiers: Vec<_> = self.footer.blocks.keys().cloned().collect();
+                identifiers.sort();
+                identifiers
+            }
+            CompactionOrder::Custom(mut identifiers) => {
+                let mut seen = std::collections::HashSet::new();
+                identifiers.retain(|identifier| {
+                    self.footer.blocks.contains_key(identifier) && seen.insert(identifier.clone())
+                });
+                identifiers.extend(by_offset.filter(|identifier| !seen.contains(identifier)));
+                identifiers
+            }
+        }
+    }
+}
+
+impl Cogtainer<File> {
+    /// Compacts the container (see `compact_into`) into a temporary file next to `path`, syncs
+    /// it to disk, and renames it over `path`, which should be the file this container was
+    /// opened from. Returns the container on the new file.
+    ///
+    /// Until the rename, the original file is untouched. If anything fails before then, the
+    /// temporary file is removed.
+    pub fn compact_file(
+        self,
+        path: impl AsRef<Path>,
+        order: CompactionOrder,
+    ) -> Result<Cogtainer<File>, CogtainerError> {
+        let path = path.as_ref();
+        let mut temp_name = path
+            .file_name()
+            .ok_or_else(|| std::io::Error::other("Not a file path"))?
+            .to_owned();
+        temp_name.push(".compacting");
+        let temp_path = path.with_file_name(temp_name);
+
+        let compact = || -> Result<Cogtainer<File>, CogtainerError> {
+            let file = File::options()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&temp_path)?;
+            let compacted = self.compact_into(file, order)?;
+            compacted.file.sync_all()?;
+            Ok(compacted)
+        };
+        let compacted = match compact() {
+            Ok(compacted) => compacted,
+            Err(err) => {
+                let _ = std::fs::remove_file(&temp_path);
+                return Err(err);
+            }
+        };
+
+        // the original is closed first, it can't be replaced while open on some platforms
+        match self.into_inner() {
+            Ok(original) => drop(original),
+            Err(err) => {
+                drop(compacted);
+                let _ = std::fs::remove_file(&temp_path);
+                return Err(err);
+            }
+        }
+        std::fs::rename(&temp_path, path)?;
+        // make the rename itself durable
+        #[cfg(unix)]
+        if let Some(dir) = path.parent() {
+            let dir = if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            };
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(compacted)
+    }
+}
@@ -0,0 +1,276 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ops::Deref,
+};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use super::*;
+
+/// How `reserve_space` picks a hole in the empty space for a new allocation.
+#[derive(Clone, Default, Debug, Copy, PartialEq, Eq)]
+pub enum AllocationStrategy {
+    /// The hole with the lowest offset that fits. Keeps data packed towards the start of the
+    /// file. Only holes in the request's own size class are scanned.
+    #[default]
+    FirstFit,
+    /// The smallest hole that fits, leaving the smallest leftover.
+    BestFit,
+    /// The largest hole, leaving the largest (and so most reusable) leftover.
+    WorstFit,
+    /// Holes are grouped in power-of-two size classes. The lowest hole that fits in the
+    /// request's own class is used, otherwise the lowest hole of the next non-empty larger class.
+    Segregated,
+}
+
+/// The empty regions of a container, by offset, with secondary indexes by size and by size
+/// class so every `AllocationStrategy` can find a hole without scanning them all.
+///
+/// Dereferences to the map of offset to length, which is also how it is stored in the footer.
+///
+/// Space freed since the footer was last committed is kept apart (see `release`), as the
+/// committed footer may still refer to it.
+#[derive(Debug, Clone, Default)]
+pub struct EmptySpace {
+    by_offset: BTreeMap<FileOffset, u64>,
+    by_size: BTreeSet<(u64, FileOffset)>,
+    by_class: BTreeMap<u32, BTreeSet<FileOffset>>,
+    /// Freed regions that aren't handed out until `reuse_released` is called.
+    released: Vec<(FileOffset, u64)>,
+    /// Regions inserted since the last `consolidate`, which may adjoin their neighbours.
+    unmerged: Vec<FileOffset>,
+}
+impl PartialEq for EmptySpace {
+    fn eq(&self, other: &Self) -> bool {
+        self.by_offset == other.by_offset && self.released == other.released
+    }
+}
+impl Eq for EmptySpace {}
+impl EmptySpace {
+    fn size_class(len: u64) -> u32 {
+        len.max(1).ilog2()
+    }
+
+    /// Adds an empty region, returning the length of the region previously at that offset.
+    pub fn insert(&mut self, offset: FileOffset, len: u64) -> Option<u64> {
+        let previous = self.remove(&offset);
+        self.by_offset.insert(offset, len);
+        self.by_size.insert((len, offset));
+        self.by_class
+            .entry(Self::size_class(len))
+            .or_default()
+            .insert(offset);
+        self.unmerged.push(offset);
+        previous
+    }
+    /// Removes the empty region at the given offset, returning its length.
+    pub fn remove(&mut self, offset: &FileOffset) -> Option<u64> {
+        let len = self.by_offset.remove(offset)?;
+        self.by_size.remove(&(len, *offset));
+        let class = Self::size_class(len);
+        if let Some(offsets) = self.by_class.get_mut(&class) {
+            offsets.remove(offset);
+            if offsets.is_empty() {
+                self.by_class.remove(&class);
+            }
+        }
+        Some(len)
+    }
+    /// Removes and returns the empty region with the lowest offset.
+    pub fn pop_first(&mut self) -> Option<(FileOffset, u64)> {
+        let offset = *self.by_offset.keys().next()?;
+        self.remove(&offset).map(|len| (offset, len))
+    }
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Adds a region freed since the footer was last committed. It is listed as empty in the
+    /// next footer, but `find` only returns it once `reuse_released` is called, after that footer
+    /// is committed. Until then, a crash leaves the committed footer's data intact.
+    pub fn release(&mut self, offset: FileOffset, len: u64) {
+        self.released.push((offset, len));
+    }
+    /// The regions freed since the footer was last committed.
+    pub fn released(&self) -> impl Iterator<Item = (FileOffset, u64)> + '_ {
+        self.released.iter().copied()
+    }
+    /// Makes the released regions available to `find`. Call once a footer listing them as
+    /// empty has been committed. The caller should consolidate the empty space afterwards.
+    pub fn reuse_released(&mut self) {
+        for (offset, len) in std::mem::take(&mut self.released) {
+            self.insert(offset, len);
+        }
+    }
+
+    /// Merges the regions inserted since the last call with the regions they adjoin. Only those
+    /// regions and their neighbours are looked at.
+    pub fn consolidate(&mut self) {
+        for offset in std::mem::take(&mut self.unmerged) {
+            let Some(mut len) = self.by_offset.get(&offset).copied() else {
+                // already merged into a region before it, or handed out again
+                continue;
+            };
+            let mut start = offset;
+            while let Some((&before, &before_len)) = self.by_offset.range(..start).next_back() {
+                if before.end_offset(before_len) != start {
+                    break;
+                }
+                self.remove(&start);
+                start = before;
+                len += before_len;
+            }
+            while let Some(after_len) = self.by_offset.get(&start.end_offset(len)).copied() {
+                self.remove(&start.end_offset(len));
+                len += after_len;
+            }
+            if self.by_offset.get(&start) != Some(&len) {
+                self.insert(start, len);
+            }
+        }
+        self.unmerged.clear();
+    }
+
+    /// Finds a hole of at least `required` bytes, using the given strategy.
+    pub fn find(&self, required: u64, strategy: AllocationStrategy) -> Option<FileOffset> {
+        match strategy {
+            AllocationStrategy::FirstFit => {
+                let class = Self::size_class(required);
+                // every hole in a larger class fits, so the lowest of those bounds the search
+                let larger = self
+                    .by_class
+                    .range(class + 1..)
+                    .filter_map(|(_, offsets)| offsets.first())
+                    .min();
+                let own_class = self.by_class.get(&class).and_then(|offsets| {
+                    offsets
+                        .iter()
+                        .take_while(|offset| larger.is_none_or(|larger| *offset < larger))
+                        .find(|offset| self.by_offset[*offset] >= required)
+                });
+                own_class.or(larger).copied()
+            }
+            AllocationStrategy::BestFit => self
+                .by_size
+                .range((required, FileOffset(0))..)
+                .next()
+                .map(|(_, offset)| *offset),
+            AllocationStrategy::WorstFit => self
+                .by_size
+                .last()
+                .filter(|(len, _)| *len >= required)
+                .map(|(_, offset)| *offset),
+            AllocationStrategy::Segregated => {
+                let class = Self::size_class(required);
+                // holes in the request's own class may be too small, larger classes always fit
+                let own_class = self.by_class.get(&class).and_then(|offsets| {
+                    offsets
+                        .iter()
+                        .find(|offset| self.by_offset[*offset] >= required)
+                });
+                own_class
+                    .or_else(|| {
+                        self.by_class
+                            .range(class + 1..)
+                            .next()
+                            .and_then(|(_, offsets)| offsets.first())
+                    })
+                    .copied()
+            }
+        }
+    }
+}
+
+/// The first offset at or after `offset` where data preceded by `prefix` bytes (such as an inline
+/// block header) starts on a multiple of `alignment`.
+pub(crate) fn aligned_offset(offset: FileOffset, alignment: u64, prefix: u64) -> FileOffset {
+    if alignment <= 1 {
+        return offset;
+    }
+    FileOffset((offset.0 + prefix).next_multiple_of(alignment) - prefix)
+}
+
+/// EmptySpace functions related to aligned allocations.
+impl EmptySpace {
+    /// Like `find`, but `required` bytes must fit after padding the start of the hole as
+    /// `aligned_offset` does. Returns the offset of the hole (not the padded offset).
+    ///
+    /// Strategies other than first-fit look for a hole that fits even with the most padding
+    /// possible, and fall back to first-fit if there isn't one.
+    pub fn find_aligned(
+        &self,
+        required: u64,
+        strategy: AllocationStrategy,
+        alignment: u64,
+        prefix: u64,
+    ) -> Option<FileOffset> {
+        if alignment <= 1 {
+            return self.find(required, strategy);
+        }
+        // a hole this large fits with any padding
+        let fits_anyway = self.find(required + alignment - 1, strategy);
+        if strategy != AllocationStrategy::FirstFit && fits_anyway.is_some() {
+            return fits_anyway;
+        }
+        let end = match strategy {
+            AllocationStrategy::FirstFit => fits_anyway,
+            _ => None,
+        };
+        self.by_offset
+            .range(..=end.unwrap_or(FileOffset(u64::MAX)))
+            .find(|(offset, len)| {
+                let padding = aligned_offset(**offset, alignment, prefix).0 - offset.0;
+                padding + required <= **len
+            })
+            .map(|(offset, _)| *offset)
+    }
+}
+
+impl Deref for EmptySpace {
+    type Target = BTreeMap<FileOffset, u64>;
+    fn deref(&self) -> &Self::Target {
+        &self.by_offset
+    }
+}
+impl<'a> IntoIterator for &'a EmptySpace {
+    type Item = (&'a FileOffset, &'a u64);
+    type IntoIter = std::collections::btree_map::Iter<'a, FileOffset, u64>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.by_offset.iter()
+    }
+}
+impl FromIterator<(FileOffset, u64)> for EmptySpace {
+    fn from_iter<T: IntoIterator<Item = (FileOffset, u64)>>(iter: T) -> Self {
+        let mut empty_space = Self::default();
+        for (offset, len) in iter {
+            empty_space.insert(offset, len);
+        }
+        empty_space
+    }
+}
+impl Serialize for EmptySpace {
+    /// Released regions are stored as empty, merged with any region they adjoin.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.released.is_empty() {
+            return self.by_offset.serialize(serializer);
+        }
+        let mut all: Vec<_> = self.by_offset.iter().map(|(o, l)| (*o, *l)).collect();
+        all.extend(self.released());
+        all.sort();
+        let mut merged: Vec<(FileOffset, u64)> = Vec::with_capacity(all.len());
+        for (offset, len) in all {
+            match merged.last_mut() {
+                Some((last, last_len)) if last.end_offset(*last_len) == offset => *last_len += len,
+                _ => merged.push((offset, len)),
+            }
+        }
+        serializer.collect_map(merged)
+    }
+}
+impl<'de> Deserialize<'de> for EmptySpace {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(BTreeMap::<FileOffset, u64>::deserialize(deserializer)?
+            .into_iter()
+            .collect())
+    }
+}
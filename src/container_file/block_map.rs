@@ -0,0 +1,533 @@
+use std::{
+    collections::{btree_map, BTreeMap},
+    fmt,
+    ops::Index,
+    sync::OnceLock,
+};
+
+use serde::{
+    de::{self, MapAccess, SeqAccess, Visitor},
+    ser, Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use crate::error::CogtainerError;
+
+use super::*;
+
+/// Location of an index page within the file.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct IndexPageLocation {
+    pub offset: FileOffset,
+    pub length: u64,
+    pub checksum: Checksum,
+}
+
+/// A range of the block map, by identifier.
+#[derive(Debug, Clone)]
+struct IndexPage {
+    /// The lowest identifier on the page. The first page has none, it starts at the lowest of all.
+    first: Option<Identifier>,
+    /// Where the page was last written.
+    location: Option<IndexPageLocation>,
+    /// Number of blocks on the page when it was last written.
+    len: u64,
+    /// The end of the furthest allocation on the page when it was last written.
+    end: FileOffset,
+    /// Set once the page has been read.
+    blocks: OnceLock<BTreeMap<Identifier, BlockDescriptor>>,
+    /// Changed since it was last written.
+    dirty: bool,
+}
+impl IndexPage {
+    fn new(first: Option<Identifier>, blocks: BTreeMap<Identifier, BlockDescriptor>) -> Self {
+        Self {
+            first,
+            location: None,
+            len: 0,
+            end: FileOffset(0),
+            blocks: OnceLock::from(blocks),
+            dirty: true,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.blocks
+            .get()
+            .map_or(self.len as usize, |blocks| blocks.len())
+    }
+
+    /// Reads the page, unless it has been read already.
+    fn load<R: Storage>(
+        &self,
+        reader: &R,
+        algorithm: ChecksumAlgorithm,
+    ) -> Result<(), CogtainerError> {
+        if self.blocks.get().is_some() {
+            return Ok(());
+        }
+        let blocks = match self.location {
+            Some(location) => {
+                let mut bytes = vec![0u8; location.length as usize];
+                reader.read_exact_at(&mut bytes, location.offset.0)?;
+                if algorithm.checksum(bytes.as_slice()) != location.checksum {
+                    return Err(CogtainerError::IndexPageChecksumError);
+                }
+                rmp_serde::from_slice(bytes.as_slice())?
+            }
+            None => BTreeMap::new(),
+        };
+        // a concurrent reader may have got there first, with the same result
+        let _ = self.blocks.set(blocks);
+        Ok(())
+    }
+}
+
+/// How a page is listed in the footer.
+#[derive(Serialize, Deserialize)]
+struct StoredPage {
+    first: Option<Identifier>,
+    location: IndexPageLocation,
+    len: u64,
+    end: FileOffset,
+}
+
+/// The blocks of a container, by identifier.
+///
+/// By default the whole map is stored in the footer, which is read in full on open and rewritten
+/// on every commit. With a page size set (see `Cogtainer::set_index_page_size`), the map is split
+/// into pages of up to that many blocks, which are stored in the data region. The footer only
+/// lists the pages. A page is read the first time a lookup needs it, and only the pages that
+/// changed are rewritten on commit.
+///
+/// Lookups and iteration only see the pages that have been read. The container reads the pages
+/// each operation needs, `Cogtainer::get_blocks_list()` reads all of them.
+#[derive(Debug, Clone)]
+pub struct BlockMap {
+    /// Blocks per page, or 0 if the map is stored in the footer.
+    page_size: usize,
+    /// Sorted by `first`. There is always at least one page.
+    pages: Vec<IndexPage>,
+    /// Pages replaced or dropped since the last commit. Their space is released once the pages
+    /// replacing them are written.
+    stale: Vec<IndexPageLocation>,
+}
+impl Default for BlockMap {
+    fn default() -> Self {
+        Self::from_map(BTreeMap::new())
+    }
+}
+impl BlockMap {
+    fn from_map(blocks: BTreeMap<Identifier, BlockDescriptor>) -> Self {
+        Self {
+            page_size: 0,
+            pages: vec![IndexPage::new(None, blocks)],
+            stale: vec![],
+        }
+    }
+
+    /// Blocks per index page, or 0 if the map is stored in the footer.
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+    /// Number of index pages (1 if the map is stored in the footer).
+    pub fn page_count(&self) -> usize {
+        self.pages.len()
+    }
+    /// Number of index pages that have been read.
+    pub fn loaded_pages(&self) -> usize {
+        self.pages
+            .iter()
+            .filter(|page| page.blocks.get().is_some())
+            .count()
+    }
+    /// Returns true if every index page has been read.
+    pub fn is_loaded(&self) -> bool {
+        self.loaded_pages() == self.pages.len()
+    }
+
+    /// Number of blocks, including those on pages that haven't been read.
+    pub fn len(&self) -> usize {
+        self.pages.iter().map(IndexPage::len).sum()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, identifier: &Identifier) -> Option<&BlockDescriptor> {
+        self.page(identifier).blocks.get()?.get(identifier)
+    }
+    pub fn contains_key(&self, identifier: &Identifier) -> bool {
+        self.get(identifier).is_some()
+    }
+    pub(crate) fn get_mut(&mut self, identifier: &Identifier) -> Option<&mut BlockDescriptor> {
+        let page = self.page_mut(identifier);
+        let descriptor = page.blocks.get_mut()?.get_mut(identifier)?;
+        page.dirty = true;
+        Some(descriptor)
+    }
+    /// Adds or replaces a block, returning the descriptor it replaced. Changes go through the
+    /// container, which reads the page first, so this isn't public.
+    ///
+    /// Panics if the page the block belongs on hasn't been read.
+    pub(crate) fn insert(
+        &mut self,
+        identifier: Identifier,
+        descriptor: BlockDescriptor,
+    ) -> Option<BlockDescriptor> {
+        let page = self.page_mut(&identifier);
+        page.dirty = true;
+        page.blocks
+            .get_mut()
+            .expect("index page has not been read")
+            .insert(identifier, descriptor)
+    }
+    pub(crate) fn remove(&mut self, identifier: &Identifier) -> Option<BlockDescriptor> {
+        let page = self.page_mut(identifier);
+        let descriptor = page.blocks.get_mut()?.remove(identifier)?;
+        page.dirty = true;
+        Some(descriptor)
+    }
+
+    /// The blocks on the pages that have been read, by identifier.
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            pages: self.pages.iter(),
+            page: btree_map::Iter::default(),
+        }
+    }
+    pub fn keys(&self) -> impl Iterator<Item = &Identifier> + '_ {
+        self.iter().map(|(identifier, _)| identifier)
+    }
+    pub fn values(&self) -> impl Iterator<Item = &BlockDescriptor> + '_ {
+        self.iter().map(|(_, descriptor)| descriptor)
+    }
+
+    fn page_index(&self, identifier: &Identifier) -> usize {
+        self.pages
+            .partition_point(|page| page.first.as_ref().is_none_or(|first| first <= identifier))
+            - 1
+    }
+    fn page(&self, identifier: &Identifier) -> &IndexPage {
+        &self.pages[self.page_index(identifier)]
+    }
+    fn page_mut(&mut self, identifier: &Identifier) -> &mut IndexPage {
+        let index = self.page_index(identifier);
+        &mut self.pages[index]
+    }
+}
+
+/// BlockMap functions related to reading pages.
+impl BlockMap {
+    /// Reads the page the block belongs on, unless it has been read already.
+    pub fn load<R: Storage>(
+        &self,
+        reader: &R,
+        identifier: &Identifier,
+        algorithm: ChecksumAlgorithm,
+    ) -> Result<(), CogtainerError> {
+        self.page(identifier).load(reader, algorithm)
+    }
+    /// Reads every page that hasn't been read yet.
+    pub fn load_all<R: Storage>(
+        &self,
+        reader: &R,
+        algorithm: ChecksumAlgorithm,
+    ) -> Result<(), CogtainerError> {
+        self.pages
+            .iter()
+            .try_for_each(|page| page.load(reader, algorithm))
+    }
+    /// Reads every page it can, and returns the offsets of those that can't be read.
+    pub(crate) fn unreadable_pages<R: Storage>(
+        &self,
+        reader: &R,
+        algorithm: ChecksumAlgorithm,
+    ) -> Vec<FileOffset> {
+        self.pages
+            .iter()
+            .filter(|page| page.load(reader, algorithm).is_err())
+            .filter_map(|page| page.location.map(|location| location.offset))
+            .collect()
+    }
+    /// Replaces the pages that haven't been read with empty ones, forgetting the blocks on them.
+    pub(crate) fn forget_unloaded_pages(&mut self) {
+        for page in &mut self.pages {
+            if page.blocks.get().is_none() {
+                page.blocks = OnceLock::from(BTreeMap::new());
+                page.dirty = true;
+                self.stale.extend(page.location.take());
+            }
+        }
+    }
+
+    /// Where every page (including replaced pages that are yet to be released) is stored.
+    pub(crate) fn page_regions(&self) -> impl Iterator<Item = (FileOffset, u64)> + '_ {
+        self.pages
+            .iter()
+            .filter_map(|page| page.location)
+            .chain(self.stale.iter().copied())
+            .map(|location| (location.offset, location.length))
+    }
+    /// The end of the furthest allocation on each page that hasn't been read.
+    pub(crate) fn unloaded_ends(&self) -> impl Iterator<Item = FileOffset> + '_ {
+        self.pages
+            .iter()
+            .filter(|page| page.blocks.get().is_none())
+            .map(|page| page.end)
+    }
+}
+
+/// BlockMap functions related to writing pages.
+impl BlockMap {
+    /// Changes how many blocks go on a page, or stores the map in the footer again with 0.
+    /// Every page is rewritten on the next commit.
+    ///
+    /// Panics if a page hasn't been read.
+    pub(crate) fn set_page_size(&mut self, page_size: usize) {
+        let pages = std::mem::take(&mut self.pages);
+        self.stale
+            .extend(pages.iter().filter_map(|page| page.location));
+        let mut blocks = pages
+            .into_iter()
+            .map(|mut page| page.blocks.take().expect("index page has not been read"));
+        // pages are split again as they are written
+        let mut merged = blocks.next().unwrap_or_default();
+        blocks.for_each(|mut page| merged.append(&mut page));
+        self.pages = vec![IndexPage::new(None, merged)];
+        self.page_size = page_size;
+    }
+
+    /// Splits the changed pages that have grown past the page size and drops those that are now
+    /// empty (except the first page), before they are written.
+    fn split_pages(&mut self) {
+        let mut pages = Vec::with_capacity(self.pages.len());
+        for mut page in std::mem::take(&mut self.pages) {
+            if !page.dirty {
+                pages.push(page);
+                continue;
+            }
+            let blocks = page.blocks.take().expect("changed pages have been read");
+            if blocks.is_empty() && !pages.is_empty() {
+                // its identifiers now belong to the page before it
+                self.stale.extend(page.location);
+                continue;
+            }
+            let mut parts = Self::split(blocks, self.page_size).into_iter();
+            page.blocks = OnceLock::from(parts.next().unwrap_or_default());
+            pages.push(page);
+            pages.extend(parts.map(|part| IndexPage::new(part.keys().next().cloned(), part)));
+        }
+        self.pages = pages;
+    }
+    /// Splits `blocks` into as few evenly sized parts of at most `page_size` blocks as possible.
+    fn split(
+        mut blocks: BTreeMap<Identifier, BlockDescriptor>,
+        page_size: usize,
+    ) -> Vec<BTreeMap<Identifier, BlockDescriptor>> {
+        let count = blocks.len().div_ceil(page_size.max(1)).max(1);
+        let mut parts = Vec::with_capacity(count);
+        for remaining in (2..=count).rev() {
+            let at = blocks.len().div_ceil(remaining);
+            let first = blocks
+                .keys()
+                .nth(at)
+                .cloned()
+                .expect("split point is in range");
+            let rest = blocks.split_off(&first);
+            parts.push(std::mem::replace(&mut blocks, rest));
+        }
+        parts.push(blocks);
+        parts
+    }
+}
+
+/// ContainerFooter functions related to the paged block index.
+impl ContainerFooter {
+    /// Reads the index page the block belongs on, unless it has been read already.
+    pub(crate) fn load_block<R: Storage>(
+        &self,
+        reader: &R,
+        identifier: &Identifier,
+    ) -> Result<(), CogtainerError> {
+        self.blocks
+            .load(reader, identifier, self.checksum_algorithm)
+    }
+    /// Reads every index page that hasn't been read yet.
+    pub(crate) fn load_all_blocks<R: Storage>(&self, reader: &R) -> Result<(), CogtainerError> {
+        self.blocks.load_all(reader, self.checksum_algorithm)
+    }
+
+    /// Writes the index pages that changed since the last commit to free space, then releases the
+    /// space of the pages they replace. Must be called before the footer is written.
+    pub(crate) fn write_index_pages<W: Storage>(
+        &mut self,
+        writer: &mut W,
+        header: &mut ContainerHeader,
+    ) -> Result<(), CogtainerError> {
+        if self.blocks.page_size > 0 {
+            self.blocks.split_pages();
+            let mut written = false;
+            for index in 0..self.blocks.pages.len() {
+                let page = &self.blocks.pages[index];
+                if !page.dirty {
+                    continue;
+                }
+                let blocks = page.blocks.get().expect("changed pages have been read");
+                let bytes = rmp_serde::to_vec(blocks)?;
+                let (len, end) = (
+                    blocks.len() as u64,
+                    blocks
+                        .values()
+                        .map(|b| b.file_offset.end_offset(b.allocated_length))
+                        .max()
+                        .unwrap_or_default(),
+                );
+
+                let (offset, length) = if self.index_pages_at_end {
+                    self.reserve_space_at_end(writer, header, bytes.len() as u64)?
+                } else {
+                    self.reserve_space(
+                        writer,
+                        header,
+                        bytes.len() as u64,
+                        OverallocationPolicy::None,
+                    )?
+                };
+                writer.write_all_at(&bytes, offset.0)?;
+                written = true;
+
+                let page = &mut self.blocks.pages[index];
+                let location = IndexPageLocation {
+                    offset,
+                    length,
+                    checksum: self.checksum_algorithm.checksum(bytes.as_slice()),
+                };
+                self.blocks.stale.extend(page.location.replace(location));
+                (page.len, page.end, page.dirty) = (len, end, false);
+            }
+            if written {
+                // the pages must be durable before a footer points to them
+                writer.sync()?;
+            }
+        }
+        if !self.blocks.stale.is_empty() {
+            for location in std::mem::take(&mut self.blocks.stale) {
+                self.release_space(location.offset, location.length);
+            }
+            self.consolidate_empty_space();
+        }
+        Ok(())
+    }
+}
+
+/// Iterator over the blocks of a `BlockMap`, see `BlockMap::iter()`.
+pub struct Iter<'a> {
+    pages: std::slice::Iter<'a, IndexPage>,
+    page: btree_map::Iter<'a, Identifier, BlockDescriptor>,
+}
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a Identifier, &'a BlockDescriptor);
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(item) = self.page.next() {
+                return Some(item);
+            }
+            if let Some(blocks) = self.pages.next()?.blocks.get() {
+                self.page = blocks.iter();
+            }
+        }
+    }
+}
+impl<'a> IntoIterator for &'a BlockMap {
+    type Item = (&'a Identifier, &'a BlockDescriptor);
+    type IntoIter = Iter<'a>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+impl Index<&Identifier> for BlockMap {
+    type Output = BlockDescriptor;
+    fn index(&self, identifier: &Identifier) -> &Self::Output {
+        self.get(identifier).expect("block not found")
+    }
+}
+impl FromIterator<(Identifier, BlockDescriptor)> for BlockMap {
+    fn from_iter<T: IntoIterator<Item = (Identifier, BlockDescriptor)>>(iter: T) -> Self {
+        Self::from_map(iter.into_iter().collect())
+    }
+}
+
+// Stored in the footer as a map of the blocks when unpaged (the format used before paging), or as
+// the page size followed by the list of pages when paged.
+impl Serialize for BlockMap {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.page_size == 0 {
+            return serializer.collect_map(self.iter());
+        }
+        let pages = self
+            .pages
+            .iter()
+            .map(|page| match page.location {
+                Some(location) if !page.dirty => Ok(StoredPage {
+                    first: page.first.clone(),
+                    location,
+                    len: page.len,
+                    end: page.end,
+                }),
+                _ => Err(ser::Error::custom(
+                    "index pages must be written before the footer",
+                )),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        (self.page_size, pages).serialize(serializer)
+    }
+}
+impl<'de> Deserialize<'de> for BlockMap {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(BlockMapVisitor)
+    }
+}
+struct BlockMapVisitor;
+impl<'de> Visitor<'de> for BlockMapVisitor {
+    type Value = BlockMap;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a map of blocks or a list of index pages")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut blocks = BTreeMap::new();
+        while let Some((identifier, descriptor)) = map.next_entry()? {
+            blocks.insert(identifier, descriptor);
+        }
+        Ok(BlockMap::from_map(blocks))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let page_size: usize = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let pages: Vec<StoredPage> = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+        if page_size == 0 || pages.is_empty() {
+            return Err(de::Error::custom("index must have at least one page"));
+        }
+        let pages = pages
+            .into_iter()
+            .map(|page| IndexPage {
+                first: page.first,
+                location: Some(page.location),
+                len: page.len,
+                end: page.end,
+                blocks: OnceLock::new(),
+                dirty: false,
+            })
+            .collect();
+        Ok(BlockMap {
+            page_size,
+            pages,
+            stale: vec![],
+        })
+    }
+}
@@ -0,0 +1,118 @@
+use std::hash::Hasher;
+
+use twox_hash::{XxHash3_64, XxHash64};
+
+use super::*;
+
+/// The algorithm used for block, chunk, journal and footer checksums. It is chosen when a
+/// container is created and recorded in the header.
+///
+/// Every algorithm produces a 64 bit `Checksum`, longer digests are truncated to their first
+/// 8 bytes (little-endian).
+#[derive(Clone, Default, Debug, Copy, PartialEq, Eq, Hash)]
+pub enum ChecksumAlgorithm {
+    /// XxHash64 with seed 4321. Used by every container created before the algorithm was
+    /// recorded in the header.
+    #[default]
+    XxHash64,
+    /// XxHash3 (64 bit) with seed 4321. Faster than XxHash64 on modern CPUs.
+    XxHash3_64,
+    /// CRC32C (Castagnoli), hardware accelerated on most CPUs and common in other formats.
+    #[cfg(feature = "crc32c")]
+    Crc32c,
+    /// BLAKE3, a cryptographic hash, for tamper evidence. Truncated to 64 bits, which is short
+    /// for a cryptographic guarantee but far stronger than the non-cryptographic options.
+    #[cfg(feature = "blake3")]
+    Blake3,
+}
+impl ChecksumAlgorithm {
+    /// Every algorithm available in this build.
+    pub const ALL: &'static [Self] = &[
+        Self::XxHash64,
+        Self::XxHash3_64,
+        #[cfg(feature = "crc32c")]
+        Self::Crc32c,
+        #[cfg(feature = "blake3")]
+        Self::Blake3,
+    ];
+
+    /// The byte identifying the algorithm in the header.
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Self::XxHash64 => 0,
+            Self::XxHash3_64 => 1,
+            #[cfg(feature = "crc32c")]
+            Self::Crc32c => 2,
+            #[cfg(feature = "blake3")]
+            Self::Blake3 => 3,
+        }
+    }
+    /// The algorithm identified by the given header byte, or None if it is unknown or not
+    /// available in this build.
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        Self::ALL.iter().copied().find(|a| a.to_byte() == byte)
+    }
+
+    pub fn checksum(self, bytes: &[u8]) -> Checksum {
+        match self {
+            Self::XxHash64 => Checksum(XxHash64::oneshot(4321, bytes)),
+            Self::XxHash3_64 => Checksum(XxHash3_64::oneshot_with_seed(4321, bytes)),
+            #[cfg(feature = "crc32c")]
+            Self::Crc32c => Checksum(crc32c::crc32c(bytes) as u64),
+            #[cfg(feature = "blake3")]
+            Self::Blake3 => truncate_blake3(&blake3::hash(bytes)),
+        }
+    }
+
+    /// A hasher for computing a checksum over data that arrives in pieces.
+    pub(crate) fn hasher(self) -> ChecksumHasher {
+        match self {
+            Self::XxHash64 => ChecksumHasher::XxHash64(XxHash64::with_seed(4321)),
+            Self::XxHash3_64 => ChecksumHasher::XxHash3_64(Box::new(XxHash3_64::with_seed(4321))),
+            #[cfg(feature = "crc32c")]
+            Self::Crc32c => ChecksumHasher::Crc32c(0),
+            #[cfg(feature = "blake3")]
+            Self::Blake3 => ChecksumHasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+}
+
+/// Streaming version of `ChecksumAlgorithm::checksum`.
+pub(crate) enum ChecksumHasher {
+    XxHash64(XxHash64),
+    XxHash3_64(Box<XxHash3_64>),
+    #[cfg(feature = "crc32c")]
+    Crc32c(u32),
+    #[cfg(feature = "blake3")]
+    Blake3(Box<blake3::Hasher>),
+}
+impl ChecksumHasher {
+    pub(crate) fn write(&mut self, bytes: &[u8]) {
+        match self {
+            Self::XxHash64(hasher) => hasher.write(bytes),
+            Self::XxHash3_64(hasher) => hasher.write(bytes),
+            #[cfg(feature = "crc32c")]
+            Self::Crc32c(crc) => *crc = crc32c::crc32c_append(*crc, bytes),
+            #[cfg(feature = "blake3")]
+            Self::Blake3(hasher) => {
+                hasher.update(bytes);
+            }
+        }
+    }
+    /// The checksum of everything written so far.
+    pub(crate) fn finish(&self) -> Checksum {
+        match self {
+            Self::XxHash64(hasher) => Checksum(hasher.finish()),
+            Self::XxHash3_64(hasher) => Checksum(hasher.finish()),
+            #[cfg(feature = "crc32c")]
+            Self::Crc32c(crc) => Checksum(*crc as u64),
+            #[cfg(feature = "blake3")]
+            Self::Blake3(hasher) => truncate_blake3(&hasher.finalize()),
+        }
+    }
+}
+
+#[cfg(feature = "blake3")]
+fn truncate_blake3(hash: &blake3::Hash) -> Checksum {
+    Checksum(u64::from_le_bytes(hash.as_bytes()[..8].try_into().unwrap()))
+}
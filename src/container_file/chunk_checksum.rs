@@ -0,0 +1,169 @@
+use std::ops::Range;
+
+use crate::error::CogtainerError;
+
+use super::*;
+
+/// How block data is checksummed.
+#[derive(Clone, Default, Debug, Copy, PartialEq, Eq)]
+pub enum ChecksumGranularity {
+    /// One checksum over the whole block. Verifying any part of a block means reading all of it.
+    #[default]
+    Block,
+    /// One checksum per `CHUNK_SIZE` bytes, stored in the `BlockDescriptor`. Ranged reads only
+    /// verify the chunks they touch, and in-place writes only rehash the chunks they change.
+    Chunks,
+}
+
+/// Size of the chunks checksummed individually with `ChecksumGranularity::Chunks`.
+pub const CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Checksum of every chunk of the data.
+pub(crate) fn calc_chunk_checksums(data: &[u8], algorithm: ChecksumAlgorithm) -> Vec<Checksum> {
+    data.chunks(CHUNK_SIZE as usize)
+        .map(|chunk| algorithm.checksum(chunk))
+        .collect()
+}
+/// The block checksum of a chunked block, which covers the checksums of its chunks.
+pub(crate) fn calc_root_checksum(
+    chunk_checksums: &[Checksum],
+    algorithm: ChecksumAlgorithm,
+) -> Checksum {
+    let mut hasher = algorithm.hasher();
+    for checksum in chunk_checksums {
+        hasher.write(&checksum.to_le_bytes());
+    }
+    hasher.finish()
+}
+/// Calculates the block checksum and chunk checksums (empty unless the data is chunked) of the
+/// given data.
+pub(crate) fn calc_block_checksums(
+    data: &[u8],
+    granularity: ChecksumGranularity,
+    algorithm: ChecksumAlgorithm,
+) -> (Checksum, Vec<Checksum>) {
+    match granularity {
+        ChecksumGranularity::Chunks if !data.is_empty() => {
+            let chunks = calc_chunk_checksums(data, algorithm);
+            (calc_root_checksum(&chunks, algorithm), chunks)
+        }
+        _ => (algorithm.checksum(data), vec![]),
+    }
+}
+
+/// Computes chunk checksums of data that arrives in pieces of any size.
+pub(crate) struct ChunkHasher {
+    algorithm: ChecksumAlgorithm,
+    hasher: ChecksumHasher,
+    /// Bytes hashed into the current chunk.
+    in_chunk: u64,
+    chunks: Vec<Checksum>,
+}
+impl ChunkHasher {
+    pub(crate) fn new(algorithm: ChecksumAlgorithm) -> Self {
+        Self {
+            algorithm,
+            hasher: algorithm.hasher(),
+            in_chunk: 0,
+            chunks: vec![],
+        }
+    }
+    pub(crate) fn write(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            let len = ((CHUNK_SIZE - self.in_chunk) as usize).min(bytes.len());
+            self.hasher.write(&bytes[..len]);
+            self.in_chunk += len as u64;
+            bytes = &bytes[len..];
+            if self.in_chunk == CHUNK_SIZE {
+                self.finish_chunk();
+            }
+        }
+    }
+    fn finish_chunk(&mut self) {
+        self.chunks.push(self.hasher.finish());
+        self.hasher = self.algorithm.hasher();
+        self.in_chunk = 0;
+    }
+    /// Returns the block checksum and the chunk checksums.
+    pub(crate) fn finish(mut self) -> (Checksum, Vec<Checksum>) {
+        if self.in_chunk > 0 {
+            self.finish_chunk();
+        }
+        (
+            calc_root_checksum(&self.chunks, self.algorithm),
+            self.chunks,
+        )
+    }
+}
+
+/// BlockDescriptor functions related to checksums.
+impl BlockDescriptor {
+    /// Returns true if the block has a checksum per chunk.
+    pub fn is_chunked(&self) -> bool {
+        !self.chunk_checksums.is_empty()
+    }
+    /// Returns true if the given data matches the block's checksums.
+    pub fn checksum_matches(&self, data: &[u8], algorithm: ChecksumAlgorithm) -> bool {
+        if self.is_chunked() {
+            let chunks = data.chunks(CHUNK_SIZE as usize);
+            chunks.len() == self.chunk_checksums.len()
+                && chunks
+                    .zip(&self.chunk_checksums)
+                    .all(|(chunk, checksum)| algorithm.checksum(chunk) == *checksum)
+                && calc_root_checksum(&self.chunk_checksums, algorithm) == self.checksum
+        } else {
+            algorithm.checksum(data) == self.checksum
+        }
+    }
+    /// Returns true if the given bytes match the checksum of the chunk with the given index.
+    pub fn chunk_matches(&self, index: usize, bytes: &[u8], algorithm: ChecksumAlgorithm) -> bool {
+        self.chunk_checksums.get(index) == Some(&algorithm.checksum(bytes))
+    }
+    /// The range of the block data covered by the chunk with the given index.
+    pub(crate) fn chunk_range(&self, index: usize) -> (u64, u64) {
+        let start = index as u64 * CHUNK_SIZE;
+        (start, (start + CHUNK_SIZE).min(self.used_length))
+    }
+
+    /// Recomputes the checksums of the chunks overlapping `changed` and of any chunks past the old
+    /// end of the block, and the block checksum, by reading them back. `used_length` must already
+    /// be the new length; other chunks are assumed unchanged.
+    pub(crate) fn rehash_chunks<R: Storage>(
+        &mut self,
+        reader: &R,
+        changed: Range<u64>,
+        algorithm: ChecksumAlgorithm,
+    ) -> Result<(), CogtainerError> {
+        let count = self.used_length.div_ceil(CHUNK_SIZE) as usize;
+        self.chunk_checksums.truncate(count);
+        let kept = self.chunk_checksums.len();
+        let touched = if changed.is_empty() {
+            0..0
+        } else {
+            (changed.start / CHUNK_SIZE) as usize..((changed.end - 1) / CHUNK_SIZE) as usize + 1
+        };
+        let mut buf = vec![0u8; CHUNK_SIZE as usize];
+        for index in touched.start.min(kept)..touched.end.min(kept) {
+            self.chunk_checksums[index] =
+                self.read_chunk_checksum(reader, index, &mut buf, algorithm)?;
+        }
+        for index in kept..count {
+            let checksum = self.read_chunk_checksum(reader, index, &mut buf, algorithm)?;
+            self.chunk_checksums.push(checksum);
+        }
+        self.checksum = calc_root_checksum(&self.chunk_checksums, algorithm);
+        Ok(())
+    }
+    fn read_chunk_checksum<R: Storage>(
+        &self,
+        reader: &R,
+        index: usize,
+        buf: &mut [u8],
+        algorithm: ChecksumAlgorithm,
+    ) -> Result<Checksum, CogtainerError> {
+        let (start, end) = self.chunk_range(index);
+        let bytes = &mut buf[..(end - start) as usize];
+        reader.read_exact_at(bytes, self.data_offset().0 + start)?;
+        Ok(algorithm.checksum(bytes))
+    }
+}
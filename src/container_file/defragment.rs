@@ -0,0 +1,192 @@
+use std::collections::BTreeMap;
+
+use crate::error::CogtainerError;
+
+use super::*;
+
+/// How much work a call to `Cogtainer::defragment_step` may do.
+///
+/// The first block of a step is always moved, even if it is larger than the budget, so every
+/// step makes progress.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DefragmentBudget {
+    /// Stop before copying more than this many bytes.
+    Bytes(u64),
+    /// Stop after moving this many blocks.
+    Blocks(usize),
+}
+impl DefragmentBudget {
+    /// Whether a step that has moved `blocks` blocks and copied `bytes` bytes may copy a block
+    /// of `length` bytes next.
+    pub(crate) fn allows(&self, blocks: usize, bytes: u64, length: u64) -> bool {
+        blocks == 0
+            || match *self {
+                Self::Bytes(max) => bytes + length <= max,
+                Self::Blocks(max) => blocks < max,
+            }
+    }
+}
+
+/// What a call to `Cogtainer::defragment_step` did.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DefragmentProgress {
+    pub blocks_moved: usize,
+    /// Bytes copied, including inline block headers. A block that has to slide into the hole
+    /// right before it is copied twice (see `ContainerFooter::move_block_down`).
+    pub bytes_moved: u64,
+    /// Total empty space left in the data region after the step.
+    pub empty_space: u64,
+    /// Set once every block that can move down has, and the footer follows the last block.
+    /// Any empty space left is alignment padding (or next to quarantined blocks).
+    pub done: bool,
+}
+
+/// ContainerFooter functions related to defragmenting.
+impl ContainerFooter {
+    /// The identifiers of the blocks that occupy space in the file, by offset.
+    pub(crate) fn blocks_by_offset(&self) -> BTreeMap<FileOffset, Identifier> {
+        self.blocks
+            .iter()
+            .filter(|(_, descriptor)| descriptor.allocated_length > 0)
+            .map(|(identifier, descriptor)| (descriptor.file_offset, identifier.clone()))
+            .collect()
+    }
+
+    /// Moves a block into the lowest hole below it that it fits in. If there is none, but
+    /// the block fits in the hole right before it together with its own allocation, it slides
+    /// down into that. Returns the number of bytes copied, or None if the block can't move down
+    /// (for example because the hole before it is alignment padding).
+    ///
+    /// `freed` lists the space freed since the footer was last committed. The committed footer
+    /// still refers to data there, so the footer is committed before any of it is overwritten.
+    /// A slide overlaps the block's own data, so it takes two hops: out of the way (and
+    /// committed), then into place.
+    ///
+    /// Expects the first-fit allocation strategy.
+    pub(crate) fn move_block_down<W: Storage>(
+        &mut self,
+        writer: &mut W,
+        header: &mut ContainerHeader,
+        identifier: &Identifier,
+        freed: &mut Vec<(FileOffset, u64)>,
+    ) -> Result<Option<u64>, CogtainerError> {
+        debug_assert_eq!(self.allocation_strategy, AllocationStrategy::FirstFit);
+        let descriptor = self
+            .blocks
+            .get(identifier)
+            .ok_or_else(|| CogtainerError::BlockNotFound(identifier.clone()))?;
+        let length = descriptor.header_length + descriptor.used_length;
+        if length == 0 {
+            // nothing to copy, only the allocation to give back
+            let descriptor = self.blocks.get_mut(identifier).unwrap();
+            let (offset, allocated_length) = (descriptor.file_offset, descriptor.allocated_length);
+            descriptor.file_offset = FileOffset(0);
+            descriptor.allocated_length = 0;
+            self.release_space_now(offset, allocated_length);
+            self.consolidate_empty_space();
+            freed.push((offset, allocated_length));
+            self.pending_changes += 1;
+            return Ok(Some(0));
+        }
+
+        let hole = self.empty_space.find_aligned(
+            length,
+            AllocationStrategy::FirstFit,
+            self.block_alignment,
+            descriptor.header_length,
+        );
+        if hole.is_some_and(|hole| hole < descriptor.file_offset) {
+            self.relocate_block(writer, header, identifier, freed)?;
+            return Ok(Some(length));
+        }
+
+        let Some((&hole, &hole_length)) =
+            self.empty_space.range(..descriptor.file_offset).next_back()
+        else {
+            return Ok(None);
+        };
+        let slid_to = aligned_offset(hole, self.block_alignment, descriptor.header_length);
+        if hole.end_offset(hole_length) != descriptor.file_offset
+            || slid_to >= descriptor.file_offset
+        {
+            return Ok(None);
+        }
+        // nothing below fits, so once the block is out of the way the first fit is the hole
+        // merged with its old allocation
+        self.relocate_block(writer, header, identifier, freed)?;
+        self.relocate_block(writer, header, identifier, freed)?;
+        debug_assert_eq!(self.blocks[identifier].file_offset, slid_to);
+        Ok(Some(length * 2))
+    }
+
+    /// Copies a block to the space `reserve_block_space` picks for it (without overallocation)
+    /// and frees its old allocation.
+    fn relocate_block<W: Storage>(
+        &mut self,
+        writer: &mut W,
+        header: &mut ContainerHeader,
+        identifier: &Identifier,
+        freed: &mut Vec<(FileOffset, u64)>,
+    ) -> Result<(), CogtainerError> {
+        let descriptor = self.blocks[identifier].clone();
+        let length = descriptor.header_length + descriptor.used_length;
+
+        let target = self
+            .empty_space
+            .find_aligned(
+                length,
+                AllocationStrategy::FirstFit,
+                self.block_alignment,
+                descriptor.header_length,
+            )
+            .map(|hole| aligned_offset(hole, self.block_alignment, descriptor.header_length));
+        if let Some(target) = target {
+            let end = target.end_offset(length);
+            if freed
+                .iter()
+                .any(|(offset, len)| *offset < end && target < offset.end_offset(*len))
+            {
+                self.persist(writer, header)?;
+                freed.clear();
+            }
+        }
+        let (file_offset, allocated_length) = self.reserve_block_space(
+            writer,
+            header,
+            descriptor.header_length,
+            descriptor.used_length,
+            OverallocationPolicy::None,
+        )?;
+        copy_within(writer, descriptor.file_offset, file_offset, length)?;
+
+        let moved = self.blocks.get_mut(identifier).unwrap();
+        moved.file_offset = file_offset;
+        moved.allocated_length = allocated_length;
+        self.write_inline_header(writer, header, identifier)?;
+
+        self.release_space_now(descriptor.file_offset, descriptor.allocated_length);
+        self.consolidate_empty_space();
+        freed.push((descriptor.file_offset, descriptor.allocated_length));
+        self.pending_changes += 1;
+        Ok(())
+    }
+}
+
+/// Copies `length` bytes from `from` to `to`, which must not overlap.
+fn copy_within<W: Storage>(
+    file: &mut W,
+    from: FileOffset,
+    to: FileOffset,
+    length: u64,
+) -> Result<(), CogtainerError> {
+    const BUFFER_SIZE: u64 = 64 * 1024;
+    let mut buf = vec![0u8; length.min(BUFFER_SIZE) as usize];
+    let mut copied = 0;
+    while copied < length {
+        let chunk = (length - copied).min(BUFFER_SIZE) as usize;
+        file.read_exact_at(&mut buf[..chunk], from.0 + copied)?;
+        file.write_all_at(&buf[..chunk], to.0 + copied)?;
+        copied += chunk as u64;
+    }
+    Ok(())
+}
@@ -0,0 +1,31 @@
+/// Controls when changes to the footer are written to the file.
+#[derive(Clone, Default, Debug, Copy, PartialEq, Eq)]
+pub enum DurabilityMode {
+    /// The footer is committed after every change (deletes still wait for `flush()`). Inserting
+    /// many blocks rewrites the whole footer every time.
+    ///
+    /// If the process dies, the file opens as of the last commit. Space freed since then isn't
+    /// reused until the next commit, so replaced blocks keep their old data until then. Writes
+    /// through `InternalFile` that fit in the block's allocation change it in place, so such a
+    /// block may fail its checksum after a crash.
+    #[default]
+    WriteThrough,
+    /// The footer is only marked dirty, and is committed on `flush()`, when the container is
+    /// dropped, or after `flush_every` changes (if set).
+    ///
+    /// Changes made since the last commit are lost if the process dies, the file opens as of the
+    /// last commit. Space freed since then isn't reused until the next commit, so replacing
+    /// blocks grows the file until then.
+    WriteBack { flush_every: Option<u64> },
+}
+impl DurabilityMode {
+    /// Returns true if `pending_changes` uncommitted changes should be written now.
+    pub fn is_due(&self, pending_changes: u64) -> bool {
+        match self {
+            DurabilityMode::WriteThrough => true,
+            DurabilityMode::WriteBack { flush_every } => {
+                flush_every.is_some_and(|n| pending_changes >= n)
+            }
+        }
+    }
+}
@@ -1,7 +1,4 @@
-use std::{
-    collections::{BTreeMap, HashMap},
-    io::SeekFrom,
-};
+use std::collections::HashMap;
 
 use serde::{Deserialize, Serialize};
 
@@ -16,33 +13,127 @@ pub struct BlockDescriptor {
     pub allocated_length: u64,
     pub checksum: Checksum,
     pub metadata: rmpv::Value,
+    /// Length of the inline block header at the start of the allocation (0 if there isn't one).
+    /// The block data starts right after it.
+    #[serde(default)]
+    pub header_length: u64,
+    /// Checksums of each `CHUNK_SIZE` chunk of the data, if the block was written with
+    /// `ChecksumGranularity::Chunks`. In that case `checksum` covers these checksums.
+    #[serde(default)]
+    pub chunk_checksums: Vec<Checksum>,
+}
+impl BlockDescriptor {
+    /// Where the block data starts.
+    pub fn data_offset(&self) -> FileOffset {
+        self.file_offset.end_offset(self.header_length)
+    }
+    /// How much data fits in the allocation.
+    pub fn capacity(&self) -> u64 {
+        self.allocated_length.saturating_sub(self.header_length)
+    }
 }
 
 /// Maintains the metadata and overall structure of the file. This includes occupied blocks and empty space.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ContainerFooter {
     /// Domain-specific information relevant for this file.
     pub metadata: rmpv::Value,
 
     /// The value is the offset location within the file where the block is stored. Blocks are
-    /// not in any guaranteed order.
-    pub blocks: HashMap<Identifier, BlockDescriptor>,
+    /// sorted by identifier. When paged, only the pages that have been read are listed.
+    pub blocks: BlockMap,
 
     /// When a block is removed (or moved to the end if it's too big), its
     /// space is merged into the empty_space list for use when another block is needed or
     /// to ease defragmenting. (neighboring BlockDescriptors are merged together)
-    pub empty_space: BTreeMap<FileOffset, u64>,
+    pub empty_space: EmptySpace,
+
+    /// A transaction journal that was committed but not yet applied.
+    /// Only set while a transaction is being committed.
+    #[serde(default)]
+    pub journal: Option<JournalLocation>,
+
+    /// Blocks removed by `repair()` because their data is corrupt or overlaps another block.
+    /// Their space stays reserved until the quarantine is cleared.
+    #[serde(default)]
+    pub quarantine: HashMap<Identifier, BlockDescriptor>,
+
+    /// When changes are written to the file. Not stored in the file.
+    #[serde(skip)]
+    pub(crate) durability: DurabilityMode,
+    /// How holes in the empty space are picked for new allocations. Not stored in the file.
+    #[serde(skip)]
+    pub(crate) allocation_strategy: AllocationStrategy,
+    /// Block data of new allocations starts on a multiple of this (values below 2 mean no
+    /// alignment). Not stored in the file.
+    #[serde(skip)]
+    pub(crate) block_alignment: u64,
+    /// How new blocks are checksummed. Not stored in the file.
+    #[serde(skip)]
+    pub(crate) checksum_granularity: ChecksumGranularity,
+    /// Copy of the header's checksum algorithm, set when the footer is created or read.
+    #[serde(skip)]
+    pub(crate) checksum_algorithm: ChecksumAlgorithm,
+    /// Empty space before this is padding that `defragment_step` found it can't fill. Not
+    /// stored in the file.
+    #[serde(skip)]
+    pub(crate) defragmented_to: FileOffset,
+    /// What freed space is overwritten with. Not stored in the file.
+    #[serde(skip)]
+    pub(crate) scrub_mode: ScrubMode,
+    /// Space freed since the last commit, scrubbed once the commit no longer refers to it.
+    #[serde(skip)]
+    pub(crate) released: Vec<(FileOffset, u64)>,
+    /// Number of changes made since the footer was last written.
+    #[serde(skip)]
+    pub(crate) pending_changes: u64,
+    /// Index pages are written after the data instead of into empty space, so they don't take
+    /// the holes `defragment_step` is moving blocks into. Not stored in the file.
+    #[serde(skip)]
+    pub(crate) index_pages_at_end: bool,
+    /// Growth at the end of the data is preallocated this many bytes ahead (0 for none). Not
+    /// stored in the file.
+    #[serde(skip)]
+    pub(crate) preallocation: u64,
+    /// The end of the space preallocated so far. Not stored in the file.
+    #[serde(skip)]
+    pub(crate) preallocated_to: FileOffset,
+    /// Space freed since the last flush, which is punched out of the file on the next one. Only
+    /// set while hole punching is on. Not stored in the file.
+    #[serde(skip)]
+    pub(crate) unpunched: Option<Vec<(FileOffset, u64)>>,
 }
 /// ContainerFooter functions related to writing.
 impl ContainerFooter {
-    pub(crate) fn create<W: std::io::Write + std::io::Seek>(
+    /// Extra space left between the end of the data and a relocated footer.
+    const FOOTER_HEADROOM: u64 = 256;
+    /// How far ahead growth is preallocated when holes are punched, so the file doesn't end up
+    /// in small extents, unless `preallocate()` set a distance.
+    const PUNCHED_PREALLOCATION: u64 = 1 << 20;
+
+    pub(crate) fn create<W: Storage>(
         writer: &mut W,
         header: &mut ContainerHeader,
     ) -> Result<Self, CogtainerError> {
         let me = Self {
             metadata: rmpv::Value::Nil,
-            blocks: HashMap::new(),
-            empty_space: BTreeMap::new(),
+            blocks: BlockMap::default(),
+            empty_space: EmptySpace::default(),
+            journal: None,
+            quarantine: HashMap::new(),
+            durability: DurabilityMode::default(),
+            allocation_strategy: AllocationStrategy::default(),
+            block_alignment: 1,
+            checksum_granularity: ChecksumGranularity::default(),
+            checksum_algorithm: header.checksum_algorithm,
+            defragmented_to: FileOffset(0),
+            scrub_mode: ScrubMode::default(),
+            released: vec![],
+            pending_changes: 0,
+            index_pages_at_end: false,
+            preallocation: 0,
+            preallocated_to: FileOffset(0),
+            unpunched: None,
         };
         me.write_to(writer, header)?;
 
@@ -50,37 +141,165 @@ impl ContainerFooter {
     }
     /// Writes this footer to the given writer.
     /// Updates the header with the footer's length, and writes that to the file as well.
-    pub fn write_to<W: std::io::Write + std::io::Seek>(
+    ///
+    /// The footer is written to `header.footer_offset`. If that overlaps the committed footer, the
+    /// new footer is first committed past both, so that there is always an intact footer for the
+    /// header to point to. The writer is flushed before the header is rewritten.
+    pub fn write_to<W: Storage>(
         &self,
         writer: &mut W,
         header: &mut ContainerHeader,
     ) -> Result<(), CogtainerError> {
-        let initial_position = writer.stream_position()?;
-        writer.seek(SeekFrom::Start(header.footer_offset.0))?;
-
         let bytes = rmp_serde::to_vec(&self)?;
-        let calc_checksum = calc_checksum(bytes.as_slice());
-
-        header.footer_length = bytes.len() as u64;
-        header.footer_checksum = calc_checksum;
-
-        writer.write_all(&bytes)?;
+        let location = FooterLocation {
+            offset: header.footer_offset,
+            length: bytes.len() as u64,
+            checksum: header.checksum_algorithm.checksum(bytes.as_slice()),
+        };
+        let committed = header.committed_footer();
+        if committed.overlaps(location.offset, location.end_offset()) {
+            let scratch = FooterLocation {
+                offset: location.end_offset().max(committed.end_offset()),
+                ..location
+            };
+            Self::write_slot(writer, header, scratch, &bytes)?;
+        }
+        Self::write_slot(writer, header, location, &bytes)?;
+        Ok(())
+    }
+    /// Writes this footer (like `write_to`) and marks it clean.
+    ///
+    /// If the data ends in empty space, the footer then moves down into it. That space may hold
+    /// blocks the previously committed footer still refers to, so this only happens once the
+    /// footer that frees them is committed.
+    pub(crate) fn persist<W: Storage>(
+        &mut self,
+        writer: &mut W,
+        header: &mut ContainerHeader,
+    ) -> Result<(), CogtainerError> {
+        self.write_index_pages(writer, header)?;
+        self.write_to(writer, header)?;
+        self.pending_changes = 0;
+        // the committed footer no longer refers to the freed space
+        if writer.freed_space_reusable() {
+            self.empty_space.reuse_released();
+            self.consolidate_empty_space();
+            self.scrub_released(writer)?;
+            if let Some((offset, _)) = self.trailing_empty_space(header) {
+                self.empty_space.remove(&offset);
+                header.footer_offset = offset;
+                self.write_to(writer, header)?;
+            }
+        }
+        Ok(())
+    }
+    /// The empty region that ends where the footer is written, if there is one.
+    pub(crate) fn trailing_empty_space(
+        &self,
+        header: &ContainerHeader,
+    ) -> Option<(FileOffset, u64)> {
+        self.empty_space
+            .iter()
+            .next_back()
+            .filter(|(offset, len)| offset.end_offset(**len) == header.footer_offset)
+            .map(|(offset, len)| (*offset, *len))
+    }
+    /// Counts a change to the footer, then writes it if the durability mode calls for it.
+    pub(crate) fn record_change<W: Storage>(
+        &mut self,
+        writer: &mut W,
+        header: &mut ContainerHeader,
+    ) -> Result<(), CogtainerError> {
+        self.pending_changes += 1;
+        self.write_if_due(writer, header)
+    }
+    /// Writes the footer if there are pending changes and the durability mode calls for it.
+    pub(crate) fn write_if_due<W: Storage>(
+        &mut self,
+        writer: &mut W,
+        header: &mut ContainerHeader,
+    ) -> Result<(), CogtainerError> {
+        if self.pending_changes > 0 && self.durability.is_due(self.pending_changes) {
+            self.persist(writer, header)?;
+        }
+        Ok(())
+    }
+    /// Returns true if there are changes that haven't been written to the file yet.
+    pub fn is_dirty(&self) -> bool {
+        self.pending_changes > 0
+    }
+    /// Copies the settings that aren't stored in the file from `old`, for a footer read again.
+    pub(crate) fn keep_settings(&mut self, old: &ContainerFooter) {
+        self.durability = old.durability;
+        self.allocation_strategy = old.allocation_strategy;
+        self.block_alignment = old.block_alignment;
+        self.checksum_granularity = old.checksum_granularity;
+        self.scrub_mode = old.scrub_mode;
+        self.preallocation = old.preallocation;
+        self.preallocated_to = old.preallocated_to;
+        // the holes of a footer read again are unknown, so all of them are punched
+        self.unpunched = old
+            .unpunched
+            .as_ref()
+            .map(|_| self.empty_space.iter().map(|(o, l)| (*o, *l)).collect());
+    }
+    /// Writes already serialized footer bytes (and the header trailer) to the given location, then
+    /// points the header at it. `header` is only changed once all of that is on disk, so a failed
+    /// commit can be retried.
+    fn write_slot<W: Storage>(
+        writer: &mut W,
+        header: &mut ContainerHeader,
+        location: FooterLocation,
+        bytes: &[u8],
+    ) -> Result<(), CogtainerError> {
+        let mut committed = header.clone();
+        let on_disk = committed.commit_footer(location);
 
-        // Since the header was updated, and the footer was just written, write the header too
-        header.write_to(writer)?;
+        writer.write_all_at(bytes, location.offset.0)?;
+        on_disk.write_trailer_to(writer)?;
+        // the footer must be durable before the header points to it
+        writer.sync()?;
 
-        writer.seek(SeekFrom::Start(initial_position))?;
+        on_disk.write_to(writer)?;
+        writer.sync()?;
+        *header = committed;
+        Ok(())
+    }
+    /// Makes sure writing to `start..end` won't overwrite the committed footer.
+    /// If it would, the committed footer is copied past the range (leaving room for the next
+    /// footer to be written at `end`) and the header is pointed at the copy.
+    pub(crate) fn protect_committed_footer<W: Storage>(
+        writer: &mut W,
+        header: &mut ContainerHeader,
+        start: FileOffset,
+        end: FileOffset,
+    ) -> Result<(), CogtainerError> {
+        let committed = header.committed_footer();
+        if !committed.overlaps(start, end) {
+            return Ok(());
+        }
+        let mut bytes = vec![0u8; committed.length as usize];
+        writer.read_exact_at(&mut bytes, committed.offset.0)?;
+
+        // leave room for the next footer to grow, otherwise it needs a second hop to land at `end`
+        let copy_offset = end.end_offset(committed.length * 2 + Self::FOOTER_HEADROOM);
+        let copy = FooterLocation {
+            offset: copy_offset.max(committed.end_offset()),
+            ..committed
+        };
+        Self::write_slot(writer, header, copy, &bytes)?;
         Ok(())
     }
     /// Updates the metadata for the given block.
     /// If the block doesn't exist, it is added with a length of 0.
-    pub fn update_block_metadata<W: std::io::Write + std::io::Seek>(
+    pub fn update_block_metadata<W: Storage>(
         &mut self,
         writer: &mut W,
         header: &mut ContainerHeader,
         identifier: Identifier,
         metadata: rmpv::Value,
     ) -> Result<(), CogtainerError> {
+        self.load_block(writer, &identifier)?;
         if let Some(descriptor) = self.blocks.get_mut(&identifier) {
             descriptor.metadata = metadata;
         } else {
@@ -90,54 +309,138 @@ impl ContainerFooter {
                 allocated_length: 0,
                 checksum: Checksum(0),
                 metadata,
+                header_length: 0,
+                chunk_checksums: vec![],
             };
             self.blocks.insert(identifier, descriptor);
         }
-        self.write_to(writer, header)?;
-        return Ok(());
+        self.record_change(writer, header)
     }
     /// Reserves the requested space and returns the FileOffset and length
-    /// - If there is space available in empty_space, removes from there and returns.
+    /// - If there is space available in empty_space (picked by the `AllocationStrategy`), removes from there and returns.
     /// - If not, then reserves at the footer's current address and updates the header with the new position after the reserved space.
-    fn reserve_space(
+    ///
+    /// If the space is reserved at the footer's address, the committed footer is moved out of the way first.
+    pub(crate) fn reserve_space<W: Storage>(
         &mut self,
+        writer: &mut W,
         header: &mut ContainerHeader,
         required_length: u64,
         policy: OverallocationPolicy,
-    ) -> (FileOffset, u64) {
-        let mut found_space = None;
-        for (file_offset, len) in self.empty_space.iter() {
-            // if the empty area is at least as large as required, then use it.
-            if len >= &required_length {
-                found_space = Some(*file_offset);
-                break;
-            }
-        }
-        if let Some(offset) = found_space {
-            if let Some(available_len) = self.empty_space.remove(&offset) {
+    ) -> Result<(FileOffset, u64), CogtainerError> {
+        self.reserve_aligned_space(writer, header, required_length, policy, 1, 0)
+    }
+    /// Reserves space for a block whose data, after a `header_length` byte inline header, starts
+    /// on a multiple of `self.block_alignment`.
+    pub(crate) fn reserve_block_space<W: Storage>(
+        &mut self,
+        writer: &mut W,
+        header: &mut ContainerHeader,
+        header_length: u64,
+        data_length: u64,
+        policy: OverallocationPolicy,
+    ) -> Result<(FileOffset, u64), CogtainerError> {
+        self.reserve_aligned_space(
+            writer,
+            header,
+            header_length + data_length,
+            policy,
+            self.block_alignment,
+            header_length,
+        )
+    }
+    /// Like `reserve_space`, but the returned offset plus `prefix` is a multiple of `alignment`.
+    /// The padding skipped to get there is added to the empty space.
+    fn reserve_aligned_space<W: Storage>(
+        &mut self,
+        writer: &mut W,
+        header: &mut ContainerHeader,
+        required_length: u64,
+        policy: OverallocationPolicy,
+        alignment: u64,
+        prefix: u64,
+    ) -> Result<(FileOffset, u64), CogtainerError> {
+        let found_space = self.empty_space.find_aligned(
+            required_length,
+            self.allocation_strategy,
+            alignment,
+            prefix,
+        );
+        if let Some(hole) = found_space {
+            if let Some(available_len) = self.empty_space.remove(&hole) {
+                let offset = aligned_offset(hole, alignment, prefix);
+                let padding = offset.0 - hole.0;
+                if padding > 0 {
+                    self.empty_space.insert(hole, padding);
+                }
                 // take only what's needed
-                let left_over = available_len - required_length;
+                let left_over = available_len - padding - required_length;
                 if left_over > 0 {
                     let end_address = offset.0 + required_length;
                     // add leftover space back to empty_space list
                     self.empty_space.insert(FileOffset(end_address), left_over);
                 }
-                return (offset, required_length);
+                return Ok((offset, required_length));
             }
         }
-        let offset = header.footer_offset;
+        let data_end = header.footer_offset;
+        let offset = aligned_offset(data_end, alignment, prefix);
         // only use overallocation policy when we have to move the footer
         let required_length = policy.calculate(required_length);
 
         // move the header to after the new block
         let new_footer_offset = offset.end_offset(required_length);
+        self.preallocate_growth(writer, data_end, new_footer_offset)?;
+        Self::protect_committed_footer(writer, header, data_end, new_footer_offset)?;
         header.footer_offset = new_footer_offset;
+        if offset > data_end {
+            self.empty_space.insert(data_end, offset.0 - data_end.0);
+            self.consolidate_empty_space();
+        }
 
-        (offset, required_length)
+        Ok((offset, required_length))
+    }
+    /// Reserves space at the end of the data, ignoring the empty space.
+    pub(crate) fn reserve_space_at_end<W: Storage>(
+        &mut self,
+        writer: &mut W,
+        header: &mut ContainerHeader,
+        required_length: u64,
+    ) -> Result<(FileOffset, u64), CogtainerError> {
+        let offset = header.footer_offset;
+        let new_footer_offset = offset.end_offset(required_length);
+        self.preallocate_growth(writer, offset, new_footer_offset)?;
+        Self::protect_committed_footer(writer, header, offset, new_footer_offset)?;
+        header.footer_offset = new_footer_offset;
+        Ok((offset, required_length))
+    }
+    /// When the data grows from `data_end` to `end` past the preallocated space, preallocates up
+    /// to `preallocation` bytes after `end`. Stops quietly if the file system can't.
+    pub(crate) fn preallocate_growth<W: Storage>(
+        &mut self,
+        writer: &mut W,
+        data_end: FileOffset,
+        end: FileOffset,
+    ) -> Result<(), CogtainerError> {
+        let step = match (self.preallocation, &self.unpunched) {
+            (0, Some(_)) => Self::PUNCHED_PREALLOCATION,
+            (step, _) => step,
+        };
+        if step == 0 || end <= self.preallocated_to {
+            return Ok(());
+        }
+        let start = data_end.max(self.preallocated_to);
+        let to = end.end_offset(step);
+        match writer.preallocate(start.0, to.0 - start.0) {
+            Err(err) if err.kind() == std::io::ErrorKind::Unsupported => {}
+            result => result?,
+        }
+        self.preallocated_to = to;
+        Ok(())
     }
 
     /// Adds the given block (or replaces it if it already exists).
-    pub fn insert_block<W: std::io::Write + std::io::Seek>(
+    pub fn insert_block<W: Storage>(
         &mut self,
         writer: &mut W,
         header: &mut ContainerHeader,
@@ -146,40 +449,35 @@ impl ContainerFooter {
         metadata: rmpv::Value,
         data: &[u8],
     ) -> Result<(), CogtainerError> {
-        let checksum = calc_checksum(data);
+        self.load_block(writer, identifier)?;
+        let (checksum, chunk_checksums) =
+            calc_block_checksums(data, self.checksum_granularity, self.checksum_algorithm);
         // always remove the old block. This gives the opportunity to consolidate empty space and simplifies the overall logic in this section.
         if let Some(descriptor) = self.blocks.remove(identifier) {
             if descriptor.allocated_length > 0 {
-                self.empty_space
-                    .insert(descriptor.file_offset, descriptor.allocated_length);
+                self.release_space(descriptor.file_offset, descriptor.allocated_length);
                 self.consolidate_empty_space();
             }
         }
         // write the data
-        if data.len() > 0 {
+        if !data.is_empty() {
+            let header_length = InlineBlockHeader::length_for(header, identifier)?;
             // find new empty space
             let (insert_file_offset, allocated_length) =
-                self.reserve_space(header, data.len() as u64, policy);
+                self.reserve_block_space(writer, header, header_length, data.len() as u64, policy)?;
 
+            let descriptor = BlockDescriptor {
+                file_offset: insert_file_offset,
+                used_length: data.len() as u64,
+                allocated_length,
+                checksum,
+                metadata,
+                header_length,
+                chunk_checksums,
+            };
+            Self::write_block_data(writer, header, identifier, &descriptor, data)?;
             // update the footer with the new offset/metadata
-            self.blocks.insert(
-                identifier.clone(),
-                BlockDescriptor {
-                    file_offset: insert_file_offset,
-                    used_length: data.len() as u64,
-                    allocated_length,
-                    checksum,
-                    metadata,
-                },
-            );
-
-            writer.seek(SeekFrom::Start(insert_file_offset.0))?;
-            writer.write_all(data)?;
-            // fill remaining space with zeros
-            if data.len() < allocated_length as usize {
-                let zeros = vec![0u8; allocated_length as usize - data.len()];
-                writer.write_all(&zeros)?;
-            }
+            self.blocks.insert(identifier.clone(), descriptor);
         } else {
             // no data block
             self.blocks.insert(
@@ -190,15 +488,70 @@ impl ContainerFooter {
                     allocated_length: 0,
                     checksum,
                     metadata,
+                    header_length: 0,
+                    chunk_checksums: vec![],
                 },
             );
         }
         // write the footer (which also writes the header)
-        self.write_to(writer, header)
+        self.record_change(writer, header)
+    }
+
+    /// Writes a block's inline header (if it has one) and data to the block's allocation, and
+    /// fills the rest of the allocation with zeros.
+    pub(crate) fn write_block_data<W: Storage>(
+        writer: &mut W,
+        header: &mut ContainerHeader,
+        identifier: &Identifier,
+        descriptor: &BlockDescriptor,
+        data: &[u8],
+    ) -> Result<(), CogtainerError> {
+        if descriptor.header_length > 0 {
+            let inline = InlineBlockHeader {
+                identifier: identifier.clone(),
+                generation: header.generation,
+                sequence: header.next_block_write(),
+                used_length: descriptor.used_length,
+                checksum: descriptor.checksum,
+            };
+            writer.write_all_at(&inline.to_bytes()?, descriptor.file_offset.0)?;
+        }
+        writer.write_all_at(data, descriptor.data_offset().0)?;
+        // fill remaining space with zeros
+        let written = descriptor.header_length + data.len() as u64;
+        if written < descriptor.allocated_length {
+            let zeros = vec![0u8; (descriptor.allocated_length - written) as usize];
+            writer.write_all_at(&zeros, descriptor.file_offset.0 + written)?;
+        }
+        Ok(())
+    }
+
+    /// Rewrites a block's inline header (if it has one) after its data was changed in place.
+    pub(crate) fn write_inline_header<W: Storage>(
+        &self,
+        writer: &mut W,
+        header: &mut ContainerHeader,
+        identifier: &Identifier,
+    ) -> Result<(), CogtainerError> {
+        let Some(descriptor) = self.blocks.get(identifier) else {
+            return Ok(());
+        };
+        if descriptor.header_length == 0 {
+            return Ok(());
+        }
+        let inline = InlineBlockHeader {
+            identifier: identifier.clone(),
+            generation: header.generation,
+            sequence: header.next_block_write(),
+            used_length: descriptor.used_length,
+            checksum: descriptor.checksum,
+        };
+        writer.write_all_at(&inline.to_bytes()?, descriptor.file_offset.0)?;
+        Ok(())
     }
 
     /// Adds the given block (or replaces it if it already exists).
-    pub fn insert_block_at<W: std::io::Write + std::io::Seek>(
+    pub fn insert_block_at<W: Storage>(
         &mut self,
         writer: &mut W,
         header: &mut ContainerHeader,
@@ -207,7 +560,8 @@ impl ContainerFooter {
         offset: u64,
         data: &[u8],
     ) -> Result<usize, CogtainerError> {
-        let checksum = calc_checksum(data);
+        self.load_block(writer, identifier)?;
+        let checksum = self.checksum_algorithm.checksum(data);
         let mut old_used_size = 0;
 
         let mut metadata = rmpv::Value::Nil;
@@ -215,8 +569,7 @@ impl ContainerFooter {
         // always remove the old block. This gives the opportunity to consolidate empty space and simplifies the overall logic in this section.
         if let Some(descriptor) = self.blocks.remove(identifier) {
             if descriptor.allocated_length > 0 {
-                self.empty_space
-                    .insert(descriptor.file_offset, descriptor.allocated_length);
+                self.release_space(descriptor.file_offset, descriptor.allocated_length);
                 self.consolidate_empty_space();
             }
             old_used_size = descriptor.used_length;
@@ -227,30 +580,25 @@ impl ContainerFooter {
 
         // write the data
         if new_used_size > 0 {
+            let header_length = InlineBlockHeader::length_for(header, identifier)?;
             // find new empty space
             let (insert_file_offset, allocated_length) =
-                self.reserve_space(header, new_used_size, policy);
-
-            // update the footer with the new offset/metadata
-            self.blocks.insert(
-                identifier.clone(),
-                BlockDescriptor {
-                    file_offset: insert_file_offset,
-                    used_length: new_used_size,
-                    allocated_length,
-                    checksum,
-                    metadata,
-                },
-            );
+                self.reserve_block_space(writer, header, header_length, new_used_size, policy)?;
 
-            writer.seek(SeekFrom::Start(insert_file_offset.0 + offset))?;
-            writer.write_all(data)?;
+            let descriptor = BlockDescriptor {
+                file_offset: insert_file_offset,
+                used_length: new_used_size,
+                allocated_length,
+                checksum,
+                metadata,
+                header_length,
+                chunk_checksums: vec![],
+            };
+            Self::write_block_data(writer, header, identifier, &descriptor, &[])?;
+            writer.write_all_at(data, descriptor.data_offset().0 + offset)?;
 
-            // fill remaining space with zeros
-            if new_used_size < allocated_length {
-                let zeros = vec![0u8; (allocated_length - new_used_size) as usize];
-                writer.write_all(&zeros)?;
-            }
+            // update the footer with the new offset/metadata
+            self.blocks.insert(identifier.clone(), descriptor);
         } else {
             // no data block
             self.blocks.insert(
@@ -261,11 +609,13 @@ impl ContainerFooter {
                     allocated_length: 0,
                     checksum,
                     metadata,
+                    header_length: 0,
+                    chunk_checksums: vec![],
                 },
             );
         }
         // write the footer (which also writes the header)
-        self.write_to(writer, header)?;
+        self.record_change(writer, header)?;
         Ok(data.len())
     }
     /// Resizes the block to at least the minimum size.
@@ -274,7 +624,7 @@ impl ContainerFooter {
     /// If the block is already at least minimum_size, might not grow the block at all.
     ///
     /// Returns the new size of the block.
-    pub fn grow_block<W: std::io::Read + std::io::Write + std::io::Seek>(
+    pub fn grow_block<W: Storage>(
         &mut self,
         file: &mut W,
         header: &mut ContainerHeader,
@@ -282,11 +632,12 @@ impl ContainerFooter {
         identifier: &Identifier,
         minimum_size: u64,
     ) -> Result<u64, CogtainerError> {
+        self.load_block(file, identifier)?;
         let minimum_size = policy.calculate(minimum_size);
 
         if let Some(block) = self.blocks.get(identifier) {
-            if block.allocated_length >= minimum_size {
-                return Ok(block.allocated_length);
+            if block.capacity() >= minimum_size {
+                return Ok(block.capacity());
             }
         }
 
@@ -295,135 +646,168 @@ impl ContainerFooter {
             Err(_) => (rmpv::Value::Nil, vec![]),
         };
 
-        let checksum = calc_checksum(data.as_slice());
+        let (checksum, chunk_checksums) = calc_block_checksums(
+            data.as_slice(),
+            self.checksum_granularity,
+            self.checksum_algorithm,
+        );
 
         // always remove the old block. This gives the opportunity to consolidate empty space and simplifies the overall logic in this section.
         if let Some(descriptor) = self.blocks.remove(identifier) {
             if descriptor.allocated_length > 0 {
-                self.empty_space
-                    .insert(descriptor.file_offset, descriptor.allocated_length);
+                self.release_space(descriptor.file_offset, descriptor.allocated_length);
                 self.consolidate_empty_space();
             }
         }
 
+        let header_length = InlineBlockHeader::length_for(header, identifier)?;
         // find new empty space
-        let (insert_file_offset, allocated_length) =
-            self.reserve_space(header, minimum_size, OverallocationPolicy::None);
-
-        // write the data
-        if data.len() > 0 {
-            // update the footer with the new offset/metadata
-            self.blocks.insert(
-                identifier.clone(),
-                BlockDescriptor {
-                    file_offset: insert_file_offset,
-                    used_length: data.len() as u64,
-                    allocated_length,
-                    checksum,
-                    metadata,
-                },
-            );
+        let (insert_file_offset, allocated_length) = self.reserve_block_space(
+            file,
+            header,
+            header_length,
+            minimum_size,
+            OverallocationPolicy::None,
+        )?;
+
+        let descriptor = BlockDescriptor {
+            file_offset: insert_file_offset,
+            used_length: data.len() as u64,
+            allocated_length,
+            checksum,
+            metadata,
+            header_length,
+            chunk_checksums,
+        };
+        Self::write_block_data(file, header, identifier, &descriptor, &data)?;
+        let capacity = descriptor.capacity();
+        // update the footer with the new offset/metadata
+        self.blocks.insert(identifier.clone(), descriptor);
 
-            file.seek(SeekFrom::Start(insert_file_offset.0))?;
-            file.write_all(&data)?;
-        } else {
-            // no data block
-            self.blocks.insert(
-                identifier.clone(),
-                BlockDescriptor {
-                    file_offset: insert_file_offset,
-                    used_length: 0,
-                    allocated_length,
-                    checksum,
-                    metadata,
-                },
-            );
-        }
-        // fill remaining space with zeros
-        if data.len() < allocated_length as usize {
-            let zeros = vec![0u8; allocated_length as usize - data.len()];
-            file.write_all(&zeros)?;
-        }
         // write the footer (which also writes the header)
-        self.write_to(file, header)?;
-        Ok(allocated_length)
+        self.record_change(file, header)?;
+        Ok(capacity)
     }
     /// Deletes the specified block. Returns an error if the block doesn't exist.
     /// Adds the block to the empty space list.
     /// Note: Does not defragment or shrink the file.
-    /// Note: Does not flush/write to disk.
+    /// Note: Does not flush/write to disk, only marks the footer dirty.
+    /// Note: The index page the block is on must have been read (see `BlockMap::load`).
     pub fn delete_block(
         &mut self,
         identifier: &Identifier,
     ) -> Result<BlockDescriptor, CogtainerError> {
-        if let Some(descriptor) = self.blocks.remove(&identifier) {
-            self.empty_space
-                .insert(descriptor.file_offset, descriptor.allocated_length);
+        if let Some(descriptor) = self.blocks.remove(identifier) {
+            self.release_space(descriptor.file_offset, descriptor.allocated_length);
             self.consolidate_empty_space();
+            self.pending_changes += 1;
             Ok(descriptor)
         } else {
             Err(CogtainerError::BlockNotFound(identifier.clone()))
         }
     }
 
-    // Iterates through empty space, consolidating adjacent empty blocks
+    // Merges the empty space added since the last call with the empty space next to it
     pub(crate) fn consolidate_empty_space(&mut self) {
-        let mut empty: Vec<_> = self.empty_space.clone().into_iter().collect();
-        empty.sort_by_key(|&(offset, _)| offset);
-
-        let mut merged = Vec::with_capacity(empty.len());
-        let mut iter = empty.into_iter().peekable();
-
-        while let Some((offset, len)) = iter.next() {
-            let current_offset = offset;
-            let mut current_len = len;
-
-            while let Some(&(next_offset, next_len)) = iter.peek() {
-                if current_offset.0 + current_len == next_offset.0 {
-                    // Merge contiguous regions
-                    current_len += next_len;
-                    iter.next();
-                } else {
-                    break;
-                }
-            }
-            merged.push((current_offset, current_len));
-        }
-        merged.sort();
-        self.empty_space = merged.into_iter().collect();
+        self.empty_space.consolidate();
     }
 }
 /// ContainerFooter functions related to reading.
 impl ContainerFooter {
     /// Read the footer from the given reader with the pre-fetched header
-    pub fn read_from<R: std::io::Read + std::io::Seek>(
-        reader: &mut R,
+    pub fn read_from<R: Storage>(
+        reader: &R,
         header: &ContainerHeader,
     ) -> Result<Self, CogtainerError> {
-        reader.seek(SeekFrom::Start(header.footer_offset.0))?;
-        let mut footer_bytes = vec![0u8; header.footer_length as usize];
-        reader.read_exact(&mut footer_bytes)?;
-        let calc_checksum = calc_checksum(footer_bytes.as_slice());
-        if calc_checksum != header.footer_checksum {
+        Self::read_at(reader, header.committed_footer(), header.checksum_algorithm)
+    }
+    /// Read the newest footer that passes its checksum.
+    /// If the footer the header points to is damaged, falls back to the previously committed
+    /// footer and points the header at it. If the footer has an unapplied journal, it is recovered.
+    ///
+    /// Also sets the header's `footer_offset` to the end of the block data described by the footer,
+    /// which is where the next footer is written.
+    pub fn read_latest<R: Storage>(
+        reader: &R,
+        header: &mut ContainerHeader,
+    ) -> Result<Self, CogtainerError> {
+        let mut footer = match Self::read_from(reader, header) {
+            Ok(footer) => footer,
+            Err(err) => {
+                if header.previous_footer.length == 0 {
+                    return Err(err);
+                }
+                let footer =
+                    Self::read_at(reader, header.previous_footer, header.checksum_algorithm)
+                        .map_err(|_| err)?;
+                header.roll_back_to_previous();
+                footer
+            }
+        };
+        footer.recover_journal(reader)?;
+        header.footer_offset = footer.data_end();
+        Ok(footer)
+    }
+    fn read_at<R: Storage>(
+        reader: &R,
+        location: FooterLocation,
+        checksum_algorithm: ChecksumAlgorithm,
+    ) -> Result<Self, CogtainerError> {
+        let mut footer_bytes = vec![0u8; location.length as usize];
+        reader.read_exact_at(&mut footer_bytes, location.offset.0)?;
+        let calc_checksum = checksum_algorithm.checksum(footer_bytes.as_slice());
+        if calc_checksum != location.checksum {
             return Err(CogtainerError::FooterChecksumError);
         }
 
-        let footer = rmp_serde::from_slice(footer_bytes.as_slice())?;
+        let mut footer: Self = rmp_serde::from_slice(footer_bytes.as_slice())?;
+        footer.checksum_algorithm = checksum_algorithm;
         Ok(footer)
     }
-    pub fn get_block_metadata<R: std::io::Read + std::io::Seek>(
+    /// The end of the block data region: the end of the last allocated (or quarantined) block,
+    /// index page or empty space.
+    pub fn data_end(&self) -> FileOffset {
+        let blocks = self
+            .blocks
+            .values()
+            .chain(self.quarantine.values())
+            .filter(|b| b.allocated_length > 0)
+            .map(|b| b.file_offset.end_offset(b.allocated_length));
+        let empty = self
+            .empty_space
+            .iter()
+            .map(|(offset, len)| (*offset, *len))
+            .chain(self.empty_space.released())
+            .map(|(offset, len)| offset.end_offset(len));
+        let pages = self
+            .blocks
+            .page_regions()
+            .map(|(offset, len)| offset.end_offset(len));
+        blocks
+            .chain(empty)
+            .chain(pages)
+            .chain(self.blocks.unloaded_ends())
+            .max()
+            .unwrap_or(FileOffset(ContainerHeader::HEADER_SIZE as u64))
+            .max(FileOffset(ContainerHeader::HEADER_SIZE as u64))
+    }
+    /// The metadata of a block, or None if there is no such block. Reads its index page first.
+    pub fn get_block_metadata<R: Storage>(
         &self,
+        reader: &R,
         identifier: &Identifier,
-    ) -> Option<&rmpv::Value> {
-        self.blocks.get(identifier).map(|bd| &bd.metadata)
+    ) -> Result<Option<&rmpv::Value>, CogtainerError> {
+        self.load_block(reader, identifier)?;
+        Ok(self.blocks.get(identifier).map(|bd| &bd.metadata))
     }
 
     /// Retrive the specified block from the file as raw bytes
-    pub fn get_block<R: std::io::Read + std::io::Seek>(
+    pub fn get_block<R: Storage>(
         &self,
-        reader: &mut R,
+        reader: &R,
         identifier: &Identifier,
     ) -> Result<(&rmpv::Value, Vec<u8>), CogtainerError> {
+        self.load_block(reader, identifier)?;
         let descriptor = self
             .blocks
             .get(identifier)
@@ -433,26 +817,62 @@ impl ContainerFooter {
         if descriptor.allocated_length == 0 {
             return Ok((&descriptor.metadata, vec![]));
         }
-        reader.seek(SeekFrom::Start(descriptor.file_offset.0))?;
-
         let mut bytes = vec![0u8; descriptor.used_length as usize];
+        reader.read_exact_at(&mut bytes, descriptor.data_offset().0)?;
+        if !descriptor.checksum_matches(&bytes, self.checksum_algorithm) {
+            return Err(CogtainerError::BlockChecksumError(identifier.clone()));
+        }
 
-        reader.read_exact(&mut bytes)?;
-        let calc_checksum = calc_checksum(bytes.as_slice());
-        if calc_checksum != descriptor.checksum {
+        Ok((&descriptor.metadata, bytes))
+    }
+
+    /// Like `get_block`, but borrows the block data from `file` (the whole file in memory)
+    /// instead of copying it.
+    pub fn get_block_ref<'a>(
+        &'a self,
+        file: &'a [u8],
+        identifier: &Identifier,
+    ) -> Result<(&'a rmpv::Value, &'a [u8]), CogtainerError> {
+        self.load_block(&file, identifier)?;
+        let descriptor = self
+            .blocks
+            .get(identifier)
+            .ok_or_else(|| CogtainerError::BlockNotFound(identifier.clone()))?;
+
+        if descriptor.allocated_length == 0 {
+            return Ok((&descriptor.metadata, &[]));
+        }
+        // a damaged descriptor can point past the end of the address space
+        let range = descriptor
+            .file_offset
+            .0
+            .checked_add(descriptor.header_length)
+            .and_then(|start| usize::try_from(start).ok())
+            .and_then(|start| {
+                let end = start.checked_add(usize::try_from(descriptor.used_length).ok()?)?;
+                Some(start..end)
+            });
+        let bytes = range
+            .and_then(|range| file.get(range))
+            .ok_or(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
+        if !descriptor.checksum_matches(bytes, self.checksum_algorithm) {
             return Err(CogtainerError::BlockChecksumError(identifier.clone()));
         }
 
         Ok((&descriptor.metadata, bytes))
     }
 
-    pub fn get_block_slice<R: std::io::Read + std::io::Seek>(
+    /// Reads part of a block, starting at `start`, into `buf`. Returns the number of bytes read.
+    /// Blocks with chunk checksums have every chunk the read touches verified; other blocks aren't
+    /// verified, as that would mean reading the whole block.
+    pub fn get_block_slice<R: Storage>(
         &self,
-        reader: &mut R,
+        reader: &R,
         identifier: &Identifier,
         start: u64,
         buf: &mut [u8],
     ) -> Result<u64, CogtainerError> {
+        self.load_block(reader, identifier)?;
         let descriptor = self
             .blocks
             .get(identifier)
@@ -470,13 +890,37 @@ impl ContainerFooter {
             //     "Start Position is out of bounds",
             // )));
         }
-        reader.seek(SeekFrom::Start(descriptor.file_offset.0 + start))?;
-
         //let mut bytes = vec![0u8; descriptor.used_length as usize];
         let read_length = descriptor.used_length - start;
         let read_length = read_length.min(buf.len() as u64);
-
-        reader.read_exact(&mut buf[..read_length as usize])?;
+        if descriptor.is_chunked() {
+            // read and verify every chunk the range touches
+            let mut chunk = vec![0u8; CHUNK_SIZE as usize];
+            let end = start + read_length;
+            let mut index = (start / CHUNK_SIZE) as usize;
+            loop {
+                let (chunk_start, chunk_end) = descriptor.chunk_range(index);
+                if chunk_start >= end {
+                    break;
+                }
+                let bytes = &mut chunk[..(chunk_end - chunk_start) as usize];
+                reader.read_exact_at(bytes, descriptor.data_offset().0 + chunk_start)?;
+                if !descriptor.chunk_matches(index, bytes, self.checksum_algorithm) {
+                    return Err(CogtainerError::BlockChecksumError(identifier.clone()));
+                }
+                let from = start.max(chunk_start);
+                let to = end.min(chunk_end);
+                buf[(from - start) as usize..(to - start) as usize].copy_from_slice(
+                    &bytes[(from - chunk_start) as usize..(to - chunk_start) as usize],
+                );
+                index += 1;
+            }
+            return Ok(read_length);
+        }
+        reader.read_exact_at(
+            &mut buf[..read_length as usize],
+            descriptor.data_offset().0 + start,
+        )?;
 
         Ok(read_length)
     }
@@ -1,10 +1,33 @@
-use std::io::SeekFrom;
-
-use crate::error::{CogtainerError, HeaderError};
+use crate::{
+    error::{CogtainerError, HeaderError},
+    storage::Storage,
+};
 
 use super::*;
 
-// Magic Number (DCCF), Version, Footer Offset, Footer Checksum, [reserved bytes]
+/// Location of a footer within the file, along with what is needed to validate it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FooterLocation {
+    pub offset: FileOffset,
+    pub length: u64,
+    pub checksum: Checksum,
+}
+impl FooterLocation {
+    /// The offset of the first byte after this footer and the header trailer that follows it.
+    pub fn end_offset(&self) -> FileOffset {
+        self.offset
+            .end_offset(self.length + ContainerHeader::HEADER_SIZE as u64)
+    }
+    /// Returns true if this footer occupies any byte in the range `start..end`.
+    pub(crate) fn overlaps(&self, start: FileOffset, end: FileOffset) -> bool {
+        self.length > 0 && self.offset < end && start < self.end_offset()
+    }
+}
+
+// Magic Number (DCCF), Version, Footer Offset, Footer Checksum, Generation, Previous Footer, Flags, Checksum Algorithm, [reserved bytes], Header Checksum
+//
+// A copy of the header is also written as a trailer right after every footer. When the header at
+// the start of the file fails its checksum, it is rebuilt from the newest valid trailer.
 #[derive(Debug, Clone)]
 pub struct ContainerHeader {
     pub magic_number: [u8; 4],
@@ -12,33 +35,84 @@ pub struct ContainerHeader {
     pub footer_offset: FileOffset,
     pub footer_length: u64,
     pub footer_checksum: Checksum,
-    pub reserved: [u64; 4],
+    /// Incremented every time a footer is committed.
+    pub generation: u32,
+    /// The footer that was committed before the current one.
+    /// Used as a fallback when the current footer fails its checksum.
+    pub previous_footer: FooterLocation,
+    /// Optional format features, see the `FLAG_*` constants.
+    pub flags: u8,
+    /// The algorithm of every checksum except the header's own. Stored as a single byte, which
+    /// is 0 (`ChecksumAlgorithm::XxHash64`) in containers created before it existed.
+    pub checksum_algorithm: ChecksumAlgorithm,
+    pub reserved: [u8; 2],
+
+    /// Where the footer the on-disk header points to actually starts. This is the same as
+    /// `footer_offset` until blocks are appended, which moves `footer_offset` past them.
+    pub(crate) committed_offset: FileOffset,
+    /// Inline block headers written since the last commit. Not stored in the file.
+    pub(crate) block_writes: u32,
 }
 /// ContainerHeader functions related to writing.
 impl ContainerHeader {
-    pub(crate) const HEADER_SIZE: usize = 4 + 8 + 8 + 8 + 8 + 8 * 4;
+    pub(crate) const HEADER_SIZE: usize = 4 + 8 + 8 + 8 + 8 + 4 + 4 + 8 + 8 + 1 + 3 + 4;
+    /// Every block is preceded by an `InlineBlockHeader`, so blocks can be salvaged if the footer
+    /// is lost.
+    pub const FLAG_INLINE_BLOCK_HEADERS: u8 = 1;
+    /// The header checksum covers every byte before it.
+    const CHECKSUM_START: usize = Self::HEADER_SIZE - 4;
+    /// The format version written by this library. Version 1 headers may have no checksum.
+    pub const VERSION: u64 = 2;
 
     /// Creates a new empty Container.
     /// This also creates an empty footer, and writes both to the provided writer.
-    pub fn create<W: std::io::Write + std::io::Seek>(
-        writer: &mut W,
+    pub fn create<S: Storage>(writer: &mut S) -> Result<(Self, ContainerFooter), CogtainerError> {
+        Self::create_with_checksum(writer, ChecksumAlgorithm::default())
+    }
+    /// Creates a new empty Container whose checksums use the given algorithm.
+    pub fn create_with_checksum<S: Storage>(
+        writer: &mut S,
+        checksum_algorithm: ChecksumAlgorithm,
     ) -> Result<(Self, ContainerFooter), CogtainerError> {
-        let mut header = Self {
+        let mut header = Self::blank(checksum_algorithm);
+        let footer = ContainerFooter::create(writer, &mut header)?;
+
+        Ok((header, footer))
+    }
+    /// A header for an empty container that hasn't committed a footer yet.
+    pub(crate) fn blank(checksum_algorithm: ChecksumAlgorithm) -> Self {
+        Self {
             magic_number: DCCF_MAGIC,
-            version: 1,
+            version: Self::VERSION,
             footer_offset: FileOffset(Self::HEADER_SIZE as u64),
             footer_length: 0,
             footer_checksum: Checksum(0),
-            reserved: [0, 0, 0, 0],
-        };
-        let footer = ContainerFooter::create(writer, &mut header)?;
-
-        Ok((header, footer))
+            generation: 0,
+            previous_footer: FooterLocation::default(),
+            flags: 0,
+            checksum_algorithm,
+            reserved: [0; 2],
+            committed_offset: FileOffset(0),
+            block_writes: 0,
+        }
     }
-    /// Returns the actual used size of the data in this container, from the header to the end of the footer.
+    /// Returns the actual used size of the data in this container, from the header to the end of the footer
+    /// (including the header trailer that follows the footer).
     /// This can be used to truncate files after defragmenting.
     pub fn file_length(&self) -> u64 {
-        self.footer_offset.0 + self.footer_length
+        self.footer_offset.0 + self.footer_length + Self::HEADER_SIZE as u64
+    }
+    /// Returns true if new blocks are written with an inline header.
+    pub fn inline_block_headers(&self) -> bool {
+        self.flags & Self::FLAG_INLINE_BLOCK_HEADERS != 0
+    }
+    /// The footer the on-disk header currently points to.
+    pub fn committed_footer(&self) -> FooterLocation {
+        FooterLocation {
+            offset: self.committed_offset,
+            length: self.footer_length,
+            checksum: self.footer_checksum,
+        }
     }
     /// Writes this header to the given writer.
     /// Returns the end of the header.
@@ -47,34 +121,95 @@ impl ContainerHeader {
     /// - When the footer is modified and its checksum and/or length change. The footer's `write_to(..)` function handles this.
     /// - When the footer must be moved because the space it occupied is needed by a block or defragmenting moves the footer up.
     ///
-    pub fn write_to<W: std::io::Write + std::io::Seek>(
+    pub fn write_to<S: Storage>(&self, writer: &mut S) -> Result<FileOffset, CogtainerError> {
+        // a single write, so the header isn't torn across several
+        writer.write_all_at(&self.to_bytes(), 0)?;
+        Ok(FileOffset(Self::HEADER_SIZE as u64))
+    }
+    /// Writes a copy of this header right after the footer it points to.
+    pub(crate) fn write_trailer_to<S: Storage>(
         &self,
-        writer: &mut W,
-    ) -> Result<FileOffset, CogtainerError> {
-        let initial_position = writer.stream_position()?;
-        writer.seek(SeekFrom::Start(0))?;
+        writer: &mut S,
+    ) -> Result<(), CogtainerError> {
+        writer.write_all_at(&self.to_bytes(), self.footer_offset.0 + self.footer_length)?;
+        Ok(())
+    }
+    fn to_bytes(&self) -> [u8; Self::HEADER_SIZE] {
+        let mut bytes = [0u8; Self::HEADER_SIZE];
+        bytes[0..4].copy_from_slice(&self.magic_number);
+        bytes[4..12].copy_from_slice(&self.version.to_le_bytes());
+        bytes[12..20].copy_from_slice(&self.footer_offset.to_le_bytes());
+        bytes[20..28].copy_from_slice(&self.footer_length.to_le_bytes());
+        bytes[28..36].copy_from_slice(&self.footer_checksum.to_le_bytes());
+        bytes[36..40].copy_from_slice(&self.generation.to_le_bytes());
+        bytes[40..44].copy_from_slice(&(self.previous_footer.length as u32).to_le_bytes());
+        bytes[44..52].copy_from_slice(&self.previous_footer.offset.to_le_bytes());
+        bytes[52..60].copy_from_slice(&self.previous_footer.checksum.to_le_bytes());
+        bytes[60] = self.flags;
+        bytes[61] = self.checksum_algorithm.to_byte();
+        bytes[62..64].copy_from_slice(&self.reserved);
+        let checksum = Self::calc_header_checksum(&bytes[..Self::CHECKSUM_START]);
+        bytes[Self::CHECKSUM_START..].copy_from_slice(&checksum.to_le_bytes());
+        bytes
+    }
+    /// Points the header at a newly written footer, returning the header as it should be written
+    /// to disk (both at the start of the file and as the footer's trailer).
+    /// The footer that was committed until now becomes the previous footer.
+    ///
+    /// `footer_offset` is left alone, it keeps pointing to where the next footer will be written.
+    pub(crate) fn commit_footer(&mut self, location: FooterLocation) -> ContainerHeader {
+        let committed = self.committed_footer();
+        // the previous footer length is stored as a u32, larger footers can't be used as a fallback
+        self.previous_footer = if committed.length <= u32::MAX as u64 {
+            committed
+        } else {
+            FooterLocation::default()
+        };
+        self.generation = self.generation.wrapping_add(1);
+        self.block_writes = 0;
+        // the header is written with a checksum from now on
+        self.version = self.version.max(Self::VERSION);
+        self.committed_offset = location.offset;
+        self.footer_length = location.length;
+        self.footer_checksum = location.checksum;
 
-        writer.write_all(&self.magic_number)?;
-        writer.write_all(&self.version.to_le_bytes())?;
-        writer.write_all(&self.footer_offset.to_le_bytes())?;
-        writer.write_all(&self.footer_length.to_le_bytes())?;
-        writer.write_all(&self.footer_checksum.to_le_bytes())?;
-        for r in self.reserved {
-            writer.write_all(&r.to_le_bytes())?;
-        }
-        writer.seek(SeekFrom::Start(initial_position))?;
-        Ok(FileOffset(writer.stream_position()?))
+        let mut on_disk = self.clone();
+        on_disk.footer_offset = location.offset;
+        on_disk
+    }
+    /// Numbers an inline block header written in the current generation, so that of two copies of
+    /// a block written before the same commit, the later one can be told apart.
+    pub(crate) fn next_block_write(&mut self) -> u32 {
+        self.block_writes = self.block_writes.wrapping_add(1);
+        self.block_writes
+    }
+    /// Points the header at the previous footer, after the current one was found to be damaged.
+    /// The previous footer is forgotten, there is only one level of fallback.
+    pub(crate) fn roll_back_to_previous(&mut self) {
+        let previous = self.previous_footer;
+        self.footer_offset = previous.offset;
+        self.committed_offset = previous.offset;
+        self.footer_length = previous.length;
+        self.footer_checksum = previous.checksum;
+        self.previous_footer = FooterLocation::default();
     }
 }
 /// ContainerHeader functions related to reading.
 impl ContainerHeader {
-    /// Read the header from the given reader
-    pub fn read_from<R: std::io::Read + std::io::Seek>(
-        reader: &mut R,
-    ) -> Result<Self, CogtainerError> {
+    /// Read the header from the given reader.
+    /// If the header is damaged, it is rebuilt from the newest header trailer found in the file.
+    pub fn read_from<S: Storage>(reader: &S) -> Result<Self, CogtainerError> {
         let mut header_bytes = [0u8; Self::HEADER_SIZE];
-        reader.seek(SeekFrom::Start(0))?;
-        reader.read_exact(&mut header_bytes)?;
+        reader.read_exact_at(&mut header_bytes, 0)?;
+        match Self::from_bytes(&header_bytes) {
+            Ok(header) => Ok(header),
+            Err(err) => match Self::read_from_trailer(reader, &header_bytes)? {
+                Some(header) => Ok(header),
+                None => Err(err),
+            },
+        }
+    }
+    fn from_bytes(header_bytes: &[u8; Self::HEADER_SIZE]) -> Result<Self, CogtainerError> {
         let magic_number = &header_bytes[0..4];
         if magic_number != DCCF_MAGIC {
             return Err(CogtainerError::InvalidHeader(HeaderError::Magic));
@@ -99,6 +234,40 @@ impl ContainerHeader {
                 .try_into()
                 .map_err(|_e| CogtainerError::InvalidHeader(HeaderError::FooterChecksum))?,
         ));
+        let generation = u32::from_le_bytes(header_bytes[36..40].try_into().map_err(|_| {
+            CogtainerError::InvalidHeader(HeaderError::Other("Generation".to_string()))
+        })?);
+        let previous_footer = FooterLocation {
+            length: u32::from_le_bytes(header_bytes[40..44].try_into().map_err(|_| {
+                CogtainerError::InvalidHeader(HeaderError::Other("PreviousFooter".to_string()))
+            })?) as u64,
+            offset: FileOffset(u64::from_le_bytes(
+                header_bytes[44..52].try_into().map_err(|_| {
+                    CogtainerError::InvalidHeader(HeaderError::Other("PreviousFooter".to_string()))
+                })?,
+            )),
+            checksum: Checksum(u64::from_le_bytes(
+                header_bytes[52..60].try_into().map_err(|_| {
+                    CogtainerError::InvalidHeader(HeaderError::Other("PreviousFooter".to_string()))
+                })?,
+            )),
+        };
+
+        let checksum = u32::from_le_bytes(
+            header_bytes[Self::CHECKSUM_START..]
+                .try_into()
+                .map_err(|_e| CogtainerError::InvalidHeader(HeaderError::Checksum))?,
+        );
+        // version 1 headers written before the checksum existed have zeros here
+        let legacy = version < Self::VERSION && checksum == 0;
+        if !legacy && checksum != Self::calc_header_checksum(&header_bytes[..Self::CHECKSUM_START])
+        {
+            return Err(CogtainerError::InvalidHeader(HeaderError::Checksum));
+        }
+
+        let checksum_algorithm = ChecksumAlgorithm::from_byte(header_bytes[61]).ok_or(
+            CogtainerError::InvalidHeader(HeaderError::ChecksumAlgorithm(header_bytes[61])),
+        )?;
 
         let header = Self {
             magic_number: DCCF_MAGIC,
@@ -106,30 +275,81 @@ impl ContainerHeader {
             footer_offset,
             footer_length,
             footer_checksum,
-            reserved: [
-                u64::from_le_bytes(header_bytes[36..44].try_into().map_err(|_| {
-                    CogtainerError::InvalidHeader(HeaderError::Other("Reserved".to_string()))
-                })?),
-                u64::from_le_bytes(header_bytes[44..52].try_into().map_err(|_| {
-                    CogtainerError::InvalidHeader(HeaderError::Other("Reserved".to_string()))
-                })?),
-                u64::from_le_bytes(header_bytes[52..60].try_into().map_err(|_| {
-                    CogtainerError::InvalidHeader(HeaderError::Other("Reserved".to_string()))
-                })?),
-                u64::from_le_bytes(header_bytes[60..68].try_into().map_err(|_| {
-                    CogtainerError::InvalidHeader(HeaderError::Other("Reserved".to_string()))
-                })?),
-            ],
+            generation,
+            previous_footer,
+            flags: header_bytes[60],
+            checksum_algorithm,
+            reserved: header_bytes[62..64].try_into().map_err(|_| {
+                CogtainerError::InvalidHeader(HeaderError::Other("Reserved".to_string()))
+            })?,
+            committed_offset: footer_offset,
+            block_writes: 0,
         };
 
         Ok(header)
     }
 
+    /// Finds the newest valid header trailer in the file.
+    /// The damaged header is tried as a hint first, before scanning the whole file.
+    fn read_from_trailer<S: Storage>(
+        reader: &S,
+        damaged: &[u8; Self::HEADER_SIZE],
+    ) -> Result<Option<Self>, CogtainerError> {
+        let hint = u64::from_le_bytes(damaged[12..20].try_into().unwrap_or_default()).checked_add(
+            u64::from_le_bytes(damaged[20..28].try_into().unwrap_or_default()),
+        );
+        if let Some(hint) = hint {
+            if let Some(header) = Self::read_trailer_at(reader, hint)? {
+                return Ok(Some(header));
+            }
+        }
+
+        let candidates = find_magic(reader, &DCCF_MAGIC, Self::HEADER_SIZE as u64)?;
+        let mut newest: Option<Self> = None;
+        for candidate in candidates {
+            if let Some(header) = Self::read_trailer_at(reader, candidate)? {
+                if newest.as_ref().is_none_or(|n| header.is_newer_than(n)) {
+                    newest = Some(header);
+                }
+            }
+        }
+        Ok(newest)
+    }
+    /// Reads a header trailer at the given position. It is only valid if it passes its checksum
+    /// and points to a footer that ends right where the trailer starts.
+    fn read_trailer_at<S: Storage>(
+        reader: &S,
+        position: u64,
+    ) -> Result<Option<Self>, CogtainerError> {
+        let mut bytes = [0u8; Self::HEADER_SIZE];
+        if reader.read_exact_at(&mut bytes, position).is_err() {
+            return Ok(None);
+        }
+        if bytes[Self::CHECKSUM_START..] == [0; 4] {
+            return Ok(None);
+        }
+        match Self::from_bytes(&bytes) {
+            Ok(header) if header.footer_offset.0 + header.footer_length == position => {
+                Ok(Some(header))
+            }
+            _ => Ok(None),
+        }
+    }
+    /// Compares generations as serial numbers, so a generation that wrapped around to 0 is still
+    /// newer than one just below `u32::MAX`.
+    fn is_newer_than(&self, other: &Self) -> bool {
+        Self::generation_is_newer(self.generation, other.generation)
+    }
+    /// Whether generation `a` came after `b`, allowing for wraparound (see `is_newer_than`).
+    pub(crate) fn generation_is_newer(a: u32, b: u32) -> bool {
+        (a.wrapping_sub(b) as i32) > 0
+    }
+    fn calc_header_checksum(bytes: &[u8]) -> u32 {
+        twox_hash::XxHash32::oneshot(4321, bytes)
+    }
+
     /// Get the footer from the file
-    pub fn get_footer<R: std::io::Read + std::io::Seek>(
-        &self,
-        reader: &mut R,
-    ) -> Result<ContainerFooter, CogtainerError> {
+    pub fn get_footer<S: Storage>(&self, reader: &S) -> Result<ContainerFooter, CogtainerError> {
         ContainerFooter::read_from(reader, self)
     }
 }
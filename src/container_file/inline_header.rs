@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+
+use crate::error::CogtainerError;
+
+use super::*;
+
+/// Framing written in front of a block's data when `ContainerHeader::FLAG_INLINE_BLOCK_HEADERS`
+/// is set. It makes blocks self-describing, so they can be found by `salvage()` if every footer
+/// is lost.
+///
+/// Layout (little-endian):
+/// - 4 byte magic number "DCBH"
+/// - 4 byte generation of the header when the block was written
+/// - 4 byte number of the write within that generation (the copy with the highest generation and
+///   write number is the newest)
+/// - 8 byte used length of the block data
+/// - 8 byte checksum of the block data
+/// - 4 byte length of the identifier, followed by the MessagePack encoded identifier
+/// - 4 byte checksum of everything before it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InlineBlockHeader {
+    pub identifier: Identifier,
+    pub generation: u32,
+    pub sequence: u32,
+    pub used_length: u64,
+    pub checksum: Checksum,
+}
+impl InlineBlockHeader {
+    pub const MAGIC: [u8; 4] = *b"DCBH";
+    const FIXED_LENGTH: u64 = 4 + 4 + 4 + 8 + 8 + 4 + 4;
+
+    /// The length of the inline header a new block with this identifier gets, or 0 if the
+    /// container doesn't use inline headers.
+    pub fn length_for(
+        header: &ContainerHeader,
+        identifier: &Identifier,
+    ) -> Result<u64, CogtainerError> {
+        if !header.inline_block_headers() {
+            return Ok(0);
+        }
+        Ok(Self::FIXED_LENGTH + rmp_serde::to_vec(identifier)?.len() as u64)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, CogtainerError> {
+        let identifier = rmp_serde::to_vec(&self.identifier)?;
+        let mut bytes = Vec::with_capacity(Self::FIXED_LENGTH as usize + identifier.len());
+        bytes.extend_from_slice(&Self::MAGIC);
+        bytes.extend_from_slice(&self.generation.to_le_bytes());
+        bytes.extend_from_slice(&self.sequence.to_le_bytes());
+        bytes.extend_from_slice(&self.used_length.to_le_bytes());
+        bytes.extend_from_slice(&self.checksum.to_le_bytes());
+        bytes.extend_from_slice(&(identifier.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&identifier);
+        let checksum = twox_hash::XxHash32::oneshot(4321, &bytes);
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+        Ok(bytes)
+    }
+
+    /// Reads an inline header at the given position.
+    /// Returns the header and its length, or None if there isn't a valid one.
+    pub fn read_at<R: Storage>(
+        reader: &R,
+        position: u64,
+    ) -> Result<Option<(Self, u64)>, CogtainerError> {
+        let file_length = reader.len()?;
+        let mut fixed = [0u8; 32];
+        if reader.read_exact_at(&mut fixed, position).is_err() || fixed[0..4] != Self::MAGIC {
+            return Ok(None);
+        }
+        let identifier_length = u32::from_le_bytes(fixed[28..32].try_into().unwrap()) as u64;
+        let length = Self::FIXED_LENGTH + identifier_length;
+        if position + length > file_length {
+            return Ok(None);
+        }
+        let mut bytes = fixed.to_vec();
+        bytes.resize(length as usize, 0);
+        reader.read_exact_at(&mut bytes[fixed.len()..], position + fixed.len() as u64)?;
+
+        let checksum_start = bytes.len() - 4;
+        let checksum = u32::from_le_bytes(bytes[checksum_start..].try_into().unwrap());
+        if checksum != twox_hash::XxHash32::oneshot(4321, &bytes[..checksum_start]) {
+            return Ok(None);
+        }
+        let Ok(identifier) = rmp_serde::from_slice(&bytes[fixed.len()..checksum_start]) else {
+            return Ok(None);
+        };
+        let header = Self {
+            identifier,
+            generation: u32::from_le_bytes(fixed[4..8].try_into().unwrap()),
+            sequence: u32::from_le_bytes(fixed[8..12].try_into().unwrap()),
+            used_length: u64::from_le_bytes(fixed[12..20].try_into().unwrap()),
+            checksum: Checksum(u64::from_le_bytes(fixed[20..28].try_into().unwrap())),
+        };
+        Ok(Some((header, length)))
+    }
+}
+
+/// ContainerFooter functions related to salvaging blocks.
+impl ContainerFooter {
+    /// Rebuilds a footer from the inline block headers found in the file, for when every footer
+    /// is lost. Returns the footer along with a header for it. Neither is written.
+    ///
+    /// Only blocks written with inline headers can be found, and only if their data passes its
+    /// checksum. Block and container metadata is lost. If the same block is found more than once,
+    /// the newest copy is kept. Blocks that were deleted can reappear if their space wasn't reused.
+    pub fn salvage<R: Storage>(reader: &R) -> Result<(ContainerHeader, Self), CogtainerError> {
+        let file_length = reader.len()?;
+        let candidates = find_magic(
+            reader,
+            &InlineBlockHeader::MAGIC,
+            ContainerHeader::HEADER_SIZE as u64,
+        )?;
+
+        let mut found: HashMap<Identifier, ((u32, u32), BlockDescriptor)> = HashMap::new();
+        let mut generation: Option<u32> = None;
+        let mut algorithm = None;
+        // magic numbers inside a valid block's data are part of that block, not blocks of their own
+        let mut skip_until = 0;
+        for candidate in candidates {
+            if candidate < skip_until {
+                continue;
+            }
+            let Some((inline, header_length)) = InlineBlockHeader::read_at(reader, candidate)?
+            else {
+                continue;
+            };
+            let data_start = candidate + header_length;
+            if inline.used_length > file_length.saturating_sub(data_start) {
+                continue;
+            }
+            let mut data = vec![0u8; inline.used_length as usize];
+            reader.read_exact_at(&mut data, data_start)?;
+            // the header with the checksum algorithm is lost, so the first block found decides it
+            let known = algorithm;
+            let algorithms = match &known {
+                Some(known) => std::slice::from_ref(known),
+                None => ChecksumAlgorithm::ALL,
+            };
+            let Some((block_algorithm, chunk_checksums)) = algorithms
+                .iter()
+                .find_map(|a| Self::match_salvaged(&data, inline.checksum, *a))
+            else {
+                continue;
+            };
+            algorithm = Some(block_algorithm);
+            skip_until = data_start + inline.used_length;
+            if generation.is_none_or(|newest| {
+                ContainerHeader::generation_is_newer(inline.generation, newest)
+            }) {
+                generation = Some(inline.generation);
+            }
+
+            let descriptor = BlockDescriptor {
+                file_offset: FileOffset(candidate),
+                used_length: inline.used_length,
+                allocated_length: header_length + inline.used_length,
+                checksum: inline.checksum,
+                metadata: rmpv::Value::Nil,
+                header_length,
+                chunk_checksums,
+            };
+            let written = (inline.generation, inline.sequence);
+            // generations wrap around, so they are compared as serial numbers
+            let newer = found
+                .get(&inline.identifier)
+                .is_none_or(|((generation, sequence), _)| {
+                    ContainerHeader::generation_is_newer(written.0, *generation)
+                        || (written.0 == *generation && written.1 > *sequence)
+                });
+            if newer {
+                found.insert(inline.identifier, (written, descriptor));
+            }
+        }
+
+        let mut footer = Self {
+            metadata: rmpv::Value::Nil,
+            blocks: found.into_iter().map(|(id, (_, d))| (id, d)).collect(),
+            empty_space: EmptySpace::default(),
+            journal: None,
+            quarantine: HashMap::new(),
+            durability: DurabilityMode::default(),
+            allocation_strategy: AllocationStrategy::default(),
+            block_alignment: 1,
+            checksum_granularity: ChecksumGranularity::default(),
+            checksum_algorithm: algorithm.unwrap_or_default(),
+            defragmented_to: FileOffset(0),
+            scrub_mode: ScrubMode::default(),
+            released: vec![],
+            pending_changes: 1,
+            index_pages_at_end: false,
+            preallocation: 0,
+            preallocated_to: FileOffset(0),
+            unpunched: None,
+        };
+        let mut header = ContainerHeader::blank(footer.checksum_algorithm);
+        header.flags |= ContainerHeader::FLAG_INLINE_BLOCK_HEADERS;
+        // blocks written from now on must win over any stale copies still in the file
+        header.generation = generation.unwrap_or(0).wrapping_add(1);
+        header.footer_offset = footer.data_end();
+        footer.rebuild_empty_space(header.footer_offset);
+        Ok((header, footer))
+    }
+
+    /// Checks salvaged block data against its checksum, which is either over the data or over
+    /// the checksums of its chunks. Returns the algorithm and the chunk checksums if it matches.
+    fn match_salvaged(
+        data: &[u8],
+        checksum: Checksum,
+        algorithm: ChecksumAlgorithm,
+    ) -> Option<(ChecksumAlgorithm, Vec<Checksum>)> {
+        if algorithm.checksum(data) == checksum {
+            return Some((algorithm, vec![]));
+        }
+        let chunk_checksums = calc_chunk_checksums(data, algorithm);
+        (calc_root_checksum(&chunk_checksums, algorithm) == checksum)
+            .then_some((algorithm, chunk_checksums))
+    }
+}
@@ -0,0 +1,264 @@
+use serde::{Deserialize, Serialize};
+
+use crate::error::CogtainerError;
+
+use super::*;
+
+/// Location of a journal within the file.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct JournalLocation {
+    pub offset: FileOffset,
+    pub length: u64,
+    pub checksum: Checksum,
+}
+
+/// A single change recorded in a journal.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum JournalOp {
+    /// Adds or replaces a block. The data has already been written to the descriptor's location.
+    InsertBlock {
+        identifier: Identifier,
+        descriptor: BlockDescriptor,
+    },
+    DeleteBlock {
+        identifier: Identifier,
+    },
+    SetMetadata {
+        metadata: rmpv::Value,
+    },
+    UpdateBlockMetadata {
+        identifier: Identifier,
+        metadata: rmpv::Value,
+    },
+}
+
+/// An intent journal: a list of changes that must be applied together or not at all.
+///
+/// The journal is written to space that no block uses, and then a footer pointing to it is
+/// committed. Only after that are the changes applied to the footer and committed again. If the
+/// process dies in between, opening the file finds the journal and replays it. If the journal
+/// itself is damaged, it is dropped, which rolls back to the state before the transaction.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct Journal {
+    pub ops: Vec<JournalOp>,
+}
+impl Journal {
+    /// Regions reserved by this journal's inserts, which aren't in the block list or the empty
+    /// space list until the journal is applied.
+    pub(crate) fn staged_regions(&self) -> impl Iterator<Item = (FileOffset, u64)> + '_ {
+        self.ops.iter().filter_map(|op| match op {
+            JournalOp::InsertBlock { descriptor, .. } if descriptor.allocated_length > 0 => {
+                Some((descriptor.file_offset, descriptor.allocated_length))
+            }
+            _ => None,
+        })
+    }
+}
+
+/// ContainerFooter functions related to journals.
+impl ContainerFooter {
+    /// Reserves space for a block and writes its data, without adding it to the block list.
+    /// Returns the descriptor the block will have once a journal adds it.
+    pub(crate) fn stage_block<W: Storage>(
+        &mut self,
+        writer: &mut W,
+        header: &mut ContainerHeader,
+        policy: OverallocationPolicy,
+        identifier: &Identifier,
+        metadata: rmpv::Value,
+        data: &[u8],
+    ) -> Result<BlockDescriptor, CogtainerError> {
+        let (checksum, chunk_checksums) =
+            calc_block_checksums(data, self.checksum_granularity, self.checksum_algorithm);
+        if data.is_empty() {
+            return Ok(BlockDescriptor {
+                file_offset: FileOffset(0),
+                used_length: 0,
+                allocated_length: 0,
+                checksum,
+                metadata,
+                header_length: 0,
+                chunk_checksums: vec![],
+            });
+        }
+        let header_length = InlineBlockHeader::length_for(header, identifier)?;
+        let (file_offset, allocated_length) =
+            self.reserve_block_space(writer, header, header_length, data.len() as u64, policy)?;
+
+        let descriptor = BlockDescriptor {
+            file_offset,
+            used_length: data.len() as u64,
+            allocated_length,
+            checksum,
+            metadata,
+            header_length,
+            chunk_checksums,
+        };
+        Self::write_block_data(writer, header, identifier, &descriptor, data)?;
+        Ok(descriptor)
+    }
+
+    /// Writes the journal and commits a footer that points to it.
+    /// After this, the journal's changes are durable even though they haven't been applied.
+    pub(crate) fn write_journal<W: Storage>(
+        &mut self,
+        writer: &mut W,
+        header: &mut ContainerHeader,
+        journal: &Journal,
+    ) -> Result<(), CogtainerError> {
+        let bytes = rmp_serde::to_vec(journal)?;
+        let (offset, length) = self.reserve_space(
+            writer,
+            header,
+            bytes.len() as u64,
+            OverallocationPolicy::None,
+        )?;
+        writer.write_all_at(&bytes, offset.0)?;
+        // the journal must be durable before a footer points to it
+        writer.sync()?;
+
+        self.journal = Some(JournalLocation {
+            offset,
+            length,
+            checksum: self.checksum_algorithm.checksum(bytes.as_slice()),
+        });
+        self.write_index_pages(writer, header)?;
+        self.write_to(writer, header)
+    }
+
+    /// Reads the index pages the journal's changes are on, which `apply_journal` needs.
+    pub(crate) fn load_journal_blocks<R: Storage>(
+        &self,
+        reader: &R,
+        journal: &Journal,
+    ) -> Result<(), CogtainerError> {
+        for op in &journal.ops {
+            match op {
+                JournalOp::InsertBlock { identifier, .. }
+                | JournalOp::DeleteBlock { identifier }
+                | JournalOp::UpdateBlockMetadata { identifier, .. } => {
+                    self.load_block(reader, identifier)?
+                }
+                JournalOp::SetMetadata { .. } => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies the journal's changes and releases the space the journal occupied.
+    /// Does not write to disk.
+    pub(crate) fn apply_journal(&mut self, journal: &Journal) {
+        for op in &journal.ops {
+            match op {
+                JournalOp::InsertBlock {
+                    identifier,
+                    descriptor,
+                } => {
+                    if let Some(old) = self.blocks.insert(identifier.clone(), descriptor.clone()) {
+                        self.release_space(old.file_offset, old.allocated_length);
+                    }
+                }
+                JournalOp::DeleteBlock { identifier } => {
+                    if let Some(old) = self.blocks.remove(identifier) {
+                        self.release_space(old.file_offset, old.allocated_length);
+                    }
+                }
+                JournalOp::SetMetadata { metadata } => {
+                    self.metadata = metadata.clone();
+                }
+                JournalOp::UpdateBlockMetadata {
+                    identifier,
+                    metadata,
+                } => {
+                    if let Some(descriptor) = self.blocks.get_mut(identifier) {
+                        descriptor.metadata = metadata.clone();
+                    } else {
+                        let descriptor = BlockDescriptor {
+                            file_offset: FileOffset(0),
+                            used_length: 0,
+                            allocated_length: 0,
+                            checksum: Checksum(0),
+                            metadata: metadata.clone(),
+                            header_length: 0,
+                            chunk_checksums: vec![],
+                        };
+                        self.blocks.insert(identifier.clone(), descriptor);
+                    }
+                }
+            }
+        }
+        if let Some(location) = self.journal.take() {
+            self.release_space(location.offset, location.length);
+        }
+        self.consolidate_empty_space();
+    }
+
+    /// Returns space that was reserved by a journal that will never be applied.
+    pub(crate) fn discard_journal(&mut self, journal: &Journal) {
+        for (offset, len) in journal.staged_regions() {
+            self.release_space(offset, len);
+        }
+        self.consolidate_empty_space();
+    }
+
+    /// Finishes a transaction that was interrupted after its journal was committed.
+    /// If the journal can be read it is applied, otherwise it is dropped and the footer stays
+    /// as it was before the transaction.
+    ///
+    /// Only the in-memory footer is changed, the result is persisted by the next commit.
+    /// Returns true if a journal was replayed.
+    pub(crate) fn recover_journal<R: Storage>(
+        &mut self,
+        reader: &R,
+    ) -> Result<bool, CogtainerError> {
+        let Some(location) = self.journal else {
+            return Ok(false);
+        };
+        match self.read_journal(reader, location) {
+            Ok(journal) => {
+                self.load_journal_blocks(reader, &journal)?;
+                self.apply_journal(&journal);
+                self.pending_changes += 1;
+                Ok(true)
+            }
+            Err(_) => {
+                // the staged blocks are unaccounted for, but the rest of the footer is intact
+                self.journal = None;
+                self.release_space(location.offset, location.length);
+                self.consolidate_empty_space();
+                self.pending_changes += 1;
+                Ok(false)
+            }
+        }
+    }
+
+    fn read_journal<R: Storage>(
+        &self,
+        reader: &R,
+        location: JournalLocation,
+    ) -> Result<Journal, CogtainerError> {
+        let mut bytes = vec![0u8; location.length as usize];
+        reader.read_exact_at(&mut bytes, location.offset.0)?;
+        if self.checksum_algorithm.checksum(bytes.as_slice()) != location.checksum {
+            return Err(CogtainerError::JournalChecksumError);
+        }
+        Ok(rmp_serde::from_slice(bytes.as_slice())?)
+    }
+
+    /// Frees space the committed footer may still refer to. It is listed as empty in the next
+    /// footer, but only handed out again once that footer is committed (see `EmptySpace::release`).
+    pub(crate) fn release_space(&mut self, offset: FileOffset, len: u64) {
+        if len > 0 {
+            self.empty_space.release(offset, len);
+            self.mark_released(offset, len);
+        }
+    }
+    /// Like `release_space`, but the space can be handed out again right away. The caller makes
+    /// sure it isn't overwritten while the committed footer still refers to it.
+    pub(crate) fn release_space_now(&mut self, offset: FileOffset, len: u64) {
+        if len > 0 {
+            self.empty_space.insert(offset, len);
+            self.mark_released(offset, len);
+        }
+    }
+}
@@ -2,13 +2,35 @@ use std::ops::{Deref, DerefMut};
 
 use serde::{Deserialize, Serialize};
 
+use crate::storage::Storage;
+
+mod allocation;
+mod block_map;
+mod checksum;
+mod chunk_checksum;
+mod defragment;
+mod durability;
 mod footer;
 mod header;
+mod inline_header;
+mod journal;
 mod overallocation;
-
+mod scrub;
+mod verify;
+
+pub use allocation::*;
+pub use block_map::*;
+pub use checksum::*;
+pub use chunk_checksum::*;
+pub use defragment::*;
+pub use durability::*;
 pub use footer::*;
 pub use header::*;
+pub use inline_header::*;
+pub use journal::*;
 pub use overallocation::*;
+pub use scrub::*;
+pub use verify::*;
 
 // ContainerFile is basically like a zip or tar file, but explicitly supports replacing
 // and deleting "blocks". A block is a similar concept to a file. It can have arbitrary
@@ -28,13 +50,22 @@ pub use overallocation::*;
 // Data format is little-endian.
 
 // The file layout is organized into 3 parts:
-// - Header: Magic Number, Version, Footer Offset, Footer Checksum
+// - Header: Magic Number, Version, Footer Offset, Footer Checksum, Generation, Previous Footer, Flags
 // - Block Data
-// - Footer
+// - Footer, followed by a copy of the header
 
 // The Magic Number is a fixed string defining the file format ("DCCF")
-// The version is a u64 number indicating the current version (1)
+// The version is a u64 number indicating the current version (2, version 1 headers have no checksum)
 // The Footer Offset is a u64 number in bytes indicating the start offset of the footer
+// The Generation is a u32 counter incremented every time a footer is committed
+// The Previous Footer is the location of the footer committed before the current one
+// The Flags are optional format features (such as inline block headers)
+// The Header Checksum is a u32 checksum over every other byte of the header
+
+// Footers are never overwritten in place. A new footer is written to space that the committed
+// footer doesn't occupy, flushed, and only then does the header flip to point to it. If the
+// process dies before the header is rewritten, the old header still points to an intact footer.
+// If the newest footer is damaged anyway, open falls back to the previous footer.
 
 // The rest of the data up to the Footer Offset is the block data. The particular format of this data
 // is defined in the footer (allocated or empty)
@@ -45,8 +76,29 @@ pub use overallocation::*;
 // - empty space that can be used for expansion/reallocation
 
 pub const DCCF_MAGIC: [u8; 4] = *b"DCCF";
-fn calc_checksum(bytes: &[u8]) -> Checksum {
-    Checksum(twox_hash::XxHash64::oneshot(4321, bytes))
+
+/// Returns the position of every occurrence of `magic` in the file from `start` onwards.
+pub(crate) fn find_magic<S: crate::storage::Storage>(
+    reader: &S,
+    magic: &[u8; 4],
+    start: u64,
+) -> Result<Vec<u64>, crate::error::CogtainerError> {
+    let file_length = reader.len()?;
+    let mut found = vec![];
+    let mut chunk = vec![0u8; 64 * 1024];
+    let mut position = start;
+    while position < file_length {
+        let len = chunk.len().min((file_length - position) as usize);
+        reader.read_exact_at(&mut chunk[..len], position)?;
+        for (i, window) in chunk[..len].windows(magic.len()).enumerate() {
+            if window == magic {
+                found.push(position + i as u64);
+            }
+        }
+        // overlap chunks so a magic number split across two of them isn't missed
+        position += (len as u64).saturating_sub(magic.len() as u64 - 1).max(1);
+    }
+    Ok(found)
 }
 
 /// Unique identifier for a block.
@@ -85,7 +137,9 @@ impl From<Vec<Self>> for Identifier {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Hash, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+#[derive(
+    Debug, Default, Serialize, Deserialize, Hash, PartialEq, Eq, PartialOrd, Ord, Clone, Copy,
+)]
 #[serde(transparent)]
 pub struct FileOffset(pub u64);
 impl FileOffset {
@@ -106,7 +160,7 @@ impl DerefMut for FileOffset {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Hash, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, Default, Serialize, Deserialize, Hash, PartialEq, Eq, Clone, Copy)]
 #[serde(transparent)]
 pub struct Checksum(pub u64);
 impl Deref for Checksum {
@@ -0,0 +1,98 @@
+use std::hash::{BuildHasher, Hasher};
+
+use crate::{error::CogtainerError, storage::Storage};
+
+use super::{ContainerFooter, FileOffset};
+
+/// What freed space is overwritten with.
+#[derive(Clone, Default, Debug, Copy, PartialEq, Eq)]
+pub enum ScrubMode {
+    /// Freed space keeps its old bytes until something else is written there.
+    #[default]
+    Off,
+    /// Freed space is overwritten with zeros.
+    Zeros,
+    /// Freed space is overwritten with pseudo-random bytes.
+    Random,
+}
+
+impl ContainerFooter {
+    /// Counts `len` bytes at `offset` as freed, so they are scrubbed on the next commit if
+    /// secure delete is on, and punched out on the next flush if hole punching is on.
+    pub(crate) fn mark_released(&mut self, offset: FileOffset, len: u64) {
+        if len > 0 && self.scrub_mode != ScrubMode::Off {
+            self.released.push((offset, len));
+        }
+        if let Some(unpunched) = &mut self.unpunched {
+            unpunched.push((offset, len));
+        }
+    }
+    /// The empty regions overlapping `len` bytes at `offset`, as start and end, highest first.
+    pub(crate) fn holes_overlapping(
+        &self,
+        offset: FileOffset,
+        len: u64,
+    ) -> Vec<(FileOffset, FileOffset)> {
+        self.empty_space
+            .range(..offset.end_offset(len))
+            .rev()
+            .map(|(hole, hole_length)| (*hole, hole.end_offset(*hole_length)))
+            .take_while(|(_, hole_end)| *hole_end > offset)
+            .collect()
+    }
+    /// Overwrites the space freed since the last commit. Parts that have been allocated again
+    /// since they were freed already hold new data and are skipped.
+    pub(crate) fn scrub_released<W: Storage>(
+        &mut self,
+        writer: &mut W,
+    ) -> Result<(), CogtainerError> {
+        let released = std::mem::take(&mut self.released);
+        for (offset, len) in released {
+            let end = offset.end_offset(len);
+            for (hole, hole_end) in self.holes_overlapping(offset, len) {
+                let from = hole.max(offset);
+                scrub(writer, from, hole_end.min(end).0 - from.0, self.scrub_mode)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Overwrites `len` bytes at `offset`. Does nothing if `mode` is `ScrubMode::Off`.
+pub(crate) fn scrub<W: Storage>(
+    writer: &mut W,
+    offset: FileOffset,
+    len: u64,
+    mode: ScrubMode,
+) -> Result<(), CogtainerError> {
+    if mode == ScrubMode::Off || len == 0 {
+        return Ok(());
+    }
+    let mut buf = vec![0u8; len.min(64 * 1024) as usize];
+    let mut state = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+    let mut written = 0;
+    while written < len {
+        let chunk = &mut buf[..(len - written).min(64 * 1024) as usize];
+        if mode == ScrubMode::Random {
+            fill_random(chunk, &mut state);
+        }
+        writer.write_all_at(chunk, offset.0 + written)?;
+        written += chunk.len() as u64;
+    }
+    Ok(())
+}
+
+/// splitmix64, seeded per call. Not meant to be unpredictable, only to leave nothing of the old
+/// data behind.
+fn fill_random(buf: &mut [u8], state: &mut u64) {
+    for chunk in buf.chunks_mut(8) {
+        *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = *state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        chunk.copy_from_slice(&z.to_le_bytes()[..chunk.len()]);
+    }
+}
@@ -0,0 +1,284 @@
+use std::collections::HashSet;
+
+use super::*;
+
+/// A problem found by `verify()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IntegrityProblem {
+    /// The block's data can't be read or doesn't match its checksum.
+    CorruptBlock { identifier: Identifier },
+    /// Two blocks claim some of the same bytes.
+    OverlappingBlocks {
+        first: Identifier,
+        second: Identifier,
+    },
+    /// The block runs past the end of the data region (into the footer).
+    BlockPastEnd { identifier: Identifier },
+    /// The empty region runs past the end of the data region (into the footer).
+    EmptySpacePastEnd { offset: FileOffset, length: u64 },
+    /// The empty region overlaps an allocated block.
+    EmptySpaceOverlapsBlock {
+        offset: FileOffset,
+        identifier: Identifier,
+    },
+    /// Two empty regions overlap each other.
+    OverlappingEmptySpace {
+        first: FileOffset,
+        second: FileOffset,
+    },
+    /// Part of the data region is neither allocated nor in the empty space list.
+    UnaccountedSpace { offset: FileOffset, length: u64 },
+    /// The index page can't be read or doesn't match its checksum, so the blocks on it are
+    /// unknown. `repair()` replaces it with an empty page.
+    CorruptIndexPage { offset: FileOffset },
+}
+
+/// The result of checking a container with `verify()`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    pub problems: Vec<IntegrityProblem>,
+}
+impl VerifyReport {
+    /// Returns true if no problems were found.
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// What a region of the data area belongs to.
+#[derive(Debug, Clone)]
+enum Owner {
+    Block(Identifier),
+    Quarantined,
+    Empty,
+    Journal,
+    IndexPage,
+}
+
+/// ContainerFooter functions related to checking and repairing the container.
+impl ContainerFooter {
+    /// Checks every block checksum and looks for inconsistencies between blocks, empty space and
+    /// the end of the data region (`header.footer_offset`).
+    ///
+    /// Quarantined blocks are not checked, but the space they occupy is accounted for. Every
+    /// index page is read.
+    pub fn verify<R: Storage>(&self, reader: &R, header: &ContainerHeader) -> VerifyReport {
+        let mut report = VerifyReport::default();
+        for offset in self
+            .blocks
+            .unreadable_pages(reader, self.checksum_algorithm)
+        {
+            report
+                .problems
+                .push(IntegrityProblem::CorruptIndexPage { offset });
+        }
+        let mut identifiers: Vec<_> = self.blocks.keys().collect();
+        identifiers.sort();
+        for identifier in identifiers {
+            if self.get_block(reader, identifier).is_err() {
+                report.problems.push(IntegrityProblem::CorruptBlock {
+                    identifier: identifier.clone(),
+                });
+            }
+        }
+        self.check_regions(header.footer_offset, &mut report);
+        report
+    }
+
+    /// Fixes what `verify()` finds, as far as possible:
+    /// - Corrupt blocks are moved to the quarantine list. Of two overlapping blocks, the one that
+    ///   fails its checksum is; if both verify, the later one is.
+    /// - Blocks running past the end of the data region move the end of the data region.
+    /// - Index pages that can't be read are replaced with empty pages.
+    /// - The empty space list is rebuilt from the remaining blocks.
+    ///
+    /// Does not write to disk. Returns the problems found before repairing.
+    pub fn repair<R: Storage>(&mut self, reader: &R, header: &mut ContainerHeader) -> VerifyReport {
+        let report = self.verify(reader, header);
+        self.blocks.forget_unloaded_pages();
+        let corrupt: HashSet<_> = report
+            .problems
+            .iter()
+            .filter_map(|problem| match problem {
+                IntegrityProblem::CorruptBlock { identifier } => Some(identifier),
+                _ => None,
+            })
+            .collect();
+        for problem in &report.problems {
+            let identifier = match problem {
+                IntegrityProblem::CorruptBlock { identifier } => identifier,
+                // a corrupt block among the two is quarantined for that already
+                IntegrityProblem::OverlappingBlocks { first, second }
+                    if !corrupt.contains(first) && !corrupt.contains(second) =>
+                {
+                    second
+                }
+                _ => continue,
+            };
+            if let Some(descriptor) = self.blocks.remove(identifier) {
+                self.quarantine.insert(identifier.clone(), descriptor);
+            }
+        }
+        header.footer_offset = header.footer_offset.max(self.occupied_end());
+        self.rebuild_empty_space(header.footer_offset);
+        self.pending_changes += 1;
+        report
+    }
+
+    /// Forgets the quarantined blocks and makes the space only they occupied available again.
+    /// Does not write to disk. Every index page must have been read.
+    pub(crate) fn clear_quarantine(&mut self, header: &ContainerHeader) {
+        if self.quarantine.is_empty() {
+            return;
+        }
+        for (_, descriptor) in std::mem::take(&mut self.quarantine) {
+            self.mark_released(descriptor.file_offset, descriptor.allocated_length);
+        }
+        self.rebuild_empty_space(header.footer_offset);
+        self.pending_changes += 1;
+    }
+
+    /// Every region of the data area that something claims, sorted by offset.
+    fn regions(&self) -> Vec<(FileOffset, u64, Owner)> {
+        let mut regions: Vec<_> = self
+            .blocks
+            .iter()
+            .filter(|(_, b)| b.allocated_length > 0)
+            .map(|(id, b)| (b.file_offset, b.allocated_length, Owner::Block(id.clone())))
+            .collect();
+        regions.extend(
+            self.quarantine
+                .values()
+                .filter(|b| b.allocated_length > 0)
+                .map(|b| (b.file_offset, b.allocated_length, Owner::Quarantined)),
+        );
+        regions.extend(
+            self.empty_space
+                .iter()
+                .map(|(offset, len)| (*offset, *len))
+                .chain(self.empty_space.released())
+                .map(|(offset, len)| (offset, len, Owner::Empty)),
+        );
+        if let Some(journal) = self.journal {
+            regions.push((journal.offset, journal.length, Owner::Journal));
+        }
+        regions.extend(
+            self.blocks
+                .page_regions()
+                .map(|(offset, len)| (offset, len, Owner::IndexPage)),
+        );
+        regions.sort_by_key(|(offset, len, _)| (*offset, *len));
+        regions
+    }
+
+    fn check_regions(&self, data_end: FileOffset, report: &mut VerifyReport) {
+        let mut covered_to = FileOffset(ContainerHeader::HEADER_SIZE as u64);
+        // the region reaching furthest so far, which is the one later regions can overlap
+        let mut furthest: Option<(FileOffset, Owner)> = None;
+        for (offset, len, owner) in self.regions() {
+            let end = offset.end_offset(len);
+            if offset > covered_to {
+                report.problems.push(IntegrityProblem::UnaccountedSpace {
+                    offset: covered_to,
+                    length: offset.0 - covered_to.0,
+                });
+            } else if let Some((other_offset, other)) =
+                furthest.as_ref().filter(|_| offset < covered_to)
+            {
+                if let Some(problem) = Self::overlap_problem(*other_offset, other, offset, &owner) {
+                    report.problems.push(problem);
+                }
+            }
+            if end > data_end {
+                match &owner {
+                    Owner::Block(identifier) => {
+                        report.problems.push(IntegrityProblem::BlockPastEnd {
+                            identifier: identifier.clone(),
+                        })
+                    }
+                    Owner::Empty => report.problems.push(IntegrityProblem::EmptySpacePastEnd {
+                        offset,
+                        length: len,
+                    }),
+                    _ => {}
+                }
+            }
+            if end > covered_to {
+                covered_to = end;
+                furthest = Some((offset, owner));
+            }
+        }
+        if covered_to < data_end {
+            report.problems.push(IntegrityProblem::UnaccountedSpace {
+                offset: covered_to,
+                length: data_end.0 - covered_to.0,
+            });
+        }
+    }
+
+    fn overlap_problem(
+        first_offset: FileOffset,
+        first: &Owner,
+        second_offset: FileOffset,
+        second: &Owner,
+    ) -> Option<IntegrityProblem> {
+        match (first, second) {
+            (Owner::Block(first), Owner::Block(second)) => {
+                Some(IntegrityProblem::OverlappingBlocks {
+                    first: first.clone(),
+                    second: second.clone(),
+                })
+            }
+            (Owner::Block(identifier), Owner::Empty) => {
+                Some(IntegrityProblem::EmptySpaceOverlapsBlock {
+                    offset: second_offset,
+                    identifier: identifier.clone(),
+                })
+            }
+            (Owner::Empty, Owner::Block(identifier)) => {
+                Some(IntegrityProblem::EmptySpaceOverlapsBlock {
+                    offset: first_offset,
+                    identifier: identifier.clone(),
+                })
+            }
+            (Owner::Empty, Owner::Empty) => Some(IntegrityProblem::OverlappingEmptySpace {
+                first: first_offset,
+                second: second_offset,
+            }),
+            // quarantined blocks are expected to overlap whatever damaged them
+            _ => None,
+        }
+    }
+
+    /// The end of the last region used by a block, journal or index page.
+    fn occupied_end(&self) -> FileOffset {
+        self.regions()
+            .iter()
+            .filter(|(_, _, owner)| !matches!(owner, Owner::Empty))
+            .map(|(offset, len, _)| offset.end_offset(*len))
+            .max()
+            .unwrap_or(FileOffset(ContainerHeader::HEADER_SIZE as u64))
+    }
+
+    /// Replaces the empty space list with every gap between blocks (including quarantined ones)
+    /// up to `data_end`. The gaps are released (see `EmptySpace::release`), as the committed
+    /// footer may still refer to them.
+    pub(crate) fn rebuild_empty_space(&mut self, data_end: FileOffset) {
+        debug_assert!(self.blocks.is_loaded());
+        let mut empty_space = EmptySpace::default();
+        let mut covered_to = FileOffset(ContainerHeader::HEADER_SIZE as u64);
+        for (offset, len, owner) in self.regions() {
+            if matches!(owner, Owner::Empty) {
+                continue;
+            }
+            if offset > covered_to {
+                empty_space.release(covered_to, offset.0 - covered_to.0);
+            }
+            covered_to = covered_to.max(offset.end_offset(len));
+        }
+        if covered_to < data_end {
+            empty_space.release(covered_to, data_end.0 - covered_to.0);
+        }
+        self.empty_space = empty_space;
+    }
+}
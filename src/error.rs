@@ -9,6 +9,9 @@ pub enum HeaderError {
     FooterOffset,
     FooterLength,
     FooterChecksum,
+    Checksum,
+    /// The checksum algorithm is unknown, or not enabled in this build.
+    ChecksumAlgorithm(u8),
     Other(String),
 }
 
@@ -20,6 +23,12 @@ pub enum CogtainerError {
     #[error("footer contains invalid data or is corrupt")]
     FooterChecksumError,
 
+    #[error("journal contains invalid data or is corrupt")]
+    JournalChecksumError,
+
+    #[error("index page contains invalid data or is corrupt")]
+    IndexPageChecksumError,
+
     #[error("block {0:?} contains invalid data or is corrupt")]
     BlockChecksumError(Identifier),
 
@@ -41,6 +50,17 @@ pub enum CogtainerError {
     #[error("Unable to deserialize: `{0}`")]
     Deserialize(#[from] rmp_serde::decode::Error),
 
+    #[error("a thread panicked while holding a lock on the container")]
+    LockPoisoned,
+
     #[error("unknown error")]
     Unknown,
 }
+impl From<CogtainerError> for std::io::Error {
+    fn from(value: CogtainerError) -> Self {
+        match value {
+            CogtainerError::IOError(err) => err,
+            err => std::io::Error::other(err),
+        }
+    }
+}
@@ -2,18 +2,20 @@ use std::io::{Error, Read, Seek, Write};
 
 use crate::{
     basic_api::Cogtainer,
-    container_file::{Checksum, Identifier},
+    container_file::{Checksum, ChecksumAlgorithm, FileOffset, Identifier},
+    storage::Storage,
+    traits::Truncate,
 };
 
 /// Provides access to a block with a file-like API.
 /// Intended for when storing other files in a container.
-pub struct InternalFile<'a, F: std::io::Read + std::io::Write + std::io::Seek> {
+pub struct InternalFile<'a, F: Storage> {
     file: &'a mut Cogtainer<F>,
     block_id: Identifier,
 
     cursor: u64,
 }
-impl<'a, F: std::io::Read + std::io::Write + std::io::Seek> InternalFile<'a, F> {
+impl<'a, F: Storage> InternalFile<'a, F> {
     pub(crate) fn new(file: &'a mut Cogtainer<F>, block_id: Identifier) -> Self {
         Self {
             file,
@@ -22,11 +24,14 @@ impl<'a, F: std::io::Read + std::io::Write + std::io::Seek> InternalFile<'a, F>
         }
     }
 }
-impl<'a, F: std::io::Read + std::io::Write + std::io::Seek> Seek for InternalFile<'a, F> {
+impl<'a, F: Storage> Seek for InternalFile<'a, F> {
     fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
         self.cursor = match pos {
             std::io::SeekFrom::Start(p) => p,
             std::io::SeekFrom::End(p) => {
+                self.file
+                    .footer
+                    .load_block(&self.file.file, &self.block_id)?;
                 let block =
                     self.file.footer.blocks.get(&self.block_id).ok_or_else(|| {
                         Error::new(std::io::ErrorKind::NotFound, "block not found")
@@ -59,12 +64,12 @@ impl<'a, F: std::io::Read + std::io::Write + std::io::Seek> Seek for InternalFil
         Ok(self.cursor)
     }
 }
-impl<'a, F: std::io::Read + std::io::Write + std::io::Seek> Read for InternalFile<'a, F> {
+impl<'a, F: Storage> Read for InternalFile<'a, F> {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         let len = self
             .file
             .footer
-            .get_block_slice(&mut self.file.file, &self.block_id, self.cursor, buf)
+            .get_block_slice(&self.file.file, &self.block_id, self.cursor, buf)
             .map_err(|e| Error::new(std::io::ErrorKind::NotFound, e))?;
 
         self.cursor = self
@@ -74,22 +79,65 @@ impl<'a, F: std::io::Read + std::io::Write + std::io::Seek> Read for InternalFil
         Ok(len as usize)
     }
 }
-// impl<'a, F: Seek + Read + Write + Truncate> Truncate for InternalFile<'a, F> {
-//     fn truncate(&self, offset: u64) -> Result<(), ()> {
-//         let block = self
-//             .file
-//             .footer
-//             .blocks
-//             .get(&self.block_id)
-//             .ok_or_else(|| ())?;
-//         self.file.trun
-//         todo!()
-//     }
-// }
-impl<'a, F: std::io::Read + std::io::Write + std::io::Seek> Write for InternalFile<'a, F> {
+impl<'a, F: Storage> Truncate for InternalFile<'a, F> {
+    /// Shrinks the block to `length` bytes and releases the rest of its allocation to the empty
+    /// space. The cursor is not moved.
+    fn truncate(&mut self, length: u64) -> std::io::Result<()> {
+        self.file
+            .footer
+            .load_block(&self.file.file, &self.block_id)?;
+        let desc = self
+            .file
+            .footer
+            .blocks
+            .get(&self.block_id)
+            .cloned()
+            .ok_or_else(|| Error::new(std::io::ErrorKind::NotFound, "block not found"))?;
+        if length >= desc.used_length {
+            return Ok(());
+        }
+        let algorithm = self.file.footer.checksum_algorithm;
+
+        let mut truncated = desc.clone();
+        truncated.used_length = length;
+        if length == 0 {
+            // the same as a block inserted without data
+            truncated.file_offset = FileOffset(0);
+            truncated.allocated_length = 0;
+            truncated.header_length = 0;
+            truncated.checksum = algorithm.checksum(&[]);
+            truncated.chunk_checksums.clear();
+        } else {
+            truncated.allocated_length = desc.header_length + length;
+            if desc.is_chunked() {
+                // only the new last chunk changed
+                truncated.rehash_chunks(&self.file.file, length - 1..length, algorithm)?;
+            } else {
+                truncated.checksum =
+                    stream_checksum(&self.file.file, truncated.data_offset(), length, algorithm)?;
+            }
+        }
+        let released = if truncated.allocated_length == 0 {
+            desc.file_offset
+        } else {
+            truncated.file_offset.end_offset(truncated.allocated_length)
+        };
+        let released_length = desc.file_offset.end_offset(desc.allocated_length).0 - released.0;
+        let footer = &mut self.file.footer;
+        footer.blocks.insert(self.block_id.clone(), truncated);
+        if released_length > 0 {
+            footer.release_space(released, released_length);
+            footer.consolidate_empty_space();
+        }
+        footer.write_inline_header(&mut self.file.file, &mut self.file.header, &self.block_id)?;
+        footer.record_change(&mut self.file.file, &mut self.file.header)?;
+        Ok(())
+    }
+}
+impl<'a, F: Storage> Write for InternalFile<'a, F> {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         use std::cmp::min;
-        use std::io::{Error, ErrorKind, SeekFrom};
+        use std::io::{Error, ErrorKind};
 
         // 0) Trivial fast path
         if buf.is_empty() {
@@ -97,6 +145,9 @@ impl<'a, F: std::io::Read + std::io::Write + std::io::Seek> Write for InternalFi
         }
 
         // 1) Snapshot block descriptor (if exists)
+        self.file
+            .footer
+            .load_block(&self.file.file, &self.block_id)?;
         let desc_opt = self.file.footer.blocks.get(&self.block_id).cloned();
 
         // When the block doesn't exist yet, fall back to the "rebuild/insert" path below.
@@ -142,62 +193,69 @@ impl<'a, F: std::io::Read + std::io::Write + std::io::Seek> Write for InternalFi
             .checked_add(write_len_u64)
             .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "size overflow"))?;
 
-        let fits_in_alloc = end_pos <= desc.allocated_length && desc.allocated_length > 0;
+        let fits_in_alloc = end_pos <= desc.capacity() && desc.allocated_length > 0;
 
         if fits_in_alloc {
             // 2a) If we're extending beyond used_length but still inside allocation, zero-fill the gap [used_length, cursor)
             if self.cursor > desc.used_length {
                 let gap = self.cursor - desc.used_length;
-                // write zeros into the gap
-                self.file
-                    .file
-                    .seek(SeekFrom::Start(desc.file_offset.0 + desc.used_length))?;
                 // Write zeros in reasonable chunks to avoid big temporary vecs
                 const ZEROS: [u8; 4096] = [0u8; 4096];
-                let mut remaining = gap;
-                while remaining > 0 {
-                    let chunk = min(remaining, ZEROS.len() as u64) as usize;
-                    self.file.file.write_all(&ZEROS[..chunk])?;
-                    remaining -= chunk as u64;
+                let mut written = 0;
+                while written < gap {
+                    let chunk = min(gap - written, ZEROS.len() as u64) as usize;
+                    self.file.file.write_all_at(
+                        &ZEROS[..chunk],
+                        desc.data_offset().0 + desc.used_length + written,
+                    )?;
+                    written += chunk as u64;
                 }
             }
 
             // 2b) Write the user buffer at current cursor
             self.file
                 .file
-                .seek(SeekFrom::Start(desc.file_offset.0 + self.cursor))?;
-            self.file.file.write_all(buf)?;
+                .write_all_at(buf, desc.data_offset().0 + self.cursor)?;
 
             // 2c) Update used_length and checksum
             let new_used = desc.used_length.max(end_pos);
 
-            // Recompute checksum by streaming the used bytes (no full in-memory rebuild).
-            // (Uses the same seed/algorithm as calc_checksum(..), but streaming.)
-            use std::hash::Hasher;
-            use twox_hash::XxHash64;
-
-            let mut hasher = XxHash64::with_seed(4321);
-            self.file.file.seek(SeekFrom::Start(desc.file_offset.0))?;
-            let mut tmp = [0u8; 8192];
-            let mut remaining = new_used;
-            while remaining > 0 {
-                let to_read = min(remaining, tmp.len() as u64) as usize;
-                self.file.file.read_exact(&mut tmp[..to_read])?;
-                hasher.write(&tmp[..to_read]);
-                remaining -= to_read as u64;
+            if desc.is_chunked() {
+                // only the chunks the write (and any zero-filled gap) touched need rehashing
+                let changed = self.cursor.min(desc.used_length)..end_pos;
+                let mut desc = desc.clone();
+                desc.used_length = new_used;
+                desc.rehash_chunks(
+                    &self.file.file,
+                    changed,
+                    self.file.footer.checksum_algorithm,
+                )
+                .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+                self.file.footer.blocks.insert(self.block_id.clone(), desc);
+            } else {
+                // Recompute checksum by streaming the used bytes (no full in-memory rebuild).
+                let checksum = stream_checksum(
+                    &self.file.file,
+                    desc.data_offset(),
+                    new_used,
+                    self.file.footer.checksum_algorithm,
+                )?;
+                if let Some(d) = self.file.footer.blocks.get_mut(&self.block_id) {
+                    d.used_length = new_used;
+                    d.checksum = checksum;
+                    // allocated_length, metadata, file_offset unchanged
+                }
             }
-            let checksum = Checksum(hasher.finish());
 
-            // 2d) Commit descriptor changes + footer
-            if let Some(d) = self.file.footer.blocks.get_mut(&self.block_id) {
-                d.used_length = new_used;
-                d.checksum = checksum;
-                // allocated_length, metadata, file_offset unchanged
-            }
-            // Persist footer (and header)
+            // 2d) Commit footer
             self.file
                 .footer
-                .write_to(&mut self.file.file, &mut self.file.header)
+                .write_inline_header(&mut self.file.file, &mut self.file.header, &self.block_id)
+                .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+            // Persist footer (and header), depending on the durability mode
+            self.file
+                .footer
+                .record_change(&mut self.file.file, &mut self.file.header)
                 .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
 
             // 2e) Advance cursor
@@ -210,7 +268,7 @@ impl<'a, F: std::io::Read + std::io::Write + std::io::Seek> Write for InternalFi
             let (m, d) = self
                 .file
                 .footer
-                .get_block(&mut self.file.file, &self.block_id)
+                .get_block(&self.file.file, &self.block_id)
                 .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
             (m.clone(), d, desc.used_length)
         };
@@ -247,3 +305,22 @@ impl<'a, F: std::io::Read + std::io::Write + std::io::Seek> Write for InternalFi
         Ok(())
     }
 }
+
+/// Checksums `length` bytes of the file from `offset` by streaming them.
+fn stream_checksum<R: Storage>(
+    reader: &R,
+    offset: FileOffset,
+    length: u64,
+    algorithm: ChecksumAlgorithm,
+) -> std::io::Result<Checksum> {
+    let mut hasher = algorithm.hasher();
+    let mut tmp = [0u8; 8192];
+    let mut read = 0;
+    while read < length {
+        let to_read = (length - read).min(tmp.len() as u64) as usize;
+        reader.read_exact_at(&mut tmp[..to_read], offset.0 + read)?;
+        hasher.write(&tmp[..to_read]);
+        read += to_read as u64;
+    }
+    Ok(hasher.finish())
+}
@@ -1,9 +1,18 @@
+#[cfg(feature = "async")]
+pub mod async_api;
 pub mod basic_api;
+pub mod block_reader;
+pub mod block_writer;
+pub mod compact;
 pub mod container_file;
 pub mod error;
+pub mod lock;
+pub mod storage;
 pub mod traits;
+pub mod transaction;
 
 pub mod internal_file;
+pub mod shared;
 
 #[cfg(test)]
 mod tests;
@@ -0,0 +1,63 @@
+use std::fs::File;
+
+use crate::{basic_api::Cogtainer, error::CogtainerError};
+
+/// An advisory lock on a container file (`flock` on Unix, `LockFileEx` on Windows), to keep
+/// processes that share a file out of each other's way. Only processes that lock the file are
+/// held back.
+#[derive(Clone, Debug, Copy, PartialEq, Eq)]
+pub enum FileLock {
+    /// Any number of readers, and no writer.
+    Shared,
+    /// A single writer, and no readers.
+    Exclusive,
+}
+
+impl Cogtainer<File> {
+    /// Waits for the lock, then opens the container. The lock is held until `unlock()` is called
+    /// or the container is dropped.
+    pub fn open_locked(file: File, lock: FileLock) -> Result<Self, CogtainerError> {
+        lock_file(&file, lock)?;
+        Self::open(file)
+    }
+    /// Waits for an exclusive lock, then creates a new container in the file. The lock is held
+    /// until `unlock()` is called or the container is dropped.
+    pub fn create_locked(file: File) -> Result<Self, CogtainerError> {
+        lock_file(&file, FileLock::Exclusive)?;
+        Self::create(file)
+    }
+
+    /// Waits for the lock, replacing any lock this container already holds. A reader that only
+    /// locks while it reads should `refresh()` once it has the lock.
+    pub fn lock(&mut self, lock: FileLock) -> Result<&mut Self, CogtainerError> {
+        lock_file(&self.file, lock)?;
+        Ok(self)
+    }
+    /// Takes the lock if no other process holds a conflicting one. Returns false if one does.
+    pub fn try_lock(&mut self, lock: FileLock) -> Result<bool, CogtainerError> {
+        let result = match lock {
+            FileLock::Shared => self.file.try_lock_shared(),
+            FileLock::Exclusive => self.file.try_lock(),
+        };
+        match result {
+            Ok(()) => Ok(true),
+            Err(std::fs::TryLockError::WouldBlock) => Ok(false),
+            Err(std::fs::TryLockError::Error(err)) => Err(err.into()),
+        }
+    }
+    /// Releases the lock. Pending changes are flushed first, so other processes see them.
+    pub fn unlock(&mut self) -> Result<&mut Self, CogtainerError> {
+        if self.footer.is_dirty() {
+            self.flush()?;
+        }
+        self.file.unlock()?;
+        Ok(self)
+    }
+}
+
+fn lock_file(file: &File, lock: FileLock) -> std::io::Result<()> {
+    match lock {
+        FileLock::Shared => file.lock_shared(),
+        FileLock::Exclusive => file.lock(),
+    }
+}
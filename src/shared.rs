@@ -0,0 +1,364 @@
+use std::{
+    io,
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockWriteGuard,
+    },
+};
+
+#[cfg(feature = "full")]
+use serde::de::DeserializeOwned;
+
+use crate::{
+    basic_api::{Cogtainer, PunchFn, TruncateFn},
+    container_file::Identifier,
+    error::CogtainerError,
+    storage::Storage,
+};
+
+/// A handle to a container that can be cloned and shared between threads.
+///
+/// Readers read from a snapshot of the container as of the latest commit, through
+/// `Storage::read_at`, so they run at the same time as each other and as the writer. Mutations
+/// take the single writer lock, and the snapshot is replaced once a change has been committed.
+/// Space freed by a commit isn't reused while a reader holds a snapshot. A block changed by an
+/// in-place write is read again under the writer lock.
+///
+/// Settings that need `Truncate` or `Sparse` must be made before the container is shared.
+#[derive(Debug)]
+pub struct SharedCogtainer<F> {
+    inner: Arc<Shared<F>>,
+}
+#[derive(Debug)]
+struct Shared<F> {
+    writer: Mutex<Cogtainer<SharedFile<F>>>,
+    snapshot: RwLock<Arc<Committed<F>>>,
+}
+impl<F> Clone for SharedCogtainer<F> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+impl<F: Storage> From<Cogtainer<F>> for SharedCogtainer<F> {
+    fn from(container: Cogtainer<F>) -> Self {
+        Self::new(container)
+    }
+}
+impl<F: Storage> SharedCogtainer<F> {
+    pub fn new(container: Cogtainer<F>) -> Self {
+        let writer = container
+            .map_file(|file, truncate, punch| {
+                let file = SharedFile {
+                    file: Arc::new(RwLock::new(file)),
+                    reuses: Arc::new(AtomicU64::new(0)),
+                    readers: Arc::new(AtomicUsize::new(0)),
+                    truncate,
+                    punch,
+                };
+                Ok((
+                    file,
+                    truncate.map(|_| truncate_shared as TruncateFn<SharedFile<F>>),
+                    punch.map(|_| punch_shared as PunchFn<SharedFile<F>>),
+                ))
+            })
+            .expect("sharing the file can't fail");
+        Self {
+            inner: Arc::new(Shared {
+                snapshot: RwLock::new(Arc::new(Committed::of(&writer))),
+                writer: Mutex::new(writer),
+            }),
+        }
+    }
+
+    /// Returns a snapshot of the container as of the latest commit, which stays intact while
+    /// changes are made.
+    pub fn read(&self) -> Result<Snapshot<F>, CogtainerError> {
+        loop {
+            let committed = Arc::clone(&*self.inner.snapshot.read().map_err(poisoned)?);
+            let snapshot = Snapshot::pin(committed);
+            if snapshot.is_current() {
+                return Ok(snapshot);
+            }
+            drop(snapshot);
+            // a change in progress made freed space reusable, its snapshot is taken once it's done
+            drop(self.write()?);
+        }
+    }
+
+    /// Takes the writer lock, for changes that aren't wrapped below (such as transactions or
+    /// settings). Readers aren't held up by it.
+    pub fn write(&self) -> Result<WriteGuard<'_, F>, CogtainerError> {
+        Ok(WriteGuard {
+            container: self.inner.writer.lock().map_err(poisoned)?,
+            snapshot: &self.inner.snapshot,
+        })
+    }
+
+    /// Returns the container if this is the last handle to it and no snapshot of it is held,
+    /// otherwise gives the handle back.
+    pub fn try_unwrap(self) -> Result<Result<Cogtainer<F>, Self>, CogtainerError> {
+        let readers = {
+            let writer = self.inner.writer.lock().map_err(poisoned)?;
+            writer.file.readers.load(Ordering::SeqCst)
+        };
+        if readers > 0 {
+            return Ok(Err(self));
+        }
+        let shared = match Arc::try_unwrap(self.inner) {
+            Ok(shared) => shared,
+            Err(inner) => return Ok(Err(Self { inner })),
+        };
+        let writer = shared.writer.into_inner().map_err(poisoned)?;
+        drop(shared.snapshot);
+        let container = writer.map_file(|file, _, _| {
+            let inner = Arc::try_unwrap(file.file)
+                .ok()
+                .expect("the snapshots are gone")
+                .into_inner()
+                .map_err(poisoned)?;
+            Ok((inner, file.truncate, file.punch))
+        })?;
+        Ok(Ok(container))
+    }
+
+    /// Runs `read` on the latest snapshot, or again on the container under the writer lock if an
+    /// in-place write changed the block it read.
+    fn read_with<T>(
+        &self,
+        read: impl Fn(&Cogtainer<SharedFile<F>>) -> Result<T, CogtainerError>,
+    ) -> Result<T, CogtainerError> {
+        let snapshot = self.read()?;
+        let result = read(&snapshot);
+        if !matches!(result, Err(CogtainerError::BlockChecksumError(_))) {
+            return result;
+        }
+        drop(snapshot);
+        read(&*self.write()?)
+    }
+
+    /// Get the metadata and data of a specific block.
+    /// Returns Err if block not found.
+    pub fn get_block(
+        &self,
+        identifier: &Identifier,
+    ) -> Result<(rmpv::Value, Vec<u8>), CogtainerError> {
+        self.read_with(|container| {
+            let (metadata, data) = container.get_block(identifier)?;
+            Ok((metadata.clone(), data))
+        })
+    }
+
+    /// Inserts a block with the given unique identifier.
+    /// If a block already exists with the given identifier, it will be replaced.
+    pub fn insert_block(
+        &self,
+        identifier: &Identifier,
+        metadata: rmpv::Value,
+        data: &[u8],
+    ) -> Result<(), CogtainerError> {
+        self.write()?.insert_block(identifier, metadata, data)?;
+        Ok(())
+    }
+
+    /// Deletes the specified block.
+    ///
+    /// (Requires a call to flush() to persist changes)
+    pub fn delete_block(&self, identifier: &Identifier) -> Result<(), CogtainerError> {
+        self.write()?.delete_block(identifier)?;
+        Ok(())
+    }
+
+    /// Saves all pending changes to the file.
+    pub fn flush(&self) -> Result<(), CogtainerError> {
+        self.write()?.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "full")]
+impl<F: Storage> SharedCogtainer<F> {
+    /// Get the metadata and data of a block written with `insert_block_as`.
+    pub fn get_as<M: DeserializeOwned, D: DeserializeOwned>(
+        &self,
+        identifier: &Identifier,
+    ) -> Result<(M, D), CogtainerError> {
+        self.read_with(|container| container.get_as(identifier))
+    }
+    pub fn get_as_raw<M: DeserializeOwned>(
+        &self,
+        identifier: &Identifier,
+    ) -> Result<(M, Vec<u8>), CogtainerError> {
+        self.read_with(|container| container.get_as_raw(identifier))
+    }
+}
+
+/// The writer lock of a `SharedCogtainer`. Once dropped, readers see the changes that were
+/// committed while it was held.
+pub struct WriteGuard<'a, F: Storage> {
+    container: MutexGuard<'a, Cogtainer<SharedFile<F>>>,
+    snapshot: &'a RwLock<Arc<Committed<F>>>,
+}
+impl<F: Storage> Deref for WriteGuard<'_, F> {
+    type Target = Cogtainer<SharedFile<F>>;
+    fn deref(&self) -> &Self::Target {
+        &self.container
+    }
+}
+impl<F: Storage> DerefMut for WriteGuard<'_, F> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.container
+    }
+}
+impl<F: Storage> Drop for WriteGuard<'_, F> {
+    fn drop(&mut self) {
+        let reuses = self.container.file.reuses.load(Ordering::SeqCst);
+        // a poisoned snapshot lock is reported to the readers instead
+        if let Ok(mut snapshot) = self.snapshot.write() {
+            let committed = snapshot.container.header.generation
+                != self.container.header.generation
+                && !self.container.footer.is_dirty();
+            if committed || snapshot.reuses != reuses {
+                *snapshot = Arc::new(Committed::of(&self.container));
+            }
+        }
+    }
+}
+
+/// The container as of a commit.
+#[derive(Debug)]
+struct Committed<F> {
+    container: Cogtainer<SharedFile<F>>,
+    /// `SharedFile::reuses` when it was taken.
+    reuses: u64,
+}
+impl<F: Storage> Committed<F> {
+    fn of(container: &Cogtainer<SharedFile<F>>) -> Self {
+        Self {
+            container: Cogtainer::from_parts(
+                container.file.clone(),
+                container.header.clone(),
+                container.footer.clone(),
+            ),
+            reuses: container.file.reuses.load(Ordering::SeqCst),
+        }
+    }
+}
+
+/// A reader's view of a `SharedCogtainer` as of a commit. While it is held, the space its blocks
+/// are in isn't reused, so the file grows instead.
+#[derive(Debug)]
+pub struct Snapshot<F> {
+    committed: Arc<Committed<F>>,
+}
+impl<F> Snapshot<F> {
+    fn pin(committed: Arc<Committed<F>>) -> Self {
+        committed
+            .container
+            .file
+            .readers
+            .fetch_add(1, Ordering::SeqCst);
+        Self { committed }
+    }
+    /// Returns false if space freed since the snapshot was taken was made reusable before it was
+    /// pinned, so its blocks may be overwritten.
+    pub fn is_current(&self) -> bool {
+        self.committed.container.file.reuses.load(Ordering::SeqCst) == self.committed.reuses
+    }
+}
+impl<F> Deref for Snapshot<F> {
+    type Target = Cogtainer<SharedFile<F>>;
+    fn deref(&self) -> &Self::Target {
+        &self.committed.container
+    }
+}
+impl<F> Drop for Snapshot<F> {
+    fn drop(&mut self) {
+        self.committed
+            .container
+            .file
+            .readers
+            .fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// The file of a `SharedCogtainer`. Each call locks it only for as long as the call takes, so
+/// readers aren't held up by a change that is being made.
+#[derive(Debug)]
+pub struct SharedFile<F> {
+    file: Arc<RwLock<F>>,
+    /// Counts the commits after which freed space may be written again.
+    reuses: Arc<AtomicU64>,
+    /// Snapshots that are held, which keep freed space from being reused.
+    readers: Arc<AtomicUsize>,
+    truncate: Option<TruncateFn<F>>,
+    punch: Option<PunchFn<F>>,
+}
+impl<F> Clone for SharedFile<F> {
+    fn clone(&self) -> Self {
+        Self {
+            file: Arc::clone(&self.file),
+            reuses: Arc::clone(&self.reuses),
+            readers: Arc::clone(&self.readers),
+            truncate: self.truncate,
+            punch: self.punch,
+        }
+    }
+}
+impl<F> SharedFile<F> {
+    fn lock_mut(&self) -> io::Result<RwLockWriteGuard<'_, F>> {
+        self.file
+            .write()
+            .map_err(|err| io::Error::other(poisoned(err)))
+    }
+}
+impl<F: Storage> Storage for SharedFile<F> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let file = self
+            .file
+            .read()
+            .map_err(|err| io::Error::other(poisoned(err)))?;
+        file.read_at(buf, offset)
+    }
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        self.lock_mut()?.write_at(buf, offset)
+    }
+    fn len(&self) -> io::Result<u64> {
+        let file = self
+            .file
+            .read()
+            .map_err(|err| io::Error::other(poisoned(err)))?;
+        file.len()
+    }
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.lock_mut()?.set_len(len)
+    }
+    fn sync(&mut self) -> io::Result<()> {
+        self.lock_mut()?.sync()
+    }
+    fn preallocate(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        self.lock_mut()?.preallocate(offset, len)
+    }
+    fn freed_space_reusable(&mut self) -> bool {
+        // a reader that pins a snapshot after this sees that it isn't current
+        if self.readers.load(Ordering::SeqCst) > 0 {
+            return false;
+        }
+        self.reuses.fetch_add(1, Ordering::SeqCst);
+        true
+    }
+}
+fn truncate_shared<F>(file: &mut SharedFile<F>, length: u64) -> io::Result<()> {
+    let truncate = file.truncate.expect("only set with the file's own hook");
+    truncate(&mut *file.lock_mut()?, length)
+}
+fn punch_shared<F>(file: &mut SharedFile<F>, offset: u64, len: u64) -> io::Result<()> {
+    let punch = file.punch.expect("only set with the file's own hook");
+    punch(&mut *file.lock_mut()?, offset, len)
+}
+
+fn poisoned<T>(_: PoisonError<T>) -> CogtainerError {
+    CogtainerError::LockPoisoned
+}
@@ -0,0 +1,277 @@
+use std::{
+    fs::File,
+    io::{self, Cursor, Read, Seek, SeekFrom, Write},
+    sync::Mutex,
+};
+
+/// Positional I/O for the file a container lives in. There is no shared cursor, so reads only
+/// need `&self` and can't disturb each other.
+pub trait Storage {
+    /// Reads into `buf` from `offset`, returning how many bytes were read (0 at the end).
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+    /// Writes `buf` at `offset`, extending the storage if needed. Returns how many bytes were
+    /// written.
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<usize>;
+    /// The current length.
+    fn len(&self) -> io::Result<u64>;
+    /// Shrinks or extends (with zeros) to `len` bytes.
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+    /// Makes everything written so far durable.
+    fn sync(&mut self) -> io::Result<()>;
+    /// Reserves space for `len` bytes at `offset` without changing the length, so writes there
+    /// don't have to allocate. Does nothing by default.
+    fn preallocate(&mut self, _offset: u64, _len: u64) -> io::Result<()> {
+        Ok(())
+    }
+    /// Asked once a committed footer no longer refers to space freed by earlier changes, before
+    /// that space is written again. Returning false leaves it alone until a later commit asks
+    /// again, for readers still using an older footer. Returns true by default.
+    fn freed_space_reusable(&mut self) -> bool {
+        true
+    }
+
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+    /// Reads exactly `buf.len()` bytes from `offset`.
+    fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.read_at(buf, offset) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+    /// Writes all of `buf` at `offset`.
+    fn write_all_at(&mut self, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.write_at(buf, offset) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    buf = &buf[n..];
+                    offset += n as u64;
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: Storage + ?Sized> Storage for &mut S {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        (**self).read_at(buf, offset)
+    }
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        (**self).write_at(buf, offset)
+    }
+    fn len(&self) -> io::Result<u64> {
+        (**self).len()
+    }
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        (**self).set_len(len)
+    }
+    fn sync(&mut self) -> io::Result<()> {
+        (**self).sync()
+    }
+    fn preallocate(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        (**self).preallocate(offset, len)
+    }
+    fn freed_space_reusable(&mut self) -> bool {
+        (**self).freed_space_reusable()
+    }
+}
+
+impl Storage for File {
+    #[cfg(unix)]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        std::os::unix::fs::FileExt::read_at(self, buf, offset)
+    }
+    #[cfg(windows)]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        std::os::windows::fs::FileExt::seek_read(self, buf, offset)
+    }
+    #[cfg(unix)]
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        std::os::unix::fs::FileExt::write_at(self, buf, offset)
+    }
+    #[cfg(windows)]
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        std::os::windows::fs::FileExt::seek_write(self, buf, offset)
+    }
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_data()
+    }
+    #[cfg(target_os = "linux")]
+    fn preallocate(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        crate::traits::fallocate(self, libc::FALLOC_FL_KEEP_SIZE, offset, len)
+    }
+}
+
+impl Storage for Vec<u8> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        Ok(read_slice_at(self, buf, offset))
+    }
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        let start = usize::try_from(offset).map_err(|_| io::ErrorKind::InvalidInput)?;
+        let end = start
+            .checked_add(buf.len())
+            .ok_or(io::ErrorKind::InvalidInput)?;
+        if end > Vec::len(self) {
+            self.resize(end, 0);
+        }
+        self[start..end].copy_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn len(&self) -> io::Result<u64> {
+        Ok(Vec::len(self) as u64)
+    }
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        let len = usize::try_from(len).map_err(|_| io::ErrorKind::InvalidInput)?;
+        self.resize(len, 0);
+        Ok(())
+    }
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+    fn preallocate(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        let end = usize::try_from(offset.saturating_add(len)).unwrap_or(usize::MAX);
+        self.reserve(end.saturating_sub(Vec::len(self)));
+        Ok(())
+    }
+}
+
+/// Storage for bytes that can't change: changes fail with `ErrorKind::PermissionDenied`.
+macro_rules! read_only_storage {
+    ($(#[$attr:meta])* $type:ty) => {
+        $(#[$attr])*
+        impl Storage for $type {
+            fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+                Ok(read_slice_at(self, buf, offset))
+            }
+            fn write_at(&mut self, _buf: &[u8], _offset: u64) -> io::Result<usize> {
+                Err(read_only())
+            }
+            fn len(&self) -> io::Result<u64> {
+                Ok(<[u8]>::len(self) as u64)
+            }
+            fn set_len(&mut self, _len: u64) -> io::Result<()> {
+                Err(read_only())
+            }
+            fn sync(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+    };
+}
+read_only_storage!(&[u8]);
+read_only_storage!(
+    #[cfg(feature = "bytes")]
+    bytes::Bytes
+);
+read_only_storage!(
+    #[cfg(feature = "mmap")]
+    memmap2::Mmap
+);
+
+/// Like `Vec<u8>`. The cursor's position is ignored.
+impl Storage for Cursor<Vec<u8>> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        Storage::read_at(self.get_ref(), buf, offset)
+    }
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        Storage::write_at(self.get_mut(), buf, offset)
+    }
+    fn len(&self) -> io::Result<u64> {
+        Storage::len(self.get_ref())
+    }
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        Storage::set_len(self.get_mut(), len)
+    }
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+    fn preallocate(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        Storage::preallocate(self.get_mut(), offset, len)
+    }
+}
+
+fn read_slice_at(slice: &[u8], buf: &mut [u8], offset: u64) -> usize {
+    let start = usize::try_from(offset)
+        .unwrap_or(usize::MAX)
+        .min(slice.len());
+    let n = buf.len().min(slice.len() - start);
+    buf[..n].copy_from_slice(&slice[start..start + n]);
+    n
+}
+
+fn read_only() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, "container is read-only")
+}
+
+/// Adapts any `Read + Write + Seek` type to `Storage`, by seeking before every read and write.
+/// Reads take a lock, so they don't run concurrently.
+///
+/// `set_len` can only extend, there is no generic way to shrink. `sync` flushes.
+#[derive(Debug, Default)]
+pub struct SeekStorage<T>(Mutex<T>);
+impl<T> SeekStorage<T> {
+    pub fn new(inner: T) -> Self {
+        Self(Mutex::new(inner))
+    }
+    pub fn get_mut(&mut self) -> &mut T {
+        self.0.get_mut().unwrap_or_else(|err| err.into_inner())
+    }
+    pub fn into_inner(self) -> T {
+        self.0.into_inner().unwrap_or_else(|err| err.into_inner())
+    }
+}
+impl<T: Read + Write + Seek> Storage for SeekStorage<T> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let mut inner = self.0.lock().unwrap_or_else(|err| err.into_inner());
+        inner.seek(SeekFrom::Start(offset))?;
+        inner.read(buf)
+    }
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        let inner = self.get_mut();
+        inner.seek(SeekFrom::Start(offset))?;
+        inner.write(buf)
+    }
+    fn len(&self) -> io::Result<u64> {
+        let mut inner = self.0.lock().unwrap_or_else(|err| err.into_inner());
+        inner.seek(SeekFrom::End(0))
+    }
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        let current = self.len()?;
+        if len < current {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Read + Write + Seek storage can't be shrunk",
+            ));
+        }
+        let padding = vec![0u8; (len - current).min(64 * 1024) as usize];
+        let mut at = current;
+        while at < len {
+            let n = padding.len().min((len - at) as usize);
+            self.write_all_at(&padding[..n], at)?;
+            at += n as u64;
+        }
+        Ok(())
+    }
+    fn sync(&mut self) -> io::Result<()> {
+        self.get_mut().flush()
+    }
+}
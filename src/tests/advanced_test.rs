@@ -113,7 +113,7 @@ mod compression_block_tests {
             .unwrap();
 
         // Tamper with the raw stored data in the container
-        let block_desc = c.get_blocks_list().get(&id).unwrap();
+        let block_desc = c.get_blocks_list().unwrap().get(&id).unwrap();
         let offset = block_desc.file_offset.0;
         let len = block_desc.used_length as usize;
 
@@ -0,0 +1,168 @@
+#[cfg(test)]
+mod alignment_tests {
+    use crate::{basic_api::Cogtainer, container_file::*};
+
+    use std::io::{Cursor, Seek, SeekFrom, Write};
+
+    const ALIGN: u64 = 4096;
+
+    fn id(i: u64) -> Identifier {
+        Identifier::U64(i)
+    }
+
+    fn aligned_container() -> Cogtainer<Cursor<Vec<u8>>> {
+        let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+        c.set_block_alignment(ALIGN);
+        c
+    }
+
+    fn assert_aligned(c: &Cogtainer<Cursor<Vec<u8>>>, identifier: &Identifier, alignment: u64) {
+        let offset = c.get_blocks_list().unwrap()[identifier].data_offset();
+        assert_eq!(offset.0 % alignment, 0, "{identifier:?} at {offset:?}");
+    }
+
+    #[test]
+    fn blocks_start_on_the_boundary() {
+        let mut c = aligned_container();
+        for i in 0..5 {
+            c.insert_block(
+                &id(i),
+                rmpv::Value::Nil,
+                &vec![i as u8; 100 + 3000 * i as usize],
+            )
+            .unwrap();
+            assert_aligned(&c, &id(i), ALIGN);
+        }
+        // the padding before the first block is free space
+        let header = ContainerHeader::HEADER_SIZE as u64;
+        assert_eq!(
+            c.footer.empty_space.get(&FileOffset(header)),
+            Some(&(ALIGN - header))
+        );
+        assert!(c.verify().is_clean());
+
+        let buf = c.into_inner().unwrap().into_inner();
+        let c = Cogtainer::open(Cursor::new(buf)).unwrap();
+        for i in 0..5 {
+            assert_eq!(
+                c.get_block(&id(i)).unwrap().1,
+                vec![i as u8; 100 + 3000 * i as usize]
+            );
+        }
+    }
+
+    #[test]
+    fn holes_are_carved_on_the_boundary() {
+        for strategy in [
+            AllocationStrategy::FirstFit,
+            AllocationStrategy::BestFit,
+            AllocationStrategy::WorstFit,
+            AllocationStrategy::Segregated,
+        ] {
+            let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+            c.set_allocation_strategy(strategy);
+            c.insert_block(&id(0), rmpv::Value::Nil, b"unaligned")
+                .unwrap();
+            c.insert_block(&id(1), rmpv::Value::Nil, &[1; 3 * ALIGN as usize])
+                .unwrap();
+            c.insert_block(&id(2), rmpv::Value::Nil, b"after").unwrap();
+            let hole = c.get_blocks_list().unwrap()[&id(1)].file_offset;
+            c.delete_block(&id(1)).unwrap();
+            c.flush().unwrap();
+
+            c.set_block_alignment(ALIGN);
+            let data_end = c.header.footer_offset;
+            c.insert_block(&id(3), rmpv::Value::Nil, &[3; 100]).unwrap();
+            assert_aligned(&c, &id(3), ALIGN);
+            let block = c.get_blocks_list().unwrap()[&id(3)].file_offset;
+            assert!(block > hole && block < data_end, "{strategy:?}");
+            // the padding went back to the free list
+            assert_eq!(
+                c.footer.empty_space.get(&hole),
+                Some(&(block.0 - hole.0)),
+                "{strategy:?}"
+            );
+            assert!(c.verify().is_clean(), "{strategy:?}");
+        }
+    }
+
+    #[test]
+    fn inline_headers_come_before_the_boundary() {
+        let mut c = aligned_container();
+        c.set_inline_block_headers(true);
+        c.insert_block(&id(1), rmpv::Value::Nil, b"aligned data")
+            .unwrap();
+        let descriptor = &c.get_blocks_list().unwrap()[&id(1)];
+        assert!(descriptor.header_length > 0);
+        assert_aligned(&c, &id(1), ALIGN);
+        assert_eq!(c.get_block(&id(1)).unwrap().1, b"aligned data");
+    }
+
+    #[test]
+    fn alignment_per_insert() {
+        let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+        c.insert_block(&id(0), rmpv::Value::Nil, b"packed").unwrap();
+        c.insert_block_aligned(&id(1), rmpv::Value::Nil, b"big page", 65536)
+            .unwrap();
+        c.insert_block(&id(2), rmpv::Value::Nil, b"packed").unwrap();
+        assert_aligned(&c, &id(1), 65536);
+        // the next block goes in the padding
+        assert!(
+            c.get_blocks_list().unwrap()[&id(2)].file_offset
+                < c.get_blocks_list().unwrap()[&id(1)].file_offset
+        );
+        assert_eq!(c.footer.block_alignment, 1);
+    }
+
+    #[test]
+    fn every_writer_aligns() {
+        let mut c = aligned_container();
+        c.insert_block(&id(0), rmpv::Value::Nil, b"first").unwrap();
+
+        let mut w = c.block_writer(&id(1), rmpv::Value::Nil).unwrap();
+        w.write_all(&[1; 10_000]).unwrap();
+        w.finish().unwrap();
+        assert_aligned(&c, &id(1), ALIGN);
+
+        let mut tx = c.transaction().unwrap();
+        tx.insert_block(&id(2), rmpv::Value::Nil, &[2; 50]).unwrap();
+        tx.commit().unwrap();
+        assert_aligned(&c, &id(2), ALIGN);
+
+        {
+            // growing past the allocation relocates the block
+            let mut f = c.get_block_as_file(&id(0));
+            f.seek(SeekFrom::End(0)).unwrap();
+            f.write_all(&[0; 5000]).unwrap();
+        }
+        assert_aligned(&c, &id(0), ALIGN);
+        assert!(c.verify().is_clean());
+    }
+
+    #[test]
+    fn defragment_keeps_blocks_aligned() {
+        let mut c = aligned_container();
+        for i in 0..6 {
+            c.insert_block(
+                &id(i),
+                rmpv::Value::from(i),
+                &vec![i as u8; 1000 * (i as usize + 1)],
+            )
+            .unwrap();
+        }
+        for i in [1, 3] {
+            c.delete_block(&id(i)).unwrap();
+        }
+        let data_end = c.header.footer_offset;
+        c.defragment().unwrap();
+        assert!(c.header.footer_offset < data_end);
+        for i in [0, 2, 4, 5] {
+            assert_aligned(&c, &id(i), ALIGN);
+            assert_eq!(
+                c.get_block(&id(i)).unwrap().1,
+                vec![i as u8; 1000 * (i as usize + 1)]
+            );
+        }
+        assert!(c.verify().is_clean());
+    }
+}
@@ -0,0 +1,239 @@
+#[cfg(test)]
+mod allocation_tests {
+    use crate::{basic_api::Cogtainer, container_file::*};
+
+    use std::{collections::HashMap, io::Cursor};
+
+    const STRATEGIES: [AllocationStrategy; 4] = [
+        AllocationStrategy::FirstFit,
+        AllocationStrategy::BestFit,
+        AllocationStrategy::WorstFit,
+        AllocationStrategy::Segregated,
+    ];
+
+    fn id(i: u64) -> Identifier {
+        Identifier::U64(i)
+    }
+
+    /// A container with holes of 100, 300, 150, 500 and 130 bytes, in that order, separated by
+    /// small blocks. Returns the offsets of the holes.
+    fn container_with_holes() -> (Cogtainer<Cursor<Vec<u8>>>, Vec<FileOffset>) {
+        let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+        let holes = [100, 300, 150, 500, 130];
+        for (i, len) in holes.iter().enumerate() {
+            c.insert_block(&id(i as u64), rmpv::Value::Nil, &vec![1u8; *len])
+                .unwrap();
+            c.insert_block(&id(100 + i as u64), rmpv::Value::Nil, b"separator")
+                .unwrap();
+        }
+        let offsets = (0..holes.len())
+            .map(|i| c.get_blocks_list().unwrap()[&id(i as u64)].file_offset)
+            .collect();
+        for i in 0..holes.len() {
+            c.delete_block(&id(i as u64)).unwrap();
+        }
+        c.flush().unwrap();
+        (c, offsets)
+    }
+
+    #[test]
+    fn strategies_pick_different_holes() {
+        let expected = [1, 4, 3, 2];
+        for (strategy, hole) in STRATEGIES.iter().zip(expected) {
+            let (mut c, holes) = container_with_holes();
+            c.set_allocation_strategy(*strategy);
+            c.insert_block(&id(50), rmpv::Value::Nil, &[2u8; 120])
+                .unwrap();
+            assert_eq!(
+                c.get_blocks_list().unwrap()[&id(50)].file_offset,
+                holes[hole],
+                "{strategy:?}"
+            );
+            // the leftover stays available
+            let lengths = [100, 300, 150, 500, 130];
+            assert_eq!(
+                c.footer.empty_space.get(&holes[hole].end_offset(120)),
+                Some(&(lengths[hole] - 120)),
+                "{strategy:?}"
+            );
+            assert!(c.verify().is_clean(), "{strategy:?}");
+        }
+    }
+
+    #[test]
+    fn nothing_fits() {
+        for strategy in STRATEGIES {
+            let (mut c, _) = container_with_holes();
+            c.set_allocation_strategy(strategy);
+            let data_end = c.header.footer_offset;
+            c.insert_block(&id(50), rmpv::Value::Nil, &[2u8; 600])
+                .unwrap();
+            assert_eq!(c.get_blocks_list().unwrap()[&id(50)].file_offset, data_end);
+        }
+    }
+
+    #[test]
+    fn empty_space_indexes_stay_consistent() {
+        let mut empty = EmptySpace::default();
+        empty.insert(FileOffset(100), 10);
+        empty.insert(FileOffset(200), 64);
+        empty.insert(FileOffset(300), 70);
+        // replacing a region updates every index
+        assert_eq!(empty.insert(FileOffset(200), 5), Some(64));
+        assert_eq!(
+            empty.find(60, AllocationStrategy::Segregated),
+            Some(FileOffset(300))
+        );
+        assert_eq!(
+            empty.find(6, AllocationStrategy::BestFit),
+            Some(FileOffset(100))
+        );
+        assert_eq!(empty.remove(&FileOffset(300)), Some(70));
+        assert_eq!(empty.find(11, AllocationStrategy::WorstFit), None);
+        assert_eq!(empty.find(11, AllocationStrategy::Segregated), None);
+        assert_eq!(empty.pop_first(), Some((FileOffset(100), 10)));
+        assert_eq!(empty.len(), 1);
+
+        // stored as a plain map of offset to length
+        let bytes = rmp_serde::to_vec(&empty).unwrap();
+        let map: std::collections::BTreeMap<FileOffset, u64> =
+            rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(&map, &*empty);
+        let round_trip: EmptySpace = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(round_trip, empty);
+    }
+
+    #[test]
+    fn first_fit_matches_a_scan_in_address_order() {
+        let mut empty = EmptySpace::default();
+        // holes of pseudo-random lengths, with gaps so none of them merge
+        let mut x = 12345u64;
+        for i in 0..500 {
+            x = x
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            empty.insert(FileOffset(i * 10_000), (x >> 33) % 5000);
+        }
+        empty.consolidate();
+        for required in [0, 1, 7, 64, 100, 1000, 2047, 2048, 4000, 4999, 5000] {
+            let scanned = empty
+                .iter()
+                .find(|(_, len)| **len >= required)
+                .map(|(offset, _)| *offset);
+            assert_eq!(
+                empty.find(required, AllocationStrategy::FirstFit),
+                scanned,
+                "{required}"
+            );
+            for alignment in [8, 512] {
+                let scanned = empty
+                    .iter()
+                    .find(|(offset, len)| {
+                        aligned_offset(**offset, alignment, 3).0 - offset.0 + required <= **len
+                    })
+                    .map(|(offset, _)| *offset);
+                assert_eq!(
+                    empty.find_aligned(required, AllocationStrategy::FirstFit, alignment, 3),
+                    scanned,
+                    "{required} {alignment}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn consolidate_merges_new_regions_with_their_neighbours() {
+        let mut empty = EmptySpace::default();
+        empty.insert(FileOffset(100), 10);
+        empty.insert(FileOffset(200), 10);
+        empty.consolidate();
+        // fills the gap between both regions in two pieces
+        empty.insert(FileOffset(150), 50);
+        empty.insert(FileOffset(110), 40);
+        empty.insert(FileOffset(300), 5);
+        empty.consolidate();
+        assert_eq!(
+            empty.iter().map(|(o, l)| (*o, *l)).collect::<Vec<_>>(),
+            vec![(FileOffset(100), 110), (FileOffset(300), 5)]
+        );
+        assert_eq!(
+            empty.find(110, AllocationStrategy::BestFit),
+            Some(FileOffset(100))
+        );
+        assert_eq!(
+            empty.find(6, AllocationStrategy::Segregated),
+            Some(FileOffset(100))
+        );
+    }
+
+    #[test]
+    fn defragment_ignores_the_strategy() {
+        let (mut c, _) = container_with_holes();
+        c.set_allocation_strategy(AllocationStrategy::WorstFit);
+        c.defragment().unwrap();
+        assert!(c.footer.empty_space.is_empty());
+        assert!(c.verify().is_clean());
+        assert_eq!(c.footer.allocation_strategy, AllocationStrategy::WorstFit);
+    }
+
+    /// Runs the same mix of inserts, replacements and deletes of small and large blocks against
+    /// every strategy and prints how fragmented each container ends up.
+    /// Random churn under every strategy, committing every few operations so that freed space
+    /// becomes reusable.
+    #[test]
+    fn fragmentation_benchmark() {
+        use rand::{Rng, SeedableRng};
+
+        let mut layouts = vec![];
+        for strategy in STRATEGIES {
+            let mut rng = rand::rngs::StdRng::seed_from_u64(0xF4A6);
+            let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+            c.set_durability_mode(DurabilityMode::WriteBack {
+                flush_every: Some(16),
+            });
+            c.set_allocation_strategy(strategy);
+
+            let mut live: HashMap<u64, Vec<u8>> = HashMap::new();
+            let mut reused = 0;
+            for _ in 0..3000 {
+                let key = rng.random_range(0..300u64);
+                if live.contains_key(&key) && rng.random_bool(0.4) {
+                    c.delete_block(&id(key)).unwrap();
+                    live.remove(&key);
+                    continue;
+                }
+                let len = if rng.random_bool(0.8) {
+                    rng.random_range(16..1024)
+                } else {
+                    rng.random_range(4096..32768)
+                };
+                let data = vec![key as u8; len];
+                let data_end = c.header.footer_offset;
+                c.insert_block(&id(key), rmpv::Value::Nil, &data).unwrap();
+                if c.get_blocks_list().unwrap()[&id(key)].file_offset < data_end {
+                    reused += 1;
+                }
+                live.insert(key, data);
+            }
+            c.flush().unwrap();
+            assert!(reused > 100, "{strategy:?} reused {reused} holes");
+
+            let live_bytes: u64 = live.values().map(|d| d.len() as u64).sum();
+            let free: u64 = c.footer.empty_space.values().sum();
+            assert!(c.header.footer_offset.0 >= live_bytes + free);
+            assert!(c.verify().is_clean(), "{strategy:?}");
+            let mut layout = vec![];
+            for (key, data) in &live {
+                assert_eq!(&c.get_block(&id(*key)).unwrap().1, data);
+                layout.push((*key, c.get_blocks_list().unwrap()[&id(*key)].file_offset));
+            }
+            layout.sort();
+            layouts.push(layout);
+        }
+        for (i, layout) in layouts.iter().enumerate() {
+            for (j, other) in layouts.iter().enumerate().skip(i + 1) {
+                assert_ne!(layout, other, "{:?} and {:?}", STRATEGIES[i], STRATEGIES[j]);
+            }
+        }
+    }
+}
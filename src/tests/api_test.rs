@@ -12,10 +12,13 @@ mod tests {
         let mut file = new_mem_file();
         let (header, footer) = ContainerHeader::create(&mut file).unwrap();
         Cogtainer {
-            file,
+            file: FileSlot::new(file),
             header,
             footer,
             overallocation_policy: OverallocationPolicy::default(),
+            flush_on_drop: None,
+            truncate_on_flush: None,
+            punch_on_flush: None,
         }
     }
 
@@ -29,7 +32,7 @@ mod tests {
 
         // Now flush, reopen, and verify
         c.flush().unwrap();
-        let buf = c.file.into_inner();
+        let buf = c.into_inner().unwrap().into_inner();
         let c2 = Cogtainer::open(Cursor::new(buf)).unwrap();
         assert_eq!(c2.get_container_metadata(), &meta);
     }
@@ -218,7 +221,7 @@ mod tests {
             ids.push(id);
         }
         c.flush().unwrap();
-        let keys: Vec<_> = c.get_blocks_list().keys().cloned().collect();
+        let keys: Vec<_> = c.get_blocks_list().unwrap().keys().cloned().collect();
         assert_eq!(keys.len(), 10);
         for id in ids {
             assert!(keys.contains(&id));
@@ -261,8 +264,8 @@ mod tests {
             .unwrap()
             .flush()
             .unwrap();
-        let buf = c.file.into_inner();
-        let mut c2 = Cogtainer::open(Cursor::new(buf)).unwrap();
+        let buf = c.into_inner().unwrap().into_inner();
+        let c2 = Cogtainer::open(Cursor::new(buf)).unwrap();
         let (m, d) = c2.get_block(&id).unwrap();
         assert_eq!(m, &rmpv::Value::from("meta"));
         assert_eq!(d, b"blob");
@@ -270,7 +273,7 @@ mod tests {
 
     #[test]
     fn test_error_on_nonexistent_block() {
-        let mut c = open_container();
+        let c = open_container();
         let missing = Identifier::String("does_not_exist".into());
         assert!(matches!(
             c.get_block(&missing),
@@ -313,7 +316,7 @@ mod tests {
         c.flush().unwrap();
 
         // Block's offset should have changed, and new allocation is large enough
-        let block = &c.get_blocks_list()[&id];
+        let block = &c.get_blocks_list().unwrap()[&id];
         assert!(block.used_length == 128);
         assert!(block.allocated_length >= 128);
         let (_meta, read) = c.get_block(&id).unwrap();
@@ -333,7 +336,7 @@ mod tests {
         assert_eq!(m, &meta);
 
         // Should be stored at offset 0 with zero length
-        let block = &c.get_blocks_list()[&id];
+        let block = &c.get_blocks_list().unwrap()[&id];
         assert_eq!(block.used_length, 0);
         assert_eq!(block.allocated_length, 0);
         assert_eq!(block.file_offset.0, 0);
@@ -351,9 +354,10 @@ mod tests {
         c.flush().unwrap();
 
         // Read the raw file bytes to check the tail
-        let block = &c.get_blocks_list()[&id];
-        let mut buf = vec![0u8; block.allocated_length as usize];
-        c.file.seek(SeekFrom::Start(block.file_offset.0)).unwrap();
+        let block = &c.get_blocks_list().unwrap()[&id];
+        let (offset, allocated_length) = (block.file_offset.0, block.allocated_length);
+        let mut buf = vec![0u8; allocated_length as usize];
+        c.file.seek(SeekFrom::Start(offset)).unwrap();
         c.file.read_exact(&mut buf).unwrap();
 
         assert_eq!(&buf[..8], &data[..]);
@@ -379,9 +383,10 @@ mod tests {
         c.insert_block(&id, rmpv::Value::Nil, &data2).unwrap();
         c.flush().unwrap();
 
-        let block = &c.get_blocks_list()[&id].clone();
-        let mut buf = vec![0u8; block.allocated_length as usize];
-        c.file.seek(SeekFrom::Start(block.file_offset.0)).unwrap();
+        let block = &c.get_blocks_list().unwrap()[&id].clone();
+        let (offset, allocated_length) = (block.file_offset.0, block.allocated_length);
+        let mut buf = vec![0u8; allocated_length as usize];
+        c.file.seek(SeekFrom::Start(offset)).unwrap();
         c.file.read_exact(&mut buf).unwrap();
 
         assert_eq!(&buf[..32], &data2[..]);
@@ -413,8 +418,8 @@ mod tests {
         c.insert_block(&id3, rmpv::Value::Nil, &d3).unwrap();
         c.flush().unwrap();
 
-        let block1 = &c.get_blocks_list()[&id3];
-        let block2 = &c.get_blocks_list()[&id2];
+        let block1 = &c.get_blocks_list().unwrap()[&id3];
+        let block2 = &c.get_blocks_list().unwrap()[&id2];
         // Their offsets should not overlap
         assert_ne!(block1.file_offset, block2.file_offset);
     }
@@ -428,7 +433,7 @@ mod tests {
         c.flush().unwrap();
 
         // Corrupt the block's data
-        let block = c.get_blocks_list()[&id].clone();
+        let block = c.get_blocks_list().unwrap()[&id].clone();
         c.file.seek(SeekFrom::Start(block.file_offset.0)).unwrap();
         c.file.write_all(&[0x99; 16]).unwrap();
 
@@ -0,0 +1,224 @@
+#[cfg(test)]
+mod async_tests {
+    use crate::{async_api::AsyncCogtainer, basic_api::Cogtainer, container_file::*};
+
+    use std::{
+        io::{self, Cursor, SeekFrom},
+        pin::Pin,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        },
+        task::{Context, Poll},
+    };
+
+    use tokio::io::{
+        AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt, ReadBuf,
+    };
+
+    fn id(i: u64) -> Identifier {
+        Identifier::U64(i)
+    }
+
+    /// An in-memory async file that counts the bytes read from it.
+    #[derive(Debug)]
+    struct CountingFile {
+        inner: Cursor<Vec<u8>>,
+        read: Arc<AtomicU64>,
+    }
+    impl AsyncRead for CountingFile {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let before = buf.filled().len();
+            let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+            let read = (buf.filled().len() - before) as u64;
+            self.read.fetch_add(read, Ordering::Relaxed);
+            result
+        }
+    }
+    impl AsyncWrite for CountingFile {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.inner).poll_write(cx, buf)
+        }
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+    impl AsyncSeek for CountingFile {
+        fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+            Pin::new(&mut self.inner).start_seek(position)
+        }
+        fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+            Pin::new(&mut self.inner).poll_complete(cx)
+        }
+    }
+
+    #[tokio::test]
+    async fn mirrors_the_sync_api() {
+        let c = AsyncCogtainer::create(Cursor::new(vec![])).await.unwrap();
+        for i in 0..10 {
+            c.insert_block(&id(i), rmpv::Value::from(i), vec![i as u8; 1000])
+                .await
+                .unwrap();
+        }
+        for i in 0..5 {
+            c.delete_block(&id(i)).await.unwrap();
+        }
+        c.flush().await.unwrap();
+        c.defragment().await.unwrap();
+        assert!(c.inner.lock().await.container.footer.empty_space.is_empty());
+
+        let (metadata, data) = c.get_block(&id(7)).await.unwrap();
+        assert_eq!((metadata, data), (rmpv::Value::from(7), vec![7; 1000]));
+        assert!(c.get_block(&id(1)).await.is_err());
+
+        // the sync API opens the same file
+        let buf = c.try_unwrap().unwrap().into_inner();
+        let reopened = AsyncCogtainer::open(Cursor::new(buf.clone()))
+            .await
+            .unwrap();
+        assert_eq!(reopened.get_block(&id(9)).await.unwrap().1, vec![9; 1000]);
+        let sync = Cogtainer::open(Cursor::new(buf)).unwrap();
+        assert_eq!(sync.get_blocks_list().unwrap().len(), 5);
+        assert!(sync.verify().is_clean());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrent_readers() {
+        let c = AsyncCogtainer::create(Cursor::new(vec![])).await.unwrap();
+        for i in 0..20 {
+            c.insert_block(&id(i), rmpv::Value::Nil, vec![i as u8; 5000])
+                .await
+                .unwrap();
+        }
+        let readers: Vec<_> = (0..20)
+            .map(|i| {
+                let c = c.clone();
+                tokio::spawn(async move { c.get_block(&id(i)).await.unwrap().1 })
+            })
+            .collect();
+        for (i, reader) in readers.into_iter().enumerate() {
+            assert_eq!(reader.await.unwrap(), vec![i as u8; 5000]);
+        }
+    }
+
+    #[tokio::test]
+    async fn internal_file() {
+        let c = AsyncCogtainer::create(Cursor::new(vec![])).await.unwrap();
+        c.insert_block(&id(1), rmpv::Value::from("meta"), b"hello world".to_vec())
+            .await
+            .unwrap();
+
+        let mut file = c.get_block_as_file(&id(1));
+        file.seek(SeekFrom::Start(6)).await.unwrap();
+        file.write_all(b"there, async world").await.unwrap();
+        file.flush().await.unwrap();
+        assert_eq!(file.seek(SeekFrom::Current(0)).await.unwrap(), 24);
+
+        file.seek(SeekFrom::End(-11)).await.unwrap();
+        let mut tail = String::new();
+        file.read_to_string(&mut tail).await.unwrap();
+        assert_eq!(tail, "async world");
+
+        file.rewind().await.unwrap();
+        let mut all = vec![];
+        file.read_to_end(&mut all).await.unwrap();
+        assert_eq!(all, b"hello there, async world");
+
+        let (metadata, data) = c.get_block(&id(1)).await.unwrap();
+        assert_eq!(metadata, rmpv::Value::from("meta"));
+        assert_eq!(data, b"hello there, async world");
+
+        // writing to a new block creates it
+        let mut file = c.get_block_as_file(&id(2));
+        file.write_all(b"new").await.unwrap();
+        file.shutdown().await.unwrap();
+        assert_eq!(c.get_block(&id(2)).await.unwrap().1, b"new");
+    }
+
+    #[tokio::test]
+    async fn only_the_needed_parts_are_read() {
+        let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+        c.set_index_page_size(8).unwrap();
+        for i in 0..64 {
+            c.insert_block(&id(i), rmpv::Value::from(i), &vec![i as u8; 100_000])
+                .unwrap();
+        }
+        let buf = c.into_inner().unwrap().into_inner();
+        let len = buf.len() as u64;
+
+        let read = Arc::new(AtomicU64::new(0));
+        let file = CountingFile {
+            inner: Cursor::new(buf),
+            read: read.clone(),
+        };
+        let c = AsyncCogtainer::open(file).await.unwrap();
+        let (metadata, data) = c.get_block(&id(40)).await.unwrap();
+        assert_eq!((metadata, data), (rmpv::Value::from(40), vec![40; 100_000]));
+        assert!(read.load(Ordering::Relaxed) < len / 10);
+
+        // changes that need an index page that hasn't been read are run again once it is
+        c.insert_block(&id(3), rmpv::Value::Nil, b"replaced".to_vec())
+            .await
+            .unwrap();
+        c.delete_block(&id(50)).await.unwrap();
+        c.flush().await.unwrap();
+        assert!(read.load(Ordering::Relaxed) < len / 2);
+
+        let buf = c.try_unwrap().unwrap().inner.into_inner();
+        let sync = Cogtainer::open(Cursor::new(buf)).unwrap();
+        assert_eq!(sync.get_block(&id(3)).unwrap().1, b"replaced");
+        assert!(sync.get_block(&id(50)).is_err());
+        assert_eq!(sync.get_block(&id(63)).unwrap().1, vec![63; 100_000]);
+        assert!(sync.verify().is_clean());
+    }
+
+    #[tokio::test]
+    async fn tokio_files() {
+        let path = std::env::temp_dir().join(format!("cogtainer-async-{}", std::process::id()));
+        let file = tokio::fs::File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .await
+            .unwrap();
+        let c = AsyncCogtainer::create(file).await.unwrap();
+        for i in 0..5 {
+            c.insert_block(&id(i), rmpv::Value::Nil, vec![i as u8; 70_000])
+                .await
+                .unwrap();
+        }
+        c.delete_block(&id(1)).await.unwrap();
+        c.flush().await.unwrap();
+        drop(c);
+
+        let file = tokio::fs::File::options()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .await
+            .unwrap();
+        let c = AsyncCogtainer::open(file).await.unwrap();
+        c.defragment().await.unwrap();
+        assert_eq!(c.get_block(&id(4)).await.unwrap().1, vec![4; 70_000]);
+        drop(c);
+
+        let sync = Cogtainer::open(std::fs::File::open(&path).unwrap()).unwrap();
+        assert_eq!(sync.get_blocks_list().unwrap().len(), 4);
+        assert!(sync.verify().is_clean());
+        drop(sync);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,114 @@
+#[cfg(test)]
+mod block_reader_tests {
+    use crate::{basic_api::Cogtainer, container_file::*, error::CogtainerError};
+
+    use std::io::{BufRead, Cursor, ErrorKind, Read, Seek, SeekFrom, Write};
+
+    fn id(i: u64) -> Identifier {
+        Identifier::U64(i)
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn container_with(data: &[u8]) -> Cogtainer<Cursor<Vec<u8>>> {
+        let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+        c.set_inline_block_headers(true);
+        c.insert_block(&id(0), rmpv::Value::Nil, b"other").unwrap();
+        c.insert_block(&id(1), rmpv::Value::Nil, data).unwrap();
+        c
+    }
+
+    fn corrupt(c: &mut Cogtainer<Cursor<Vec<u8>>>, position: u64) {
+        let offset = c.get_blocks_list().unwrap()[&id(1)].data_offset();
+        c.file.seek(SeekFrom::Start(offset.0 + position)).unwrap();
+        c.file.write_all(&[0xFF]).unwrap();
+    }
+
+    fn assert_checksum_error(err: std::io::Error) {
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        let inner = err.into_inner().unwrap();
+        assert!(matches!(
+            inner.downcast_ref::<CogtainerError>(),
+            Some(CogtainerError::BlockChecksumError(_))
+        ));
+    }
+
+    #[test]
+    fn reads_a_block_in_pieces() {
+        let data = pattern(200_000);
+        let c = container_with(&data);
+        let mut r = c.block_reader(&id(1)).unwrap();
+        assert_eq!(r.len(), data.len() as u64);
+
+        let mut read = vec![];
+        let mut chunk = [0u8; 999];
+        loop {
+            let len = r.read(&mut chunk).unwrap();
+            if len == 0 {
+                break;
+            }
+            read.extend_from_slice(&chunk[..len]);
+        }
+        assert_eq!(read, data);
+
+        let mut r = c.block_reader(&id(0)).unwrap();
+        let mut line = String::new();
+        r.read_line(&mut line).unwrap();
+        assert_eq!(line, "other");
+        assert!(c.block_reader(&id(2)).is_err());
+    }
+
+    #[test]
+    fn corrupt_block_errors_at_the_end() {
+        let data = pattern(200_000);
+        let mut c = container_with(&data);
+        corrupt(&mut c, 150_000);
+
+        let mut r = c.block_reader(&id(1)).unwrap();
+        // everything before the end can still be read
+        let mut start = vec![0u8; 199_999];
+        r.read_exact(&mut start).unwrap();
+        let mut rest = vec![];
+        assert_checksum_error(r.read_to_end(&mut rest).unwrap_err());
+    }
+
+    #[test]
+    fn seeking_still_verifies_once_everything_was_read() {
+        let data = pattern(100_000);
+        let mut c = container_with(&data);
+        corrupt(&mut c, 10);
+
+        let mut r = c.block_reader(&id(1)).unwrap();
+        r.seek(SeekFrom::Start(50_000)).unwrap();
+        let mut end = vec![];
+        // the start was skipped, so the block can't be verified
+        r.read_to_end(&mut end).unwrap();
+        assert_eq!(end, data[50_000..]);
+
+        r.seek(SeekFrom::Start(0)).unwrap();
+        let mut all = vec![];
+        assert_checksum_error(r.read_to_end(&mut all).unwrap_err());
+
+        // seeking back within what was already read doesn't hash bytes twice
+        let c = container_with(&data);
+        let mut r = c.block_reader(&id(1)).unwrap();
+        let mut buf = vec![0u8; 1000];
+        r.read_exact(&mut buf).unwrap();
+        r.seek(SeekFrom::Current(-500)).unwrap();
+        let mut all = vec![];
+        r.read_to_end(&mut all).unwrap();
+        assert_eq!(all, data[500..]);
+        assert_eq!(r.seek(SeekFrom::End(-1)).unwrap(), 99_999);
+        assert!(r.seek(SeekFrom::Current(-100_000)).is_err());
+    }
+
+    #[test]
+    fn empty_block() {
+        let c = container_with(b"");
+        let mut r = c.block_reader(&id(1)).unwrap();
+        let mut buf = vec![];
+        assert_eq!(r.read_to_end(&mut buf).unwrap(), 0);
+    }
+}
@@ -0,0 +1,123 @@
+#[cfg(test)]
+mod block_writer_tests {
+    use crate::{basic_api::Cogtainer, container_file::*};
+
+    use std::io::{Cursor, Seek, SeekFrom, Write};
+
+    fn id(i: u64) -> Identifier {
+        Identifier::U64(i)
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn streams_a_large_block() {
+        let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+        c.insert_block(&id(0), rmpv::Value::Nil, b"before").unwrap();
+        let data = pattern(1024 * 1024 + 17);
+
+        let mut w = c.block_writer(&id(1), rmpv::Value::from("meta")).unwrap();
+        for chunk in data.chunks(1000) {
+            w.write_all(chunk).unwrap();
+        }
+        assert_eq!(w.len(), data.len() as u64);
+        w.finish().unwrap();
+
+        let descriptor = c.get_blocks_list().unwrap()[&id(1)].clone();
+        assert_eq!(descriptor.used_length, data.len() as u64);
+        assert_eq!(descriptor.allocated_length, data.len() as u64);
+        let (metadata, read) = c.get_block(&id(1)).unwrap();
+        assert_eq!(metadata, &rmpv::Value::from("meta"));
+        assert_eq!(read, data);
+        assert!(c.verify().is_clean());
+
+        let buf = c.into_inner().unwrap().into_inner();
+        let c = Cogtainer::open(Cursor::new(buf)).unwrap();
+        assert_eq!(c.get_block(&id(1)).unwrap().1, data);
+        assert_eq!(c.get_block(&id(0)).unwrap().1, b"before");
+    }
+
+    #[test]
+    fn moves_out_of_a_hole_that_is_too_small() {
+        let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+        c.insert_block(&id(0), rmpv::Value::Nil, &[0; 5000])
+            .unwrap();
+        c.insert_block(&id(1), rmpv::Value::Nil, b"after").unwrap();
+        c.delete_block(&id(0)).unwrap();
+        c.flush().unwrap();
+        let hole = c.footer.empty_space.clone();
+
+        let data = pattern(20_000);
+        let mut w = c.block_writer(&id(2), rmpv::Value::Nil).unwrap();
+        w.write_all(&data[..3000]).unwrap();
+        w.write_all(&data[3000..]).unwrap();
+        w.finish().unwrap();
+
+        assert!(
+            c.get_blocks_list().unwrap()[&id(2)].file_offset
+                > c.get_blocks_list().unwrap()[&id(1)].file_offset
+        );
+        assert_eq!(c.footer.empty_space, hole);
+        assert_eq!(c.get_block(&id(2)).unwrap().1, data);
+        assert!(c.verify().is_clean());
+    }
+
+    #[test]
+    fn seeking_back_and_overwriting() {
+        let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+        let mut w = c.block_writer(&id(1), rmpv::Value::Nil).unwrap();
+        w.write_all(b"hello world").unwrap();
+        assert_eq!(w.seek(SeekFrom::Start(6)).unwrap(), 6);
+        w.write_all(b"there, friend").unwrap();
+        w.seek(SeekFrom::End(-6)).unwrap();
+        w.write_all(b"F").unwrap();
+        assert!(w.seek(SeekFrom::End(1)).is_err());
+        assert!(w.seek(SeekFrom::Current(-100)).is_err());
+        w.finish().unwrap();
+
+        assert_eq!(c.get_block(&id(1)).unwrap().1, b"hello there, Friend");
+        assert!(c.verify().is_clean());
+    }
+
+    #[test]
+    fn dropped_writer_changes_nothing() {
+        let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+        c.insert_block(&id(1), rmpv::Value::Nil, b"original")
+            .unwrap();
+        let data_end = c.header.footer_offset;
+
+        let mut w = c.block_writer(&id(1), rmpv::Value::Nil).unwrap();
+        w.write_all(&pattern(10_000)).unwrap();
+        drop(w);
+
+        assert_eq!(c.header.footer_offset, data_end);
+        assert_eq!(c.get_block(&id(1)).unwrap().1, b"original");
+        assert!(c.verify().is_clean());
+    }
+
+    #[test]
+    fn replaces_existing_block() {
+        let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+        c.set_inline_block_headers(true);
+        c.insert_block(&id(1), rmpv::Value::Nil, &[1; 100]).unwrap();
+        c.insert_block(&id(2), rmpv::Value::Nil, &[2; 100]).unwrap();
+
+        let mut w = c.block_writer(&id(1), rmpv::Value::Nil).unwrap();
+        w.write_all(&[3; 300]).unwrap();
+        w.finish().unwrap();
+        let mut w = c.block_writer(&id(2), rmpv::Value::Nil).unwrap();
+        w.write_all(b"").unwrap();
+        w.finish().unwrap();
+
+        assert_eq!(c.get_block(&id(1)).unwrap().1, [3; 300]);
+        assert_eq!(c.get_block(&id(2)).unwrap().1, b"");
+        assert!(c.verify().is_clean());
+
+        // the inline header was written
+        let buf = c.into_inner().unwrap().into_inner();
+        let c = Cogtainer::salvage(Cursor::new(buf)).unwrap();
+        assert_eq!(c.get_block(&id(1)).unwrap().1, [3; 300]);
+    }
+}
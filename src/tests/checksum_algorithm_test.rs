@@ -0,0 +1,116 @@
+#[cfg(test)]
+mod checksum_algorithm_tests {
+    use crate::{
+        basic_api::Cogtainer,
+        container_file::*,
+        error::{CogtainerError, HeaderError},
+    };
+
+    use std::io::{Cursor, Seek, SeekFrom, Write};
+
+    fn id(i: u64) -> Identifier {
+        Identifier::U64(i)
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn every_algorithm_round_trips() {
+        let big = pattern(CHUNK_SIZE as usize + 10);
+        for algorithm in ChecksumAlgorithm::ALL {
+            let mut c = Cogtainer::create_with_checksum(Cursor::new(vec![]), *algorithm).unwrap();
+            c.insert_block(&id(1), rmpv::Value::from(1), b"hello")
+                .unwrap();
+            c.set_checksum_granularity(ChecksumGranularity::Chunks);
+            c.insert_block(&id(2), rmpv::Value::Nil, &big).unwrap();
+            let mut w = c.block_writer(&id(3), rmpv::Value::Nil).unwrap();
+            w.write_all(&big).unwrap();
+            w.finish().unwrap();
+            {
+                let mut f = c.get_block_as_file(&id(1));
+                f.seek(SeekFrom::End(0)).unwrap();
+                f.write_all(b" world").unwrap();
+            }
+
+            let buf = c.into_inner().unwrap().into_inner();
+            let mut c = Cogtainer::open(Cursor::new(buf)).unwrap();
+            assert_eq!(c.header.checksum_algorithm, *algorithm);
+            assert_eq!(c.get_block(&id(1)).unwrap().1, b"hello world");
+            assert_eq!(c.get_block(&id(2)).unwrap().1, big);
+            assert_eq!(c.get_block(&id(3)).unwrap().1, big);
+            assert!(c.verify().is_clean(), "{algorithm:?}");
+
+            // corruption is still caught
+            let offset = c.get_blocks_list().unwrap()[&id(1)].data_offset();
+            c.file.seek(SeekFrom::Start(offset.0)).unwrap();
+            c.file.write_all(b"J").unwrap();
+            assert!(matches!(
+                c.get_block(&id(1)),
+                Err(CogtainerError::BlockChecksumError(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn default_containers_keep_the_v1_format() {
+        let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+        c.insert_block(&id(1), rmpv::Value::Nil, b"data").unwrap();
+        assert_eq!(c.header.checksum_algorithm, ChecksumAlgorithm::XxHash64);
+        assert_eq!(
+            c.get_blocks_list().unwrap()[&id(1)].checksum,
+            Checksum(twox_hash::XxHash64::oneshot(4321, b"data"))
+        );
+
+        let header = c.header.committed_footer();
+        let buf = c.into_inner().unwrap().into_inner();
+        assert_eq!(buf[61], 0);
+        let footer = &buf[header.offset.0 as usize..(header.offset.0 + header.length) as usize];
+        assert_eq!(
+            header.checksum,
+            Checksum(twox_hash::XxHash64::oneshot(4321, footer))
+        );
+    }
+
+    #[test]
+    fn unknown_algorithms_are_rejected() {
+        let c = Cogtainer::create_with_checksum(Cursor::new(vec![]), ChecksumAlgorithm::XxHash3_64)
+            .unwrap();
+        let mut buf = c.into_inner().unwrap().into_inner();
+        assert_eq!(buf[61], 1);
+        // only the header, so there is no trailer to fall back to
+        buf.truncate(ContainerHeader::HEADER_SIZE);
+        buf[61] = 200;
+        // a version 1 header without a checksum
+        buf[4..12].copy_from_slice(&1u64.to_le_bytes());
+        buf[64..68].fill(0);
+        assert!(matches!(
+            ContainerHeader::read_from(&Cursor::new(buf)),
+            Err(CogtainerError::InvalidHeader(
+                HeaderError::ChecksumAlgorithm(200)
+            ))
+        ));
+    }
+
+    #[test]
+    fn salvage_detects_the_algorithm() {
+        let algorithm = *ChecksumAlgorithm::ALL.last().unwrap();
+        let mut c = Cogtainer::create_with_checksum(Cursor::new(vec![]), algorithm).unwrap();
+        c.set_inline_block_headers(true);
+        c.insert_block(&id(1), rmpv::Value::Nil, b"salvaged")
+            .unwrap();
+        let data_end = c.header.footer_offset.0 as usize;
+        let mut buf = c.into_inner().unwrap().into_inner();
+        buf[..ContainerHeader::HEADER_SIZE].fill(0);
+        buf[data_end..].fill(0);
+
+        let c = Cogtainer::salvage(Cursor::new(buf)).unwrap();
+        assert_eq!(c.header.checksum_algorithm, algorithm);
+        assert_eq!(c.get_block(&id(1)).unwrap().1, b"salvaged");
+
+        let buf = c.into_inner().unwrap().into_inner();
+        let c = Cogtainer::open(Cursor::new(buf)).unwrap();
+        assert_eq!(c.get_block(&id(1)).unwrap().1, b"salvaged");
+    }
+}
@@ -0,0 +1,197 @@
+#[cfg(test)]
+mod chunk_checksum_tests {
+    use crate::{basic_api::Cogtainer, container_file::*, error::CogtainerError};
+
+    use std::io::{Cursor, ErrorKind, Read, Seek, SeekFrom, Write};
+
+    fn id(i: u64) -> Identifier {
+        Identifier::U64(i)
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn chunked_container(data: &[u8]) -> Cogtainer<Cursor<Vec<u8>>> {
+        let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+        c.set_checksum_granularity(ChecksumGranularity::Chunks);
+        c.insert_block(&id(1), rmpv::Value::Nil, data).unwrap();
+        c
+    }
+
+    fn corrupt(c: &mut Cogtainer<Cursor<Vec<u8>>>, position: u64) {
+        let offset = c.get_blocks_list().unwrap()[&id(1)].data_offset();
+        c.file.seek(SeekFrom::Start(offset.0 + position)).unwrap();
+        c.file.write_all(&[0xFF]).unwrap();
+    }
+
+    #[test]
+    fn chunked_blocks_round_trip() {
+        let data = pattern(3 * CHUNK_SIZE as usize + 100);
+        let c = chunked_container(&data);
+        let descriptor = &c.get_blocks_list().unwrap()[&id(1)];
+        assert!(descriptor.is_chunked());
+        assert_eq!(descriptor.chunk_checksums.len(), 4);
+
+        let buf = c.into_inner().unwrap().into_inner();
+        let c = Cogtainer::open(Cursor::new(buf)).unwrap();
+        assert!(c.get_blocks_list().unwrap()[&id(1)].is_chunked());
+        assert_eq!(c.get_block(&id(1)).unwrap().1, data);
+        assert!(c.verify().is_clean());
+
+        // the default stays one checksum per block
+        let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+        c.insert_block(&id(1), rmpv::Value::Nil, &data).unwrap();
+        assert!(!c.get_blocks_list().unwrap()[&id(1)].is_chunked());
+    }
+
+    #[test]
+    fn ranged_reads_only_verify_touched_chunks() {
+        let data = pattern(3 * CHUNK_SIZE as usize);
+        let mut c = chunked_container(&data);
+        corrupt(&mut c, 2 * CHUNK_SIZE + 5);
+
+        let mut buf = vec![0u8; 1000];
+        let start = CHUNK_SIZE - 500;
+        let read = c
+            .footer
+            .get_block_slice(&c.file, &id(1), start, &mut buf)
+            .unwrap();
+        assert_eq!(read, 1000);
+        assert_eq!(buf, data[start as usize..start as usize + 1000]);
+
+        let err = c
+            .footer
+            .get_block_slice(&c.file, &id(1), 2 * CHUNK_SIZE, &mut buf)
+            .unwrap_err();
+        assert!(matches!(err, CogtainerError::BlockChecksumError(_)));
+
+        let mut f = c.get_block_as_file(&id(1));
+        f.seek(SeekFrom::Start(10)).unwrap();
+        f.read_exact(&mut buf).unwrap();
+        f.seek(SeekFrom::Start(2 * CHUNK_SIZE)).unwrap();
+        assert!(f.read(&mut buf).is_err());
+        drop(f);
+
+        assert!(c.get_block(&id(1)).is_err());
+        assert!(!c.verify().is_clean());
+    }
+
+    #[test]
+    fn block_reader_verifies_each_chunk() {
+        let data = pattern(2 * CHUNK_SIZE as usize + 10);
+        let mut c = chunked_container(&data);
+        corrupt(&mut c, CHUNK_SIZE + 1);
+
+        let mut r = c.block_reader(&id(1)).unwrap();
+        r.seek(SeekFrom::Start(2 * CHUNK_SIZE)).unwrap();
+        let mut tail = vec![];
+        r.read_to_end(&mut tail).unwrap();
+        assert_eq!(tail, data[2 * CHUNK_SIZE as usize..]);
+
+        r.seek(SeekFrom::Start(CHUNK_SIZE + 1000)).unwrap();
+        let err = r.read(&mut [0u8; 10]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn in_place_writes_keep_chunks_valid() {
+        let data = pattern(3 * CHUNK_SIZE as usize);
+        let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+        c.set_checksum_granularity(ChecksumGranularity::Chunks);
+        c.set_overallocation_policy(OverallocationPolicy::Bytes(CHUNK_SIZE));
+        c.insert_block(&id(1), rmpv::Value::Nil, &data).unwrap();
+        let before = c.get_blocks_list().unwrap()[&id(1)].chunk_checksums.clone();
+
+        let mut expected = data.clone();
+        {
+            let mut f = c.get_block_as_file(&id(1));
+            f.seek(SeekFrom::Start(2 * CHUNK_SIZE + 7)).unwrap();
+            f.write_all(b"changed").unwrap();
+            f.seek(SeekFrom::End(0)).unwrap();
+            f.write_all(b"appended").unwrap();
+        }
+        expected[2 * CHUNK_SIZE as usize + 7..2 * CHUNK_SIZE as usize + 14]
+            .copy_from_slice(b"changed");
+        expected.extend_from_slice(b"appended");
+
+        let descriptor = &c.get_blocks_list().unwrap()[&id(1)];
+        assert_eq!(descriptor.chunk_checksums.len(), 4);
+        assert_eq!(descriptor.chunk_checksums[..2], before[..2]);
+        assert_ne!(descriptor.chunk_checksums[2], before[2]);
+        assert_eq!(c.get_block(&id(1)).unwrap().1, expected);
+        assert!(c.verify().is_clean());
+    }
+
+    #[test]
+    fn small_in_place_writes_only_rehash_their_chunks() {
+        let data = pattern(4 * CHUNK_SIZE as usize);
+        let mut c = chunked_container(&data);
+        let before = c.get_blocks_list().unwrap()[&id(1)].chunk_checksums.clone();
+        // a chunk that is rehashed would take on the corruption
+        corrupt(&mut c, 3 * CHUNK_SIZE + 5);
+
+        {
+            let mut f = c.get_block_as_file(&id(1));
+            f.seek(SeekFrom::Start(CHUNK_SIZE - 2)).unwrap();
+            f.write_all(b"span").unwrap();
+        }
+
+        let after = &c.get_blocks_list().unwrap()[&id(1)].chunk_checksums;
+        assert_eq!(after.len(), 4);
+        assert_eq!(after[2..], before[2..]);
+        assert_ne!(after[0], before[0]);
+        assert_ne!(after[1], before[1]);
+        assert!(c.get_block(&id(1)).is_err());
+        let mut buf = vec![0u8; 10];
+        c.footer
+            .get_block_slice(&c.file, &id(1), CHUNK_SIZE - 2, &mut buf)
+            .unwrap();
+        assert_eq!(&buf[..4], b"span");
+    }
+
+    #[test]
+    fn block_writer_writes_chunk_checksums() {
+        let data = pattern(2 * CHUNK_SIZE as usize + 3);
+        let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+        c.set_checksum_granularity(ChecksumGranularity::Chunks);
+
+        let mut w = c.block_writer(&id(1), rmpv::Value::Nil).unwrap();
+        for piece in data.chunks(10_000) {
+            w.write_all(piece).unwrap();
+        }
+        w.finish().unwrap();
+
+        // overwriting earlier data rehashes on finish
+        let mut w = c.block_writer(&id(2), rmpv::Value::Nil).unwrap();
+        w.write_all(&data).unwrap();
+        w.seek(SeekFrom::Start(5)).unwrap();
+        w.write_all(b"x").unwrap();
+        w.finish().unwrap();
+
+        assert_eq!(
+            c.get_blocks_list().unwrap()[&id(1)].chunk_checksums.len(),
+            3
+        );
+        assert_eq!(c.get_block(&id(1)).unwrap().1, data);
+        assert_eq!(c.get_block(&id(2)).unwrap().1[5], b'x');
+        assert!(c.verify().is_clean());
+    }
+
+    #[test]
+    fn salvage_finds_chunked_blocks() {
+        let data = pattern(CHUNK_SIZE as usize + 10);
+        let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+        c.set_inline_block_headers(true);
+        c.set_checksum_granularity(ChecksumGranularity::Chunks);
+        c.insert_block(&id(1), rmpv::Value::Nil, &data).unwrap();
+        let data_end = c.header.footer_offset.0 as usize;
+        let mut buf = c.into_inner().unwrap().into_inner();
+        buf[..ContainerHeader::HEADER_SIZE].fill(0);
+        buf[data_end..].fill(0);
+
+        let c = Cogtainer::salvage(Cursor::new(buf)).unwrap();
+        assert!(c.get_blocks_list().unwrap()[&id(1)].is_chunked());
+        assert_eq!(c.get_block(&id(1)).unwrap().1, data);
+    }
+}
@@ -0,0 +1,225 @@
+#[cfg(test)]
+mod commit_tests {
+    use crate::{
+        basic_api::Cogtainer, container_file::*, error::CogtainerError, tests::CrashingFile,
+    };
+
+    use std::io::{Cursor, Seek, SeekFrom, Write};
+
+    fn open_new_container() -> Cogtainer<Cursor<Vec<u8>>> {
+        let file = Cursor::new(vec![0u8; 64 * 1024]);
+        Cogtainer::create(file).unwrap()
+    }
+
+    fn corrupt_committed_footer(c: &mut Cogtainer<Cursor<Vec<u8>>>) {
+        let committed = c.header.committed_footer();
+        c.file.seek(SeekFrom::Start(committed.offset.0)).unwrap();
+        c.file
+            .write_all(&vec![0xFF; committed.length as usize])
+            .unwrap();
+    }
+
+    #[test]
+    fn generation_increments_on_every_commit() {
+        let mut c = open_new_container();
+        let start = c.header.generation;
+        c.insert_block(&Identifier::U64(1), rmpv::Value::Nil, b"abc")
+            .unwrap();
+        let after_insert = c.header.generation;
+        assert!(after_insert > start);
+        c.flush().unwrap();
+        assert!(c.header.generation > after_insert);
+
+        let header = ContainerHeader::read_from(&c.file).unwrap();
+        assert_eq!(header.generation, c.header.generation);
+    }
+
+    #[test]
+    fn footer_is_never_written_over_the_committed_footer() {
+        let mut c = open_new_container();
+        for i in 0..10 {
+            let before = c.header.committed_footer();
+            c.insert_block(&Identifier::U64(i), rmpv::Value::Nil, &[i as u8; 3])
+                .unwrap();
+            // the footer committed before the insert was relocated, and the copy is still intact
+            let mut header = ContainerHeader::read_from(&c.file).unwrap();
+            assert_ne!(header.previous_footer, before);
+            header.roll_back_to_previous();
+            ContainerFooter::read_from(&c.file, &header).unwrap();
+        }
+        // appended blocks still end up packed, with the footer right after them
+        assert!(c.footer.empty_space.is_empty());
+        assert_eq!(c.header.footer_offset, c.footer.data_end());
+        assert_eq!(c.header.committed_footer().offset, c.header.footer_offset);
+    }
+
+    #[test]
+    fn open_falls_back_to_previous_footer() {
+        let mut c = open_new_container();
+        let a = Identifier::String("A".into());
+        let b = Identifier::String("B".into());
+        c.insert_block(&a, rmpv::Value::from(1), b"first").unwrap();
+        c.insert_block(&b, rmpv::Value::from(2), b"second").unwrap();
+        corrupt_committed_footer(&mut c);
+
+        let buf = c.into_inner().unwrap().into_inner();
+        let mut c2 = Cogtainer::open(Cursor::new(buf)).unwrap();
+        // the footer from before B was inserted
+        assert_eq!(c2.get_block(&a).unwrap().1, b"first");
+        assert!(matches!(
+            c2.get_block(&b),
+            Err(CogtainerError::BlockNotFound(_))
+        ));
+        // the container is usable after falling back
+        c2.insert_block(&b, rmpv::Value::from(2), b"again").unwrap();
+        let buf = c2.into_inner().unwrap().into_inner();
+        let c3 = Cogtainer::open(Cursor::new(buf)).unwrap();
+        assert_eq!(c3.get_block(&a).unwrap().1, b"first");
+        assert_eq!(c3.get_block(&b).unwrap().1, b"again");
+    }
+
+    #[test]
+    fn open_fails_when_both_footers_are_damaged() {
+        let mut c = open_new_container();
+        c.insert_block(&Identifier::U64(1), rmpv::Value::Nil, b"x")
+            .unwrap();
+        let previous = c.header.previous_footer;
+        corrupt_committed_footer(&mut c);
+        c.file.seek(SeekFrom::Start(previous.offset.0)).unwrap();
+        c.file
+            .write_all(&vec![0xFF; previous.length as usize])
+            .unwrap();
+
+        let buf = c.into_inner().unwrap().into_inner();
+        let result = Cogtainer::open(Cursor::new(buf));
+        assert!(matches!(result, Err(CogtainerError::FooterChecksumError)));
+    }
+
+    #[test]
+    fn crash_at_any_write_leaves_an_openable_container() {
+        // Build a container with a few blocks, then replay one more operation, crashing after
+        // every possible number of writes. Whatever got written, the file must open and hold
+        // either the state before or the state after the operation.
+        let mut base = open_new_container();
+        for i in 0..4 {
+            base.insert_block(&Identifier::U64(i), rmpv::Value::from(i), &[i as u8; 20])
+                .unwrap();
+        }
+        base.delete_block(&Identifier::U64(1)).unwrap();
+        base.flush().unwrap();
+        let base_bytes = base.into_inner().unwrap().into_inner();
+
+        for writes_left in 0.. {
+            let file = CrashingFile {
+                inner: Cursor::new(base_bytes.clone()),
+                writes_left,
+            };
+            let mut c = Cogtainer::open(file).unwrap();
+            let result = c
+                .insert_block(&Identifier::U64(9), rmpv::Value::Nil, &[9u8; 100])
+                .map(|_| ());
+            let finished = result.is_ok();
+
+            let buf = c.into_inner().unwrap().inner.into_inner();
+            let reopened = Cogtainer::open(Cursor::new(buf))
+                .unwrap_or_else(|e| panic!("crash after {writes_left} writes: {e}"));
+            for i in [0u64, 2, 3] {
+                let (_, data) = reopened.get_block(&Identifier::U64(i)).unwrap();
+                assert_eq!(data, vec![i as u8; 20]);
+            }
+            match reopened.get_block(&Identifier::U64(9)) {
+                Ok((_, data)) => assert_eq!(data, vec![9u8; 100]),
+                Err(CogtainerError::BlockNotFound(_)) => assert!(!finished),
+                Err(e) => panic!("crash after {writes_left} writes: {e}"),
+            }
+            if finished {
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn failed_commit_leaves_the_header_as_it_was() {
+        let mut c = Cogtainer::create(CrashingFile {
+            inner: Cursor::new(vec![]),
+            writes_left: usize::MAX,
+        })
+        .unwrap();
+        c.set_durability_mode(DurabilityMode::WriteBack { flush_every: None });
+        c.insert_block(&Identifier::U64(1), rmpv::Value::Nil, &[1u8; 100])
+            .unwrap()
+            .flush()
+            .unwrap();
+        c.insert_block(&Identifier::U64(2), rmpv::Value::Nil, &[2u8; 100])
+            .unwrap();
+        let before = c.header.clone();
+
+        for writes_left in 0..3 {
+            c.file.writes_left = writes_left;
+            assert!(c.flush().is_err());
+            assert_eq!(c.header.generation, before.generation);
+            assert_eq!(c.header.committed_footer(), before.committed_footer());
+            assert_eq!(c.header.previous_footer, before.previous_footer);
+        }
+
+        // the retry keeps the fallback chain pointing at footers that were written
+        c.file.writes_left = usize::MAX;
+        c.flush().unwrap();
+        assert_eq!(c.header.generation, before.generation.wrapping_add(1));
+        assert_eq!(c.header.previous_footer, before.committed_footer());
+        let buf = c.into_inner().unwrap().inner.into_inner();
+        let reopened = Cogtainer::open(Cursor::new(buf)).unwrap();
+        assert_eq!(
+            reopened.get_block(&Identifier::U64(2)).unwrap().1,
+            vec![2u8; 100]
+        );
+        assert!(reopened.verify().is_clean());
+    }
+
+    #[test]
+    fn crash_while_replacing_a_block_keeps_the_old_data() {
+        // A block replaced by data of the same size fits in its own space, which the committed
+        // footer still points to until the replacement is committed.
+        let mut base = open_new_container();
+        for i in 0..4 {
+            base.insert_block(&Identifier::U64(i), rmpv::Value::from(i), &[i as u8; 20])
+                .unwrap();
+        }
+        let base_bytes = base.into_inner().unwrap().into_inner();
+
+        for mode in [
+            DurabilityMode::WriteThrough,
+            DurabilityMode::WriteBack { flush_every: None },
+        ] {
+            for writes_left in 0.. {
+                let file = CrashingFile {
+                    inner: Cursor::new(base_bytes.clone()),
+                    writes_left,
+                };
+                let mut c = Cogtainer::open(file).unwrap();
+                c.set_durability_mode(mode);
+                let result = c
+                    .insert_block(&Identifier::U64(1), rmpv::Value::Nil, &[0xEE; 20])
+                    .and_then(|c| c.insert_block(&Identifier::U64(2), rmpv::Value::Nil, &[0xEE; 8]))
+                    .and_then(|c| c.flush())
+                    .map(|_| ());
+                let finished = result.is_ok();
+
+                let buf = std::mem::replace(&mut c.file.inner, Cursor::new(vec![])).into_inner();
+                drop(c);
+                let reopened = Cogtainer::open(Cursor::new(buf))
+                    .unwrap_or_else(|e| panic!("{mode:?}, crash after {writes_left} writes: {e}"));
+                for (i, new) in [(1u64, vec![0xEE; 20]), (2, vec![0xEE; 8])] {
+                    let (_, data) = reopened.get_block(&Identifier::U64(i)).unwrap_or_else(|e| {
+                        panic!("{mode:?}, crash after {writes_left} writes: {e}")
+                    });
+                    assert!(data == new || (!finished && data == vec![i as u8; 20]));
+                }
+                assert!(reopened.verify().is_clean());
+                if finished {
+                    break;
+                }
+            }
+        }
+    }
+}
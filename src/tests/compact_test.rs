@@ -0,0 +1,188 @@
+#[cfg(test)]
+mod compact_tests {
+    use crate::{
+        basic_api::Cogtainer, compact::CompactionOrder, container_file::*, error::CogtainerError,
+    };
+
+    use std::io::{Cursor, Seek, SeekFrom, Write};
+
+    fn id(i: u64) -> Identifier {
+        Identifier::U64(i)
+    }
+
+    fn data(i: u64) -> Vec<u8> {
+        vec![i as u8; 50 + 13 * i as usize]
+    }
+
+    /// A fragmented, overallocated container holding blocks 1, 3, 4, 6 and 8 (inserted in
+    /// descending order) and an empty block 10.
+    fn fragmented() -> Cogtainer<Cursor<Vec<u8>>> {
+        let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+        c.set_overallocation_policy(OverallocationPolicy::Bytes(100));
+        c.set_metadata(rmpv::Value::from("container")).unwrap();
+        for i in (0..10).rev() {
+            c.insert_block(&id(i), rmpv::Value::from(i), &data(i))
+                .unwrap();
+        }
+        for i in [0, 2, 5, 7, 9] {
+            c.delete_block(&id(i)).unwrap();
+        }
+        c.insert_block(&id(10), rmpv::Value::from(10), b"").unwrap();
+        c
+    }
+
+    fn offsets_in(c: &Cogtainer<Cursor<Vec<u8>>>, order: &[u64]) -> bool {
+        order.windows(2).all(|pair| {
+            c.get_blocks_list().unwrap()[&id(pair[0])].file_offset
+                < c.get_blocks_list().unwrap()[&id(pair[1])].file_offset
+        })
+    }
+
+    #[test]
+    fn copy_has_no_empty_space() {
+        let c = fragmented();
+        let live = [1, 3, 4, 6, 8];
+        let compacted = c
+            .compact_into(Cursor::new(vec![]), CompactionOrder::default())
+            .unwrap();
+
+        assert!(compacted.footer.empty_space.is_empty());
+        let live_bytes: u64 = live.iter().map(|i| data(*i).len() as u64).sum();
+        assert_eq!(
+            compacted.header.footer_offset.0,
+            ContainerHeader::HEADER_SIZE as u64 + live_bytes
+        );
+        // same layout order as the original
+        assert!(offsets_in(&compacted, &[8, 6, 4, 3, 1]));
+        assert!(compacted.verify().is_clean());
+        // created, the empty footer moved out of the way once, and committed once
+        assert_eq!(compacted.header.generation, 3);
+
+        // nothing is left past the footer
+        let length = compacted.file_length();
+        let buf = compacted.into_inner().unwrap().into_inner();
+        assert_eq!(buf.len() as u64, length);
+        let compacted = Cogtainer::open(Cursor::new(buf)).unwrap();
+        assert_eq!(
+            compacted.get_container_metadata(),
+            &rmpv::Value::from("container")
+        );
+        assert_eq!(compacted.get_blocks_list().unwrap().len(), 6);
+        for i in live {
+            let (metadata, block) = compacted.get_block(&id(i)).unwrap();
+            assert_eq!(metadata, &rmpv::Value::from(i));
+            assert_eq!(block, data(i));
+        }
+        assert_eq!(
+            compacted.get_block(&id(10)).unwrap(),
+            (&rmpv::Value::from(10), vec![])
+        );
+        // the source is unchanged
+        assert!(!c.footer.empty_space.is_empty());
+        assert_eq!(c.get_block(&id(3)).unwrap().1, data(3));
+    }
+
+    #[test]
+    fn orders() {
+        let c = fragmented();
+        let compacted = c
+            .compact_into(Cursor::new(vec![]), CompactionOrder::Identifier)
+            .unwrap();
+        assert!(offsets_in(&compacted, &[1, 3, 4, 6, 8]));
+
+        let order = vec![id(4), id(99), id(1), id(4)];
+        let compacted = c
+            .compact_into(Cursor::new(vec![]), CompactionOrder::Custom(order))
+            .unwrap();
+        // listed blocks first, then the rest in their current order
+        assert!(offsets_in(&compacted, &[4, 1, 8, 6, 3]));
+    }
+
+    #[test]
+    fn settings_are_carried_over() {
+        let algorithm = *ChecksumAlgorithm::ALL.last().unwrap();
+        let mut c = Cogtainer::create_with_checksum(Cursor::new(vec![]), algorithm).unwrap();
+        c.set_inline_block_headers(true);
+        c.set_checksum_granularity(ChecksumGranularity::Chunks);
+        let big = vec![3; CHUNK_SIZE as usize * 2 + 1];
+        c.insert_block(&id(1), rmpv::Value::Nil, &big).unwrap();
+        c.insert_block(&id(2), rmpv::Value::Nil, b"small").unwrap();
+        c.delete_block(&id(2)).unwrap();
+        c.insert_block(&id(3), rmpv::Value::Nil, b"pending")
+            .unwrap();
+
+        let compacted = c
+            .compact_into(Cursor::new(vec![]), CompactionOrder::default())
+            .unwrap();
+        assert_eq!(compacted.header.checksum_algorithm, algorithm);
+        assert!(compacted.header.inline_block_headers());
+        assert_eq!(
+            compacted.get_blocks_list().unwrap()[&id(1)]
+                .chunk_checksums
+                .len(),
+            3
+        );
+
+        // the inline headers are enough to salvage the copy
+        let data_end = compacted.header.footer_offset.0 as usize;
+        let mut buf = compacted.into_inner().unwrap().into_inner();
+        buf[data_end..].fill(0);
+        let salvaged = Cogtainer::salvage(Cursor::new(buf)).unwrap();
+        assert_eq!(salvaged.get_block(&id(1)).unwrap().1, big);
+        assert_eq!(salvaged.get_block(&id(3)).unwrap().1, b"pending");
+    }
+
+    #[test]
+    fn corrupt_blocks_stop_the_copy() {
+        let mut c = fragmented();
+        let offset = c.get_blocks_list().unwrap()[&id(4)].data_offset();
+        c.file.seek(SeekFrom::Start(offset.0)).unwrap();
+        c.file.write_all(b"X").unwrap();
+        let result = c.compact_into(Cursor::new(vec![]), CompactionOrder::default());
+        assert!(matches!(result, Err(CogtainerError::IOError(_))));
+    }
+
+    #[test]
+    fn compact_file_replaces_the_original() {
+        let dir = std::env::temp_dir().join(format!("cogtainer-compact-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("container.cog");
+
+        let file = std::fs::File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        let mut c = Cogtainer::create(file).unwrap();
+        for i in 0..20 {
+            c.insert_block(&id(i), rmpv::Value::Nil, &data(i)).unwrap();
+        }
+        for i in 0..15 {
+            c.delete_block(&id(i)).unwrap();
+        }
+        c.flush().unwrap();
+        let before = std::fs::metadata(&path).unwrap().len();
+
+        let mut c = c.compact_file(&path, CompactionOrder::default()).unwrap();
+        c.insert_block(&id(30), rmpv::Value::Nil, b"after").unwrap();
+        drop(c);
+        assert!(std::fs::metadata(&path).unwrap().len() < before);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        let file = std::fs::File::options()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let c = Cogtainer::open(file).unwrap();
+        assert_eq!(c.get_blocks_list().unwrap().len(), 6);
+        for i in 15..20 {
+            assert_eq!(c.get_block(&id(i)).unwrap().1, data(i));
+        }
+        assert_eq!(c.get_block(&id(30)).unwrap().1, b"after");
+        drop(c);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,218 @@
+#[cfg(test)]
+mod defrag_step_tests {
+    use crate::{basic_api::Cogtainer, container_file::*, tests::CrashingFile};
+
+    use std::{collections::HashMap, io::Cursor};
+
+    fn id(i: u64) -> Identifier {
+        Identifier::U64(i)
+    }
+
+    fn data(i: u64) -> Vec<u8> {
+        vec![i as u8; 100 + 37 * i as usize]
+    }
+
+    /// A container with 20 blocks, every third one deleted.
+    fn fragmented() -> (Cogtainer<Cursor<Vec<u8>>>, Vec<u64>) {
+        let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+        for i in 0..20 {
+            c.insert_block(&id(i), rmpv::Value::from(i), &data(i))
+                .unwrap();
+        }
+        let mut live = vec![];
+        for i in 0..20 {
+            if i % 3 == 0 {
+                c.delete_block(&id(i)).unwrap();
+            } else {
+                live.push(i);
+            }
+        }
+        c.flush().unwrap();
+        (c, live)
+    }
+
+    /// Opens a copy of what is committed to the file and checks every live block.
+    fn assert_committed(c: &mut Cogtainer<Cursor<Vec<u8>>>, live: &[u64]) {
+        let buf = c.get_inner_file().get_ref().clone();
+        let copy = Cogtainer::open(Cursor::new(buf)).unwrap();
+        assert!(copy.verify().is_clean());
+        assert_eq!(copy.get_blocks_list().unwrap().len(), live.len());
+        for i in live {
+            assert_eq!(copy.get_block(&id(*i)).unwrap().1, data(*i));
+        }
+    }
+
+    #[test]
+    fn steps_reach_the_same_result_as_defragment() {
+        let (mut whole, _) = fragmented();
+        whole.defragment().unwrap();
+
+        let (mut c, live) = fragmented();
+        let mut steps = 0;
+        loop {
+            let progress = c.defragment_step(DefragmentBudget::Blocks(2)).unwrap();
+            assert!(progress.blocks_moved <= 2);
+            assert!(!c.footer.is_dirty());
+            assert_committed(&mut c, &live);
+            steps += 1;
+            if progress.done {
+                assert_eq!(progress.empty_space, 0);
+                break;
+            }
+        }
+        assert!(steps > 2, "{steps}");
+        assert_eq!(c.header.footer_offset, whole.header.footer_offset);
+        assert_eq!(c.footer.data_end(), whole.footer.data_end());
+        for i in &live {
+            assert_eq!(
+                c.get_blocks_list().unwrap()[&id(*i)].file_offset,
+                whole.get_blocks_list().unwrap()[&id(*i)].file_offset
+            );
+            assert_eq!(c.get_block(&id(*i)).unwrap().0, &rmpv::Value::from(*i));
+        }
+
+        // nothing left to do
+        let progress = c.defragment_step(DefragmentBudget::Blocks(2)).unwrap();
+        assert_eq!(
+            progress,
+            DefragmentProgress {
+                done: true,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn byte_budget() {
+        let (mut c, live) = fragmented();
+        loop {
+            let progress = c.defragment_step(DefragmentBudget::Bytes(1000)).unwrap();
+            assert!(progress.bytes_moved <= 1000 || progress.blocks_moved == 1);
+            if progress.done {
+                break;
+            }
+            assert!(progress.blocks_moved > 0);
+        }
+        assert!(c.footer.empty_space.is_empty());
+        assert_committed(&mut c, &live);
+
+        // a block larger than the budget still moves
+        let (mut c, _) = fragmented();
+        let progress = c.defragment_step(DefragmentBudget::Bytes(1)).unwrap();
+        assert_eq!(progress.blocks_moved, 1);
+    }
+
+    #[test]
+    fn blocks_slide_into_smaller_holes() {
+        let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+        c.insert_block(&id(0), rmpv::Value::Nil, &data(0)).unwrap();
+        c.insert_block(&id(40), rmpv::Value::Nil, &data(40))
+            .unwrap();
+        let hole = c.get_blocks_list().unwrap()[&id(0)].file_offset;
+        c.delete_block(&id(0)).unwrap();
+        let length = data(40).len() as u64;
+
+        let progress = c.defragment_step(DefragmentBudget::Blocks(1)).unwrap();
+        assert_eq!(progress.blocks_moved, 1);
+        // out of the way, then into place
+        assert_eq!(progress.bytes_moved, 2 * length);
+        assert_eq!(c.get_blocks_list().unwrap()[&id(40)].file_offset, hole);
+        assert_committed(&mut c, &[40]);
+
+        let progress = c.defragment_step(DefragmentBudget::Blocks(1)).unwrap();
+        assert!(progress.done);
+        assert_eq!(c.header.footer_offset, hole.end_offset(length));
+        assert!(c.verify().is_clean());
+    }
+
+    #[test]
+    fn changes_between_steps() {
+        let (mut c, live) = fragmented();
+        let mut expected: HashMap<u64, Vec<u8>> = live.iter().map(|i| (*i, data(*i))).collect();
+        let mut next = 100;
+        loop {
+            let progress = c.defragment_step(DefragmentBudget::Blocks(1)).unwrap();
+            if progress.done {
+                break;
+            }
+            // delete one block and add another
+            let victim = *expected.keys().min().unwrap();
+            c.delete_block(&id(victim)).unwrap();
+            expected.remove(&victim);
+            if next < 105 {
+                c.insert_block(&id(next), rmpv::Value::Nil, &[7; 60])
+                    .unwrap();
+                expected.insert(next, vec![7; 60]);
+                next += 1;
+            }
+        }
+        assert!(c.footer.empty_space.is_empty());
+        assert!(c.verify().is_clean());
+        let buf = c.into_inner().unwrap().into_inner();
+        let c = Cogtainer::open(Cursor::new(buf)).unwrap();
+        assert_eq!(c.get_blocks_list().unwrap().len(), expected.len());
+        for (i, data) in &expected {
+            assert_eq!(&c.get_block(&id(*i)).unwrap().1, data);
+        }
+    }
+
+    #[test]
+    fn alignment_padding_ends_the_steps() {
+        let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+        c.set_block_alignment(4096);
+        c.set_inline_block_headers(true);
+        for i in 0..6 {
+            c.insert_block(&id(i), rmpv::Value::Nil, &data(i)).unwrap();
+        }
+        c.delete_block(&id(1)).unwrap();
+        c.delete_block(&id(4)).unwrap();
+        let mut progress = DefragmentProgress::default();
+        for _ in 0..10 {
+            progress = c.defragment_step(DefragmentBudget::Blocks(1)).unwrap();
+            if progress.done {
+                break;
+            }
+        }
+        assert!(progress.done);
+        // only padding before each block is left
+        assert!(progress.empty_space > 0);
+        assert_eq!(c.header.footer_offset, c.footer.data_end());
+        for i in [0, 2, 3, 5] {
+            assert_eq!(
+                c.get_blocks_list().unwrap()[&id(i)].data_offset().0 % 4096,
+                0
+            );
+            assert_eq!(c.get_block(&id(i)).unwrap().1, data(i));
+        }
+        assert!(c.verify().is_clean());
+    }
+
+    #[test]
+    fn crashing_mid_step_keeps_the_committed_state() {
+        let (c, live) = fragmented();
+        let base = c.into_inner().unwrap().into_inner();
+        for writes_left in 0.. {
+            let file = CrashingFile {
+                inner: Cursor::new(base.clone()),
+                writes_left,
+            };
+            let mut c = Cogtainer::open(file).unwrap();
+            let finished = c.defragment().is_ok();
+
+            let buf = c.into_inner().unwrap().inner.into_inner();
+            let reopened = Cogtainer::open(Cursor::new(buf))
+                .unwrap_or_else(|e| panic!("crash after {writes_left} writes: {e}"));
+            for i in &live {
+                assert_eq!(
+                    reopened.get_block(&id(*i)).unwrap().1,
+                    data(*i),
+                    "crash after {writes_left} writes"
+                );
+            }
+            if finished {
+                assert!(reopened.footer.empty_space.is_empty());
+                break;
+            }
+        }
+    }
+}
@@ -26,12 +26,12 @@ mod defrag_tests {
     fn defrag_single_hole_at_start_moves_block() {
         let mut c = open_new_container();
         // Insert A, B, C
-        let ids: Vec<_> = (0..3).map(|i| i).collect();
+        let ids: Vec<_> = (0..3).collect();
         for id in &ids {
             c.insert_block(
                 &Identifier::U64(*id),
                 rmpv::Value::Nil,
-                &vec![*id as u8 + 1; 32],
+                &[*id as u8 + 1; 32],
             )
             .unwrap();
         }
@@ -73,18 +73,15 @@ mod defrag_tests {
     fn defrag_multiple_holes_all_packed_and_single_gap_left() {
         let mut c = open_new_container();
         // Insert 5 blocks
-        let ids: Vec<_> = (0..5).map(|i| i).collect();
+        let ids: Vec<_> = (0..5).collect();
         for id in &ids {
-            c.insert_block(
-                &Identifier::U64(*id),
-                rmpv::Value::Nil,
-                &vec![*id as u8; 16],
-            )
-            .unwrap();
+            c.insert_block(&Identifier::U64(*id), rmpv::Value::Nil, &[*id as u8; 16])
+                .unwrap();
         }
         // Delete 1 and 3 (creates two holes)
         c.delete_block(&Identifier::U64(ids[1])).unwrap();
         c.delete_block(&Identifier::U64(ids[3])).unwrap();
+        c.flush().unwrap();
         // Verify two holes
         assert_eq!(c.footer.empty_space.len(), 2);
         c.defragment().unwrap();
@@ -103,7 +100,7 @@ mod defrag_tests {
     #[test]
     fn defrag_blocks_of_varying_size() {
         let mut c = open_new_container();
-        let ids: Vec<_> = (0..4).map(|i| i).collect();
+        let ids: Vec<_> = (0..4).collect();
         let sizes = [8, 64, 4, 128];
         for (id, size) in ids.iter().zip(sizes.iter()) {
             c.insert_block(
@@ -135,14 +132,10 @@ mod defrag_tests {
             .unwrap();
         c.insert_block(&zero_id, rmpv::Value::Nil, &[]).unwrap();
         // Insert some data blocks
-        let ids: Vec<_> = (0..3).map(|i| i).collect();
+        let ids: Vec<_> = (0..3).collect();
         for id in &ids {
-            c.insert_block(
-                &Identifier::U64(*id),
-                rmpv::Value::Nil,
-                &vec![*id as u8; 10],
-            )
-            .unwrap();
+            c.insert_block(&Identifier::U64(*id), rmpv::Value::Nil, &[*id as u8; 10])
+                .unwrap();
         }
         // Delete the middle data block
         c.delete_block(&Identifier::U64(ids[1])).unwrap();
@@ -239,7 +232,7 @@ mod defrag_tests {
     fn defrag_does_not_move_blocks_when_already_packed() {
         let mut c = open_new_container();
         for i in 0..4 {
-            c.insert_block(&Identifier::U64(i), rmpv::Value::Nil, &vec![i as u8; 8])
+            c.insert_block(&Identifier::U64(i), rmpv::Value::Nil, &[i as u8; 8])
                 .unwrap();
         }
         let orig_offsets: Vec<_> = (0..4)
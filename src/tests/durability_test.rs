@@ -0,0 +1,100 @@
+#[cfg(test)]
+mod durability_tests {
+    use crate::{basic_api::Cogtainer, container_file::*, storage::Storage};
+
+    use std::io::Cursor;
+
+    fn write_back_container(
+        buf: &mut Vec<u8>,
+        flush_every: Option<u64>,
+    ) -> Cogtainer<&mut Vec<u8>> {
+        let mut c = Cogtainer::create(buf).unwrap();
+        c.set_durability_mode(DurabilityMode::WriteBack { flush_every });
+        c
+    }
+
+    fn insert_blocks<F: Storage>(c: &mut Cogtainer<F>, ids: std::ops::Range<u64>) {
+        for i in ids {
+            c.insert_block(&Identifier::U64(i), rmpv::Value::Nil, &[i as u8; 16])
+                .unwrap();
+        }
+    }
+
+    /// Number of blocks in the container as it is on disk right now.
+    fn blocks_on_disk(c: &mut Cogtainer<&mut Vec<u8>>) -> usize {
+        let bytes = c.get_inner_file().to_vec();
+        Cogtainer::open(Cursor::new(bytes))
+            .unwrap()
+            .get_blocks_list()
+            .unwrap()
+            .len()
+    }
+
+    #[test]
+    fn write_back_defers_footer_until_flush() {
+        let mut buf = vec![];
+        let mut c = write_back_container(&mut buf, None);
+        let generation = c.header.generation;
+        insert_blocks(&mut c, 0..100);
+        // the only commits are the unchanged footer being moved out of the way of new blocks
+        assert!(c.header.generation - generation < 10);
+        assert!(c.footer.is_dirty());
+        assert_eq!(blocks_on_disk(&mut c), 0);
+
+        c.flush().unwrap();
+        assert!(!c.footer.is_dirty());
+        assert_eq!(blocks_on_disk(&mut c), 100);
+    }
+
+    #[test]
+    fn write_back_flushes_every_n_changes() {
+        let mut buf = vec![];
+        let mut c = write_back_container(&mut buf, Some(10));
+        insert_blocks(&mut c, 0..25);
+        assert_eq!(blocks_on_disk(&mut c), 20);
+
+        // deletes count as changes too
+        for i in 0..5 {
+            c.delete_block(&Identifier::U64(i)).unwrap();
+        }
+        assert_eq!(blocks_on_disk(&mut c), 20);
+        c.set_metadata(rmpv::Value::from("done")).unwrap();
+        assert_eq!(blocks_on_disk(&mut c), 20);
+    }
+
+    #[test]
+    fn dropping_writes_pending_changes() {
+        let mut buf = vec![];
+        let mut c = write_back_container(&mut buf, None);
+        insert_blocks(&mut c, 0..10);
+        c.delete_block(&Identifier::U64(3)).unwrap();
+        drop(c);
+
+        let c = Cogtainer::open(Cursor::new(buf)).unwrap();
+        assert_eq!(c.get_blocks_list().unwrap().len(), 9);
+        assert_eq!(c.get_block(&Identifier::U64(9)).unwrap().1, [9u8; 16]);
+    }
+
+    #[test]
+    fn into_inner_writes_pending_changes() {
+        let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+        c.set_durability_mode(DurabilityMode::WriteBack { flush_every: None });
+        insert_blocks(&mut c, 0..10);
+        let buf = c.into_inner().unwrap().into_inner();
+
+        let c = Cogtainer::open(Cursor::new(buf)).unwrap();
+        assert_eq!(c.get_blocks_list().unwrap().len(), 10);
+    }
+
+    #[test]
+    fn write_through_leaves_deletes_for_flush() {
+        let mut buf = vec![];
+        let mut c = Cogtainer::create(&mut buf).unwrap();
+        insert_blocks(&mut c, 0..3);
+        c.delete_block(&Identifier::U64(0)).unwrap();
+        drop(c);
+
+        let c = Cogtainer::open(Cursor::new(buf)).unwrap();
+        assert_eq!(c.get_blocks_list().unwrap().len(), 3);
+    }
+}
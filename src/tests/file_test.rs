@@ -1,6 +1,9 @@
 #[cfg(test)]
 mod tests {
-    use crate::{container_file::*, error::CogtainerError};
+    use crate::{
+        container_file::*,
+        error::{CogtainerError, HeaderError},
+    };
 
     use std::io::{Cursor, Read, Seek, SeekFrom, Write};
 
@@ -22,9 +25,9 @@ mod tests {
             .write_to(&mut file, &mut { header.clone() })
             .expect("write footer");
         // Read back header/footer
-        let header2 = ContainerHeader::read_from(&mut file).expect("read header");
-        let footer2 = ContainerFooter::read_from(&mut file, &header2).expect("read footer");
-        assert_eq!(header2.version, 1);
+        let header2 = ContainerHeader::read_from(&file).expect("read header");
+        let footer2 = ContainerFooter::read_from(&file, &header2).expect("read footer");
+        assert_eq!(header2.version, 2);
         assert!(footer2.blocks.is_empty());
     }
 
@@ -45,7 +48,7 @@ mod tests {
                 data,
             )
             .expect("insert");
-        let (block_metadata, bytes) = footer.get_block(&mut file, &id).expect("read block");
+        let (block_metadata, bytes) = footer.get_block(&file, &id).expect("read block");
         assert_eq!(&bytes, data);
         assert_eq!(block_metadata.clone(), meta);
     }
@@ -66,9 +69,22 @@ mod tests {
                 &data1,
             )
             .unwrap();
-        // Replace with same-length data, should reuse space (empty_space stays empty)
+        // Replace with same-length data. The committed footer still refers to the old space, so
+        // the new data goes elsewhere and the old space is free once the replacement is committed
         let data2 = vec![99u8; 32];
         let meta2 = rmpv::Value::from(2);
+        footer
+            .insert_block(
+                &mut file,
+                &mut header,
+                OverallocationPolicy::None,
+                &id,
+                rmpv::Value::Nil,
+                &data1,
+            )
+            .unwrap();
+        assert_eq!(footer.empty_space.values().sum::<u64>(), 32);
+        // the next replacement reuses it (empty_space ends up empty)
         footer
             .insert_block(
                 &mut file,
@@ -80,7 +96,7 @@ mod tests {
             )
             .unwrap();
         assert!(footer.empty_space.is_empty());
-        let (block_metadata, read_back) = footer.get_block(&mut file, &id).unwrap();
+        let (block_metadata, read_back) = footer.get_block(&file, &id).unwrap();
         assert_eq!(&read_back, &data2);
         assert_eq!(block_metadata.clone(), meta2);
     }
@@ -116,6 +132,7 @@ mod tests {
             .unwrap();
         // Remove A (creates a hole)
         footer.delete_block(&id1).unwrap();
+        footer.persist(&mut file, &mut header).unwrap();
         assert!(footer.empty_space.values().any(|&v| v == 64));
         // Insert a block that fits in the hole
         let id3 = Identifier::String("C".into());
@@ -132,7 +149,7 @@ mod tests {
             .unwrap();
         // Should create a leftover hole of 4 bytes
         assert!(footer.empty_space.values().any(|&v| v == 4));
-        let (_block_metadata, read_back) = footer.get_block(&mut file, &id3).unwrap();
+        let (_block_metadata, read_back) = footer.get_block(&file, &id3).unwrap();
         assert_eq!(&read_back, &data_c);
     }
 
@@ -168,7 +185,7 @@ mod tests {
                 &[],
             )
             .unwrap();
-        let (_block_metadata, read) = footer.get_block(&mut file, &id).unwrap();
+        let (_block_metadata, read) = footer.get_block(&file, &id).unwrap();
         assert_eq!(read.len(), 0);
     }
 
@@ -203,7 +220,8 @@ mod tests {
     fn test_consolidate_empty_space() {
         let (mut file, mut header, mut footer) = open_new_container();
         // Insert and delete several adjacent blocks, check they consolidate
-        for i in 0..5 {
+        // (the last block stays, so the empty space isn't at the end of the data)
+        for i in 0..6 {
             let id = Identifier::U64(i);
             let data = vec![0u8; 8];
             footer
@@ -222,6 +240,7 @@ mod tests {
             let id = Identifier::U64(i);
             footer.delete_block(&id).unwrap();
         }
+        footer.persist(&mut file, &mut header).unwrap();
         // Should be one large empty space
         assert_eq!(footer.empty_space.len(), 1);
         let (&FileOffset(_start), &len) = footer.empty_space.iter().next().unwrap();
@@ -249,7 +268,7 @@ mod tests {
             .unwrap();
         file.write_all(b"bad data").unwrap();
         // Should fail on checksum mismatch
-        let result = footer.get_block(&mut file, &id);
+        let result = footer.get_block(&file, &id);
         assert!(matches!(result, Err(CogtainerError::BlockChecksumError(_))));
     }
 
@@ -276,10 +295,114 @@ mod tests {
         file.seek(SeekFrom::Start(header.footer_offset.0)).unwrap();
         file.write_all(&bad_footer).unwrap();
         // Should fail on footer checksum
-        let result = ContainerFooter::read_from(&mut file, &header);
+        let result = ContainerFooter::read_from(&file, &header);
         assert!(matches!(result, Err(CogtainerError::FooterChecksumError)));
     }
 
+    fn insert_two_blocks() -> (Cursor<Vec<u8>>, ContainerHeader, ContainerFooter) {
+        let (mut file, mut header, mut footer) = open_new_container();
+        for i in 0..2 {
+            footer
+                .insert_block(
+                    &mut file,
+                    &mut header,
+                    OverallocationPolicy::None,
+                    &Identifier::U64(i),
+                    rmpv::Value::Nil,
+                    &[i as u8; 10],
+                )
+                .unwrap();
+        }
+        (file, header, footer)
+    }
+
+    #[test]
+    fn test_header_checksum_detection_rebuilds_from_trailer() {
+        let (mut file, header, _footer) = insert_two_blocks();
+        // Flip a bit in the footer offset
+        file.seek(SeekFrom::Start(12)).unwrap();
+        let mut byte = [0u8; 1];
+        file.read_exact(&mut byte).unwrap();
+        file.seek(SeekFrom::Start(12)).unwrap();
+        file.write_all(&[byte[0] ^ 0x01]).unwrap();
+
+        let header2 = ContainerHeader::read_from(&file).unwrap();
+        assert_eq!(header2.footer_offset, header.committed_footer().offset);
+        assert_eq!(header2.footer_length, header.footer_length);
+        assert_eq!(header2.footer_checksum, header.footer_checksum);
+        assert_eq!(header2.generation, header.generation);
+        let footer2 = ContainerFooter::read_from(&file, &header2).unwrap();
+        assert_eq!(footer2.blocks.len(), 2);
+    }
+
+    #[test]
+    fn test_header_rebuilt_by_scanning_when_wiped() {
+        let (mut file, header, _footer) = insert_two_blocks();
+        // Wipe the whole header, including the hint to where the trailer is
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.write_all(&[0u8; ContainerHeader::HEADER_SIZE])
+            .unwrap();
+
+        let header2 = ContainerHeader::read_from(&file).unwrap();
+        assert_eq!(header2.generation, header.generation);
+        assert_eq!(header2.footer_checksum, header.footer_checksum);
+    }
+
+    #[test]
+    fn test_header_checksum_error_without_trailer() {
+        let (mut file, _header, _footer) = open_new_container();
+        // Keep only a damaged header, so there is no trailer to rebuild it from
+        let mut bad = vec![0u8; ContainerHeader::HEADER_SIZE];
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_exact(&mut bad).unwrap();
+        bad[20] ^= 0x01;
+        let result = ContainerHeader::read_from(&Cursor::new(bad));
+        assert!(matches!(
+            result,
+            Err(CogtainerError::InvalidHeader(HeaderError::Checksum))
+        ));
+    }
+
+    #[test]
+    fn test_header_without_checksum_is_accepted() {
+        let (mut file, header, _footer) = insert_two_blocks();
+        // Version 1 headers written before the checksum existed have zeros in its place
+        let mut bytes = vec![0u8; ContainerHeader::HEADER_SIZE];
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_exact(&mut bytes).unwrap();
+        bytes[4..12].copy_from_slice(&1u64.to_le_bytes());
+        bytes[ContainerHeader::HEADER_SIZE - 4..].fill(0);
+        let header2 = ContainerHeader::read_from(&Cursor::new(bytes.clone())).unwrap();
+        assert_eq!(header2.version, 1);
+        assert_eq!(header2.footer_checksum, header.footer_checksum);
+
+        // a zeroed checksum doesn't make a current header pass
+        bytes[4..12].copy_from_slice(&ContainerHeader::VERSION.to_le_bytes());
+        let result = ContainerHeader::read_from(&Cursor::new(bytes));
+        assert!(matches!(
+            result,
+            Err(CogtainerError::InvalidHeader(HeaderError::Checksum))
+        ));
+    }
+
+    #[test]
+    fn test_header_rebuilt_from_trailer_after_generation_wraps() {
+        let (_file, header, _footer) = open_new_container();
+        // an all-zero file with two trailers, the newer one after the generation wrapped around
+        let mut file = Cursor::new(vec![0u8; 3000]);
+        for (generation, offset) in [(u32::MAX, 2000), (0, 1000)] {
+            let mut trailer = header.clone();
+            trailer.generation = generation;
+            trailer.footer_offset = FileOffset(offset);
+            trailer.footer_length = 0;
+            trailer.write_trailer_to(&mut file).unwrap();
+        }
+
+        let header2 = ContainerHeader::read_from(&file).unwrap();
+        assert_eq!(header2.generation, 0);
+        assert_eq!(header2.footer_offset, FileOffset(1000));
+    }
+
     #[test]
     fn test_block_path_identifier() {
         let (mut file, mut header, mut footer) = open_new_container();
@@ -299,7 +422,7 @@ mod tests {
                 data,
             )
             .unwrap();
-        let (_block_metadata, read_back) = footer.get_block(&mut file, &id).unwrap();
+        let (_block_metadata, read_back) = footer.get_block(&file, &id).unwrap();
         assert_eq!(&read_back, data);
     }
 
@@ -323,7 +446,7 @@ mod tests {
         }
         // Read all back
         for (id, d) in &data {
-            let (_block_metadata, rb) = footer.get_block(&mut file, id).unwrap();
+            let (_block_metadata, rb) = footer.get_block(&file, id).unwrap();
             assert_eq!(rb, *d);
         }
     }
@@ -357,6 +480,7 @@ mod tests {
             )
             .unwrap();
         footer.delete_block(&id1).unwrap();
+        footer.persist(&mut file, &mut header).unwrap();
         let orig_hole_offset = footer.empty_space.keys().next().copied().unwrap();
         // Insert a smaller block
         let id3 = Identifier::String("block3".into());
@@ -387,11 +511,23 @@ mod tests {
     #[test]
     fn test_reserved_fields_nonzero() {
         let (mut file, mut header, _footer) = open_new_container();
-        header.reserved = [1, 2, 3, 4];
+        header.generation = 1;
+        header.previous_footer = FooterLocation {
+            offset: FileOffset(2),
+            length: 3,
+            checksum: Checksum(4),
+        };
+        header.flags = 5;
+        header.checksum_algorithm = ChecksumAlgorithm::XxHash3_64;
+        header.reserved = [7, 8];
         header.write_to(&mut file).unwrap();
         // Still able to read header/footer after
-        let header2 = ContainerHeader::read_from(&mut file).unwrap();
-        assert_eq!(header2.reserved, [1, 2, 3, 4]);
+        let header2 = ContainerHeader::read_from(&file).unwrap();
+        assert_eq!(header2.generation, 1);
+        assert_eq!(header2.previous_footer, header.previous_footer);
+        assert_eq!(header2.flags, 5);
+        assert_eq!(header2.checksum_algorithm, ChecksumAlgorithm::XxHash3_64);
+        assert_eq!(header2.reserved, [7, 8]);
     }
 
     #[test]
@@ -418,7 +554,7 @@ mod tests {
         for i in 0..20 {
             let id = Identifier::U64(i);
             if i % 3 != 0 {
-                let (_block_metadata, data) = footer.get_block(&mut file, &id).unwrap();
+                let (_block_metadata, data) = footer.get_block(&file, &id).unwrap();
                 assert_eq!(data, vec![i as u8; 10]);
             }
         }
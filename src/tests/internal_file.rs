@@ -9,10 +9,13 @@ fn open_container() -> Cogtainer<Cursor<Vec<u8>>> {
     let mut file = new_mem_file();
     let (header, footer) = ContainerHeader::create(&mut file).unwrap();
     Cogtainer {
-        file,
+        file: FileSlot::new(file),
         header,
         footer,
         overallocation_policy: OverallocationPolicy::default(),
+        flush_on_drop: None,
+        truncate_on_flush: None,
+        punch_on_flush: None,
     }
 }
 
@@ -78,7 +81,7 @@ fn internal_file_seek_semantics_start_end_current() {
     let err = f.seek(SeekFrom::Current(-5)).unwrap_err();
     assert!(matches!(err.kind(), std::io::ErrorKind::InvalidInput));
     // Cursor unchanged (still 2)
-    assert_eq!(f.seek(SeekFrom::Current(0)).unwrap(), 2);
+    assert_eq!(f.stream_position().unwrap(), 2);
 }
 
 #[test]
@@ -110,12 +113,12 @@ fn internal_file_in_place_write_within_allocation_zero_fills_gap_and_updates_use
     {
         let mut f = c.get_block_as_file(&id);
         f.seek(SeekFrom::Start(12)).unwrap();
-        f.write(b"ZZ").unwrap();
+        f.write_all(b"ZZ").unwrap();
         f.flush().unwrap();
     } // drop f
 
     // Verify: used_length expanded to 14, gap zero-filled
-    let block = c.get_blocks_list()[&id].clone();
+    let block = c.get_blocks_list().unwrap()[&id].clone();
     assert_eq!(block.used_length, 14);
 
     let (_m, data) = c.get_block(&id).unwrap();
@@ -141,17 +144,17 @@ fn internal_file_write_beyond_allocation_triggers_rebuild_and_extends() {
     {
         let mut f = c.get_block_as_file(&id);
         f.seek(SeekFrom::Start(20)).unwrap();
-        f.write(b"WWWW").unwrap(); // should rebuild and extend
+        f.write_all(b"WWWW").unwrap(); // should rebuild and extend
         f.flush().unwrap();
     }
 
-    let block = c.get_blocks_list()[&id].clone();
+    let block = c.get_blocks_list().unwrap()[&id].clone();
     assert_eq!(block.used_length, 24);
 
     let (_m, data) = c.get_block(&id).unwrap();
     // Expect original 8, then zero gap [8..20], then "WWWW"
     let mut expected = Vec::from(&b"12345678"[..]);
-    expected.extend_from_slice(&vec![0u8; 12]); // 8..20
+    expected.extend_from_slice(&[0u8; 12]); // 8..20
     expected.extend_from_slice(b"WWWW");
     assert_eq!(data, expected);
 }
@@ -180,12 +183,12 @@ fn internal_file_flush_persists_changes_after_reopen() {
 
     {
         let mut f = c.get_block_as_file(&id);
-        f.write(b"persist me").unwrap();
+        f.write_all(b"persist me").unwrap();
         f.flush().unwrap();
     }
     // Reopen container
-    let buf = c.file.into_inner();
-    let mut c2 = Cogtainer::open(Cursor::new(buf)).unwrap();
+    let buf = c.into_inner().unwrap().into_inner();
+    let c2 = Cogtainer::open(Cursor::new(buf)).unwrap();
 
     let (m, data) = c2.get_block(&id).unwrap();
     assert_eq!(m, &rmpv::Value::Nil);
@@ -221,7 +224,7 @@ fn internal_file_overwrite_existing_region_in_place() {
     {
         let mut f = c.get_block_as_file(&id);
         f.seek(SeekFrom::Start(2)).unwrap();
-        f.write(b"ZZZ").unwrap(); // overwrite 'cde' -> 'ZZZ'
+        f.write_all(b"ZZZ").unwrap(); // overwrite 'cde' -> 'ZZZ'
         f.flush().unwrap();
     }
 
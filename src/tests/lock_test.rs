@@ -0,0 +1,113 @@
+#[cfg(test)]
+mod lock_tests {
+    use crate::{basic_api::Cogtainer, container_file::*, lock::FileLock};
+
+    use std::{fs::File, path::PathBuf};
+
+    fn id(i: u64) -> Identifier {
+        Identifier::U64(i)
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("cogtainer-lock-{name}-{}", std::process::id()))
+    }
+
+    fn open_rw(path: &PathBuf) -> File {
+        File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .unwrap()
+    }
+
+    #[test]
+    fn writer_excludes_others() {
+        let path = temp_path("exclusive");
+        let _ = std::fs::remove_file(&path);
+        let mut writer = Cogtainer::create_locked(open_rw(&path)).unwrap();
+        writer
+            .insert_block(&id(1), rmpv::Value::Nil, b"first")
+            .unwrap();
+
+        let mut reader = Cogtainer::open(File::open(&path).unwrap()).unwrap();
+        assert!(!reader.try_lock(FileLock::Shared).unwrap());
+        assert!(!reader.try_lock(FileLock::Exclusive).unwrap());
+
+        writer.unlock().unwrap();
+        assert!(reader.try_lock(FileLock::Shared).unwrap());
+        // readers share
+        let mut other = Cogtainer::open(File::open(&path).unwrap()).unwrap();
+        assert!(other.try_lock(FileLock::Shared).unwrap());
+        assert!(!writer.try_lock(FileLock::Exclusive).unwrap());
+
+        drop((reader, other));
+        assert!(writer.try_lock(FileLock::Exclusive).unwrap());
+        drop(writer);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn open_waits_for_the_writer() {
+        let path = temp_path("wait");
+        let _ = std::fs::remove_file(&path);
+        let mut writer = Cogtainer::create_locked(open_rw(&path)).unwrap();
+        writer.set_durability_mode(DurabilityMode::WriteBack { flush_every: None });
+
+        let reader = {
+            let path = path.clone();
+            std::thread::spawn(move || {
+                let c =
+                    Cogtainer::open_locked(File::open(&path).unwrap(), FileLock::Shared).unwrap();
+                c.get_block(&id(1)).unwrap().1
+            })
+        };
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        writer
+            .insert_block(&id(1), rmpv::Value::Nil, b"committed")
+            .unwrap();
+        // flushed before the lock is released
+        drop(writer);
+        assert_eq!(reader.join().unwrap(), b"committed");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn refresh_picks_up_commits() {
+        let path = temp_path("refresh");
+        let _ = std::fs::remove_file(&path);
+        let mut writer = Cogtainer::create(open_rw(&path)).unwrap();
+        writer
+            .insert_block(&id(1), rmpv::Value::Nil, b"first")
+            .unwrap();
+
+        let mut reader = Cogtainer::open(File::open(&path).unwrap()).unwrap();
+        reader.set_checksum_granularity(ChecksumGranularity::Chunks);
+        assert!(!reader.refresh().unwrap());
+
+        writer
+            .insert_block(&id(2), rmpv::Value::Nil, b"second")
+            .unwrap();
+        assert!(reader.refresh().unwrap());
+        assert_eq!(reader.get_blocks_list().unwrap().len(), 2);
+
+        // deletes aren't committed until flush()
+        writer.delete_block(&id(1)).unwrap();
+        assert!(!reader.refresh().unwrap());
+        assert!(reader.get_blocks_list().unwrap().contains_key(&id(1)));
+
+        writer.flush().unwrap();
+        assert!(reader.refresh().unwrap());
+        assert!(!reader.get_blocks_list().unwrap().contains_key(&id(1)));
+        assert_eq!(reader.get_block(&id(2)).unwrap().1, b"second");
+        assert_eq!(
+            reader.footer.checksum_granularity,
+            ChecksumGranularity::Chunks
+        );
+        assert!(!reader.refresh().unwrap());
+
+        drop((writer, reader));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
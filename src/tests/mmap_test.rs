@@ -0,0 +1,85 @@
+#[cfg(test)]
+mod mmap_tests {
+    use crate::{basic_api::Cogtainer, container_file::*, error::CogtainerError};
+
+    use std::io::{Cursor, ErrorKind};
+
+    fn id(i: u64) -> Identifier {
+        Identifier::U64(i)
+    }
+
+    fn write_container(path: &std::path::Path, granularity: ChecksumGranularity) {
+        let file = std::fs::File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .unwrap();
+        let mut c = Cogtainer::create(file).unwrap();
+        c.set_checksum_granularity(granularity);
+        for i in 0..100 {
+            c.insert_block(
+                &id(i),
+                rmpv::Value::from(i),
+                &vec![i as u8; i as usize * 3000],
+            )
+            .unwrap();
+        }
+        c.flush().unwrap();
+    }
+
+    #[test]
+    fn borrowed_blocks_from_a_map() {
+        for (n, granularity) in [ChecksumGranularity::Block, ChecksumGranularity::Chunks]
+            .into_iter()
+            .enumerate()
+        {
+            let path =
+                std::env::temp_dir().join(format!("cogtainer-mmap-{}-{n}", std::process::id()));
+            write_container(&path, granularity);
+
+            let mut c = Cogtainer::open_mmap(&path).unwrap();
+            for i in 0..100 {
+                let (metadata, data) = c.get_block_ref(&id(i)).unwrap();
+                assert_eq!(metadata, &rmpv::Value::from(i));
+                assert_eq!(data, vec![i as u8; i as usize * 3000]);
+                // the slice points into the map
+                let map = c.file.as_ptr_range();
+                assert!(data.is_empty() || map.contains(&data.as_ptr()));
+            }
+            assert!(c.verify().is_clean());
+
+            let err = c
+                .insert_block(&id(1000), rmpv::Value::Nil, b"no")
+                .unwrap_err();
+            assert!(
+                matches!(err, CogtainerError::IOError(e) if e.kind() == ErrorKind::PermissionDenied)
+            );
+            drop(c);
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn corrupt_blocks_are_reported() {
+        let mut c = Cogtainer::create(vec![]).unwrap();
+        c.insert_block(&id(1), rmpv::Value::Nil, b"hello").unwrap();
+        c.insert_block(&id(2), rmpv::Value::Nil, b"world").unwrap();
+        let offset = c.get_blocks_list().unwrap()[&id(1)].data_offset().0 as usize;
+        c.get_inner_file()[offset] = b'J';
+
+        assert!(matches!(
+            c.get_block_ref(&id(1)),
+            Err(CogtainerError::BlockChecksumError(_))
+        ));
+        assert_eq!(c.get_block_ref(&id(2)).unwrap().1, b"world");
+        assert!(matches!(
+            c.get_block_ref(&id(3)),
+            Err(CogtainerError::BlockNotFound(_))
+        ));
+        // Cursor<Vec<u8>> has no zero-copy reads, but the copying ones still work
+        let c = Cogtainer::open(Cursor::new(c.into_inner().unwrap())).unwrap();
+        assert_eq!(c.get_block(&id(2)).unwrap().1, b"world");
+    }
+}
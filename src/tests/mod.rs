@@ -1,6 +1,63 @@
+mod alignment_test;
+mod allocation_test;
 mod api_test;
+#[cfg(feature = "async")]
+mod async_test;
+mod block_reader_test;
+mod block_writer_test;
+mod checksum_algorithm_test;
+mod chunk_checksum_test;
+mod commit_test;
+mod compact_test;
+mod defrag_step_test;
 mod defrag_test;
+mod durability_test;
 mod file_test;
 mod internal_file;
+mod lock_test;
+#[cfg(feature = "mmap")]
+mod mmap_test;
+mod paged_index_test;
+mod salvage_test;
+mod scrub_test;
+mod shared_test;
+mod slice_test;
+mod sparse_test;
+mod storage_test;
+mod tail_test;
+mod truncate_test;
+mod verify_test;
 
 mod advanced_test;
+mod transaction_test;
+
+use std::io::Cursor;
+
+use crate::storage::Storage;
+
+/// A file that "crashes" (fails every write) once a number of writes have gone through.
+pub(crate) struct CrashingFile {
+    pub(crate) inner: Cursor<Vec<u8>>,
+    pub(crate) writes_left: usize,
+}
+impl Storage for CrashingFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        self.inner.read_at(buf, offset)
+    }
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> std::io::Result<usize> {
+        if self.writes_left == 0 {
+            return Err(std::io::Error::other("crashed"));
+        }
+        self.writes_left -= 1;
+        self.inner.write_at(buf, offset)
+    }
+    fn len(&self) -> std::io::Result<u64> {
+        self.inner.len()
+    }
+    fn set_len(&mut self, len: u64) -> std::io::Result<()> {
+        self.inner.set_len(len)
+    }
+    fn sync(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,205 @@
+#[cfg(test)]
+mod paged_index_tests {
+    use crate::{
+        basic_api::Cogtainer, compact::CompactionOrder, container_file::*, error::CogtainerError,
+        tests::CrashingFile,
+    };
+
+    use std::io::Cursor;
+
+    fn id(i: u64) -> Identifier {
+        Identifier::U64(i)
+    }
+
+    /// A container with 100 blocks, on pages of up to 8.
+    fn paged_container() -> Vec<u8> {
+        let mut c = Cogtainer::create(vec![]).unwrap();
+        c.set_durability_mode(DurabilityMode::WriteBack { flush_every: None });
+        c.set_index_page_size(8).unwrap();
+        for i in 0..100 {
+            c.insert_block(&id(i), rmpv::Value::from(i), &[i as u8; 64])
+                .unwrap();
+        }
+        c.into_inner().unwrap()
+    }
+
+    #[test]
+    fn pages_are_read_when_needed() {
+        let c = Cogtainer::open(paged_container()).unwrap();
+        assert_eq!(c.footer.blocks.page_count(), 13);
+        assert_eq!(c.footer.blocks.loaded_pages(), 0);
+        assert_eq!(c.footer.blocks.len(), 100);
+
+        let (metadata, data) = c.get_block(&id(57)).unwrap();
+        assert_eq!((metadata, data), (&rmpv::Value::from(57), vec![57; 64]));
+        assert!(c.get_block(&id(100)).is_err());
+        assert_eq!(c.footer.blocks.loaded_pages(), 2);
+        let metadata = c.footer.get_block_metadata(&*c.file, &id(20)).unwrap();
+        assert_eq!(metadata, Some(&rmpv::Value::from(20)));
+        assert_eq!(c.footer.blocks.loaded_pages(), 3);
+
+        assert!(c.verify().is_clean());
+        assert!(c.footer.blocks.is_loaded());
+        let identifiers: Vec<_> = c.get_blocks_list().unwrap().keys().cloned().collect();
+        assert_eq!(identifiers, (0..100).map(id).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn commits_rewrite_only_changed_pages() {
+        let mut c = Cogtainer::open(paged_container()).unwrap();
+        let before: Vec<_> = c.footer.blocks.page_regions().collect();
+        c.insert_block(&id(42), rmpv::Value::Nil, b"changed")
+            .unwrap();
+        let after: Vec<_> = c.footer.blocks.page_regions().collect();
+        assert_eq!(before.len(), after.len());
+        assert_eq!(before.iter().zip(&after).filter(|(a, b)| a != b).count(), 1);
+        assert_eq!(c.footer.blocks.loaded_pages(), 1);
+
+        // the footer only lists the pages
+        let mut unpaged = Cogtainer::open(paged_container()).unwrap();
+        unpaged.set_index_page_size(0).unwrap();
+        assert!(c.header.committed_footer().length * 4 < unpaged.header.committed_footer().length);
+
+        let c = Cogtainer::open(c.into_inner().unwrap()).unwrap();
+        assert_eq!(c.get_block(&id(42)).unwrap().1, b"changed");
+        assert!(c.verify().is_clean());
+    }
+
+    #[test]
+    fn pages_split_and_empty_pages_are_dropped() {
+        let mut c = Cogtainer::open(paged_container()).unwrap();
+        c.set_durability_mode(DurabilityMode::WriteBack { flush_every: None });
+        for i in 100..200 {
+            c.insert_block(&id(i), rmpv::Value::Nil, &[i as u8; 16])
+                .unwrap();
+        }
+        for i in 0..50 {
+            c.delete_block(&id(i)).unwrap();
+        }
+        c.flush().unwrap();
+        assert!(c.verify().is_clean());
+
+        let c = Cogtainer::open(c.into_inner().unwrap()).unwrap();
+        // every page holds at most 8 blocks, and only the first page may be empty
+        assert!(c.footer.blocks.page_count() >= 150 / 8);
+        assert!(c.footer.blocks.page_count() < 13 + 100 / 4);
+        assert_eq!(c.footer.blocks.len(), 150);
+        assert_eq!(c.get_block(&id(150)).unwrap().1, vec![150; 16]);
+        assert!(c.get_block(&id(10)).is_err());
+        assert!(c.verify().is_clean());
+    }
+
+    #[test]
+    fn unpaged_again() {
+        let mut c = Cogtainer::open(paged_container()).unwrap();
+        c.set_index_page_size(0).unwrap();
+        assert_eq!(c.footer.blocks.page_count(), 1);
+        assert!(c.verify().is_clean());
+
+        let c = Cogtainer::open(c.into_inner().unwrap()).unwrap();
+        assert_eq!(c.footer.blocks.page_size(), 0);
+        assert!(c.footer.blocks.is_loaded());
+        assert_eq!(c.get_blocks_list().unwrap().len(), 100);
+        assert!(c.verify().is_clean());
+    }
+
+    #[test]
+    fn transactions_defragment_and_compact() {
+        let mut c = Cogtainer::open(paged_container()).unwrap();
+        let mut tx = c.transaction().unwrap();
+        tx.delete_block(&id(3))
+            .unwrap()
+            .insert_block(&id(300), rmpv::Value::Nil, b"tx")
+            .unwrap()
+            .update_block_metadata(&id(77), rmpv::Value::from("updated"));
+        tx.commit().unwrap();
+
+        for i in (0..100).step_by(2) {
+            c.delete_block(&id(i)).unwrap();
+        }
+        c.flush().unwrap();
+        c.defragment().unwrap();
+        assert!(c.verify().is_clean());
+        assert_eq!(c.get_blocks_list().unwrap().len(), 50);
+        assert_eq!(c.get_block(&id(300)).unwrap().1, b"tx");
+        assert_eq!(
+            c.get_block(&id(77)).unwrap().0,
+            &rmpv::Value::from("updated")
+        );
+
+        let compacted = c.compact_into(vec![], CompactionOrder::Identifier).unwrap();
+        let compacted = Cogtainer::open(compacted.into_inner().unwrap()).unwrap();
+        assert_eq!(compacted.footer.blocks.page_size(), 8);
+        assert_eq!(compacted.get_blocks_list().unwrap().len(), 50);
+        assert_eq!(compacted.get_block(&id(99)).unwrap().1, vec![99; 64]);
+        assert!(compacted.verify().is_clean());
+    }
+
+    #[test]
+    fn corrupt_page() {
+        let mut data = paged_container();
+        let (offset, _) = Cogtainer::open(data.as_slice())
+            .unwrap()
+            .footer
+            .blocks
+            .page_regions()
+            .nth(3)
+            .unwrap();
+        data[offset.0 as usize + 1] ^= 0xFF;
+
+        let mut c = Cogtainer::open(data).unwrap();
+        let missing: Vec<_> = (0..100)
+            .filter(|i| {
+                matches!(
+                    c.get_block(&id(*i)),
+                    Err(CogtainerError::IndexPageChecksumError)
+                )
+            })
+            .collect();
+        assert!((1..=8).contains(&missing.len()));
+        assert!(matches!(
+            c.get_blocks_list(),
+            Err(CogtainerError::IndexPageChecksumError)
+        ));
+        assert!(c
+            .verify()
+            .problems
+            .contains(&IntegrityProblem::CorruptIndexPage { offset }));
+
+        // the blocks on the page are lost, the rest of the container is fine
+        c.repair().unwrap();
+        assert!(c.verify().is_clean());
+        assert_eq!(c.get_blocks_list().unwrap().len(), 100 - missing.len());
+        assert!(c.get_block(&id(missing[0])).is_err());
+    }
+
+    #[test]
+    fn crash_while_committing() {
+        let base = paged_container();
+        let mut finished_after = None;
+        for writes_left in 0..20 {
+            let file = CrashingFile {
+                inner: Cursor::new(base.clone()),
+                writes_left,
+            };
+            let mut c = Cogtainer::open(file).unwrap();
+            let finished = c.insert_block(&id(1000), rmpv::Value::Nil, b"new").is_ok();
+
+            let buf = c.into_inner().unwrap().inner.into_inner();
+            let reopened = Cogtainer::open(buf)
+                .unwrap_or_else(|e| panic!("crash after {writes_left} writes: {e}"));
+            match reopened.get_block(&id(1000)) {
+                Ok((_, data)) => assert_eq!(data, b"new"),
+                Err(CogtainerError::BlockNotFound(_)) => assert!(!finished),
+                Err(e) => panic!("crash after {writes_left} writes: {e}"),
+            }
+            if finished {
+                finished_after.get_or_insert(writes_left);
+            }
+            assert_eq!(reopened.get_block(&id(51)).unwrap().1, vec![51; 64]);
+            assert!(reopened.verify().is_clean());
+        }
+        // every write of the commit was interrupted at least once
+        assert!(finished_after.is_some_and(|writes| writes > 2));
+    }
+}
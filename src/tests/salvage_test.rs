@@ -0,0 +1,182 @@
+#[cfg(test)]
+mod salvage_tests {
+    use crate::{basic_api::Cogtainer, container_file::*};
+
+    use std::io::{Cursor, Seek, SeekFrom, Write};
+
+    fn id(name: &str) -> Identifier {
+        Identifier::String(name.into())
+    }
+
+    fn inline_container() -> Cogtainer<Cursor<Vec<u8>>> {
+        let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+        c.set_inline_block_headers(true);
+        c
+    }
+
+    /// Zeroes the header and everything from the end of the block data onwards, which loses every
+    /// footer and header trailer.
+    fn lose_footers(c: Cogtainer<Cursor<Vec<u8>>>) -> Vec<u8> {
+        let data_end = c.header.footer_offset.0 as usize;
+        let mut buf = c.into_inner().unwrap().into_inner();
+        buf[..ContainerHeader::HEADER_SIZE].fill(0);
+        buf[data_end..].fill(0);
+        assert!(Cogtainer::open(Cursor::new(buf.clone())).is_err());
+        buf
+    }
+
+    #[test]
+    fn inline_headers_are_transparent() {
+        let mut c = inline_container();
+        c.insert_block(&id("a"), rmpv::Value::from(1), b"hello")
+            .unwrap();
+        c.insert_block(&id("empty"), rmpv::Value::Nil, b"").unwrap();
+        let descriptor = &c.get_blocks_list().unwrap()[&id("a")];
+        assert!(descriptor.header_length > 0);
+        assert_eq!(descriptor.capacity(), 5);
+
+        {
+            let mut f = c.get_block_as_file(&id("a"));
+            f.seek(SeekFrom::End(0)).unwrap();
+            f.write_all(b" world").unwrap();
+        }
+
+        let buf = c.into_inner().unwrap().into_inner();
+        let mut c = Cogtainer::open(Cursor::new(buf)).unwrap();
+        assert!(c.header.inline_block_headers());
+        assert_eq!(c.get_block(&id("a")).unwrap().1, b"hello world");
+        assert_eq!(c.get_block(&id("empty")).unwrap().1, b"");
+        assert!(c.verify().is_clean());
+
+        // only new blocks are affected by turning them off again
+        c.set_inline_block_headers(false);
+        c.insert_block(&id("b"), rmpv::Value::Nil, b"plain")
+            .unwrap();
+        assert_eq!(c.get_blocks_list().unwrap()[&id("b")].header_length, 0);
+        assert!(c.get_blocks_list().unwrap()[&id("a")].header_length > 0);
+    }
+
+    #[test]
+    fn salvage_recovers_the_newest_blocks() {
+        let mut c = inline_container();
+        c.insert_block(&id("a"), rmpv::Value::from("meta"), b"first version")
+            .unwrap();
+        c.insert_block(&id("b"), rmpv::Value::Nil, &[7; 100])
+            .unwrap();
+        c.insert_block(&id("a"), rmpv::Value::Nil, b"second, longer version")
+            .unwrap();
+        c.insert_block(&id("c"), rmpv::Value::Nil, b"c").unwrap();
+        {
+            let mut f = c.get_block_as_file(&id("c"));
+            f.seek(SeekFrom::End(0)).unwrap();
+            f.write_all(b"++").unwrap();
+        }
+        let buf = lose_footers(c);
+
+        let mut c = Cogtainer::salvage(Cursor::new(buf)).unwrap();
+        assert_eq!(c.get_blocks_list().unwrap().len(), 3);
+        assert_eq!(c.get_block(&id("a")).unwrap().1, b"second, longer version");
+        assert_eq!(c.get_block(&id("b")).unwrap().1, [7; 100]);
+        assert_eq!(c.get_block(&id("c")).unwrap().1, b"c++");
+        assert!(c.verify().is_clean());
+
+        // the salvaged container is usable, and its blocks win over the stale copies
+        c.insert_block(&id("a"), rmpv::Value::Nil, b"third")
+            .unwrap();
+        let buf = c.into_inner().unwrap().into_inner();
+        let c = Cogtainer::open(Cursor::new(buf.clone())).unwrap();
+        assert_eq!(c.get_block(&id("a")).unwrap().1, b"third");
+
+        let c = Cogtainer::salvage(Cursor::new(buf)).unwrap();
+        assert_eq!(c.get_block(&id("a")).unwrap().1, b"third");
+    }
+
+    #[test]
+    fn salvage_picks_the_newest_copy_after_the_generation_wraps() {
+        let mut c = inline_container();
+        c.header.generation = u32::MAX - 1;
+        c.insert_block(&id("a"), rmpv::Value::Nil, b"before the wrap")
+            .unwrap();
+        c.insert_block(&id("b"), rmpv::Value::Nil, &[7; 100])
+            .unwrap();
+        c.insert_block(&id("a"), rmpv::Value::Nil, b"after the wrap, longer")
+            .unwrap();
+        assert!(c.header.generation < 10);
+        let buf = lose_footers(c);
+
+        let mut c = Cogtainer::salvage(Cursor::new(buf)).unwrap();
+        assert_eq!(c.get_block(&id("a")).unwrap().1, b"after the wrap, longer");
+        assert_eq!(c.get_block(&id("b")).unwrap().1, [7; 100]);
+        // numbered after the newest block found, not after the largest number
+        assert!(c.header.generation < 10);
+        assert!(c.verify().is_clean());
+
+        c.insert_block(&id("a"), rmpv::Value::Nil, b"salvaged")
+            .unwrap();
+        let buf = lose_footers(c);
+        let c = Cogtainer::salvage(Cursor::new(buf)).unwrap();
+        assert_eq!(c.get_block(&id("a")).unwrap().1, b"salvaged");
+    }
+
+    #[test]
+    fn salvage_picks_the_newest_copy_written_before_the_same_commit() {
+        let mut c = inline_container();
+        c.set_durability_mode(DurabilityMode::WriteBack { flush_every: None });
+        c.insert_block(&id("x"), rmpv::Value::Nil, &[1; 64])
+            .unwrap();
+        c.insert_block(&id("z"), rmpv::Value::Nil, &[3; 16])
+            .unwrap();
+        c.delete_block(&id("x")).unwrap();
+        c.flush().unwrap();
+
+        // the second copy of "a" goes into the hole "x" left, below the first copy
+        c.insert_block(&id("a"), rmpv::Value::Nil, &[0xAA; 200])
+            .unwrap();
+        let first = c.get_blocks_list().unwrap()[&id("a")].file_offset;
+        c.insert_block(&id("w"), rmpv::Value::Nil, &[4; 100])
+            .unwrap();
+        c.insert_block(&id("a"), rmpv::Value::Nil, &[0xBB; 16])
+            .unwrap();
+        assert!(c.get_blocks_list().unwrap()[&id("a")].file_offset < first);
+        c.flush().unwrap();
+        let buf = lose_footers(c);
+
+        let c = Cogtainer::salvage(Cursor::new(buf)).unwrap();
+        assert_eq!(c.get_block(&id("a")).unwrap().1, [0xBB; 16]);
+        assert_eq!(c.get_block(&id("z")).unwrap().1, [3; 16]);
+    }
+
+    #[test]
+    fn salvage_skips_blocks_without_inline_headers() {
+        let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+        c.insert_block(&id("plain"), rmpv::Value::Nil, b"plain")
+            .unwrap();
+        c.set_inline_block_headers(true);
+        c.insert_block(&id("framed"), rmpv::Value::Nil, b"framed")
+            .unwrap();
+        let buf = lose_footers(c);
+
+        let c = Cogtainer::salvage(Cursor::new(buf)).unwrap();
+        assert_eq!(c.get_blocks_list().unwrap().len(), 1);
+        assert_eq!(c.get_block(&id("framed")).unwrap().1, b"framed");
+        assert!(c.verify().is_clean());
+    }
+
+    #[test]
+    fn salvage_ignores_containers_stored_in_blocks() {
+        let mut inner = inline_container();
+        inner
+            .insert_block(&id("nested"), rmpv::Value::Nil, b"nested")
+            .unwrap();
+        let inner = inner.into_inner().unwrap().into_inner();
+
+        let mut c = inline_container();
+        c.insert_block(&id("outer"), rmpv::Value::Nil, &inner)
+            .unwrap();
+        let buf = lose_footers(c);
+
+        let c = Cogtainer::salvage(Cursor::new(buf)).unwrap();
+        assert_eq!(c.get_blocks_list().unwrap().len(), 1);
+        assert_eq!(c.get_block(&id("outer")).unwrap().1, inner);
+    }
+}
@@ -0,0 +1,182 @@
+#[cfg(test)]
+mod scrub_tests {
+    use crate::{basic_api::Cogtainer, container_file::*, tests::CrashingFile, traits::Truncate};
+
+    use std::io::Cursor;
+
+    const SECRET: &[u8] = b"top secret payload";
+
+    fn id(i: u64) -> Identifier {
+        Identifier::U64(i)
+    }
+
+    fn secret() -> Vec<u8> {
+        SECRET.repeat(50)
+    }
+
+    fn contains(c: &Cogtainer<Cursor<Vec<u8>>>, needle: &[u8]) -> bool {
+        c.file
+            .get_ref()
+            .windows(needle.len())
+            .any(|window| window == needle)
+    }
+
+    /// A container with a secret block followed by another one, so its space isn't at the end.
+    fn with_secret(mode: ScrubMode) -> Cogtainer<Cursor<Vec<u8>>> {
+        let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+        c.set_secure_delete(mode);
+        c.insert_block(&id(1), rmpv::Value::Nil, &secret()).unwrap();
+        c.insert_block(&id(2), rmpv::Value::Nil, b"after").unwrap();
+        assert!(contains(&c, SECRET));
+        c
+    }
+
+    #[test]
+    fn deleted_blocks_are_overwritten_on_flush() {
+        let mut c = with_secret(ScrubMode::Off);
+        c.delete_block(&id(1)).unwrap();
+        c.flush().unwrap();
+        assert!(contains(&c, SECRET));
+
+        let mut c = with_secret(ScrubMode::Zeros);
+        let block = c.get_blocks_list().unwrap()[&id(1)].clone();
+        c.delete_block(&id(1)).unwrap();
+        c.flush().unwrap();
+        assert!(!contains(&c, SECRET));
+        let start = block.file_offset.0 as usize;
+        let buf = c.file.get_ref();
+        assert!(buf[start..start + block.allocated_length as usize]
+            .iter()
+            .all(|b| *b == 0));
+        assert_eq!(c.get_block(&id(2)).unwrap().1, b"after");
+        assert!(c.verify().is_clean());
+    }
+
+    #[test]
+    fn replaced_blocks_are_overwritten() {
+        let mut c = with_secret(ScrubMode::Random);
+        let block = c.get_blocks_list().unwrap()[&id(1)].clone();
+        // too big for the old allocation, so it moves
+        c.insert_block(&id(1), rmpv::Value::Nil, &[1; 5000])
+            .unwrap();
+        assert!(!contains(&c, SECRET));
+        let start = block.file_offset.0 as usize;
+        let old = &c.file.get_ref()[start..start + block.allocated_length as usize];
+        assert!(old.iter().any(|b| *b != 0));
+
+        let buf = c.into_inner().unwrap().into_inner();
+        let c = Cogtainer::open(Cursor::new(buf)).unwrap();
+        assert_eq!(c.get_block(&id(1)).unwrap().1, vec![1; 5000]);
+        assert_eq!(c.get_block(&id(2)).unwrap().1, b"after");
+    }
+
+    #[test]
+    fn freed_space_is_reused_once_scrubbed() {
+        let mut c = with_secret(ScrubMode::Zeros);
+        c.set_durability_mode(DurabilityMode::WriteBack { flush_every: None });
+        let offset = c.get_blocks_list().unwrap()[&id(1)].file_offset;
+        c.delete_block(&id(1)).unwrap();
+        // the committed footer still refers to the space
+        c.insert_block(&id(3), rmpv::Value::Nil, b"new data")
+            .unwrap();
+        assert_ne!(c.get_blocks_list().unwrap()[&id(3)].file_offset, offset);
+        c.flush().unwrap();
+        assert!(!contains(&c, SECRET));
+
+        c.insert_block(&id(4), rmpv::Value::Nil, b"new data in the hole")
+            .unwrap();
+        assert_eq!(c.get_blocks_list().unwrap()[&id(4)].file_offset, offset);
+        c.flush().unwrap();
+        assert_eq!(c.get_block(&id(4)).unwrap().1, b"new data in the hole");
+        assert!(c.verify().is_clean());
+    }
+
+    #[test]
+    fn moved_and_shrunk_blocks() {
+        let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+        c.set_secure_delete(ScrubMode::Zeros);
+        c.insert_block(&id(0), rmpv::Value::Nil, &[0; 100]).unwrap();
+        c.insert_block(&id(1), rmpv::Value::Nil, &secret()).unwrap();
+        c.insert_block(&id(2), rmpv::Value::Nil, b"after").unwrap();
+        c.delete_block(&id(0)).unwrap();
+        // defragmenting copies the block down, the copy stays readable
+        c.defragment().unwrap();
+        assert_eq!(c.get_block(&id(1)).unwrap().1, secret());
+        assert_eq!(
+            c.file
+                .get_ref()
+                .windows(SECRET.len())
+                .filter(|window| *window == SECRET)
+                .count(),
+            50
+        );
+
+        c.get_block_as_file(&id(1))
+            .truncate(SECRET.len() as u64)
+            .unwrap();
+        c.flush().unwrap();
+        assert_eq!(
+            c.file
+                .get_ref()
+                .windows(SECRET.len())
+                .filter(|window| *window == SECRET)
+                .count(),
+            1
+        );
+        assert!(c.verify().is_clean());
+    }
+
+    #[test]
+    fn scrub_free_space_cleans_existing_files() {
+        let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+        let name = Identifier::String("secret-name".into());
+        c.insert_block(&name, rmpv::Value::Nil, &secret()).unwrap();
+        c.insert_block(&id(2), rmpv::Value::Nil, b"after").unwrap();
+        c.insert_block(&id(3), rmpv::Value::Nil, &[3; 2000])
+            .unwrap();
+        c.delete_block(&name).unwrap();
+        c.delete_block(&id(3)).unwrap();
+        c.flush().unwrap();
+        assert!(contains(&c, SECRET));
+        assert!(contains(&c, b"secret-name"));
+
+        c.scrub_free_space().unwrap();
+        assert!(!contains(&c, SECRET));
+        // old footers still listed the deleted block
+        assert!(!contains(&c, b"secret-name"));
+
+        let buf = c.into_inner().unwrap().into_inner();
+        let c = Cogtainer::open(Cursor::new(buf)).unwrap();
+        assert_eq!(c.get_block(&id(2)).unwrap().1, b"after");
+        assert!(c.verify().is_clean());
+    }
+
+    #[test]
+    fn crashing_while_scrubbing() {
+        let base = with_secret(ScrubMode::Zeros)
+            .into_inner()
+            .unwrap()
+            .into_inner();
+        for writes_left in 0.. {
+            let file = CrashingFile {
+                inner: Cursor::new(base.clone()),
+                writes_left,
+            };
+            let mut c = Cogtainer::open(file).unwrap();
+            c.set_secure_delete(ScrubMode::Zeros);
+            let finished = c.delete_block(&id(1)).and_then(|c| c.flush()).is_ok();
+
+            let buf = c.into_inner().unwrap().inner.into_inner();
+            let reopened = Cogtainer::open(Cursor::new(buf))
+                .unwrap_or_else(|e| panic!("crash after {writes_left} writes: {e}"));
+            assert_eq!(reopened.get_block(&id(2)).unwrap().1, b"after");
+            if reopened.get_blocks_list().unwrap().contains_key(&id(1)) {
+                assert_eq!(reopened.get_block(&id(1)).unwrap().1, secret());
+            }
+            if finished {
+                assert!(!reopened.get_blocks_list().unwrap().contains_key(&id(1)));
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,251 @@
+#[cfg(test)]
+mod shared_tests {
+    use crate::{
+        basic_api::{BlockCompression, Cogtainer},
+        container_file::*,
+        error::CogtainerError,
+        shared::SharedCogtainer,
+    };
+
+    use std::{
+        io::{Cursor, Read, Write},
+        sync::{mpsc, Arc, Barrier},
+        thread,
+        time::Duration,
+    };
+
+    fn id(i: u64) -> Identifier {
+        Identifier::U64(i)
+    }
+
+    #[test]
+    fn is_clone_send_and_sync() {
+        fn check<T: Clone + Send + Sync>() {}
+        check::<SharedCogtainer<std::fs::File>>();
+        check::<SharedCogtainer<Cursor<Vec<u8>>>>();
+    }
+
+    #[test]
+    fn readers_and_a_writer() {
+        let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+        for i in 0..10 {
+            c.insert_block(&id(i), rmpv::Value::from(i), &[i as u8; 1000])
+                .unwrap();
+        }
+        let shared = SharedCogtainer::new(c);
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    for _ in 0..200 {
+                        for i in 0..10 {
+                            // every block is either the old or the new version, never a mix
+                            let (metadata, data) = shared.get_block(&id(i)).unwrap();
+                            let version = metadata.as_u64().unwrap();
+                            assert!(version == i || version == i + 100);
+                            assert_eq!(data, vec![version as u8; 1000]);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for i in 0..10 {
+            shared
+                .insert_block(&id(i), rmpv::Value::from(i + 100), &[i as u8 + 100; 1000])
+                .unwrap();
+        }
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        let c = shared.try_unwrap().unwrap().unwrap();
+        assert!(c.verify().is_clean());
+        for i in 0..10 {
+            assert_eq!(c.get_block(&id(i)).unwrap().1, vec![i as u8 + 100; 1000]);
+        }
+    }
+
+    #[test]
+    fn streaming_holds_a_consistent_view() {
+        let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+        c.insert_block(&id(1), rmpv::Value::Nil, &[1; 200_000])
+            .unwrap();
+        let shared = SharedCogtainer::new(c);
+        let started = Arc::new(Barrier::new(2));
+        let changed = Arc::new(Barrier::new(2));
+
+        let reader = {
+            let shared = shared.clone();
+            let (started, changed) = (Arc::clone(&started), Arc::clone(&changed));
+            thread::spawn(move || {
+                let container = shared.read().unwrap();
+                let mut reader = container.block_reader(&id(1)).unwrap();
+                started.wait();
+                changed.wait();
+                let mut data = vec![];
+                reader.read_to_end(&mut data).unwrap();
+                data
+            })
+        };
+        started.wait();
+        // doesn't wait for the reader, whose snapshot keeps the block's space from being reused
+        shared.delete_block(&id(1)).unwrap();
+        shared.flush().unwrap();
+        shared
+            .insert_block(&id(2), rmpv::Value::Nil, &[2; 200_000])
+            .unwrap();
+        changed.wait();
+
+        assert_eq!(reader.join().unwrap(), vec![1; 200_000]);
+        assert!(shared.get_block(&id(1)).is_err());
+        assert_eq!(shared.get_block(&id(2)).unwrap().1, vec![2; 200_000]);
+    }
+
+    #[test]
+    fn typed_blocks_and_the_writer_lock() {
+        let shared = SharedCogtainer::from(Cogtainer::create(Cursor::new(vec![])).unwrap());
+        shared
+            .write()
+            .unwrap()
+            .insert_block_as(
+                &id(1),
+                BlockCompression::Gzip(6),
+                &"meta",
+                &vec![1u32, 2, 3],
+            )
+            .unwrap();
+        {
+            let mut container = shared.write().unwrap();
+            let mut tx = container.transaction().unwrap();
+            tx.insert_block(&id(2), rmpv::Value::Nil, b"in a transaction")
+                .unwrap();
+            tx.commit().unwrap();
+        }
+
+        let other = shared.clone();
+        let (metadata, data): (String, Vec<u32>) = other.get_as(&id(1)).unwrap();
+        assert_eq!((metadata.as_str(), data), ("meta", vec![1, 2, 3]));
+        assert_eq!(other.get_block(&id(2)).unwrap().1, b"in a transaction");
+
+        // still shared, so the handle comes back
+        let shared = shared.try_unwrap().unwrap().unwrap_err();
+        drop(other);
+        // so does a snapshot that is still held
+        let snapshot = shared.read().unwrap();
+        let shared = shared.try_unwrap().unwrap().unwrap_err();
+        drop(snapshot);
+        assert!(shared.try_unwrap().unwrap().is_ok());
+    }
+
+    #[test]
+    fn readers_are_not_held_up_by_the_writer() {
+        let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+        c.set_durability_mode(DurabilityMode::WriteBack { flush_every: None });
+        c.insert_block(&id(1), rmpv::Value::Nil, b"committed")
+            .unwrap();
+        c.flush().unwrap();
+        let shared = SharedCogtainer::new(c);
+
+        let mut writer = shared.write().unwrap();
+        writer
+            .insert_block(&id(2), rmpv::Value::Nil, b"pending")
+            .unwrap();
+        let (sender, receiver) = mpsc::channel();
+        {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                let read = (shared.get_block(&id(1)), shared.get_block(&id(2)));
+                sender.send(read).unwrap();
+            });
+        }
+        let (committed, pending) = receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(committed.unwrap().1, b"committed");
+        // readers see the latest commit
+        assert!(pending.is_err());
+
+        writer.flush().unwrap();
+        drop(writer);
+        assert_eq!(shared.get_block(&id(2)).unwrap().1, b"pending");
+    }
+
+    #[test]
+    fn held_snapshots_keep_freed_space() {
+        let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+        c.insert_block(&id(1), rmpv::Value::Nil, &[1; 1000])
+            .unwrap();
+        c.insert_block(&id(2), rmpv::Value::Nil, b"after").unwrap();
+        let offset = c.get_blocks_list().unwrap()[&id(1)].file_offset;
+        let shared = SharedCogtainer::new(c);
+
+        let snapshot = shared.read().unwrap();
+        shared.delete_block(&id(1)).unwrap();
+        shared
+            .insert_block(&id(3), rmpv::Value::Nil, &[3; 1000])
+            .unwrap();
+        let moved = shared.read().unwrap().get_blocks_list().unwrap()[&id(3)].file_offset;
+        assert!(moved > offset);
+        assert!(snapshot.is_current());
+        assert_eq!(snapshot.get_block(&id(1)).unwrap().1, [1; 1000]);
+        assert!(shared.get_block(&id(1)).is_err());
+        drop(snapshot);
+
+        // once no snapshot is held, the next commit frees the space
+        shared.delete_block(&id(3)).unwrap();
+        shared.flush().unwrap();
+        shared
+            .insert_block(&id(4), rmpv::Value::Nil, &[4; 1000])
+            .unwrap();
+        let c = shared.try_unwrap().unwrap().unwrap();
+        assert_eq!(c.get_blocks_list().unwrap()[&id(4)].file_offset, offset);
+        assert!(c.verify().is_clean());
+    }
+
+    #[test]
+    fn blocks_written_in_place_are_read_under_the_writer_lock() {
+        let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+        c.set_durability_mode(DurabilityMode::WriteBack { flush_every: None });
+        c.insert_block(&id(1), rmpv::Value::Nil, b"original")
+            .unwrap();
+        c.flush().unwrap();
+        let shared = SharedCogtainer::new(c);
+
+        {
+            let mut writer = shared.write().unwrap();
+            let mut f = writer.get_block_as_file(&id(1));
+            f.write_all(b"ORIG").unwrap();
+        }
+        // the snapshot's checksum no longer matches the data
+        assert!(shared.read().unwrap().get_block(&id(1)).is_err());
+        assert_eq!(shared.get_block(&id(1)).unwrap().1, b"ORIGinal");
+    }
+
+    #[test]
+    fn poisoned_locks_are_errors() {
+        let shared = SharedCogtainer::new(Cogtainer::create(Cursor::new(vec![])).unwrap());
+        shared
+            .insert_block(&id(1), rmpv::Value::Nil, b"data")
+            .unwrap();
+        {
+            let shared = shared.clone();
+            let panicked = thread::spawn(move || {
+                let _writer = shared.write().unwrap();
+                panic!("while holding the writer lock");
+            })
+            .join();
+            assert!(panicked.is_err());
+        }
+
+        assert!(matches!(shared.write(), Err(CogtainerError::LockPoisoned)));
+        assert!(matches!(
+            shared.insert_block(&id(2), rmpv::Value::Nil, b"more"),
+            Err(CogtainerError::LockPoisoned)
+        ));
+        // the committed snapshot is still readable
+        assert_eq!(shared.get_block(&id(1)).unwrap().1, b"data");
+        assert!(matches!(
+            shared.try_unwrap(),
+            Err(CogtainerError::LockPoisoned)
+        ));
+    }
+}
@@ -0,0 +1,79 @@
+#[cfg(test)]
+mod slice_tests {
+    use crate::{basic_api::Cogtainer, container_file::*, error::CogtainerError};
+
+    use std::io::ErrorKind;
+
+    fn id(i: u64) -> Identifier {
+        Identifier::U64(i)
+    }
+
+    fn container_bytes() -> Vec<u8> {
+        let mut c = Cogtainer::create(vec![]).unwrap();
+        c.set_metadata(rmpv::Value::from("embedded")).unwrap();
+        c.insert_block(&id(1), rmpv::Value::from(1), b"first")
+            .unwrap();
+        c.insert_block(&id(2), rmpv::Value::Nil, b"").unwrap();
+        c.into_inner().unwrap()
+    }
+
+    #[test]
+    fn borrowed_slice() {
+        let data = container_bytes();
+        let c = Cogtainer::from_slice(&data).unwrap();
+        assert_eq!(c.get_container_metadata(), &rmpv::Value::from("embedded"));
+        let (metadata, block) = c.get_block_ref(&id(1)).unwrap();
+        assert_eq!((metadata, block), (&rmpv::Value::from(1), &b"first"[..]));
+        assert!(data.as_ptr_range().contains(&block.as_ptr()));
+        assert!(c.get_block_ref(&id(2)).unwrap().1.is_empty());
+        assert!(c.verify().is_clean());
+    }
+
+    #[test]
+    fn descriptors_past_the_end_are_refused() {
+        let data = container_bytes();
+        let mut c = Cogtainer::from_slice(&data).unwrap();
+        for (file_offset, header_length, used_length) in [
+            (FileOffset(u64::MAX), 2, 5),
+            (FileOffset(16), 0, u64::MAX),
+            (FileOffset(data.len() as u64 - 2), 0, 5),
+        ] {
+            let descriptor = c.footer.blocks.get_mut(&id(1)).unwrap();
+            descriptor.file_offset = file_offset;
+            descriptor.header_length = header_length;
+            descriptor.used_length = used_length;
+            assert!(matches!(
+                c.get_block_ref(&id(1)),
+                Err(CogtainerError::IOError(e)) if e.kind() == ErrorKind::UnexpectedEof
+            ));
+        }
+    }
+
+    #[test]
+    fn changes_are_refused() {
+        let data = container_bytes();
+        let mut c = Cogtainer::from_slice(&data).unwrap();
+        for result in [
+            c.insert_block(&id(3), rmpv::Value::Nil, b"new").map(|_| ()),
+            c.delete_block(&id(1)).and_then(|c| c.flush()).map(|_| ()),
+        ] {
+            assert!(
+                matches!(result, Err(CogtainerError::IOError(e)) if e.kind() == ErrorKind::PermissionDenied)
+            );
+        }
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn shared_bytes() {
+        let data = bytes::Bytes::from(container_bytes());
+        let c = Cogtainer::from_bytes(data.clone()).unwrap();
+        let (_, block) = c.get_block_bytes(&id(1)).unwrap();
+        let (_, empty) = c.get_block_bytes(&id(2)).unwrap();
+        drop(c);
+        // still valid, and still pointing into the original buffer
+        assert_eq!(block, &b"first"[..]);
+        assert!(data.as_ptr_range().contains(&block.as_ptr()));
+        assert!(empty.is_empty());
+    }
+}
@@ -0,0 +1,202 @@
+#[cfg(test)]
+mod sparse_tests {
+    use crate::{basic_api::Cogtainer, container_file::*, storage::Storage, traits::Sparse};
+
+    use std::io::{self, Cursor};
+
+    /// A buffer that records the space it was asked to preallocate and punch.
+    #[derive(Default)]
+    struct Recorder {
+        buf: Vec<u8>,
+        preallocated: Vec<(u64, u64)>,
+        punched: Vec<(u64, u64)>,
+    }
+    impl Storage for Recorder {
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+            self.buf.read_at(buf, offset)
+        }
+        fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<usize> {
+            self.buf.write_at(buf, offset)
+        }
+        fn len(&self) -> io::Result<u64> {
+            Storage::len(&self.buf)
+        }
+        fn set_len(&mut self, len: u64) -> io::Result<()> {
+            Storage::set_len(&mut self.buf, len)
+        }
+        fn sync(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+        fn preallocate(&mut self, offset: u64, len: u64) -> io::Result<()> {
+            self.preallocated.push((offset, len));
+            Ok(())
+        }
+    }
+    impl Sparse for Recorder {
+        fn punch_hole(&mut self, offset: u64, len: u64) -> io::Result<()> {
+            self.punched.push((offset, len));
+            Ok(())
+        }
+    }
+
+    fn id(i: u64) -> Identifier {
+        Identifier::U64(i)
+    }
+
+    fn data(i: u64) -> Vec<u8> {
+        vec![i as u8 + 1; 20_000]
+    }
+
+    /// Blocks 0 to 5, with 1 to 3 deleted and the change flushed.
+    fn with_hole<F>(c: &mut Cogtainer<F>) -> (FileOffset, u64)
+    where
+        F: crate::storage::Storage,
+    {
+        for i in 0..6 {
+            c.insert_block(&id(i), rmpv::Value::Nil, &data(i)).unwrap();
+        }
+        for i in 1..4 {
+            c.delete_block(&id(i)).unwrap();
+        }
+        c.flush().unwrap();
+        let (offset, len) = c.footer.empty_space.iter().next().unwrap();
+        (*offset, *len)
+    }
+
+    #[test]
+    fn whole_pages_of_holes_are_punched() {
+        let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+        let (offset, len) = with_hole(&mut c);
+        // off by default
+        assert!(c.file.get_ref()[offset.0 as usize..][..len as usize]
+            .iter()
+            .all(|b| *b != 0));
+
+        c.set_punch_holes(true);
+        c.flush().unwrap();
+        let start = offset.0.next_multiple_of(4096) as usize;
+        let end = (offset.0 + len) as usize / 4096 * 4096;
+        let buf = c.file.get_ref();
+        assert!(buf[start..end].iter().all(|b| *b == 0));
+        // the partial pages at either end are left alone
+        assert_ne!(buf[start - 1], 0);
+        assert_ne!(buf[end], 0);
+
+        for i in [0, 4, 5] {
+            assert_eq!(c.get_block(&id(i)).unwrap().1, data(i));
+        }
+        assert!(c.verify().is_clean());
+    }
+
+    #[test]
+    fn only_newly_freed_space_is_punched() {
+        let mut c = Cogtainer::create(Recorder::default()).unwrap();
+        c.set_punch_holes(true);
+        let (offset, len) = with_hole(&mut c);
+        let punched = std::mem::take(&mut c.file.punched);
+        let start = offset.0.next_multiple_of(4096);
+        assert_eq!(punched, [(start, (offset.0 + len) / 4096 * 4096 - start)]);
+
+        c.insert_block(&id(6), rmpv::Value::Nil, &data(6)).unwrap();
+        c.flush().unwrap();
+        // nothing was freed, so the hole isn't punched again
+        assert!(c
+            .file
+            .punched
+            .iter()
+            .all(|(o, _)| *o < offset.0 || *o >= offset.0 + len));
+    }
+
+    #[test]
+    fn growth_is_preallocated() {
+        let mut c = Cogtainer::create(Recorder::default()).unwrap();
+        c.insert_block(&id(0), rmpv::Value::Nil, &data(0)).unwrap();
+        assert!(c.file.preallocated.is_empty());
+
+        c.preallocate(64 * 1024).unwrap();
+        for i in 1..8 {
+            c.insert_block(&id(i), rmpv::Value::Nil, &data(i)).unwrap();
+        }
+        let preallocated = &c.file.preallocated;
+        // reserved in steps, not for every block
+        assert!(preallocated.len() > 1 && preallocated.len() < 7);
+        for pair in preallocated.windows(2) {
+            assert_eq!(pair[0].0 + pair[0].1, pair[1].0);
+        }
+        let (offset, len) = preallocated.last().unwrap();
+        assert!(offset + len >= c.header.footer_offset.0);
+
+        // punching holes preallocates as well
+        let mut c = Cogtainer::create(Recorder::default()).unwrap();
+        c.set_punch_holes(true);
+        c.insert_block(&id(0), rmpv::Value::Nil, &data(0)).unwrap();
+        assert_eq!(c.file.preallocated.len(), 1);
+    }
+
+    #[test]
+    fn streamed_blocks_are_preallocated() {
+        use std::io::Write;
+
+        let mut c = Cogtainer::create(Recorder::default()).unwrap();
+        c.preallocate(64 * 1024).unwrap();
+        let mut w = c.block_writer(&id(0), rmpv::Value::Nil).unwrap();
+        for _ in 0..64 {
+            w.write_all(&[5; 16 * 1024]).unwrap();
+        }
+        w.finish().unwrap();
+
+        // every growth of the block in place reserves ahead of it
+        let preallocated = &c.file.preallocated;
+        assert!(preallocated.len() > 2);
+        for pair in preallocated.windows(2) {
+            assert_eq!(pair[0].0 + pair[0].1, pair[1].0);
+        }
+        let (offset, len) = preallocated.last().unwrap();
+        assert!(offset + len >= c.header.footer_offset.0);
+        assert_eq!(c.get_block(&id(0)).unwrap().1, vec![5; 1 << 20]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn files_give_back_disk_space() {
+        use std::os::unix::fs::MetadataExt;
+
+        let path = std::env::temp_dir().join(format!("cogtainer-sparse-{}", std::process::id()));
+        let file = std::fs::File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        let mut c = Cogtainer::create(file).unwrap();
+        c.preallocate(1 << 20).unwrap();
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert!(metadata.blocks() * 512 >= 1 << 20);
+        assert!(metadata.len() < 1 << 20);
+
+        with_hole(&mut c);
+        c.file.sync_all().unwrap();
+        let before = std::fs::metadata(&path).unwrap();
+        c.set_punch_holes(true);
+        c.flush().unwrap();
+        c.file.sync_all().unwrap();
+        let after = std::fs::metadata(&path).unwrap();
+        assert_eq!(after.len(), before.len());
+        assert!(after.blocks() < before.blocks());
+        drop(c);
+
+        let file = std::fs::File::options()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let c = Cogtainer::open(file).unwrap();
+        for i in [0, 4, 5] {
+            assert_eq!(c.get_block(&id(i)).unwrap().1, data(i));
+        }
+        assert!(c.verify().is_clean());
+        drop(c);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,86 @@
+#[cfg(test)]
+mod storage_tests {
+    use crate::{
+        basic_api::Cogtainer,
+        container_file::*,
+        storage::{SeekStorage, Storage},
+    };
+
+    use std::io::{Cursor, ErrorKind};
+
+    fn id(i: u64) -> Identifier {
+        Identifier::U64(i)
+    }
+
+    fn round_trip<S: Storage>(mut storage: S) -> S {
+        assert!(storage.is_empty().unwrap());
+        storage.write_all_at(b"world", 6).unwrap();
+        storage.write_all_at(b"hello", 0).unwrap();
+        assert_eq!(storage.len().unwrap(), 11);
+
+        let mut buf = [0u8; 11];
+        storage.read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(&buf, b"hello\0world");
+        // reading past the end stops short
+        assert_eq!(storage.read_at(&mut buf, 8).unwrap(), 3);
+        assert_eq!(storage.read_at(&mut buf, 20).unwrap(), 0);
+        assert_eq!(
+            storage.read_exact_at(&mut buf, 8).unwrap_err().kind(),
+            ErrorKind::UnexpectedEof
+        );
+
+        storage.set_len(16).unwrap();
+        assert_eq!(storage.len().unwrap(), 16);
+        storage.sync().unwrap();
+        storage
+    }
+
+    #[test]
+    fn implementations() {
+        let mut vec = round_trip(vec![]);
+        Storage::set_len(&mut vec, 5).unwrap();
+        assert_eq!(vec, b"hello");
+
+        let cursor = round_trip(Cursor::new(vec![]));
+        assert_eq!(&cursor.get_ref()[..5], b"hello");
+
+        let mut seek = round_trip(SeekStorage::new(Cursor::new(vec![])));
+        assert_eq!(seek.set_len(5).unwrap_err().kind(), ErrorKind::Unsupported);
+        assert_eq!(&seek.into_inner().into_inner()[..5], b"hello");
+
+        let path = std::env::temp_dir().join(format!("cogtainer-storage-{}", std::process::id()));
+        let file = std::fs::File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        let file = round_trip(file);
+        file.set_len(5).unwrap();
+        assert_eq!(Storage::len(&file).unwrap(), 5);
+        drop(file);
+        assert_eq!(std::fs::read(&path).unwrap(), b"hello");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn containers_over_vec_and_seek_storage() {
+        let mut c = Cogtainer::create(vec![]).unwrap();
+        c.insert_block(&id(1), rmpv::Value::Nil, b"in a vec")
+            .unwrap();
+        let buf = c.into_inner().unwrap();
+        let c = Cogtainer::open(buf.clone()).unwrap();
+        assert_eq!(c.get_block(&id(1)).unwrap().1, b"in a vec");
+
+        let mut c = Cogtainer::open(SeekStorage::new(Cursor::new(buf))).unwrap();
+        c.insert_block(&id(2), rmpv::Value::Nil, b"seeked").unwrap();
+        c.delete_block(&id(1)).unwrap().flush().unwrap();
+        assert!(c.verify().is_clean());
+        let buf = c.into_inner().unwrap().into_inner().into_inner();
+
+        let c = Cogtainer::open(buf).unwrap();
+        assert_eq!(c.get_blocks_list().unwrap().len(), 1);
+        assert_eq!(c.get_block(&id(2)).unwrap().1, b"seeked");
+    }
+}
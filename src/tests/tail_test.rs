@@ -0,0 +1,135 @@
+#[cfg(test)]
+mod tail_tests {
+    use crate::{basic_api::Cogtainer, container_file::*, tests::CrashingFile};
+
+    use std::io::Cursor;
+
+    fn id(i: u64) -> Identifier {
+        Identifier::U64(i)
+    }
+
+    #[test]
+    fn footer_moves_into_trailing_free_space() {
+        let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+        for i in 0..4 {
+            c.insert_block(&id(i), rmpv::Value::Nil, &[i as u8; 1000])
+                .unwrap();
+        }
+        let second_end = {
+            let block = &c.get_blocks_list().unwrap()[&id(1)];
+            block.file_offset.end_offset(block.allocated_length)
+        };
+
+        // a hole in the middle stays
+        c.delete_block(&id(2)).unwrap();
+        c.flush().unwrap();
+        assert_eq!(c.footer.empty_space.len(), 1);
+
+        // and joins the freed space at the end, which the footer moves into
+        c.delete_block(&id(3)).unwrap();
+        c.flush().unwrap();
+        assert!(c.footer.empty_space.is_empty());
+        assert_eq!(c.header.footer_offset, second_end);
+        assert_eq!(c.header.committed_footer().offset, second_end);
+        assert!(c.verify().is_clean());
+
+        let buf = c.into_inner().unwrap().into_inner();
+        let c = Cogtainer::open(Cursor::new(buf)).unwrap();
+        assert_eq!(c.header.footer_offset, second_end);
+        for i in 0..2 {
+            assert_eq!(c.get_block(&id(i)).unwrap().1, vec![i as u8; 1000]);
+        }
+    }
+
+    #[test]
+    fn write_back_and_transactions() {
+        let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+        c.set_durability_mode(DurabilityMode::WriteBack { flush_every: None });
+        c.insert_block(&id(0), rmpv::Value::Nil, b"kept").unwrap();
+        let data_end = c.header.footer_offset;
+        for i in 1..10 {
+            c.insert_block(&id(i), rmpv::Value::Nil, &[1; 100]).unwrap();
+        }
+        for i in 1..10 {
+            c.delete_block(&id(i)).unwrap();
+        }
+        c.flush().unwrap();
+        assert_eq!(c.header.footer_offset, data_end);
+
+        c.set_durability_mode(DurabilityMode::WriteThrough);
+        c.insert_block(&id(1), rmpv::Value::Nil, &[1; 100]).unwrap();
+        let mut tx = c.transaction().unwrap();
+        tx.delete_block(&id(1)).unwrap();
+        tx.commit().unwrap();
+        // the journal's space is reclaimed too
+        assert_eq!(c.header.footer_offset, data_end);
+        assert!(c.verify().is_clean());
+    }
+
+    #[test]
+    fn truncate_on_flush_bounds_the_file() {
+        let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+        c.set_truncate_on_flush(true);
+        c.insert_block(&id(0), rmpv::Value::Nil, b"kept").unwrap();
+        c.flush().unwrap();
+        let length = c.get_inner_file().get_ref().len();
+        assert_eq!(length as u64, c.file_length());
+
+        // an append-and-delete cache
+        for i in 1..50 {
+            c.insert_block(&id(i), rmpv::Value::Nil, &vec![i as u8; 10_000])
+                .unwrap();
+            assert_eq!(c.get_block(&id(i)).unwrap().1, vec![i as u8; 10_000]);
+            c.delete_block(&id(i)).unwrap();
+            c.flush().unwrap();
+            assert_eq!(c.get_inner_file().get_ref().len(), length);
+        }
+
+        let buf = c.into_inner().unwrap().into_inner();
+        let c = Cogtainer::open(Cursor::new(buf)).unwrap();
+        assert_eq!(c.get_block(&id(0)).unwrap().1, b"kept");
+        assert_eq!(c.get_blocks_list().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn crashing_while_reclaiming() {
+        let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+        for i in 0..3 {
+            c.insert_block(&id(i), rmpv::Value::Nil, &[i as u8; 2000])
+                .unwrap();
+        }
+        let base = c.into_inner().unwrap().into_inner();
+
+        for writes_left in 0.. {
+            let file = CrashingFile {
+                inner: Cursor::new(base.clone()),
+                writes_left,
+            };
+            let mut c = Cogtainer::open(file).unwrap();
+            let finished = c
+                .delete_block(&id(2))
+                .and_then(|c| c.delete_block(&id(1)))
+                .and_then(|c| c.flush())
+                .is_ok();
+
+            let buf = c.into_inner().unwrap().inner.into_inner();
+            let reopened = Cogtainer::open(Cursor::new(buf))
+                .unwrap_or_else(|e| panic!("crash after {writes_left} writes: {e}"));
+            for i in 0..3 {
+                if reopened.get_blocks_list().unwrap().contains_key(&id(i)) {
+                    assert_eq!(
+                        reopened.get_block(&id(i)).unwrap().1,
+                        vec![i as u8; 2000],
+                        "crash after {writes_left} writes"
+                    );
+                } else {
+                    assert!(i > 0);
+                }
+            }
+            if finished {
+                assert_eq!(reopened.get_blocks_list().unwrap().len(), 1);
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,190 @@
+#[cfg(test)]
+mod transaction_tests {
+    use crate::{
+        basic_api::Cogtainer, container_file::*, error::CogtainerError, storage::Storage,
+        tests::CrashingFile,
+    };
+
+    use std::io::Cursor;
+
+    fn open_new_container() -> Cogtainer<Cursor<Vec<u8>>> {
+        let file = Cursor::new(vec![0u8; 64 * 1024]);
+        Cogtainer::create(file).unwrap()
+    }
+
+    /// Blocks 0, 1 and 2, each filled with its own id.
+    fn base_container() -> Cogtainer<Cursor<Vec<u8>>> {
+        let mut c = open_new_container();
+        for i in 0..3 {
+            c.insert_block(&Identifier::U64(i), rmpv::Value::from(i), &[i as u8; 16])
+                .unwrap();
+        }
+        c
+    }
+
+    /// Replaces 0, deletes 1, adds 3, and updates metadata for 2 and the container.
+    fn run_transaction<F: Storage>(c: &mut Cogtainer<F>) -> Result<(), CogtainerError> {
+        let mut tx = c.transaction()?;
+        tx.insert_block(&Identifier::U64(0), rmpv::Value::from("new"), &[0xAA; 40])?
+            .delete_block(&Identifier::U64(1))?
+            .insert_block(&Identifier::U64(3), rmpv::Value::Nil, &[3; 8])?
+            .update_block_metadata(&Identifier::U64(2), rmpv::Value::from("two"))
+            .set_metadata(rmpv::Value::from("after"));
+        tx.commit()
+    }
+
+    fn assert_before<F: Storage>(c: &Cogtainer<F>) {
+        assert_eq!(c.get_container_metadata(), &rmpv::Value::Nil);
+        for i in 0..3u64 {
+            let (meta, data) = c.get_block(&Identifier::U64(i)).unwrap();
+            assert_eq!(meta, &rmpv::Value::from(i));
+            assert_eq!(data, vec![i as u8; 16]);
+        }
+        assert!(c.get_block(&Identifier::U64(3)).is_err());
+    }
+
+    fn assert_after<F: Storage>(c: &Cogtainer<F>) {
+        assert_eq!(c.get_container_metadata(), &rmpv::Value::from("after"));
+        let (meta, data) = c.get_block(&Identifier::U64(0)).unwrap();
+        assert_eq!(meta, &rmpv::Value::from("new"));
+        assert_eq!(data, vec![0xAA; 40]);
+        assert!(matches!(
+            c.get_block(&Identifier::U64(1)),
+            Err(CogtainerError::BlockNotFound(_))
+        ));
+        let (meta, data) = c.get_block(&Identifier::U64(2)).unwrap();
+        assert_eq!(meta, &rmpv::Value::from("two"));
+        assert_eq!(data, vec![2; 16]);
+        assert_eq!(c.get_block(&Identifier::U64(3)).unwrap().1, vec![3; 8]);
+    }
+
+    #[test]
+    fn commit_applies_every_change() {
+        let mut c = base_container();
+        run_transaction(&mut c).unwrap();
+        assert_after(&c);
+        assert!(c.footer.journal.is_none());
+
+        let buf = c.into_inner().unwrap().into_inner();
+        let c2 = Cogtainer::open(Cursor::new(buf)).unwrap();
+        assert_after(&c2);
+    }
+
+    #[test]
+    fn dropped_transaction_changes_nothing() {
+        let mut c = base_container();
+        let data_end = c.header.footer_offset;
+        {
+            let mut tx = c.transaction().unwrap();
+            tx.insert_block(&Identifier::U64(0), rmpv::Value::Nil, &[0xAA; 40])
+                .unwrap()
+                .delete_block(&Identifier::U64(1))
+                .unwrap()
+                .set_metadata(rmpv::Value::from("after"));
+        }
+        assert_before(&c);
+
+        c.flush().unwrap();
+        // the space reserved for the staged block is free again, at the end of the data
+        assert!(c.footer.empty_space.is_empty());
+        assert_eq!(c.header.footer_offset, data_end);
+        let buf = c.into_inner().unwrap().into_inner();
+        let c2 = Cogtainer::open(Cursor::new(buf)).unwrap();
+        assert_before(&c2);
+    }
+
+    #[test]
+    fn delete_respects_earlier_changes_in_the_transaction() {
+        let mut c = base_container();
+        let mut tx = c.transaction().unwrap();
+        let missing = Identifier::U64(9);
+        assert!(matches!(
+            tx.delete_block(&missing),
+            Err(CogtainerError::BlockNotFound(_))
+        ));
+        tx.insert_block(&missing, rmpv::Value::Nil, b"staged")
+            .unwrap();
+        tx.delete_block(&missing).unwrap();
+        assert!(matches!(
+            tx.delete_block(&missing),
+            Err(CogtainerError::BlockNotFound(_))
+        ));
+        tx.commit().unwrap();
+        assert!(c.get_block(&missing).is_err());
+    }
+
+    #[test]
+    fn transaction_flushes_pending_deletes_first() {
+        let mut c = base_container();
+        c.delete_block(&Identifier::U64(2)).unwrap();
+        let tx = c.transaction().unwrap();
+        tx.rollback();
+
+        let buf = c.into_inner().unwrap().into_inner();
+        let c2 = Cogtainer::open(Cursor::new(buf)).unwrap();
+        assert!(c2.get_block(&Identifier::U64(2)).is_err());
+    }
+
+    /// Crashes the transaction after every possible number of writes, returning the file contents
+    /// of the runs where the journal was committed but not applied.
+    fn crash_at_every_write() -> Vec<Vec<u8>> {
+        let base = base_container().into_inner().unwrap().into_inner();
+        let mut pending_journals = vec![];
+        for writes_left in 0.. {
+            let file = CrashingFile {
+                inner: Cursor::new(base.clone()),
+                writes_left,
+            };
+            let mut c = Cogtainer::open(file).unwrap();
+            let finished = run_transaction(&mut c).is_ok();
+            let buf = c.into_inner().unwrap().inner.into_inner();
+
+            let header = ContainerHeader::read_from(&buf).unwrap();
+            let footer = ContainerFooter::read_from(&buf, &header).unwrap();
+            let reopened = Cogtainer::open(Cursor::new(buf.clone()))
+                .unwrap_or_else(|e| panic!("crash after {writes_left} writes: {e}"));
+            if footer.journal.is_some() {
+                assert_after(&reopened);
+                pending_journals.push(buf);
+            } else if finished {
+                assert_after(&reopened);
+                break;
+            } else if footer.metadata == rmpv::Value::Nil {
+                assert_before(&reopened);
+            } else {
+                // crashed while moving the final footer into place
+                assert_after(&reopened);
+            }
+        }
+        pending_journals
+    }
+
+    #[test]
+    fn crash_at_any_write_is_all_or_nothing() {
+        let pending = crash_at_every_write();
+        assert!(
+            !pending.is_empty(),
+            "some crash should have happened with the journal committed"
+        );
+    }
+
+    #[test]
+    fn damaged_journal_rolls_back() {
+        let mut buf = crash_at_every_write().remove(0);
+        let header = ContainerHeader::read_from(&buf).unwrap();
+        let footer = ContainerFooter::read_from(&buf, &header).unwrap();
+        let journal = footer.journal.unwrap();
+        buf[journal.offset.0 as usize..][..journal.length as usize].fill(0xFF);
+
+        let mut c = Cogtainer::open(Cursor::new(buf)).unwrap();
+        assert_before(&c);
+        assert!(c.footer.journal.is_none());
+        // the container is still writable
+        c.insert_block(&Identifier::U64(4), rmpv::Value::Nil, b"ok")
+            .unwrap();
+        let buf = c.into_inner().unwrap().into_inner();
+        let c2 = Cogtainer::open(Cursor::new(buf)).unwrap();
+        assert_before(&c2);
+        assert_eq!(c2.get_block(&Identifier::U64(4)).unwrap().1, b"ok");
+    }
+}
@@ -0,0 +1,150 @@
+#[cfg(test)]
+mod truncate_tests {
+    use crate::{basic_api::Cogtainer, container_file::*, traits::Truncate};
+
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+    fn id(i: u64) -> Identifier {
+        Identifier::U64(i)
+    }
+
+    #[test]
+    fn cursor_and_file() {
+        let mut cursor = Cursor::new(vec![1u8; 100]);
+        cursor.truncate(40).unwrap();
+        assert_eq!(cursor.get_ref().len(), 40);
+        cursor.truncate(80).unwrap();
+        assert_eq!(cursor.get_ref().len(), 40);
+
+        let path = std::env::temp_dir().join(format!("cogtainer-truncate-{}", std::process::id()));
+        let mut file = std::fs::File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(&[1u8; 100]).unwrap();
+        file.truncate(40).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), 40);
+        file.truncate(80).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), 40);
+        drop(file);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn defragment_then_truncate() {
+        let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+        for i in 0..10 {
+            c.insert_block(&id(i), rmpv::Value::Nil, &[i as u8; 500])
+                .unwrap();
+        }
+        for i in 0..8 {
+            c.delete_block(&id(i)).unwrap();
+        }
+        let before = c.get_inner_file().get_ref().len() as u64;
+        c.defragment_then_truncate().unwrap();
+        let length = c.get_inner_file().get_ref().len() as u64;
+        assert_eq!(length, c.file_length());
+        assert!(length < before);
+
+        let buf = c.into_inner().unwrap().into_inner();
+        let c = Cogtainer::open(Cursor::new(buf)).unwrap();
+        assert_eq!(c.get_block(&id(9)).unwrap().1, vec![9; 500]);
+        assert!(c.verify().is_clean());
+    }
+
+    #[test]
+    fn truncating_keeps_a_committed_footer_elsewhere() {
+        // after salvaging, the committed footer is past the end of the old file
+        let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+        c.set_inline_block_headers(true);
+        c.insert_block(&id(1), rmpv::Value::Nil, b"kept").unwrap();
+        let data_end = c.header.footer_offset.0 as usize;
+        let mut buf = c.into_inner().unwrap().into_inner();
+        buf[data_end..].fill(0);
+        let mut c = Cogtainer::salvage(Cursor::new(buf)).unwrap();
+        assert_ne!(c.header.committed_footer().offset, c.header.footer_offset);
+
+        c.defragment_then_truncate().unwrap();
+        let buf = c.into_inner().unwrap().into_inner();
+        let c = Cogtainer::open(Cursor::new(buf)).unwrap();
+        assert_eq!(c.get_block(&id(1)).unwrap().1, b"kept");
+    }
+
+    #[test]
+    fn internal_file_releases_the_tail() {
+        let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+        c.insert_block(&id(1), rmpv::Value::Nil, &[1; 1000])
+            .unwrap();
+        c.insert_block(&id(2), rmpv::Value::Nil, b"after").unwrap();
+        let offset = c.get_blocks_list().unwrap()[&id(1)].file_offset;
+        {
+            let mut f = c.get_block_as_file(&id(1));
+            f.truncate(2000).unwrap();
+            f.truncate(300).unwrap();
+            // the cursor stays where it was
+            f.seek(SeekFrom::Start(250)).unwrap();
+            let mut tail = vec![];
+            f.read_to_end(&mut tail).unwrap();
+            assert_eq!(tail, vec![1; 50]);
+        }
+        let block = &c.get_blocks_list().unwrap()[&id(1)];
+        assert_eq!((block.used_length, block.allocated_length), (300, 300));
+        assert_eq!(
+            c.footer.empty_space.get(&offset.end_offset(300)),
+            Some(&700)
+        );
+        assert!(c.verify().is_clean());
+
+        let buf = c.into_inner().unwrap().into_inner();
+        let c = Cogtainer::open(Cursor::new(buf)).unwrap();
+        assert_eq!(c.get_block(&id(1)).unwrap().1, vec![1; 300]);
+        assert_eq!(c.get_block(&id(2)).unwrap().1, b"after");
+    }
+
+    #[test]
+    fn internal_file_chunks_and_inline_headers() {
+        let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+        c.set_inline_block_headers(true);
+        c.set_checksum_granularity(ChecksumGranularity::Chunks);
+        let data: Vec<u8> = (0..CHUNK_SIZE * 3).map(|i| (i % 251) as u8).collect();
+        c.insert_block(&id(1), rmpv::Value::Nil, &data).unwrap();
+        let length = CHUNK_SIZE + 10;
+        c.get_block_as_file(&id(1)).truncate(length).unwrap();
+
+        let block = &c.get_blocks_list().unwrap()[&id(1)];
+        assert_eq!(block.chunk_checksums.len(), 2);
+        assert_eq!(block.allocated_length, block.header_length + length);
+        assert_eq!(c.get_block(&id(1)).unwrap().1, &data[..length as usize]);
+        assert!(c.verify().is_clean());
+
+        // the inline header was rewritten, so the block can still be salvaged
+        let data_end = c.header.footer_offset.0 as usize;
+        let mut buf = c.into_inner().unwrap().into_inner();
+        buf[data_end..].fill(0);
+        let c = Cogtainer::salvage(Cursor::new(buf)).unwrap();
+        assert_eq!(c.get_block(&id(1)).unwrap().1, &data[..length as usize]);
+    }
+
+    #[test]
+    fn internal_file_to_zero() {
+        let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+        c.set_checksum_granularity(ChecksumGranularity::Chunks);
+        c.insert_block(&id(1), rmpv::Value::from("meta"), &[1; 100])
+            .unwrap();
+        c.insert_block(&id(2), rmpv::Value::Nil, b"after").unwrap();
+        c.get_block_as_file(&id(1)).truncate(0).unwrap();
+        assert_eq!(c.get_blocks_list().unwrap()[&id(1)].allocated_length, 0);
+        assert_eq!(
+            c.get_block(&id(1)).unwrap(),
+            (&rmpv::Value::from("meta"), vec![])
+        );
+        assert!(c.verify().is_clean());
+
+        // writing again starts a new allocation
+        c.get_block_as_file(&id(1)).write_all(b"again").unwrap();
+        assert_eq!(c.get_block(&id(1)).unwrap().1, b"again");
+    }
+}
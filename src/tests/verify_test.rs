@@ -0,0 +1,164 @@
+#[cfg(test)]
+mod verify_tests {
+    use crate::{basic_api::Cogtainer, container_file::*};
+
+    use std::io::{Cursor, Seek, SeekFrom, Write};
+
+    fn id(i: u64) -> Identifier {
+        Identifier::U64(i)
+    }
+
+    /// A container with five 32 byte blocks and a hole where block 1 used to be.
+    fn test_container() -> Cogtainer<Cursor<Vec<u8>>> {
+        let mut c = Cogtainer::create(Cursor::new(vec![])).unwrap();
+        for i in 0..5 {
+            c.insert_block(&id(i), rmpv::Value::from(i), &[i as u8; 32])
+                .unwrap();
+        }
+        c.delete_block(&id(1)).unwrap();
+        c.flush().unwrap();
+        c
+    }
+
+    #[test]
+    fn healthy_container_is_clean() {
+        let mut c = test_container();
+        assert!(c.verify().is_clean());
+
+        c.insert_block(&id(10), rmpv::Value::Nil, &[1; 8]).unwrap();
+        c.insert_block(&id(11), rmpv::Value::Nil, &[]).unwrap();
+        c.insert_block(&id(2), rmpv::Value::Nil, &[2; 100]).unwrap();
+        assert!(c.verify().is_clean());
+
+        c.defragment().unwrap();
+        assert!(c.verify().is_clean());
+
+        let buf = c.into_inner().unwrap().into_inner();
+        let c = Cogtainer::open(Cursor::new(buf)).unwrap();
+        assert!(c.verify().is_clean());
+    }
+
+    #[test]
+    fn corrupt_block_is_quarantined() {
+        let mut c = test_container();
+        let offset = c.footer.blocks[&id(3)].file_offset;
+        c.file.seek(SeekFrom::Start(offset.0 + 4)).unwrap();
+        c.file.write_all(b"oops").unwrap();
+
+        let report = c.verify();
+        assert_eq!(
+            report.problems,
+            vec![IntegrityProblem::CorruptBlock { identifier: id(3) }]
+        );
+
+        assert_eq!(c.repair().unwrap(), report);
+        assert_eq!(c.verify(), VerifyReport::default());
+        assert!(c.get_block(&id(3)).is_err());
+        assert_eq!(c.get_block(&id(4)).unwrap().1, [4; 32]);
+
+        // the quarantine is persisted, and its space isn't reused
+        let buf = c.into_inner().unwrap().into_inner();
+        let mut c = Cogtainer::open(Cursor::new(buf)).unwrap();
+        assert_eq!(c.get_quarantined_blocks()[&id(3)].file_offset, offset);
+        c.insert_block(&id(5), rmpv::Value::Nil, &[5; 32]).unwrap();
+        assert_ne!(c.footer.blocks[&id(5)].file_offset, offset);
+        assert!(c.verify().is_clean());
+
+        c.clear_quarantine().unwrap();
+        assert!(c.get_quarantined_blocks().is_empty());
+        assert!(c.footer.empty_space.contains_key(&offset));
+        assert!(c.verify().is_clean());
+    }
+
+    #[test]
+    fn overlapping_blocks_are_detected() {
+        let mut c = test_container();
+        let mut moved = c.footer.blocks[&id(3)].clone();
+        moved.file_offset = c.footer.blocks[&id(2)].file_offset.end_offset(16);
+        moved.checksum = c.footer.blocks[&id(2)].checksum;
+        c.footer.blocks.insert(id(3), moved);
+
+        let report = c.verify();
+        assert!(report
+            .problems
+            .contains(&IntegrityProblem::CorruptBlock { identifier: id(3) }));
+        assert!(report
+            .problems
+            .contains(&IntegrityProblem::OverlappingBlocks {
+                first: id(2),
+                second: id(3),
+            }));
+
+        c.repair().unwrap();
+        assert!(c.verify().is_clean());
+        assert!(c.get_quarantined_blocks().contains_key(&id(3)));
+        assert_eq!(c.get_block(&id(2)).unwrap().1, [2; 32]);
+    }
+
+    #[test]
+    fn only_the_corrupt_one_of_overlapping_blocks_is_quarantined() {
+        let mut c = test_container();
+        // block 2 claims the first half of block 3, which still verifies
+        let mut moved = c.footer.blocks[&id(2)].clone();
+        moved.file_offset = FileOffset(c.footer.blocks[&id(3)].file_offset.0 - 16);
+        c.footer.blocks.insert(id(2), moved);
+
+        let report = c.verify();
+        assert!(report
+            .problems
+            .contains(&IntegrityProblem::CorruptBlock { identifier: id(2) }));
+        assert!(report
+            .problems
+            .contains(&IntegrityProblem::OverlappingBlocks {
+                first: id(2),
+                second: id(3),
+            }));
+
+        c.repair().unwrap();
+        assert!(c.verify().is_clean());
+        assert!(c.get_quarantined_blocks().contains_key(&id(2)));
+        assert!(!c.get_quarantined_blocks().contains_key(&id(3)));
+        assert_eq!(c.get_block(&id(3)).unwrap().1, [3; 32]);
+    }
+
+    #[test]
+    fn inconsistent_empty_space_is_rebuilt() {
+        let mut c = test_container();
+        let hole = c.footer.blocks[&id(0)].file_offset.end_offset(32);
+        let block_2 = c.footer.blocks[&id(2)].file_offset;
+        assert_eq!(c.footer.empty_space.get(&hole), Some(&32));
+
+        // forget the hole, and claim part of block 2 is free
+        c.footer.empty_space.clear();
+        c.footer.empty_space.insert(block_2.end_offset(8), 8);
+        let data_end = c.header.footer_offset;
+        c.footer.empty_space.insert(data_end, 8);
+
+        let report = c.verify();
+        assert_eq!(
+            report.problems,
+            vec![
+                IntegrityProblem::UnaccountedSpace {
+                    offset: hole,
+                    length: 32
+                },
+                IntegrityProblem::EmptySpaceOverlapsBlock {
+                    offset: block_2.end_offset(8),
+                    identifier: id(2)
+                },
+                IntegrityProblem::EmptySpacePastEnd {
+                    offset: data_end,
+                    length: 8
+                },
+            ]
+        );
+
+        c.repair().unwrap();
+        assert!(c.verify().is_clean());
+        assert_eq!(c.footer.empty_space.get(&hole), Some(&32));
+        assert!(c.get_quarantined_blocks().is_empty());
+        for i in [0, 2, 3, 4] {
+            assert_eq!(c.get_block(&id(i)).unwrap().1, [i as u8; 32]);
+        }
+    }
+}
@@ -1,3 +1,72 @@
+use std::io;
+
+/// Storage that can be shrunk, such as to give back the space freed by `defragment`.
 pub trait Truncate {
-    fn truncate(&self, offset: u64) -> Result<(), ()>;
+    /// Shrinks to `length` bytes. Does nothing if it is not longer than that.
+    fn truncate(&mut self, length: u64) -> io::Result<()>;
+}
+impl Truncate for std::fs::File {
+    fn truncate(&mut self, length: u64) -> io::Result<()> {
+        if self.metadata()?.len() > length {
+            self.set_len(length)?;
+        }
+        Ok(())
+    }
+}
+impl Truncate for io::Cursor<Vec<u8>> {
+    fn truncate(&mut self, length: u64) -> io::Result<()> {
+        let length = usize::try_from(length).unwrap_or(usize::MAX);
+        self.get_mut().truncate(length);
+        Ok(())
+    }
+}
+
+/// Storage that can give freed space in the middle back to the file system. Implemented for
+/// `File` on Linux (with `fallocate`). Reserving space ahead of writes is part of `Storage`.
+pub trait Sparse {
+    /// Deallocates `len` bytes at `offset`, which read back as zeros. The length doesn't change.
+    fn punch_hole(&mut self, offset: u64, len: u64) -> io::Result<()>;
+}
+#[cfg(target_os = "linux")]
+impl Sparse for std::fs::File {
+    fn punch_hole(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        fallocate(
+            self,
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            offset,
+            len,
+        )
+    }
+}
+#[cfg(target_os = "linux")]
+pub(crate) fn fallocate(
+    file: &std::fs::File,
+    mode: libc::c_int,
+    offset: u64,
+    len: u64,
+) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let to_off_t = |value: u64| {
+        libc::off_t::try_from(value).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))
+    };
+    // SAFETY: the descriptor is owned by `file`, which outlives the call
+    let result =
+        unsafe { libc::fallocate(file.as_raw_fd(), mode, to_off_t(offset)?, to_off_t(len)?) };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+impl Sparse for io::Cursor<Vec<u8>> {
+    fn punch_hole(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        let buf = self.get_mut();
+        let start = usize::try_from(offset).unwrap_or(usize::MAX).min(buf.len());
+        let end = usize::try_from(offset.saturating_add(len))
+            .unwrap_or(usize::MAX)
+            .min(buf.len());
+        buf[start..end].fill(0);
+        Ok(())
+    }
 }
@@ -0,0 +1,130 @@
+use crate::{
+    basic_api::Cogtainer,
+    container_file::{Identifier, Journal, JournalOp},
+    error::CogtainerError,
+    storage::Storage,
+};
+
+/// A set of changes that are applied to a container together, or not at all.
+///
+/// Block data is written to free space as soon as it is inserted, but nothing is visible through
+/// the container until `commit()`. Dropping the transaction without committing discards it.
+pub struct Transaction<'a, F: Storage> {
+    container: &'a mut Cogtainer<F>,
+    journal: Journal,
+}
+impl<'a, F: Storage> Transaction<'a, F> {
+    pub(crate) fn new(container: &'a mut Cogtainer<F>) -> Self {
+        Self {
+            container,
+            journal: Journal::default(),
+        }
+    }
+
+    /// Inserts a block with the given unique identifier.
+    /// If a block already exists with the given identifier, it will be replaced.
+    pub fn insert_block(
+        &mut self,
+        identifier: &Identifier,
+        metadata: rmpv::Value,
+        data: &[u8],
+    ) -> Result<&mut Self, CogtainerError> {
+        let container = &mut *self.container;
+        let descriptor = container.footer.stage_block(
+            &mut container.file,
+            &mut container.header,
+            container.overallocation_policy,
+            identifier,
+            metadata,
+            data,
+        )?;
+        self.journal.ops.push(JournalOp::InsertBlock {
+            identifier: identifier.clone(),
+            descriptor,
+        });
+        Ok(self)
+    }
+
+    /// Deletes the specified block. Returns an error if the block doesn't exist, taking earlier
+    /// changes in this transaction into account.
+    pub fn delete_block(&mut self, identifier: &Identifier) -> Result<&mut Self, CogtainerError> {
+        let container = &*self.container;
+        container.footer.load_block(&container.file, identifier)?;
+        if !self.block_exists(identifier) {
+            return Err(CogtainerError::BlockNotFound(identifier.clone()));
+        }
+        self.journal.ops.push(JournalOp::DeleteBlock {
+            identifier: identifier.clone(),
+        });
+        Ok(self)
+    }
+
+    /// Updates container-wide metadata
+    pub fn set_metadata(&mut self, metadata: rmpv::Value) -> &mut Self {
+        self.journal.ops.push(JournalOp::SetMetadata { metadata });
+        self
+    }
+
+    /// Updates the metadata for the given block.
+    /// If the block doesn't exist, it is added with a length of 0.
+    pub fn update_block_metadata(
+        &mut self,
+        identifier: &Identifier,
+        metadata: rmpv::Value,
+    ) -> &mut Self {
+        self.journal.ops.push(JournalOp::UpdateBlockMetadata {
+            identifier: identifier.clone(),
+            metadata,
+        });
+        self
+    }
+
+    /// Writes the journal, then applies every change and commits the result.
+    /// If the process dies before this returns, opening the file either replays the journal or
+    /// finds the container as it was before the transaction.
+    pub fn commit(mut self) -> Result<(), CogtainerError> {
+        let container = &mut *self.container;
+        container
+            .footer
+            .load_journal_blocks(&container.file, &self.journal)?;
+        container.footer.write_journal(
+            &mut container.file,
+            &mut container.header,
+            &self.journal,
+        )?;
+        container.footer.apply_journal(&self.journal);
+        // the changes belong to the footer now, there's nothing left to discard
+        self.journal.ops.clear();
+
+        container
+            .footer
+            .persist(&mut container.file, &mut container.header)?;
+        container.file.sync()?;
+        Ok(())
+    }
+
+    /// Discards every change made in this transaction. Same as dropping it.
+    pub fn rollback(self) {}
+
+    fn block_exists(&self, identifier: &Identifier) -> bool {
+        for op in self.journal.ops.iter().rev() {
+            match op {
+                JournalOp::InsertBlock { identifier: id, .. }
+                | JournalOp::UpdateBlockMetadata { identifier: id, .. }
+                    if id == identifier =>
+                {
+                    return true
+                }
+                JournalOp::DeleteBlock { identifier: id } if id == identifier => return false,
+                _ => {}
+            }
+        }
+        self.container.footer.blocks.contains_key(identifier)
+    }
+}
+impl<'a, F: Storage> Drop for Transaction<'a, F> {
+    fn drop(&mut self) {
+        // give the space reserved for staged blocks back
+        self.container.footer.discard_journal(&self.journal);
+    }
+}